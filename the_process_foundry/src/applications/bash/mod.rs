@@ -6,11 +6,12 @@
 //!        portions
 
 const APP_NAME: &str = "Bash";
-const MODULE_VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const MODULE_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 use anyhow::{Context, Result};
+use async_trait::async_trait;
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::process::Command;
 
 // This should likely be enumerated as it will be forked off into a separate project sooner rather than later
@@ -23,16 +24,17 @@ pub struct Bash {
   // HACK: This should be a registry/cache rather than a simple hashmap
   app_cache: HashMap<String, AppInstance>,
   instance: AppInstance,
+
+  /// How to run commands as another user. If not set, it is picked based on who we are running as.
+  escalation: Option<Escalation>,
 }
 
 impl Bash {
   fn get_module_version() -> Result<semver::Version> {
-    Ok({
-      semver::Version::parse(MODULE_VERSION).context(format!(
-        "{} has an invalid version number '{}' Cargo.toml",
-        APP_NAME, MODULE_VERSION
-      ))
-    }?)
+    semver::Version::parse(MODULE_VERSION).context(format!(
+      "{} has an invalid version number '{}' Cargo.toml",
+      APP_NAME, MODULE_VERSION
+    ))
   }
 
   fn get_name(&self) -> String {
@@ -41,13 +43,61 @@ impl Bash {
       None => format!("{} (Unknown Version)", APP_NAME),
     }
   }
+
+  /// Choose how commands with a run_as are run as the other user
+  pub fn set_escalation(&self, escalation: Escalation) -> Bash {
+    Bash {
+      escalation: Some(escalation),
+      ..self.clone()
+    }
+  }
+
+  pub fn run_action(&self, action: Action) -> Result<ActionResult> {
+    action.run(self.clone())
+  }
+
+  pub async fn run_action_async(&self, action: Action) -> Result<ActionResult> {
+    action.run_async(self.clone()).await
+  }
 }
 
 impl LocalTrait for Bash {
-  //
+  /// The bash found on the path
   fn get_local() -> Result<AppInstance> {
-    // TODO: Actually fill out the app instance
-    Ok(AppInstance::new("bash".to_string()))
+    Bash::get_local_at("bash")
+  }
+}
+
+impl Bash {
+  /// Ask the local bash at the path (or name on the path) where it lives and what version it is
+  pub fn get_local_at(path: &str) -> Result<AppInstance> {
+    let output = Command::new(path)
+      .args(["-c", r#"printf '%s\n%s\n' "$BASH" "$BASH_VERSION""#])
+      .output()
+      .context(format!("Could not run the local bash at '{}'", path))?;
+    if !output.status.success() {
+      Err(FoundryError::RemoteError).context(format!(
+        "Bash at '{}' failed to report its version:\n{}",
+        path,
+        String::from_utf8_lossy(&output.stderr).trim_end()
+      ))?;
+    }
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let mut lines = stdout.lines();
+    let location = lines.next().unwrap_or(path).to_string();
+    // eg: "5.1.16(1)-release"
+    let version = lines.next().and_then(|ver| {
+      let regex = regex::Regex::new(r"^(\d+)\.(\d+)\.(\d+)").unwrap();
+      regex
+        .captures(ver)
+        .and_then(|cap| semver::Version::parse(&format!("{}.{}.{}", &cap[1], &cap[2], &cap[3])).ok())
+    });
+
+    Ok(AppInstance {
+      version,
+      ..AppInstance::new("bash".to_string()).set_command_path(None, location)?
+    })
   }
 }
 
@@ -56,9 +106,10 @@ impl AppTrait for Bash {
     self.get_name()
   }
 
-  fn build(instance: AppInstance, _parent: Option<Rc<dyn ContainerTrait>>) -> Result<Bash> {
+  fn build(instance: AppInstance, _parent: Option<Arc<dyn ContainerTrait>>) -> Result<Bash> {
     Ok(Bash {
       app_cache: HashMap::new(),
+      escalation: None,
       instance: AppInstance {
         module_version: Some(Bash::get_module_version()?),
         ..instance.clone()
@@ -68,23 +119,31 @@ impl AppTrait for Bash {
 
   /// Knows how to get the version number of the installed app (not the module version)
   fn set_version(&self, _instance: AppInstance) -> Result<AppInstance> {
-    unimplemented!()
+    Err(FoundryError::NotImplemented).context(format!("{} can't look up its version yet", APP_NAME))
   }
   /// Figures out how to call the cli using the given container
   fn set_cli(
     &self,
     _instance: AppInstance,
-    _container: Rc<dyn ContainerTrait>,
+    _container: Arc<dyn ContainerTrait>,
   ) -> Result<AppInstance> {
-    unimplemented!()
+    Err(FoundryError::NotImplemented).context(format!("{} can't set its cli yet", APP_NAME))
   }
 }
 
+#[async_trait]
 impl ContainerTrait for Bash {
   /// This will find a list of apps with configurations that the container knows about
   fn find(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
     match Action::FindApp(FindAppQuery(query)).run(self.clone())? {
-      ActionResult::FindApp(result) => Ok(result),
+      ActionResult::FindApp(result) => result
+        .into_iter()
+        .map(|app| {
+          // Replace the cli so it points back to this shell
+          let path = app.get_command_path()?;
+          AppInstance { cli: None, ..app }.set_command_path(Some(Arc::new(self.clone())), path)
+        })
+        .collect(),
       x => Err(FoundryError::Unreachable).context(format!(
         "Received a non-FindApp Result from Bash::find:\n{:#?}",
         x
@@ -94,11 +153,47 @@ impl ContainerTrait for Bash {
 
   /// List the known items in the app cache
   fn cached_apps(&self) -> Result<Vec<AppInstance>> {
-    unimplemented!("No App Cache for Bash Yet")
+    Err(FoundryError::NotImplemented).context("No App Cache for Bash Yet")
   }
 
+  /// Run the command on the local machine and return its output
   fn forward(&self, to: AppInstance, message: Message) -> Result<String> {
-    unimplemented!("No ContainerTrait::forward yet")
+    block_on(self.forward_async(to, message))
+  }
+
+  /// Run the command on the local machine without blocking
+  async fn forward_async(&self, _to: AppInstance, message: Message) -> Result<String> {
+    match message {
+      Message::Command(cmd) => {
+        let action = Action::Run(RunOptions {
+          command: cmd.command,
+          args: cmd.args,
+          run_as: cmd.run_as,
+          env: cmd.env,
+          workdir: cmd.workdir,
+          escalation: None,
+          timeout: cmd.timeout,
+          cancel: cmd.cancel,
+        });
+        match self.run_action_async(action).await? {
+          ActionResult::Run(RunResult(output)) => Ok(output),
+          x => Err(FoundryError::Unreachable).context(format!(
+            "Received a non-Run Result from Bash::forward:\n{:#?}",
+            x
+          )),
+        }
+      }
+      _ => Err(FoundryError::UnexpectedValue)
+        .context(format!("{} can only forward commands", self.get_name())),
+    }
+  }
+
+  /// Commands already run locally, so only switching users needs anything extra
+  fn local_command(&self, cmd: Cmd) -> Result<Cmd> {
+    match &cmd.run_as {
+      Some(user) => Ok(self.escalation.unwrap_or_else(Escalation::detect).wrap(user, &cmd)),
+      None => Ok(cmd),
+    }
   }
 
   /// Get the name/version of the container, usually for use in logging/errors.
@@ -123,7 +218,25 @@ pub enum ActionResult {
 impl Action {
   fn run(&self, target: Bash) -> Result<ActionResult> {
     match self {
-      Action::Run(_opts) => unimplemented!("Don't know how to run a command on Bash yet"),
+      Action::Run(opts) => RunOptions {
+        escalation: opts.escalation.or(target.escalation),
+        ..opts.clone()
+      }
+      .run(target.instance),
+      Action::FindApp(query) => query.run(target.instance),
+    }
+  }
+
+  async fn run_async(&self, target: Bash) -> Result<ActionResult> {
+    match self {
+      Action::Run(opts) => {
+        RunOptions {
+          escalation: opts.escalation.or(target.escalation),
+          ..opts.clone()
+        }
+        .run_async(target.instance)
+        .await
+      }
       Action::FindApp(query) => query.run(target.instance),
     }
   }
@@ -133,39 +246,88 @@ impl Action {
 pub struct RunOptions {
   pub command: String,
   pub args: Vec<String>,
+  /// Run the command as this user instead of the one running the foundry
+  pub run_as: Option<String>,
+  /// Extra variables to set for the command
+  pub env: BTreeMap<String, String>,
+  /// The directory to run the command in, instead of the current one
+  pub workdir: Option<String>,
+  /// How to switch to the run_as user. Detected if not set.
+  pub escalation: Option<Escalation>,
+  /// Kill the command if it runs for longer than this
+  #[serde(default)]
+  pub timeout: Option<std::time::Duration>,
+  /// Kill the command when this is cancelled
+  #[serde(skip)]
+  pub cancel: Option<CancellationToken>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RunResult(String);
+pub struct RunResult(pub String);
 
+#[async_trait]
 impl ActionTrait for RunOptions {
   type RESPONSE = ActionResult;
 
-  fn run(&self, _target: AppInstance) -> Result<Self::RESPONSE> {
-    let result = Command::new(&self.command).args(&self.args).output();
+  fn run(&self, target: AppInstance) -> Result<Self::RESPONSE> {
+    block_on(self.run_async(target))
+  }
 
-    // This should be another command based on ActionDefinition
-    // TODO: Figure out how errors are returned (stderr vs stdout)
-    match result {
-      Ok(output) => Ok(ActionResult::Run(RunResult(String::from_utf8(
-        output.stdout,
-      )?))),
-      Err(err) => {
-        let msg = format!(
-          "Error running the command:\n\tcmd: {:#?}\n\terr: {:#?}",
-          self, err,
-        );
-        log::warn!("{}", msg);
-        Err(FoundryError::UnhandledError).context(msg)
+  async fn run_async(&self, _target: AppInstance) -> Result<Self::RESPONSE> {
+    let cmd = Cmd {
+      env: self.env.clone(),
+      workdir: self.workdir.clone(),
+      timeout: self.timeout,
+      cancel: self.cancel.clone(),
+      ..Cmd::argv(self.command.clone(), self.args.clone())
+    };
+    let (cmd, escalation) = match &self.run_as {
+      None => (cmd, None),
+      Some(user) => {
+        let escalation = self.escalation.unwrap_or_else(Escalation::detect);
+        (escalation.wrap(user, &cmd), Some(escalation))
       }
+    };
+
+    // stdin is closed, so password requests fail rather than hang
+    let output = cmd.output().await?;
+    if output.status.success() {
+      return Ok(ActionResult::Run(RunResult(
+        String::from_utf8(output.stdout)?.trim_end().to_string(),
+      )));
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr).trim_end().to_string();
+    match (&self.run_as, escalation) {
+      (Some(user), Some(escalation)) if escalation.needs_password(&stderr) => {
+        Err(FoundryError::NotConfigured).context(format!(
+          "Running '{}' as {} with {:?} needs a password, which can't be entered here. Set up passwordless \
+           access for it or run the foundry as root:\n{}",
+          self.command, user, escalation, stderr
+        ))
+      }
+      _ => Err(FoundryError::RemoteError).context(format!(
+        "'{}' failed with exit code {}:\n{}",
+        cmd.to_script(ShellType::Bash),
+        output
+          .status
+          .code()
+          .map_or("unknown".to_string(), |x| x.to_string()),
+        stderr
+      )),
     }
   }
 
   fn to_message(&self, _target: Option<AppInstance>) -> Result<Vec<Message>> {
     let message = Message::Command(Cmd {
-      run_as: None,
+      run_as: self.run_as.clone(),
       command: self.command.clone(),
       args: self.args.clone(),
+      env: self.env.clone(),
+      workdir: self.workdir.clone(),
+      timeout: self.timeout,
+      cancel: self.cancel.clone(),
+      escalated: None,
     });
     // TODO: change this to use target.CliAccess.path instead of bash
     Ok(vec![message])
@@ -182,18 +344,20 @@ impl ActionTrait for FindAppQuery {
   /// Find the first app that matches the conditions of AppDefinition (name, version,  path, etc)
   /// TODO: Convert this to reuse "Run"
   fn run(&self, target: AppInstance) -> Result<Self::RESPONSE> {
-    let result = Command::new("bash")
-      .args(&["-c", &format!("command -v {}", self.0.name)])
+    let bash = target.get_command_path().unwrap_or_else(|_| "bash".to_string());
+    let result = Command::new(bash)
+      .args(["-c", &ShellType::Bash.lookup(&self.0.name)])
       .output();
 
     // THis should be another command based on ActionDefinition
     match result {
-      Ok(_output) => {
-        // TODO: Set the networking/cli in the AppInstance
-        let app = AppInstance::new(self.0.name.clone());
+      Ok(output) if output.status.success() => {
+        // TODO: Set the networking in the AppInstance
+        let path = String::from_utf8(output.stdout)?.trim().to_string();
+        let app = AppInstance::new(self.0.name.clone()).set_command_path(None, path)?;
         Ok(ActionResult::FindApp(vec![app]))
       }
-      Err(_err) => {
+      _ => {
         let msg = format!(
           "{} could not find local executable for {}",
           target.full_name(),
@@ -206,6 +370,101 @@ impl ActionTrait for FindAppQuery {
   }
 
   fn to_message(&self, _target: Option<AppInstance>) -> Result<Vec<Message>> {
-    unimplemented!("ActionTrait not implemented for Bash::FindApp::to_string")
+    Err(FoundryError::NotImplemented).context("ActionTrait not implemented for Bash::FindApp::to_string")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  fn bash() -> Bash {
+    Bash::build(Bash::get_local().unwrap(), None).unwrap()
+  }
+
+  fn kind(err: &anyhow::Error) -> Option<&FoundryError> {
+    err.downcast_ref::<FoundryError>()
+  }
+
+  fn send(cmd: Cmd) -> Result<String> {
+    bash().forward(AppInstance::new("bash".to_string()), Message::Command(cmd))
+  }
+
+  #[test]
+  fn commands_run_locally_with_their_env_and_workdir() {
+    let cmd = Cmd {
+      env: vec![("GREETING".to_string(), "hello $USER".to_string())].into_iter().collect(),
+      workdir: Some("/".to_string()),
+      ..ShellType::Sh.script(r#"printf '%s in %s\n\n' "$GREETING" "$PWD""#.to_string())
+    };
+    assert_eq!(send(cmd).unwrap(), "hello $USER in /");
+
+    let err = send(ShellType::Sh.script("echo broken >&2; exit 2".to_string())).unwrap_err();
+    assert!(matches!(kind(&err), Some(FoundryError::RemoteError)));
+    assert!(format!("{:#}", err).contains("exit code 2:\nbroken"), "{:#}", err);
+  }
+
+  #[test]
+  fn forwarded_commands_keep_their_limits() {
+    let slow = Cmd::argv("sleep".to_string(), vec!["10".to_string()]).timeout(Duration::from_millis(200));
+    let started = std::time::Instant::now();
+    let err = block_on(bash().forward_async(AppInstance::new("sleep".to_string()), Message::Command(slow)))
+      .unwrap_err();
+    assert!(FoundryError::is_interrupted(&err), "{:#}", err);
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+
+  #[test]
+  fn only_commands_are_forwarded() {
+    let rest = Message::Rest(RestRequest::get("/".to_string()));
+    let err = bash().forward(AppInstance::new("bash".to_string()), rest).unwrap_err();
+    assert!(matches!(kind(&err), Some(FoundryError::UnexpectedValue)));
+  }
+
+  #[test]
+  fn local_commands_only_change_to_switch_users() {
+    let cmd = Cmd::argv("id".to_string(), vec!["-un".to_string()]);
+    let local = bash().local_command(cmd.clone()).unwrap();
+    assert_eq!(local.words(), cmd.words());
+
+    let as_postgres = Cmd {
+      run_as: Some("postgres".to_string()),
+      ..cmd
+    };
+    let local = bash().set_escalation(Escalation::Sudo).local_command(as_postgres.clone()).unwrap();
+    assert_eq!(local.command, "sudo");
+    assert_eq!(local.args, vec!["-n", "-u", "postgres", "--", "id", "-un"]);
+    assert_eq!(local.run_as, None);
+
+    // Root switches without a password, so it can actually run as someone else
+    if get_current_uid().as_deref() == Some("0") {
+      let nobody = Cmd {
+        run_as: Some("nobody".to_string()),
+        ..as_postgres
+      };
+      assert_eq!(send(nobody).unwrap(), "nobody");
+    }
+  }
+
+  #[test]
+  fn found_apps_point_back_to_bash() {
+    let found = bash().find(AppQuery::new("sh".to_string())).unwrap();
+    assert_eq!(found.len(), 1);
+    assert!(found[0].get_command_path().unwrap().ends_with("/sh"));
+    let container = found[0].cli.as_ref().and_then(|cli| cli.container.clone()).unwrap();
+    assert!(container.get_name().starts_with(APP_NAME));
+
+    let err = bash().find(AppQuery::new("no-such-program-here".to_string())).unwrap_err();
+    assert!(matches!(kind(&err), Some(FoundryError::NotFound)));
+  }
+
+  #[test]
+  fn unsupported_features_are_errors() {
+    assert!(matches!(kind(&bash().cached_apps().unwrap_err()), Some(FoundryError::NotImplemented)));
+    let err = bash().set_version(AppInstance::new("bash".to_string())).unwrap_err();
+    assert!(matches!(kind(&err), Some(FoundryError::NotImplemented)));
+    let query = FindAppQuery(AppQuery::new("sh".to_string()));
+    assert!(matches!(kind(&query.to_message(None).unwrap_err()), Some(FoundryError::NotImplemented)));
   }
 }
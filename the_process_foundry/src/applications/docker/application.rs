@@ -0,0 +1,1029 @@
+//! Docker functionality
+//!
+//! Talks directly to the Docker Engine API, so we can manage containers that weren't started by compose
+
+const APP_NAME: &str = "Docker";
+const MODULE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Where the docker daemon listens unless DOCKER_HOST says otherwise
+const DEFAULT_SOCKET: &str = "unix:///var/run/docker.sock";
+
+/// Where podman's docker compatible API listens when run as root
+const PODMAN_ROOT_SOCKET: &str = "/run/podman/podman.sock";
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::client::{self, Client, Response};
+use super::docker_container::Status;
+use super::docker_compose::schema::{split_image, version_from_tag};
+use super::FoundryError;
+use super::{ActionTrait, AppInstance, AppQuery, AppTrait, ContainerTrait, LocalTrait, Message};
+use super::{block_on, get_current_uid, ApiAccess, CancellationToken, Cmd, DockerContainer};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Docker {
+  instance: AppInstance,
+}
+
+impl LocalTrait for Docker {
+  /// The local daemon, using DOCKER_HOST (or podman's CONTAINER_HOST) if it points to a unix socket. Without
+  /// either, podman's socket is used when docker's doesn't exist, since podman serves the same API.
+  fn get_local() -> Result<AppInstance> {
+    let host = std::env::var("DOCKER_HOST").or_else(|_| std::env::var("CONTAINER_HOST"));
+    let uri = match host {
+      Ok(host) if host.starts_with("unix://") => host,
+      Ok(host) => {
+        log::warn!(
+          "The container host is set to '{}', but only unix sockets are supported. Using {}",
+          host,
+          DEFAULT_SOCKET
+        );
+        DEFAULT_SOCKET.to_string()
+      }
+      Err(_) => Docker::find_local_socket(),
+    };
+    Ok(AppInstance {
+      api: Some(ApiAccess::new(uri)),
+      ..AppInstance::new("docker".to_string())
+    })
+  }
+}
+
+impl Docker {
+  /// Docker's socket if it exists, otherwise the first podman one found. Rootless podman puts it in the
+  /// user's runtime directory.
+  fn find_local_socket() -> String {
+    let runtime_dir = std::env::var("XDG_RUNTIME_DIR")
+      .ok()
+      .or_else(|| get_current_uid().map(|uid| format!("/run/user/{}", uid)));
+    Docker::choose_socket(runtime_dir, |path| std::path::Path::new(path).exists())
+  }
+
+  /// Pick the socket to use from the ones that exist
+  fn choose_socket(runtime_dir: Option<String>, exists: impl Fn(&str) -> bool) -> String {
+    let podman = runtime_dir
+      .map(|dir| format!("{}/podman/podman.sock", dir))
+      .into_iter()
+      .chain(std::iter::once(PODMAN_ROOT_SOCKET.to_string()));
+
+    if exists(DEFAULT_SOCKET.trim_start_matches("unix://")) {
+      return DEFAULT_SOCKET.to_string();
+    }
+    for socket in podman {
+      if exists(&socket) {
+        log::debug!("The docker socket doesn't exist, using podman's at {}", socket);
+        return format!("unix://{}", socket);
+      }
+    }
+    DEFAULT_SOCKET.to_string()
+  }
+}
+
+impl AppTrait for Docker {
+  fn get_name(&self) -> String {
+    self.get_name()
+  }
+
+  /// If the instance doesn't say where the daemon is, the local one is used
+  fn build(instance: AppInstance, _parent: Option<Arc<dyn ContainerTrait>>) -> Result<Docker> {
+    let api = match instance.api.clone() {
+      Some(api) => api,
+      None => match Docker::get_local()?.api {
+        Some(api) => api,
+        None => Err(FoundryError::NotConfigured).context("Could not find the API of the local docker daemon")?,
+      },
+    };
+    Ok(Docker {
+      instance: AppInstance {
+        module_version: Some(Docker::get_module_version()?),
+        api: Some(api),
+        ..instance
+      },
+    })
+  }
+
+  /// Knows how to get the version number of the installed app (not the module version)
+  fn set_version(&self, instance: AppInstance) -> Result<AppInstance> {
+    let response = check(get_client(&instance)?.get("/version")?, "get the docker version")?;
+    let version = response.json()?["Version"]
+      .as_str()
+      .and_then(|x| semver::Version::parse(x).ok());
+    Ok(AppInstance { version, ..instance })
+  }
+
+  /// Figures out how to call the cli using the given container
+  fn set_cli(
+    &self,
+    _instance: AppInstance,
+    _container: Arc<dyn ContainerTrait>,
+  ) -> Result<AppInstance> {
+    Err(FoundryError::NotImplemented).context(format!("{} can't set its cli yet", APP_NAME))
+  }
+}
+
+#[async_trait]
+impl ContainerTrait for Docker {
+  /// Find containers by name or image name, using the image tag as the version
+  fn find(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
+    let names: Vec<String> = std::iter::once(query.name.clone())
+      .chain(query.aliases.clone().unwrap_or_default())
+      .map(|name| name.to_lowercase())
+      .collect();
+
+    let mut found = vec![];
+    for summary in self.list(true)? {
+      let (image_name, tag) = split_image(&summary.image);
+      let candidates: Vec<String> = summary
+        .get_names()
+        .into_iter()
+        .chain(vec![image_name, summary.image.clone()])
+        .map(|name| name.to_lowercase())
+        .collect();
+      if !candidates.iter().any(|name| names.contains(name)) {
+        continue;
+      }
+
+      let version = tag.and_then(|x| version_from_tag(&x));
+      if let Some(req) = &query.works_with {
+        match &version {
+          Some(ver) if req.matches(ver) => (),
+          _ => {
+            log::debug!(
+              "Skipping container {:?} since image {} does not match version {}",
+              summary.get_names(),
+              summary.image,
+              req
+            );
+            continue;
+          }
+        }
+      }
+
+      let container = self.container_from_summary(&summary)?;
+      let instance = AppInstance {
+        version,
+        ..container.instance.clone()
+      }
+      // The command is what runs in the container
+      .set_command_path(Some(Arc::new(container)), summary.command.clone())?;
+      found.push(instance);
+    }
+    Ok(found)
+  }
+
+  /// List the known items in the app cache
+  fn cached_apps(&self) -> Result<Vec<AppInstance>> {
+    Err(FoundryError::NotImplemented).context("No App Cache for Docker Yet")
+  }
+
+  fn forward(&self, to: AppInstance, message: Message) -> Result<String> {
+    block_on(self.forward_async(to, message))
+  }
+
+  /// Run the command inside the container using the exec API
+  async fn forward_async(&self, to: AppInstance, message: Message) -> Result<String> {
+    match message {
+      Message::Command(cmd) => {
+        let exec = ExecOptions {
+          timeout: cmd.timeout,
+          cancel: cmd.cancel.clone(),
+          ..ExecOptions::new(to.instance_id.unwrap_or(to.name), String::new())
+        }
+        .with_cmd(&cmd);
+        match exec.run_async(self.instance.clone()).await? {
+          ActionResult::Exec(val) => Ok(val),
+          err => Err(FoundryError::UnexpectedValue).context(format!(
+            "Running Docker::ExecOptions did not return an ExecResult:\n{:#?}",
+            err
+          )),
+        }
+      }
+      _ => Err(FoundryError::UnexpectedValue)
+        .context("Docker tried to forward a non-command to a container"),
+    }
+  }
+
+  /// Get the name/version of the container, usually for use in logging/errors.
+  fn get_name(&self) -> String {
+    self.get_name()
+  }
+}
+
+impl Docker {
+  fn get_module_version() -> Result<semver::Version> {
+    semver::Version::parse(MODULE_VERSION).context(format!(
+      "{} has an invalid version number '{}' Cargo.toml",
+      APP_NAME, MODULE_VERSION
+    ))
+  }
+
+  fn get_name(&self) -> String {
+    match &self.instance.version {
+      Some(ver) => format!("{} ({})", APP_NAME, ver),
+      None => format!("{} (Unknown Version)", APP_NAME),
+    }
+  }
+
+  pub fn run_action(&self, action: Action) -> Result<ActionResult> {
+    action.run(self.clone())
+  }
+
+  /// List the containers, including stopped ones if all is set
+  pub fn list(&self, all: bool) -> Result<Vec<ContainerSummary>> {
+    match self.run_action(Action::List(ListOptions { all }))? {
+      ActionResult::List(containers) => Ok(containers),
+      x => Err(FoundryError::UnexpectedValue).context(format!(
+        "Running Docker::ListOptions did not return a List result:\n{:#?}",
+        x
+      )),
+    }
+  }
+
+  /// Look up a container by name or id, so it can be managed without knowing how it was started
+  pub fn get_container(&self, name: String) -> Result<DockerContainer> {
+    let details = match self.run_action(Action::Inspect(InspectOptions::new(name.clone())))? {
+      ActionResult::Inspect(details) => details,
+      x => Err(FoundryError::UnexpectedValue).context(format!(
+        "Running Docker::InspectOptions did not return an Inspect result:\n{:#?}",
+        x
+      ))?,
+    };
+
+    let image = details["Config"]["Image"].as_str().unwrap_or_default();
+    let instance = AppInstance {
+      instance_id: details["Id"].as_str().map(|x| x.to_string()),
+      version: split_image(image).1.and_then(|x| version_from_tag(&x)),
+      ..AppInstance::new(
+        details["Name"]
+          .as_str()
+          .map_or(name, |x| x.trim_start_matches('/').to_string()),
+      )
+    };
+    Ok(
+      DockerContainer::build(instance, Some(Arc::new(self.clone())))?
+        .status(Status::from_state(details["State"]["Status"].as_str().unwrap_or_default())),
+    )
+  }
+
+  fn container_from_summary(&self, summary: &ContainerSummary) -> Result<DockerContainer> {
+    let name = summary
+      .get_names()
+      .first()
+      .cloned()
+      .unwrap_or_else(|| summary.id.clone());
+    let instance = AppInstance {
+      instance_id: Some(summary.id.clone()),
+      ..AppInstance::new(name)
+    };
+    Ok(DockerContainer::build(instance, Some(Arc::new(self.clone())))?.status(Status::from_state(&summary.state)))
+  }
+}
+
+/// Connect to the daemon the instance points to
+fn get_client(docker: &AppInstance) -> Result<Client> {
+  match &docker.api {
+    Some(api) => Client::new(&api.uri),
+    None => Err(FoundryError::NotConfigured).context(format!(
+      "{} does not have an API set to talk to the docker daemon",
+      docker.full_name()
+    )),
+  }
+}
+
+/// The API path for an action on a container. The name is escaped so it can't reach another endpoint.
+fn container_path(container: &str, action: &str) -> String {
+  format!("/containers/{}/{}", client::encode_segment(container), action)
+}
+
+/// Turn a non-2xx response into a RemoteError with the daemon's message
+fn check(response: Response, action: &str) -> Result<Response> {
+  match response.is_success() {
+    true => Ok(response),
+    false => Err(FoundryError::RemoteError).context(format!(
+      "Docker could not {} (status {}): {}",
+      action,
+      response.status,
+      response.get_message()
+    )),
+  }
+}
+
+/// The brief description of a container from the list endpoint
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerSummary {
+  pub id: String,
+  #[serde(default)]
+  pub names: Vec<String>,
+  #[serde(default)]
+  pub image: String,
+  #[serde(default)]
+  pub command: String,
+  /// eg: "running", "exited"
+  #[serde(default)]
+  pub state: String,
+  /// Human readable, eg: "Up 2 hours"
+  #[serde(default)]
+  pub status: String,
+}
+
+impl ContainerSummary {
+  /// Docker prefixes names with a "/"
+  pub fn get_names(&self) -> Vec<String> {
+    self
+      .names
+      .iter()
+      .map(|name| name.trim_start_matches('/').to_string())
+      .collect()
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Action {
+  List(ListOptions),
+  Inspect(InspectOptions),
+  Exec(ExecOptions),
+  Start(StartOptions),
+  Stop(StopOptions),
+  Logs(LogsOptions),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ActionResult {
+  List(Vec<ContainerSummary>),
+  /// The raw json from "docker inspect"
+  Inspect(Value),
+  Exec(String),
+  /// True if the container was started, false if it was already running
+  Start(bool),
+  /// True if the container was stopped, false if it was already stopped
+  Stop(bool),
+  Logs(String),
+}
+
+impl Action {
+  fn run(&self, docker: Docker) -> Result<ActionResult> {
+    match self {
+      Action::List(opts) => opts.run(docker.instance),
+      Action::Inspect(opts) => opts.run(docker.instance),
+      Action::Exec(opts) => opts.run(docker.instance),
+      Action::Start(opts) => opts.run(docker.instance),
+      Action::Stop(opts) => opts.run(docker.instance),
+      Action::Logs(opts) => opts.run(docker.instance),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListOptions {
+  /// Include containers that aren't running
+  pub all: bool,
+}
+
+impl ActionTrait for ListOptions {
+  type RESPONSE = ActionResult;
+
+  fn run(&self, docker: AppInstance) -> Result<Self::RESPONSE> {
+    let response = check(
+      get_client(&docker)?.get(&format!("/containers/json?all={}", self.all))?,
+      "list the containers",
+    )?;
+    let containers = serde_json::from_value(response.json()?)
+      .context("The docker daemon returned an unexpected container list")?;
+    Ok(ActionResult::List(containers))
+  }
+
+  fn to_message(&self, _target: Option<AppInstance>) -> Result<Vec<Message>> {
+    Err(FoundryError::NotImplemented).context("ActionTrait not implemented for Docker::ListOptions")
+  }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InspectOptions {
+  container: String,
+}
+
+impl InspectOptions {
+  pub fn new(container: String) -> InspectOptions {
+    InspectOptions { container }
+  }
+}
+
+impl ActionTrait for InspectOptions {
+  type RESPONSE = ActionResult;
+
+  fn run(&self, docker: AppInstance) -> Result<Self::RESPONSE> {
+    let response = get_client(&docker)?.get(&container_path(&self.container, "json"))?;
+    if response.status == 404 {
+      Err(FoundryError::NotFound).context(format!(
+        "Docker does not have a container named '{}'",
+        self.container
+      ))?;
+    }
+    let response = check(response, &format!("inspect '{}'", self.container))?;
+    Ok(ActionResult::Inspect(response.json()?))
+  }
+
+  fn to_message(&self, _target: Option<AppInstance>) -> Result<Vec<Message>> {
+    Err(FoundryError::NotImplemented).context("ActionTrait not implemented for Docker::InspectOptions")
+  }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExecOptions {
+  container: String,
+  command: String,
+  args: Vec<String>,
+  /// Start the command and return without waiting for it
+  detach: bool,
+  /// Give extended privileges to the process
+  privileged: bool,
+  /// Run the command as this user
+  user: Option<String>,
+  /// Environment variables for the command
+  env: Option<HashMap<String, String>>,
+  /// The working directory for the command
+  workdir: Option<String>,
+  /// Give up on the command after this long, killing it in the container
+  #[serde(default)]
+  timeout: Option<std::time::Duration>,
+  /// Give up on the command when this is cancelled, killing it in the container
+  #[serde(skip)]
+  cancel: Option<CancellationToken>,
+}
+
+impl ExecOptions {
+  pub fn new(container: String, command: String) -> ExecOptions {
+    ExecOptions {
+      container,
+      command,
+      ..Default::default()
+    }
+  }
+
+  pub fn args(&self, args: Vec<String>) -> ExecOptions {
+    ExecOptions {
+      args,
+      ..self.clone()
+    }
+  }
+
+  pub fn detach(&self) -> ExecOptions {
+    ExecOptions {
+      detach: true,
+      ..self.clone()
+    }
+  }
+
+  pub fn privileged(&self) -> ExecOptions {
+    ExecOptions {
+      privileged: true,
+      ..self.clone()
+    }
+  }
+
+  pub fn user(&self, user: String) -> ExecOptions {
+    ExecOptions {
+      user: Some(user),
+      ..self.clone()
+    }
+  }
+
+  /// Add an environment variable, keeping any that are already set
+  pub fn env(&self, key: String, value: String) -> ExecOptions {
+    let mut env = self.env.clone().unwrap_or_default();
+    env.insert(key, value);
+    ExecOptions {
+      env: Some(env),
+      ..self.clone()
+    }
+  }
+
+  pub fn workdir(&self, workdir: String) -> ExecOptions {
+    ExecOptions {
+      workdir: Some(workdir),
+      ..self.clone()
+    }
+  }
+
+  pub fn timeout(&self, timeout: std::time::Duration) -> ExecOptions {
+    ExecOptions {
+      timeout: Some(timeout),
+      ..self.clone()
+    }
+  }
+
+  pub fn cancel_on(&self, token: CancellationToken) -> ExecOptions {
+    ExecOptions {
+      cancel: Some(token),
+      ..self.clone()
+    }
+  }
+
+  /// Replace what is run (the command, user, environment and working directory) with the Cmd
+  fn with_cmd(&self, cmd: &Cmd) -> ExecOptions {
+    ExecOptions {
+      command: cmd.command.clone(),
+      args: cmd.args.clone(),
+      user: cmd.run_as.clone(),
+      env: Some(cmd.env.clone().into_iter().collect()),
+      workdir: cmd.workdir.clone(),
+      ..self.clone()
+    }
+  }
+
+  /// What runs in the container, with its user and limits
+  fn as_cmd(&self) -> Cmd {
+    Cmd {
+      run_as: self.user.clone(),
+      env: self.env.clone().unwrap_or_default().into_iter().collect(),
+      workdir: self.workdir.clone(),
+      timeout: self.timeout,
+      cancel: self.cancel.clone(),
+      ..Cmd::argv(self.command.clone(), self.args.clone())
+    }
+  }
+
+  /// The API calls block, so they are made on their own thread while we wait to see if the command hits its
+  /// limits. If it does, the thread carries on until the command is killed in the container.
+  async fn exec(&self, docker: &AppInstance, cmd: Cmd) -> Result<ActionResult> {
+    let exec = self.with_cmd(&cmd);
+    let docker = docker.clone();
+    let task = tokio::task::spawn_blocking(move || exec.exec_blocking(docker));
+    cmd
+      .limit(async { task.await.context("The thread running docker exec panicked")? })
+      .await
+  }
+
+  /// Create the exec instance, start it, then check the exit code once the output is done
+  fn exec_blocking(&self, docker: AppInstance) -> Result<ActionResult> {
+    let client = get_client(&docker)?;
+    let cmd: Vec<String> = std::iter::once(self.command.clone())
+      .chain(self.args.clone())
+      .collect();
+    let mut env: Vec<String> = self
+      .env
+      .clone()
+      .unwrap_or_default()
+      .into_iter()
+      .map(|(key, value)| format!("{}={}", key, value))
+      .collect();
+    env.sort();
+
+    let mut config = json!({
+      "AttachStdout": !self.detach,
+      "AttachStderr": !self.detach,
+      "Privileged": self.privileged,
+      "Cmd": cmd,
+      "Env": env,
+    });
+    if let Some(user) = &self.user {
+      config["User"] = json!(user);
+    }
+    if let Some(workdir) = &self.workdir {
+      config["WorkingDir"] = json!(workdir);
+    }
+
+    let action = format!("run {:?} in '{}'", cmd.join(" "), self.container);
+    let created = check(
+      client.post(&container_path(&self.container, "exec"), Some(&config))?,
+      &action,
+    )?;
+    let exec_id = match created.json()?["Id"].as_str() {
+      Some(id) => id.to_string(),
+      None => Err(FoundryError::UnexpectedValue)
+        .context(format!("Docker did not return an exec id when trying to {}", action))?,
+    };
+
+    let started = check(
+      client.post(
+        &format!("/exec/{}/start", exec_id),
+        Some(&json!({"Detach": self.detach, "Tty": false})),
+      )?,
+      &action,
+    )?;
+    if self.detach {
+      return Ok(ActionResult::Exec(String::new()));
+    }
+    let (stdout, stderr) = client::demux(&started.body);
+
+    let status = check(client.get(&format!("/exec/{}/json", exec_id))?, &action)?.json()?;
+    match status["ExitCode"].as_i64() {
+      Some(0) => Ok(ActionResult::Exec(stdout.trim_end().to_string())),
+      code => Err(FoundryError::RemoteError).context(format!(
+        "Failed to {} with exit code {}:\n{}",
+        action,
+        code.map_or("unknown".to_string(), |x| x.to_string()),
+        stderr.trim_end()
+      )),
+    }
+  }
+}
+
+#[async_trait]
+impl ActionTrait for ExecOptions {
+  type RESPONSE = ActionResult;
+
+  fn run(&self, docker: AppInstance) -> Result<Self::RESPONSE> {
+    block_on(self.run_async(docker))
+  }
+
+  /// A command with a timeout or cancel is also killed in the container if it hits them
+  async fn run_async(&self, docker: AppInstance) -> Result<Self::RESPONSE> {
+    let docker = &docker;
+    self
+      .as_cmd()
+      .run_tagged(move |cmd| self.exec(docker, cmd))
+      .await
+  }
+
+  fn to_message(&self, _target: Option<AppInstance>) -> Result<Vec<Message>> {
+    Err(FoundryError::NotImplemented).context("ActionTrait not implemented for Docker::ExecOptions")
+  }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StartOptions {
+  container: String,
+}
+
+impl StartOptions {
+  pub fn new(container: String) -> StartOptions {
+    StartOptions { container }
+  }
+}
+
+impl ActionTrait for StartOptions {
+  type RESPONSE = ActionResult;
+
+  fn run(&self, docker: AppInstance) -> Result<Self::RESPONSE> {
+    let response = get_client(&docker)?.post(&container_path(&self.container, "start"), None)?;
+    // 304 means it was already running
+    match response.status {
+      304 => Ok(ActionResult::Start(false)),
+      _ => {
+        check(response, &format!("start '{}'", self.container))?;
+        Ok(ActionResult::Start(true))
+      }
+    }
+  }
+
+  fn to_message(&self, _target: Option<AppInstance>) -> Result<Vec<Message>> {
+    Err(FoundryError::NotImplemented).context("ActionTrait not implemented for Docker::StartOptions")
+  }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StopOptions {
+  container: String,
+  /// Seconds to wait before killing the container
+  timeout: Option<u32>,
+}
+
+impl StopOptions {
+  pub fn new(container: String) -> StopOptions {
+    StopOptions {
+      container,
+      ..Default::default()
+    }
+  }
+
+  pub fn timeout(&self, seconds: u32) -> StopOptions {
+    StopOptions {
+      timeout: Some(seconds),
+      ..self.clone()
+    }
+  }
+}
+
+impl ActionTrait for StopOptions {
+  type RESPONSE = ActionResult;
+
+  fn run(&self, docker: AppInstance) -> Result<Self::RESPONSE> {
+    let path = match self.timeout {
+      Some(seconds) => format!("{}?t={}", container_path(&self.container, "stop"), seconds),
+      None => container_path(&self.container, "stop"),
+    };
+    let response = get_client(&docker)?.post(&path, None)?;
+    // 304 means it was already stopped
+    match response.status {
+      304 => Ok(ActionResult::Stop(false)),
+      _ => {
+        check(response, &format!("stop '{}'", self.container))?;
+        Ok(ActionResult::Stop(true))
+      }
+    }
+  }
+
+  fn to_message(&self, _target: Option<AppInstance>) -> Result<Vec<Message>> {
+    Err(FoundryError::NotImplemented).context("ActionTrait not implemented for Docker::StopOptions")
+  }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LogsOptions {
+  container: String,
+  /// Only return this many lines from the end of the logs
+  tail: Option<usize>,
+  /// Prefix every line with its timestamp
+  timestamps: bool,
+}
+
+impl LogsOptions {
+  pub fn new(container: String) -> LogsOptions {
+    LogsOptions {
+      container,
+      ..Default::default()
+    }
+  }
+
+  pub fn tail(&self, lines: usize) -> LogsOptions {
+    LogsOptions {
+      tail: Some(lines),
+      ..self.clone()
+    }
+  }
+
+  pub fn timestamps(&self) -> LogsOptions {
+    LogsOptions {
+      timestamps: true,
+      ..self.clone()
+    }
+  }
+}
+
+impl ActionTrait for LogsOptions {
+  type RESPONSE = ActionResult;
+
+  /// Both stdout and stderr are returned, in the order they were written
+  fn run(&self, docker: AppInstance) -> Result<Self::RESPONSE> {
+    let path = format!(
+      "{}?stdout=true&stderr=true&timestamps={}&tail={}",
+      container_path(&self.container, "logs"),
+      self.timestamps,
+      self.tail.map_or("all".to_string(), |x| x.to_string())
+    );
+    let response = check(
+      get_client(&docker)?.get(&path)?,
+      &format!("get the logs for '{}'", self.container),
+    )?;
+    let logs: Vec<u8> = client::frames(&response.body)
+      .into_iter()
+      .flat_map(|(_, data)| data.to_vec())
+      .collect();
+    Ok(ActionResult::Logs(String::from_utf8_lossy(&logs).to_string()))
+  }
+
+  fn to_message(&self, _target: Option<AppInstance>) -> Result<Vec<Message>> {
+    Err(FoundryError::NotImplemented).context("ActionTrait not implemented for Docker::LogsOptions")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{BufRead, BufReader, Read, Write};
+  use std::os::unix::net::{UnixListener, UnixStream};
+  use std::path::PathBuf;
+  use std::sync::Mutex;
+
+  /// A fake docker daemon on a unix socket, answering "METHOD path" from its routes and anything else with a
+  /// 404. The requests are recorded with their bodies.
+  struct Daemon {
+    dir: PathBuf,
+    requests: Arc<Mutex<Vec<(String, String)>>>,
+  }
+
+  impl Daemon {
+    fn start(routes: Vec<(&str, u16, Vec<u8>)>) -> Daemon {
+      let dir = std::env::temp_dir().join(format!("foundry-docker-{}", uuid::Uuid::new_v4()));
+      std::fs::create_dir_all(&dir).unwrap();
+      let listener = UnixListener::bind(dir.join("docker.sock")).unwrap();
+      let routes: HashMap<String, (u16, Vec<u8>)> = routes
+        .into_iter()
+        .map(|(route, status, body)| (route.to_string(), (status, body)))
+        .collect();
+      let requests = Arc::new(Mutex::new(vec![]));
+      let seen = requests.clone();
+      std::thread::spawn(move || {
+        for stream in listener.incoming() {
+          let mut stream = stream.unwrap();
+          let request = Daemon::read_request(&mut stream);
+          let (status, body) = routes
+            .get(&request.0)
+            .cloned()
+            .unwrap_or((404, br#"{"message": "No such container"}"#.to_vec()));
+          seen.lock().unwrap().push(request);
+          write!(stream, "HTTP/1.1 {} Stub\r\nContent-Length: {}\r\n\r\n", status, body.len()).unwrap();
+          stream.write_all(&body).unwrap();
+        }
+      });
+      Daemon { dir, requests }
+    }
+
+    fn read_request(stream: &mut UnixStream) -> (String, String) {
+      let mut reader = BufReader::new(stream);
+      let mut line = String::new();
+      reader.read_line(&mut line).unwrap();
+      let route = line.split_whitespace().take(2).collect::<Vec<&str>>().join(" ");
+      let mut length = 0;
+      loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).unwrap();
+        if header.trim().is_empty() {
+          break;
+        }
+        if let Some(value) = header.to_lowercase().strip_prefix("content-length:") {
+          length = value.trim().parse().unwrap();
+        }
+      }
+      let mut body = vec![0; length];
+      reader.read_exact(&mut body).unwrap();
+      (route, String::from_utf8(body).unwrap())
+    }
+
+    fn instance(&self) -> AppInstance {
+      AppInstance {
+        api: Some(ApiAccess::new(format!("unix://{}", self.dir.join("docker.sock").display()))),
+        ..AppInstance::new("docker".to_string())
+      }
+    }
+
+    fn docker(&self) -> Docker {
+      Docker::build(self.instance(), None).unwrap()
+    }
+
+    fn requests(&self) -> Vec<(String, String)> {
+      self.requests.lock().unwrap().clone()
+    }
+  }
+
+  impl Drop for Daemon {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.dir);
+    }
+  }
+
+  fn frame(stream: u8, data: &str) -> Vec<u8> {
+    let mut frame = vec![stream, 0, 0, 0];
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(data.as_bytes());
+    frame
+  }
+
+  fn kind(err: &anyhow::Error) -> Option<&FoundryError> {
+    err.downcast_ref::<FoundryError>()
+  }
+
+  #[test]
+  fn lists_and_finds_containers() {
+    let daemon = Daemon::start(vec![(
+      "GET /containers/json?all=true",
+      200,
+      br#"[
+        {"Id": "abc", "Names": ["/db"], "Image": "postgres:13.2", "Command": "postgres", "State": "running"},
+        {"Id": "def", "Names": ["/web"], "Image": "nginx", "Command": "nginx -g 'daemon off;'", "State": "exited"}
+      ]"#
+        .to_vec(),
+    )]);
+    let docker = daemon.docker();
+
+    let containers = docker.list(true).unwrap();
+    assert_eq!(containers.len(), 2);
+    assert_eq!(containers[0].get_names(), vec!["db".to_string()]);
+    assert_eq!(containers[1].state, "exited");
+
+    let found = docker.find(AppQuery::new("postgres".to_string())).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].name, "db");
+    assert_eq!(found[0].instance_id, Some("abc".to_string()));
+    assert_eq!(found[0].version, Some(semver::Version::new(13, 2, 0)));
+    assert_eq!(found[0].get_command_path().unwrap(), "postgres");
+  }
+
+  #[test]
+  fn inspects_containers() {
+    let daemon = Daemon::start(vec![(
+      "GET /containers/db/json",
+      200,
+      br#"{"Id": "abc123", "Name": "/db", "Config": {"Image": "postgres:13"}, "State": {"Status": "running"}}"#
+        .to_vec(),
+    )]);
+    let docker = daemon.docker();
+
+    let container = docker.get_container("db".to_string()).unwrap();
+    assert_eq!(container.instance.name, "db");
+    assert_eq!(container.instance.instance_id, Some("abc123".to_string()));
+    assert_eq!(container.instance.version, Some(semver::Version::new(13, 0, 0)));
+    assert!(matches!(container.status, Status::Up));
+
+    let err = docker.get_container("missing".to_string()).unwrap_err();
+    assert!(matches!(kind(&err), Some(FoundryError::NotFound)));
+  }
+
+  #[test]
+  fn exec_separates_the_output_and_checks_the_exit_code() {
+    let output = [frame(1, "hello\n"), frame(2, "careful\n"), frame(1, "world\n")].concat();
+    let routes = |code: i32| {
+      vec![
+        ("POST /containers/db/exec", 201, br#"{"Id": "e1"}"#.to_vec()),
+        ("POST /exec/e1/start", 200, output.clone()),
+        ("GET /exec/e1/json", 200, format!(r#"{{"ExitCode": {}}}"#, code).into_bytes()),
+      ]
+    };
+    let exec = ExecOptions::new("db".to_string(), "sh".to_string())
+      .args(vec!["-c".to_string(), "echo hi".to_string()])
+      .user("postgres".to_string())
+      .env("A".to_string(), "1".to_string())
+      .workdir("/tmp".to_string());
+
+    let daemon = Daemon::start(routes(0));
+    match exec.run(daemon.instance()).unwrap() {
+      ActionResult::Exec(stdout) => assert_eq!(stdout, "hello\nworld"),
+      x => panic!("Expected an Exec result, got {:?}", x),
+    }
+    let requests = daemon.requests();
+    let created: Value = serde_json::from_str(&requests[0].1).unwrap();
+    assert_eq!(created["Cmd"], json!(["sh", "-c", "echo hi"]));
+    assert_eq!(created["User"], "postgres");
+    assert_eq!(created["Env"], json!(["A=1"]));
+    assert_eq!(created["WorkingDir"], "/tmp");
+    assert_eq!(requests.len(), 3);
+
+    let daemon = Daemon::start(routes(3));
+    let err = exec.run(daemon.instance()).unwrap_err();
+    assert!(matches!(kind(&err), Some(FoundryError::RemoteError)));
+    let text = format!("{:#}", err);
+    assert!(text.contains("exit code 3") && text.contains("careful"), "{}", text);
+  }
+
+  #[test]
+  fn start_and_stop_report_when_nothing_changed() {
+    let daemon = Daemon::start(vec![
+      ("POST /containers/db/start", 204, vec![]),
+      ("POST /containers/web/start", 304, vec![]),
+      ("POST /containers/db/stop?t=5", 304, vec![]),
+      ("POST /containers/web/stop", 204, vec![]),
+      ("POST /containers/bad/start", 500, br#"{"message": "port is already allocated"}"#.to_vec()),
+    ]);
+    let docker = daemon.instance();
+    let run = |action: &dyn ActionTrait<RESPONSE = ActionResult>| action.run(docker.clone());
+
+    assert!(matches!(run(&StartOptions::new("db".to_string())).unwrap(), ActionResult::Start(true)));
+    assert!(matches!(run(&StartOptions::new("web".to_string())).unwrap(), ActionResult::Start(false)));
+    assert!(matches!(
+      run(&StopOptions::new("db".to_string()).timeout(5)).unwrap(),
+      ActionResult::Stop(false)
+    ));
+    assert!(matches!(run(&StopOptions::new("web".to_string())).unwrap(), ActionResult::Stop(true)));
+
+    let err = run(&StartOptions::new("bad".to_string())).unwrap_err();
+    assert!(matches!(kind(&err), Some(FoundryError::RemoteError)));
+    assert!(format!("{:#}", err).contains("status 500): port is already allocated"), "{:#}", err);
+  }
+
+  #[test]
+  fn podman_is_used_when_docker_is_missing() {
+    let existing = |paths: &'static [&'static str]| move |path: &str| paths.contains(&path);
+    let runtime = Some("/run/user/1000".to_string());
+
+    assert_eq!(
+      Docker::choose_socket(runtime.clone(), existing(&["/var/run/docker.sock", PODMAN_ROOT_SOCKET])),
+      DEFAULT_SOCKET
+    );
+    assert_eq!(
+      Docker::choose_socket(runtime.clone(), existing(&["/run/user/1000/podman/podman.sock", PODMAN_ROOT_SOCKET])),
+      "unix:///run/user/1000/podman/podman.sock"
+    );
+    assert_eq!(
+      Docker::choose_socket(runtime.clone(), existing(&[PODMAN_ROOT_SOCKET])),
+      format!("unix://{}", PODMAN_ROOT_SOCKET)
+    );
+    assert_eq!(Docker::choose_socket(None, existing(&[])), DEFAULT_SOCKET);
+  }
+
+  #[test]
+  fn container_names_stay_in_their_path_segment() {
+    let daemon = Daemon::start(vec![("POST /containers/..%2Fimages%2Fcreate%3Fx%3D1/stop", 204, vec![])]);
+    let stop = StopOptions::new("../images/create?x=1".to_string());
+    assert!(matches!(stop.run(daemon.instance()).unwrap(), ActionResult::Stop(true)));
+
+    let err = daemon.docker().get_container("db#1 x".to_string()).unwrap_err();
+    assert!(matches!(kind(&err), Some(FoundryError::NotFound)));
+    let routes: Vec<String> = daemon.requests().into_iter().map(|(route, _)| route).collect();
+    assert_eq!(routes[1], "GET /containers/db%231%20x/json");
+  }
+
+  #[test]
+  fn unsupported_features_are_errors() {
+    let docker = Docker::build(AppInstance::new("docker".to_string()), None).unwrap();
+    assert!(matches!(kind(&docker.cached_apps().unwrap_err()), Some(FoundryError::NotImplemented)));
+    let message = StartOptions::new("db".to_string()).to_message(None).unwrap_err();
+    assert!(matches!(kind(&message), Some(FoundryError::NotImplemented)));
+  }
+}
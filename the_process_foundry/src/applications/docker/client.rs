@@ -0,0 +1,164 @@
+//! A minimal HTTP/1.1 client for the Docker Engine API over a unix socket
+//!
+//! We only need simple request/response calls, so this sends one request per connection with
+//! "Connection: close" and reads until the daemon hangs up.
+//! https://docs.docker.com/engine/api/
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+// The daemon speaks plain http, so the parsing and escaping are shared with the http client
+pub use super::http::client::{encode_path, encode_segment, parse_response, Response};
+use super::FoundryError;
+
+#[derive(Debug, Clone)]
+pub struct Client {
+  socket: PathBuf,
+}
+
+impl Client {
+  /// Connect using a uri like "unix:///var/run/docker.sock". Docker over tcp isn't supported yet.
+  pub fn new(uri: &str) -> Result<Client> {
+    match uri.strip_prefix("unix://") {
+      Some(path) => Ok(Client {
+        socket: PathBuf::from(path),
+      }),
+      None if uri.starts_with('/') => Ok(Client {
+        socket: PathBuf::from(uri),
+      }),
+      None => Err(FoundryError::ConfigurationError).context(format!(
+        "'{}' is not a unix socket. Only local docker daemons are currently supported",
+        uri
+      )),
+    }
+  }
+
+  pub fn get(&self, path: &str) -> Result<Response> {
+    self.request("GET", path, None)
+  }
+
+  pub fn post(&self, path: &str, body: Option<&Value>) -> Result<Response> {
+    self.request("POST", path, body)
+  }
+
+  pub fn request(&self, method: &str, path: &str, body: Option<&Value>) -> Result<Response> {
+    let path = encode_path(path);
+    log::debug!("Docker API request: {} {}", method, path);
+    let mut stream = UnixStream::connect(&self.socket).context(format!(
+      "Could not connect to the docker daemon at {}",
+      self.socket.display()
+    ))?;
+
+    let body = body.map_or(Ok(vec![]), serde_json::to_vec)?;
+    let mut request = format!(
+      "{} {} HTTP/1.1\r\nHost: docker\r\nConnection: close\r\nContent-Length: {}\r\n",
+      method,
+      path,
+      body.len()
+    );
+    if !body.is_empty() {
+      request.push_str("Content-Type: application/json\r\n");
+    }
+    request.push_str("\r\n");
+
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(&body)?;
+
+    let mut raw = vec![];
+    stream.read_to_end(&mut raw).context(format!(
+      "Failed reading the response to {} {} from the docker daemon",
+      method, path
+    ))?;
+    parse_response(&raw).context(format!("Bad response to {} {}", method, path))
+  }
+}
+
+/// Which output a frame of a multiplexed stream came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+  Stdout,
+  Stderr,
+}
+
+/// Split the multiplexed stream from exec and logs into frames, in the order they were written
+///
+/// Each frame is an 8 byte header [stream, 0, 0, 0, size (u32 big endian)] followed by the data. Containers
+/// with a TTY send a raw stream instead, which is all treated as stdout.
+pub fn frames(raw: &[u8]) -> Vec<(Stream, &[u8])> {
+  let is_multiplexed = raw.len() >= 8 && raw[0] <= 2 && raw[1..4] == [0, 0, 0];
+  if !is_multiplexed {
+    return vec![(Stream::Stdout, raw)];
+  }
+
+  let mut frames = vec![];
+  let mut rest = raw;
+  while rest.len() >= 8 {
+    let size = u32::from_be_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+    let end = (8 + size).min(rest.len());
+    let stream = match rest[0] {
+      2 => Stream::Stderr,
+      _ => Stream::Stdout,
+    };
+    frames.push((stream, &rest[8..end]));
+    rest = &rest[end..];
+  }
+  frames
+}
+
+/// Collect the multiplexed stream into stdout and stderr
+pub fn demux(raw: &[u8]) -> (String, String) {
+  let mut stdout = vec![];
+  let mut stderr = vec![];
+  for (stream, data) in frames(raw) {
+    match stream {
+      Stream::Stdout => stdout.extend_from_slice(data),
+      Stream::Stderr => stderr.extend_from_slice(data),
+    }
+  }
+  (
+    String::from_utf8_lossy(&stdout).to_string(),
+    String::from_utf8_lossy(&stderr).to_string(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn multiplexed_streams_are_split() {
+    let mut raw = vec![1, 0, 0, 0, 0, 0, 0, 3];
+    raw.extend_from_slice(b"out");
+    raw.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 3]);
+    raw.extend_from_slice(b"err");
+    raw.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 4]);
+    raw.extend_from_slice(b"put\n");
+
+    assert_eq!(
+      frames(&raw),
+      vec![
+        (Stream::Stdout, &b"out"[..]),
+        (Stream::Stderr, &b"err"[..]),
+        (Stream::Stdout, &b"put\n"[..])
+      ]
+    );
+    assert_eq!(demux(&raw), ("output\n".to_string(), "err".to_string()));
+  }
+
+  #[test]
+  fn tty_output_is_all_stdout() {
+    assert_eq!(demux(b"plain text\n"), ("plain text\n".to_string(), String::new()));
+    assert_eq!(demux(b""), (String::new(), String::new()));
+  }
+
+  #[test]
+  fn only_unix_sockets_are_supported() {
+    assert!(Client::new("unix:///var/run/docker.sock").is_ok());
+    assert!(Client::new("/run/podman/podman.sock").is_ok());
+    let err = Client::new("tcp://localhost:2375").unwrap_err();
+    assert!(matches!(err.downcast_ref::<FoundryError>(), Some(FoundryError::ConfigurationError)));
+  }
+}
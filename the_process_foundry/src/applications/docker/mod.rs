@@ -0,0 +1,12 @@
+//! Manage containers through the Docker Engine API
+//!
+//! Many containers are started by other tools rather than compose, so this talks to the daemon directly
+//! over its unix socket instead of shelling out to the docker cli.
+//!
+//! TODO: Support DOCKER_HOST over tcp/ssh
+
+pub mod application;
+pub mod client;
+
+use super::*;
+pub use application::{Action, ActionResult, ContainerSummary, Docker};
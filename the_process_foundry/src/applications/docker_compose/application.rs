@@ -3,24 +3,29 @@
 //! A separate app to examine and run docker compose
 
 const APP_NAME: &str = "Docker Compose";
-const MODULE_VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const MODULE_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 use anyhow::{Context, Result};
+use async_trait::async_trait;
 use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::sync::Arc;
 
+use super::dependencies::DependencyGraph;
+use super::diff::{self, Change};
+use super::merge;
 use super::schema::*;
 use super::FoundryError;
-use super::{docker_container, DockerContainer};
-use super::{ActionTrait, AppInstance, AppQuery, AppTrait, Cmd, ContainerTrait, Message};
+use super::DockerContainer;
+use super::{block_on, ActionTrait, AppInstance, AppQuery, AppTrait, Cmd, ContainerTrait, Message};
+use super::CancellationToken;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DockerCompose {
-  /// We want to put the shell/parent container here
+  /// We want to put the shell/parent container here. It is used to find the compose executable.
   #[serde(skip)]
-  parent: Option<Rc<dyn ContainerTrait>>,
+  parent: Option<Arc<dyn ContainerTrait>>,
   // docker: Docker,
   instance: AppInstance,
   config: Option<Schema>,
@@ -35,7 +40,18 @@ impl AppTrait for DockerCompose {
     }
   }
 
-  fn build(instance: AppInstance, parent: Option<Rc<dyn ContainerTrait>>) -> Result<DockerCompose> {
+  /// If the instance doesn't have a CLI path yet, it is looked up in the parent. An instance named after one of
+  /// the executables (eg: "docker-compose", "podman") uses that style, and anything else whichever is found.
+  fn build(instance: AppInstance, parent: Option<Arc<dyn ContainerTrait>>) -> Result<DockerCompose> {
+    let instance = match (&instance.cli, &parent) {
+      (None, Some(container)) => AppInstance {
+        instance_id: instance.instance_id.clone(),
+        config_file: instance.config_file.clone(),
+        ..DockerCompose::find_cli(container.clone(), CliStyle::from_name(&instance.name))?
+      },
+      _ => instance,
+    };
+
     Ok(DockerCompose {
       parent,
       instance: AppInstance {
@@ -52,31 +68,76 @@ impl AppTrait for DockerCompose {
   fn set_cli(
     &self,
     _instance: AppInstance,
-    _container: Rc<dyn ContainerTrait>,
+    _container: Arc<dyn ContainerTrait>,
   ) -> Result<AppInstance> {
     unimplemented!()
   }
 
   /// Knows how to get the version number of the installed app (not the module version)
-  fn set_version(&self, _instance: AppInstance) -> Result<AppInstance> {
-    unimplemented!()
+  fn set_version(&self, instance: AppInstance) -> Result<AppInstance> {
+    DockerCompose::get_version(instance)
   }
 }
 
+#[async_trait]
 impl ContainerTrait for DockerCompose {
   /// This will find a list of apps with configurations that the container knows about
+  ///
+  /// A service matches if the query name or one of its aliases is the service name, container name or the
+  /// name of the image it runs. If works_with is set, the version is taken from the image tag.
   fn find(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
     let conf = self.get_conf()?;
-    Ok(
-      conf
-        .list_service_names()
-        .into_iter()
-        .filter_map(|item| match item.to_lowercase() == query.name {
-          false => None,
-          true => Some(AppInstance::new(item)),
-        })
-        .collect(),
-    )
+    let names: Vec<String> = std::iter::once(query.name.clone())
+      .chain(query.aliases.clone().unwrap_or_default())
+      .map(|name| name.to_lowercase())
+      .collect();
+
+    let mut found = vec![];
+    for (service_name, service) in &conf.services {
+      let candidates: Vec<String> = vec![
+        Some(service_name.clone()),
+        service.get_container_name(),
+        service.get_image_name(),
+        service.get_image(),
+      ]
+      .into_iter()
+      .flatten()
+      .map(|name| name.to_lowercase())
+      .collect();
+
+      if !candidates.iter().any(|name| names.contains(name)) {
+        continue;
+      }
+
+      let version = service.get_image_version();
+      if let Some(req) = &query.works_with {
+        match &version {
+          Some(ver) if req.matches(ver) => (),
+          _ => {
+            log::debug!(
+              "Skipping service '{}' since image {:?} does not match version {}",
+              service_name,
+              service.get_image(),
+              req
+            );
+            continue;
+          }
+        }
+      }
+
+      let container = self.get_container(service_name.clone())?;
+      let instance = AppInstance {
+        version,
+        ..container.instance.clone()
+      }
+      // The service's command is what runs in the container, empty if it uses the image's default
+      .set_command_path(
+        Some(Arc::new(container)),
+        service.get_command().unwrap_or_default().join(" "),
+      )?;
+      found.push(instance);
+    }
+    Ok(found)
   }
 
   /// List the known items in the app cache
@@ -84,8 +145,12 @@ impl ContainerTrait for DockerCompose {
     unimplemented!("No App Cache for Bash Yet")
   }
 
-  /// Send the message to a child item
   fn forward(&self, to: AppInstance, message: Message) -> Result<String> {
+    block_on(self.forward_async(to, message))
+  }
+
+  /// Send the message to a child item
+  async fn forward_async(&self, to: AppInstance, message: Message) -> Result<String> {
     match message {
       Message::Command(cmd) => {
         let exec = ExecOptions {
@@ -93,9 +158,14 @@ impl ContainerTrait for DockerCompose {
           user: cmd.run_as.clone(),
           command: cmd.command,
           args: cmd.args,
+          env: Some(cmd.env.into_iter().collect()),
+          workdir: cmd.workdir,
+          config_files: self.get_conf()?.get_files(),
+          timeout: cmd.timeout,
+          cancel: cmd.cancel,
           ..Default::default()
         };
-        match exec.run(self.instance.clone())? {
+        match exec.run_async(self.instance.clone()).await? {
           ActionResult::Exec(val) => Ok(val),
           err => Err(FoundryError::UnexpectedValue).context(format!(
             "Running DockerCompose::ExecOptions did not return an ExecResult:\n{:#?}",
@@ -116,12 +186,10 @@ impl ContainerTrait for DockerCompose {
 
 impl DockerCompose {
   fn get_module_version() -> Result<semver::Version> {
-    Ok({
-      semver::Version::parse(MODULE_VERSION).context(format!(
-        "{} has an invalid version number '{}' Cargo.toml",
-        APP_NAME, MODULE_VERSION
-      ))
-    }?)
+    semver::Version::parse(MODULE_VERSION).context(format!(
+      "{} has an invalid version number '{}' Cargo.toml",
+      APP_NAME, MODULE_VERSION
+    ))
   }
 
   fn get_name(&self) -> String {
@@ -131,6 +199,96 @@ impl DockerCompose {
     }
   }
 
+  /// Look up the compose executable in the container. If no style is given, each one is tried in the order of
+  /// CliStyle::SEARCH_ORDER, so Docker is preferred and Podman is used where Docker isn't installed.
+  pub fn find_cli(container: Arc<dyn ContainerTrait>, style: Option<CliStyle>) -> Result<AppInstance> {
+    let style = match style {
+      Some(x) => x,
+      None => {
+        let mut errors = vec![];
+        for style in CliStyle::SEARCH_ORDER {
+          match DockerCompose::find_cli(container.clone(), Some(style)) {
+            Ok(instance) => return Ok(instance),
+            Err(err) => {
+              log::debug!("Could not use {:?}, trying the next style:\n{:#}", style, err);
+              errors.push(format!("{:?}: {:#}", style, err));
+            }
+          }
+        }
+        return Err(FoundryError::NotFound).context(format!(
+          "Could not find any form of docker compose or podman compose in {}:\n{}",
+          container.get_name(),
+          errors.join("\n")
+        ));
+      }
+    };
+
+    let instance = container
+      .find_one(AppQuery::new(style.get_executable().to_string()))
+      .context(format!(
+        "Could not find the {} executable in {}",
+        style.get_executable(),
+        container.get_name()
+      ))?;
+    match style.is_plugin() {
+      true => DockerCompose::get_version(instance).context(format!(
+        "{} was found, but the compose plugin does not seem to be installed",
+        style.get_executable()
+      )),
+      false => DockerCompose::get_version(instance),
+    }
+  }
+
+  /// Switch to another way of running compose (eg: docker-compose to the plugin), looking it up in the parent
+  pub fn set_cli_style(&self, style: CliStyle) -> Result<DockerCompose> {
+    let parent = match &self.parent {
+      Some(x) => x.clone(),
+      None => Err(FoundryError::NotConfigured).context(format!(
+        "Cannot switch {} to {:?}: No parent is set to find it in",
+        self.get_name(),
+        style
+      ))?,
+    };
+    let found = DockerCompose::find_cli(parent, Some(style))?;
+    Ok(DockerCompose {
+      instance: AppInstance {
+        cli: found.cli,
+        version: found.version,
+        ..self.instance.clone()
+      },
+      ..self.clone()
+    })
+  }
+
+  /// Ask the compose executable for its version, which also makes sure it can be run
+  fn get_version(instance: AppInstance) -> Result<AppInstance> {
+    let mut cmd = compose_executable(&instance);
+    cmd.arg("version").arg("--short");
+    let result = launch(&instance, &cmd)?
+      .output()
+      .context(format!("Could not run '{}'", command_str(&cmd)?))?;
+    if !result.status.success() {
+      Err(FoundryError::RemoteError).context(format!(
+        "'{}' failed:\n{}",
+        command_str(&cmd)?,
+        String::from_utf8(result.stderr)?.trim_end()
+      ))?;
+    }
+
+    // Docker prints "2.20.0" or "v2.20.0", while some podman-compose versions add their name and podman's version
+    let output = String::from_utf8(result.stdout)?;
+    let version = output.split_whitespace().find_map(version_from_tag);
+    if version.is_none() {
+      log::warn!("Could not parse the docker compose version '{}'", output.trim());
+    }
+    Ok(AppInstance { version, ..instance })
+  }
+
+  /// The effective configuration after merging all the files, resolving extends and substituting variables
+  pub fn get_config(&self) -> Result<Schema> {
+    self.get_conf()
+  }
+
   fn get_conf(&self) -> Result<Schema> {
     match self.config.clone() {
       Some(conf) => Ok(conf),
@@ -142,23 +300,24 @@ impl DockerCompose {
 
   /// Load the configuration from an existing yaml file
   pub fn load(&self, config_file: String) -> Result<DockerCompose> {
-    log::debug!("reading the docker compose schema");
+    self.load_files(vec![config_file])
+  }
 
-    log::info!("Parsing the docker compose file at {}", config_file);
-    let contents = std::fs::read_to_string(config_file.clone()).context(format!(
-      "Failed to open docker-compose file at {}",
-      config_file
-    ))?;
+  /// Load docker-compose.yml and docker-compose.override.yml (if it exists) from the project directory, the
+  /// same as running docker-compose there without any "-f" options
+  pub fn load_project(&self, project_dir: String) -> Result<DockerCompose> {
+    let files = merge::find_project_files(std::path::Path::new(&project_dir))?;
+    self.load_files(files)
+  }
 
-    let conf = Schema {
-      source: Some(config_file.clone()),
-      ..serde_yaml::from_str(&contents).context(format!(
-        "Failed to parse the docker-compose file at {}",
-        config_file
-      ))?
-    };
+  /// Load several configuration files, merging each one on top of the previous like "-f a.yml -f b.yml"
+  pub fn load_files(&self, config_files: Vec<String>) -> Result<DockerCompose> {
+    log::debug!("reading the docker compose schema");
+
+    let conf = merge::load_files(&config_files)?.resolve_paths()?;
+    let config_file = conf.get_source();
 
-    log::debug!("Successfully parsed the schema at {}", config_file,);
+    log::debug!("Successfully parsed the schema from {:?}", config_files);
 
     log::debug!("Getting the docker containers");
     let containers = HashMap::new();
@@ -186,8 +345,15 @@ impl DockerCompose {
   /// TODO: Add in result from "docker inspect" if running
   /// TODO: If status is "Up", we want to get/set shell
   fn define_container(&self, name: String) -> Result<DockerContainer> {
-    let instance = AppInstance::new(name.clone());
-    DockerContainer::build(instance, Some(Rc::new(self.clone())))
+    let service = self.get_conf()?.services.get(&name).cloned().unwrap_or_default();
+    let instance = AppInstance {
+      // Without a container_name, compose names it (eg: <project>-<service>-1) when it is created, so it can't
+      // be known yet. Commands are sent to it through compose using the service name.
+      instance_id: service.get_container_name(),
+      version: service.get_image_version(),
+      ..AppInstance::new(name.clone())
+    };
+    DockerContainer::build(instance, Some(Arc::new(self.clone())))
   }
 
   /// Set the status of all the services that this instance knows about
@@ -216,9 +382,229 @@ impl DockerCompose {
     }
   }
 
+  /// List the volumes mounted by the named service
+  pub fn get_volumes(&self, service_name: String) -> Result<Vec<ServiceVolume>> {
+    let conf = self
+      .get_conf()
+      .context("Failed to run DockerCompose::get_volumes")?;
+    match conf.services.get(&service_name) {
+      Some(service) => Ok(service.get_volumes()),
+      None => Err(FoundryError::NotFound).context(format!(
+        "Docker Compose does not have a service named '{}' in conf at '{}'",
+        service_name,
+        conf.get_source(),
+      )),
+    }
+  }
+
+  /// Find the host port that reaches a service's container port
+  pub fn get_host_port(
+    &self,
+    service_name: String,
+    container_port: u16,
+    protocol: PortProtocol,
+  ) -> Result<HostPort> {
+    self
+      .get_conf()
+      .context("Failed to run DockerCompose::get_host_port")?
+      .get_host_port(service_name, container_port, protocol)
+  }
+
+  /// Describe what is currently running for each service, as a schema that can be compared to the config
+  ///
+  /// Services without a running container are left out. Only the environment variables set in the
+  /// configuration are kept, since images add their own (eg: PATH).
+  /// TODO: Use the Docker API instead of the docker CLI for inspect
+  pub fn get_running_config(&self) -> Result<Schema> {
+    let conf = self
+      .get_conf()
+      .context("Failed to run DockerCompose::get_running_config")?;
+
+    let mut services = std::collections::BTreeMap::new();
+    for (name, service) in &conf.services {
+      let details = match inspect_service(&self.instance, &conf.get_files(), name, false)? {
+        Some(x) => x,
+        None => continue,
+      };
+      services.insert(name.clone(), running_service(service, &details)?);
+    }
+
+    Ok(Schema {
+      source: None,
+      overrides: vec![],
+      services,
+      ..conf
+    })
+  }
+
+  /// List what would change if the loaded configuration were deployed over what is running now
+  pub fn diff_running(&self) -> Result<Vec<Change>> {
+    Ok(diff::diff(&self.get_running_config()?, &self.get_conf()?))
+  }
+
+  /// The depends_on relationships between the services in the configuration
+  pub fn get_dependencies(&self) -> Result<DependencyGraph> {
+    DependencyGraph::new(&self.get_conf()?)
+  }
+
+  /// Start the services and everything they need, one tier at a time. Returns the services started.
+  pub fn up(&self, services: Vec<String>) -> Result<Vec<String>> {
+    match self.run_action(Action::Up(UpOptions::new(services)))? {
+      ActionResult::Up(started) => Ok(started),
+      x => Err(FoundryError::UnexpectedValue).context(format!(
+        "Running DockerCompose::UpOptions did not return an Up result:\n{:#?}",
+        x
+      )),
+    }
+  }
+
   // Cli functions will go here
 }
 
+/// Find the first container for a service and run "docker inspect" on it. None if it isn't running, or doesn't
+/// exist when stopped containers are included.
+fn inspect_service(
+  compose: &AppInstance,
+  config_files: &[String],
+  name: &str,
+  include_stopped: bool,
+) -> Result<Option<serde_json::Value>> {
+  let mut cmd = compose_command(compose, config_files)?;
+  cmd.arg("ps").arg("-q");
+  if include_stopped {
+    cmd.arg("-a");
+  }
+  cmd.arg(name);
+  let ps = launch(compose, &cmd)?.output()?;
+  if !ps.status.success() {
+    Err(FoundryError::RemoteError).context(format!(
+      "Could not list the containers for '{}':\n{}",
+      name,
+      String::from_utf8(ps.stderr)?.trim_end()
+    ))?;
+  }
+  let id = match first_container_id(&String::from_utf8(ps.stdout)?)? {
+    Some(x) => x,
+    None => return Ok(None),
+  };
+
+  // A plugin is already the engine's executable, otherwise hope the engine is next to the compose executable
+  let style = CliStyle::for_instance(compose);
+  let mut inspect = match style.is_plugin() {
+    true => std::process::Command::new(compose_executable(compose).get_program()),
+    false => std::process::Command::new(style.get_engine()),
+  };
+  inspect.arg("inspect").arg(&id);
+  let inspect = launch(compose, &inspect)?.output()?;
+  if !inspect.status.success() {
+    Err(FoundryError::RemoteError).context(format!(
+      "Could not inspect the container {} for '{}':\n{}",
+      id,
+      name,
+      String::from_utf8(inspect.stderr)?.trim_end()
+    ))?;
+  }
+  let details: serde_json::Value = serde_json::from_slice(&inspect.stdout)
+    .context(format!("{} inspect returned invalid json for '{}'", style.get_engine(), name))?;
+  Ok(Some(details[0].clone()))
+}
+
+/// The first container id listed by "ps -q". podman-compose can also print the podman commands it runs and
+/// its version, so only take what looks like a container id
+fn first_container_id(ps_output: &str) -> Result<Option<String>> {
+  let container_id = regex::Regex::new(r"^[0-9a-f]{12,64}$")?;
+  Ok(
+    ps_output
+      .lines()
+      .map(|x| x.trim())
+      .find(|x| container_id.is_match(x))
+      .map(|x| x.to_string()),
+  )
+}
+
+/// Drop the default registry, which podman always shows and docker hides (eg: docker.io/library/postgres:13)
+fn short_image_name(image: &str) -> &str {
+  image
+    .strip_prefix("docker.io/library/")
+    .or_else(|| image.strip_prefix("docker.io/"))
+    .unwrap_or(image)
+}
+
+/// Convert the output of "docker inspect" into a service so it can be compared with the configuration
+fn running_service(configured: &Service, details: &serde_json::Value) -> Result<Service> {
+  // Either side may use the full name, so the configured spelling is kept when they are the same image.
+  // A service that is only built gets an image named by compose, which there is nothing to compare with.
+  let image = match configured.get_image() {
+    Some(conf) => details["Config"]["Image"].as_str().map(|x| {
+      match short_image_name(x) == short_image_name(&conf) {
+        true => conf.clone(),
+        false => short_image_name(x).to_string(),
+      }
+    }),
+    None => None,
+  };
+
+  let known_env = configured.get_environment();
+  let environment = details["Config"]["Env"]
+    .as_array()
+    .map_or(vec![], |x| x.clone())
+    .iter()
+    .filter_map(|var| var.as_str()?.split_once('='))
+    .filter(|(key, _)| known_env.contains_key(*key))
+    .map(|(key, value)| (key.to_string(), Some(value.to_string())))
+    .collect();
+
+  let mut ports = vec![];
+  if let Some(bindings) = details["HostConfig"]["PortBindings"].as_object() {
+    for (container_port, hosts) in bindings {
+      for host in hosts.as_array().map_or(vec![], |x| x.clone()) {
+        let host_port = host["HostPort"].as_str().unwrap_or_default();
+        let short = match host["HostIp"].as_str().unwrap_or_default() {
+          "" | "0.0.0.0" | "::" => format!("{}:{}", host_port, container_port),
+          ip => format!("{}:{}:{}", ip, host_port, container_port),
+        };
+        ports.push(Port::parse_short(&short)?);
+      }
+    }
+  }
+
+  // Compose prefixes named volumes with the project name, which isn't in the configuration
+  let labels = &details["Config"]["Labels"];
+  let project = labels["com.docker.compose.project"]
+    .as_str()
+    .or_else(|| labels["io.podman.compose.project"].as_str())
+    .map(|x| format!("{}_", x));
+  let anonymous = regex::Regex::new(r"^[0-9a-f]{64}$")?;
+  let mut volumes = vec![];
+  for mount in details["Mounts"].as_array().map_or(vec![], |x| x.clone()) {
+    let target = mount["Destination"].as_str().unwrap_or_default();
+    let mode = match mount["RW"].as_bool() {
+      Some(false) => ":ro",
+      _ => "",
+    };
+    let short = match (mount["Type"].as_str(), mount["Name"].as_str()) {
+      (Some("bind"), _) => format!(
+        "{}:{}{}",
+        mount["Source"].as_str().unwrap_or_default(),
+        target,
+        mode
+      ),
+      (Some("volume"), Some(name)) if anonymous.is_match(name) => target.to_string(),
+      (Some("volume"), Some(name)) => {
+        let name = match &project {
+          Some(prefix) => name.strip_prefix(prefix.as_str()).unwrap_or(name),
+          None => name,
+        };
+        format!("{}:{}{}", name, target, mode)
+      }
+      _ => continue,
+    };
+    volumes.push(ServiceVolume::parse_short(&short)?);
+  }
+
+  Ok(Service::from_running(image, environment, ports, volumes))
+}
+
 // Let examine messages for the foundry for communicating rather than directly returning values
 #[derive(Clone, Debug, JsonSchema, Serialize, Deserialize)]
 pub enum Event {
@@ -234,6 +620,12 @@ pub enum Action {
   Find(FindApp),
   // Run the exec command against a running container
   Exec(ExecOptions),
+  /// Build or rebuild the images for services with a build configuration
+  Build(BuildOptions),
+  /// Start services after the ones they depend on are up and healthy
+  Up(UpOptions),
+  /// Stop services after the ones depending on them
+  Stop(StopOptions),
   /// Dump the configuration to the given file location. Useful for adding volumes/ports on the fly
   Export,
 }
@@ -245,6 +637,11 @@ pub enum ActionResult {
   FindResult(Vec<AppInstance>),
   ListServices(Vec<String>),
   Exec(String),
+  Build(String),
+  /// The services that were started, in order
+  Up(Vec<String>),
+  /// The services that were stopped, in order
+  Stop(Vec<String>),
 }
 
 impl Action {
@@ -257,7 +654,20 @@ impl Action {
           compose.find_one(query.0.clone())?
         ])),
       },
-      Action::Exec(opts) => opts.run(compose.instance.clone()),
+      Action::Exec(opts) => ExecOptions {
+        config_files: compose.get_conf()?.get_files(),
+        ..opts.clone()
+      }
+      .run(compose.instance.clone()),
+      Action::Build(opts) => opts
+        .for_schema(&compose.get_conf()?)?
+        .run(compose.instance.clone()),
+      Action::Up(opts) => opts
+        .for_schema(&compose.get_conf()?)?
+        .run(compose.instance.clone()),
+      Action::Stop(opts) => opts
+        .for_schema(&compose.get_conf()?)?
+        .run(compose.instance.clone()),
     }
   }
 }
@@ -272,7 +682,7 @@ impl ActionTrait for FindApp {
     unimplemented!("Still haven't figured out Actions yet")
   }
 
-  fn to_message(&self, target: Option<AppInstance>) -> Result<Vec<Message>> {
+  fn to_message(&self, _target: Option<AppInstance>) -> Result<Vec<Message>> {
     unimplemented!("ActionTrait not implemented for shell")
   }
 }
@@ -282,12 +692,27 @@ pub struct ExecOptions {
   command: String,
   args: Vec<String>,
   service_name: String,
+  /// -d, --detach: Run the command in the background
   detach: bool,
+  /// --privileged: Give extended privileges to the process
   privileged: bool,
+  /// --index: Which container to use if the service has multiple replicas (starts at 1)
   index: Option<u8>,
+  /// -u, --user: Run the command as this user
   user: Option<String>,
+  /// -e, --env: Set environment variables for the command
   env: Option<HashMap<String, String>>,
+  /// -w, --workdir: The working directory for the command
   workdir: Option<String>,
+  /// All the compose files to pass with "-f". If empty, the config_file of the instance is used
+  #[serde(default)]
+  config_files: Vec<String>,
+  /// Give up on the command after this long, killing it in the container
+  #[serde(default)]
+  timeout: Option<std::time::Duration>,
+  /// Give up on the command when this is cancelled, killing it in the container
+  #[serde(skip)]
+  cancel: Option<CancellationToken>,
 }
 
 impl ExecOptions {
@@ -298,57 +723,684 @@ impl ExecOptions {
       ..Default::default()
     }
   }
+
+  pub fn args(&self, args: Vec<String>) -> ExecOptions {
+    ExecOptions {
+      args,
+      ..self.clone()
+    }
+  }
+
+  pub fn detach(&self) -> ExecOptions {
+    ExecOptions {
+      detach: true,
+      ..self.clone()
+    }
+  }
+
+  pub fn privileged(&self) -> ExecOptions {
+    ExecOptions {
+      privileged: true,
+      ..self.clone()
+    }
+  }
+
+  pub fn index(&self, index: u8) -> ExecOptions {
+    ExecOptions {
+      index: Some(index),
+      ..self.clone()
+    }
+  }
+
+  pub fn user(&self, user: String) -> ExecOptions {
+    ExecOptions {
+      user: Some(user),
+      ..self.clone()
+    }
+  }
+
+  /// Add an environment variable, keeping any that are already set
+  pub fn env(&self, key: String, value: String) -> ExecOptions {
+    let mut env = self.env.clone().unwrap_or_default();
+    env.insert(key, value);
+    ExecOptions {
+      env: Some(env),
+      ..self.clone()
+    }
+  }
+
+  pub fn workdir(&self, workdir: String) -> ExecOptions {
+    ExecOptions {
+      workdir: Some(workdir),
+      ..self.clone()
+    }
+  }
+
+  pub fn timeout(&self, timeout: std::time::Duration) -> ExecOptions {
+    ExecOptions {
+      timeout: Some(timeout),
+      ..self.clone()
+    }
+  }
+
+  pub fn cancel_on(&self, token: CancellationToken) -> ExecOptions {
+    ExecOptions {
+      cancel: Some(token),
+      ..self.clone()
+    }
+  }
+
+  /// What runs in the container, with its user and limits
+  fn as_cmd(&self) -> Cmd {
+    Cmd {
+      run_as: self.user.clone(),
+      env: self.env.clone().unwrap_or_default().into_iter().collect(),
+      workdir: self.workdir.clone(),
+      timeout: self.timeout,
+      cancel: self.cancel.clone(),
+      ..Cmd::argv(self.command.clone(), self.args.clone())
+    }
+  }
+
+  /// Exec the command in the service, giving up on it when it hits its limits
+  async fn exec(&self, compose: &AppInstance, cmd: Cmd) -> Result<ActionResult> {
+    let exec = ExecOptions {
+      command: cmd.command.clone(),
+      args: cmd.args.clone(),
+      user: cmd.run_as.clone(),
+      env: Some(cmd.env.clone().into_iter().collect()),
+      workdir: cmd.workdir.clone(),
+      ..self.clone()
+    };
+    let process = exec.to_command(compose)?;
+    log::debug!("Docker compose is executing a cmd:\n{}", command_str(&process)?);
+    cmd.limit(exec_output_async(launch(compose, &process)?)).await
+  }
+
+  /// Build the full "docker-compose exec" command
+  fn to_command(&self, compose: &AppInstance) -> Result<std::process::Command> {
+    let mut cmd = compose_command(compose, &self.config_files)?;
+    cmd.arg("exec").arg("-T");
+    if self.detach {
+      cmd.arg("--detach");
+    }
+    if self.privileged {
+      cmd.arg("--privileged");
+    }
+    if let Some(index) = self.index {
+      cmd.arg("--index").arg(index.to_string());
+    }
+    if let Some(user) = &self.user {
+      cmd.arg("--user").arg(user);
+    }
+    // Sorted so the command is the same every time, which makes the logs easier to compare
+    let env: std::collections::BTreeMap<_, _> = self.env.clone().unwrap_or_default().into_iter().collect();
+    for (key, value) in env {
+      cmd.arg("--env").arg(format!("{}={}", key, value));
+    }
+    if let Some(workdir) = &self.workdir {
+      cmd.arg("--workdir").arg(workdir);
+    }
+    cmd.arg(&self.service_name);
+
+    // And add the command
+    cmd.arg(&self.command);
+    cmd.args(&self.args);
+    Ok(cmd)
+  }
+
+  /// Run the command, streaming the input to its stdin (eg: "psql < dump.sql")
+  pub fn run_with_input<R: std::io::Read + Send + 'static>(
+    &self,
+    compose: AppInstance,
+    input: R,
+  ) -> Result<ActionResult> {
+    let cmd = self.to_command(&compose)?;
+    log::debug!("Docker compose is executing a cmd with input:\n{}", command_str(&cmd)?);
+    exec_output(&mut launch(&compose, &cmd)?, Some(Box::new(input)))
+  }
+}
+
+/// Run the command and return stdout. Input is copied to stdin from a separate thread so a command
+/// writing a lot of output can't block us while we're still sending it data.
+fn exec_output(
+  cmd: &mut std::process::Command,
+  input: Option<Box<dyn std::io::Read + Send>>,
+) -> Result<ActionResult> {
+  use std::process::Stdio;
+
+  let mut child = cmd
+    .stdin(match input.is_some() {
+      true => Stdio::piped(),
+      false => Stdio::null(),
+    })
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .context(format!("Could not start '{}'", command_str(cmd)?))?;
+
+  let writer = match (input, child.stdin.take()) {
+    (Some(mut input), Some(mut stdin)) => Some(std::thread::spawn(move || {
+      // stdin is closed when dropped at the end of the thread, so the command sees EOF
+      std::io::copy(&mut input, &mut stdin)
+    })),
+    _ => None,
+  };
+
+  let result = child.wait_with_output()?;
+  let description = command_str(cmd)?;
+  if let Some(writer) = writer {
+    match writer.join() {
+      Ok(Ok(_)) => (),
+      // The command can exit before reading everything, in which case its status is the better error
+      Ok(Err(err)) if !result.status.success() => log::debug!("Stopped writing to stdin: {}", err),
+      Ok(Err(err)) => Err(FoundryError::IoError)
+        .context(format!("Failed to write to stdin of '{}': {}", description, err))?,
+      Err(_) => Err(FoundryError::UnhandledError)
+        .context(format!("The thread writing to stdin of '{}' panicked", description))?,
+    }
+  }
+  exec_result(&description, result)
+}
+
+/// The same as exec_output without any input, waiting without blocking. The command is killed if the future is
+/// dropped before it finishes.
+async fn exec_output_async(cmd: std::process::Command) -> Result<ActionResult> {
+  let description = command_str(&cmd)?;
+  let result = tokio::process::Command::from(cmd)
+    .stdin(std::process::Stdio::null())
+    .kill_on_drop(true)
+    .output()
+    .await
+    .context(format!("Could not start '{}'", description))?;
+  exec_result(&description, result)
+}
+
+/// Stdout if the command worked, otherwise an error with what it wrote to stderr
+fn exec_result(description: &str, result: std::process::Output) -> Result<ActionResult> {
+  match result.status.success() {
+    true => Ok(ActionResult::Exec(
+      String::from_utf8(result.stdout)?.trim_end().to_string(),
+    )),
+    false => Err(FoundryError::RemoteError).context(format!(
+      "'{}' failed with {}:\n{}",
+      description,
+      result.status.code().map_or("no exit code (killed by a signal)".to_string(), |code| {
+        format!("exit code {}", code)
+      }),
+      String::from_utf8_lossy(&result.stderr).trim_end()
+    )),
+  }
 }
 
-// "/usr/local/bin/docker-compose" "-f" "/home/dfogelson/Foundry/TheProcessFoundry/the_process_foundry/tests/data/postgres.docker-compose.yml" "exec" "-T" "postgres" "bash" "-c" "command -v pg_basebackup"
+/// Print the command the way it would be typed, for logging and errors. eg:
+// /usr/bin/docker-compose -f /home/dfogelson/Foundry/TheProcessFoundry/the_process_foundry/tests/data/postgres.docker-compose.yml exec -T postgres bash -c type -P pg_basebackup
 fn command_str(command: &std::process::Command) -> Result<String> {
-  let cmd = format!("{:#?}", command);
-  let regex = regex::Regex::new(r#"(^\s*")|(" ")|("\s*$)"#)?;
-  Ok(regex.replace_all(&cmd, " ").to_string())
+  Ok(
+    std::iter::once(command.get_program())
+      .chain(command.get_args())
+      .map(|x| x.to_string_lossy().to_string())
+      .collect::<Vec<String>>()
+      .join(" "),
+  )
+}
+
+/// The ways docker compose (or podman's version of it) can be installed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CliStyle {
+  /// The original standalone "docker-compose" executable
+  Standalone,
+  /// The Compose v2 docker plugin, run as "docker compose"
+  Plugin,
+  /// The standalone "podman-compose", for machines with podman instead of docker
+  PodmanCompose,
+  /// Podman's "podman compose", which passes everything on to podman-compose or docker-compose
+  PodmanPlugin,
+}
+
+impl CliStyle {
+  /// The order to look for them in when the style isn't given. podman compose only wraps the other two, so it
+  /// is the last resort.
+  pub const SEARCH_ORDER: [CliStyle; 4] = [
+    CliStyle::Plugin,
+    CliStyle::Standalone,
+    CliStyle::PodmanCompose,
+    CliStyle::PodmanPlugin,
+  ];
+
+  /// Pick the style from the name of an app instance, if it is specific
+  pub fn from_name(name: &str) -> Option<CliStyle> {
+    match &name.to_lowercase()[..] {
+      "docker-compose" => Some(CliStyle::Standalone),
+      "docker" | "docker compose" => Some(CliStyle::Plugin),
+      "podman-compose" => Some(CliStyle::PodmanCompose),
+      "podman" | "podman compose" => Some(CliStyle::PodmanPlugin),
+      _ => None,
+    }
+  }
+
+  /// The plugins are run through the engine's executable, so we can tell them apart by the path
+  pub fn for_instance(compose: &AppInstance) -> CliStyle {
+    let path = compose
+      .get_command_path()
+      .unwrap_or_else(|_| compose.name.clone());
+    let executable = std::path::Path::new(&path)
+      .file_name()
+      .map_or(path.clone(), |x| x.to_string_lossy().to_string());
+    match &executable[..] {
+      "docker" => CliStyle::Plugin,
+      "podman" => CliStyle::PodmanPlugin,
+      "podman-compose" => CliStyle::PodmanCompose,
+      _ => CliStyle::Standalone,
+    }
+  }
+
+  /// The name of the executable to look for
+  pub fn get_executable(&self) -> &'static str {
+    match self {
+      CliStyle::Standalone => "docker-compose",
+      CliStyle::Plugin => "docker",
+      CliStyle::PodmanCompose => "podman-compose",
+      CliStyle::PodmanPlugin => "podman",
+    }
+  }
+
+  /// Whether "compose" needs to be added as the first argument
+  pub fn is_plugin(&self) -> bool {
+    matches!(self, CliStyle::Plugin | CliStyle::PodmanPlugin)
+  }
+
+  /// The command for managing the containers themselves (eg: inspect)
+  pub fn get_engine(&self) -> &'static str {
+    match self {
+      CliStyle::Standalone | CliStyle::Plugin => "docker",
+      CliStyle::PodmanCompose | CliStyle::PodmanPlugin => "podman",
+    }
+  }
+}
+
+/// Start a command with the compose executable, adding "compose" when it is a plugin
+fn compose_executable(compose: &AppInstance) -> std::process::Command {
+  let path = compose.get_command_path().unwrap_or_else(|_| {
+    log::debug!(
+      "No CLI path is set for {}, looking for '{}' on the PATH",
+      compose.full_name(),
+      compose.name
+    );
+    compose.name.clone()
+  });
+
+  let mut cmd = std::process::Command::new(path);
+  if CliStyle::for_instance(compose).is_plugin() {
+    cmd.arg("compose");
+  }
+  cmd
 }
 
+/// Turn the command into one that runs wherever the compose CLI lives. Without a CLI container it runs as is
+/// on the local machine, otherwise the container decides how to reach it (eg: over ssh).
+fn launch(compose: &AppInstance, cmd: &std::process::Command) -> Result<std::process::Command> {
+  let container = match compose.cli.as_ref().and_then(|cli| cli.container.clone()) {
+    Some(x) => x,
+    None => return Ok(copy_command(cmd)),
+  };
+  let local = container
+    .local_command(Cmd::argv(
+      cmd.get_program().to_string_lossy().to_string(),
+      cmd.get_args().map(|x| x.to_string_lossy().to_string()).collect(),
+    ))
+    .context(format!("Could not run docker compose in {}", container.get_name()))?;
+
+  let mut launched = std::process::Command::new(&local.command);
+  launched.args(&local.args).envs(&local.env);
+  if let Some(workdir) = &local.workdir {
+    launched.current_dir(workdir);
+  }
+  Ok(launched)
+}
+
+/// std::process::Command can't be cloned, so rebuild it from the parts we use
+fn copy_command(cmd: &std::process::Command) -> std::process::Command {
+  let mut copy = std::process::Command::new(cmd.get_program());
+  copy.args(cmd.get_args());
+  copy
+}
+
+/// Start a docker-compose command with the config file options already set
+fn compose_command(compose: &AppInstance, config_files: &[String]) -> Result<std::process::Command> {
+  let mut cmd = compose_executable(compose);
+
+  // Add the option args
+  let files = match (config_files.is_empty(), &compose.config_file) {
+    (false, _) => config_files.to_vec(),
+    (true, Some(path)) => vec![path.clone()],
+    // TODO: Actually make the write function exist
+    (true, None) => Err(FoundryError::ConfigurationError).context(
+      "Docker Compose does not configuration file. TODO: Use DockerCompose::write to create one",
+    )?,
+  };
+  for file in files {
+    cmd.arg("-f").arg(file);
+  }
+  Ok(cmd)
+}
+
+#[async_trait]
 impl ActionTrait for ExecOptions {
   type RESPONSE = ActionResult;
 
   fn run(&self, compose: AppInstance) -> Result<Self::RESPONSE> {
-    // TODO: change this to use the instance path
-    let mut cmd = std::process::Command::new(format!("/usr/local/bin/{}", compose.name));
+    block_on(self.run_async(compose))
+  }
 
-    // Add the option args
-    match compose.config_file {
-      Some(path) => cmd.arg("-f").arg(path.clone()),
-      // TODO: Actually make the write function exist
-      None => Err(FoundryError::ConfigurationError).context(
-        "Docker Compose does not configuration file. TODO: Use DockerCompose::write to create one",
-      )?,
+  /// A command with a timeout or cancel is also killed in the container if it hits them
+  async fn run_async(&self, compose: AppInstance) -> Result<Self::RESPONSE> {
+    let compose = &compose;
+    self
+      .as_cmd()
+      .run_tagged(move |cmd| self.exec(compose, cmd))
+      .await
+  }
+
+  fn to_message(&self, _target: Option<AppInstance>) -> Result<Vec<Message>> {
+    unimplemented!("ActionTrait not implemented for shell")
+  }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BuildOptions {
+  /// The services to build. If empty, every service with a build configuration is built
+  services: Vec<String>,
+  /// Do not use cache when building the image
+  no_cache: bool,
+  /// Always attempt to pull a newer version of the image
+  pull: bool,
+  /// Set build-time variables, overriding the args in the build configuration. Sorted so the command is the
+  /// same every time
+  build_args: Option<std::collections::BTreeMap<String, String>>,
+  /// All the compose files to pass with "-f". If empty, the config_file of the instance is used
+  #[serde(default)]
+  config_files: Vec<String>,
+}
+
+impl BuildOptions {
+  pub fn new(services: Vec<String>) -> BuildOptions {
+    BuildOptions {
+      services,
+      ..Default::default()
+    }
+  }
+
+  /// Make sure the services can be built with the given schema, filling in all of them if none were given
+  pub fn for_schema(&self, conf: &Schema) -> Result<BuildOptions> {
+    let services = match self.services.is_empty() {
+      true => conf
+        .services
+        .iter()
+        .filter(|(_, service)| service.get_build().is_some())
+        .map(|(name, _)| name.clone())
+        .collect(),
+      false => self.services.clone(),
     };
-    cmd.arg("exec").arg("-T");
-    match &self.user {
-      None => (),
-      Some(user) => {
-        cmd.arg("--user");
-        cmd.arg(&user.clone());
+    if services.is_empty() {
+      Err(FoundryError::NotConfigured).context(format!(
+        "There are no services with a build configuration in '{}'",
+        conf.get_source()
+      ))?;
+    }
+
+    for name in &services {
+      let build = match conf.services.get(name).map(|service| service.get_build()) {
+        None => Err(FoundryError::NotFound).context(format!(
+          "Cannot build '{}': it is not a service in '{}'",
+          name,
+          conf.get_source()
+        ))?,
+        Some(None) => Err(FoundryError::NotConfigured).context(format!(
+          "Cannot build '{}': it does not have a build configuration in '{}'",
+          name,
+          conf.get_source()
+        ))?,
+        Some(Some(build)) => build,
+      };
+      if let (Some(ctx), false) = (build.get_context(), build.is_remote_context()) {
+        if !std::path::Path::new(&ctx).is_dir() {
+          log::warn!("The build context '{}' for '{}' is not a local directory", ctx, name);
+        }
       }
-    };
-    cmd.arg(self.service_name.clone());
+    }
 
-    // And add the command
-    cmd.arg(self.command.clone());
-    cmd.args(&self.args);
-    log::debug!("Docker compose is executing a cmd:\n{}", command_str(&cmd)?);
-    let result = cmd.arg("").output()?;
+    Ok(BuildOptions {
+      services,
+      config_files: conf.get_files(),
+      ..self.clone()
+    })
+  }
+
+  /// Build the full "docker-compose build" command
+  fn to_command(&self, compose: &AppInstance) -> Result<std::process::Command> {
+    let mut cmd = compose_command(compose, &self.config_files)?;
+    cmd.arg("build");
+    if self.no_cache {
+      cmd.arg("--no-cache");
+    }
+    if self.pull {
+      cmd.arg("--pull");
+    }
+    if let Some(args) = &self.build_args {
+      for (key, value) in args {
+        cmd.arg("--build-arg").arg(format!("{}={}", key, value));
+      }
+    }
+    cmd.args(&self.services);
+    Ok(cmd)
+  }
+}
+
+impl ActionTrait for BuildOptions {
+  type RESPONSE = ActionResult;
+
+  fn run(&self, compose: AppInstance) -> Result<Self::RESPONSE> {
+    let cmd = self.to_command(&compose)?;
+    log::debug!("Docker compose is building:\n{}", command_str(&cmd)?);
+    let result = launch(&compose, &cmd)?.output()?;
     match result.status.success() {
-      true => Ok(ActionResult::Exec(
+      true => Ok(ActionResult::Build(
         String::from_utf8(result.stdout)?.trim_end().to_string(),
       )),
-      false => {
-        Err(FoundryError::RemoteError).context("Received an error trying to run in docker compose")
+      false => Err(FoundryError::RemoteError).context(format!(
+        "Docker compose failed to build {:#?}:\n{}",
+        self.services,
+        String::from_utf8(result.stderr)?.trim_end()
+      )),
+    }
+  }
+
+  fn to_message(&self, _target: Option<AppInstance>) -> Result<Vec<Message>> {
+    unimplemented!("ActionTrait not implemented for DockerCompose::BuildOptions")
+  }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpOptions {
+  /// The services to start along with everything they depend on. If empty, all services are started
+  services: Vec<String>,
+  /// How long to wait for each tier to be healthy before giving up
+  health_timeout: Option<std::time::Duration>,
+  /// Services grouped by the order they need to be started in, filled in by for_schema
+  #[serde(default)]
+  tiers: Vec<Vec<String>>,
+  /// All the compose files to pass with "-f". If empty, the config_file of the instance is used
+  #[serde(default)]
+  config_files: Vec<String>,
+}
+
+impl UpOptions {
+  pub fn new(services: Vec<String>) -> UpOptions {
+    UpOptions {
+      services,
+      ..Default::default()
+    }
+  }
+
+  /// Work out the tiers from the dependencies in the schema
+  pub fn for_schema(&self, conf: &Schema) -> Result<UpOptions> {
+    Ok(UpOptions {
+      tiers: DependencyGraph::new(conf)?.start_tiers(&self.services)?,
+      config_files: conf.get_files(),
+      ..self.clone()
+    })
+  }
+}
+
+/// Default for how long a tier has to become healthy
+const HEALTH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+/// Wait for the service's container to be healthy, or just running if it doesn't have a healthcheck
+fn wait_for_healthy(
+  compose: &AppInstance,
+  config_files: &[String],
+  service: &str,
+  timeout: std::time::Duration,
+) -> Result<()> {
+  let start = std::time::Instant::now();
+  loop {
+    // One-shot services have already exited, so stopped containers are needed too
+    let state = inspect_service(compose, config_files, service, true)?
+      .map_or(serde_json::Value::Null, |details| details["State"].clone());
+    if is_ready(service, &state)? {
+      return Ok(());
+    }
+
+    if start.elapsed() > timeout {
+      Err(FoundryError::RemoteError).context(format!(
+        "Timed out after {:?} waiting for '{}' to be healthy",
+        timeout, service
+      ))?;
+    }
+    log::debug!("Waiting for '{}' to be healthy", service);
+    std::thread::sleep(std::time::Duration::from_secs(1));
+  }
+}
+
+/// Whether the services depending on this one can be started, using the State from docker inspect.
+/// It is an error if the container will never be ready.
+fn is_ready(service: &str, state: &serde_json::Value) -> Result<bool> {
+  // Podman before 4.3 calls it Healthcheck
+  let health = state["Health"]["Status"]
+    .as_str()
+    .or_else(|| state["Healthcheck"]["Status"].as_str());
+  let status = state["Status"].as_str();
+
+  match (health, status) {
+    (Some("healthy"), _) => Ok(true),
+    (None, Some("running")) => Ok(true),
+    // Without a healthcheck, a service that exits cleanly is a one-shot task (eg: migrations) that is done
+    (None, Some("exited")) if state["ExitCode"].as_i64() == Some(0) => Ok(true),
+    (Some("unhealthy"), _) => Err(FoundryError::RemoteError).context(format!(
+      "The healthcheck for '{}' is reporting it as unhealthy",
+      service
+    )),
+    (_, Some("exited")) | (_, Some("dead")) => Err(FoundryError::RemoteError).context(format!(
+      "'{}' stopped with exit code {} while waiting for it to start",
+      service, state["ExitCode"]
+    )),
+    _ => Ok(false),
+  }
+}
+
+impl ActionTrait for UpOptions {
+  type RESPONSE = ActionResult;
+
+  fn run(&self, compose: AppInstance) -> Result<Self::RESPONSE> {
+    let mut started = vec![];
+    for tier in &self.tiers {
+      let mut cmd = compose_command(&compose, &self.config_files)?;
+      cmd.arg("up").arg("-d").arg("--no-deps").args(tier);
+
+      log::debug!("Docker compose is starting a tier:\n{}", command_str(&cmd)?);
+      let result = launch(&compose, &cmd)?.output()?;
+      if !result.status.success() {
+        Err(FoundryError::RemoteError).context(format!(
+          "Docker compose failed to start {:?}:\n{}",
+          tier,
+          String::from_utf8(result.stderr)?.trim_end()
+        ))?;
       }
+
+      for service in tier {
+        wait_for_healthy(
+          &compose,
+          &self.config_files,
+          service,
+          self.health_timeout.unwrap_or(HEALTH_TIMEOUT),
+        )?;
+      }
+      started.extend(tier.clone());
     }
+    Ok(ActionResult::Up(started))
   }
 
-  fn to_message(&self, target: Option<AppInstance>) -> Result<Vec<Message>> {
-    unimplemented!("ActionTrait not implemented for shell")
+  fn to_message(&self, _target: Option<AppInstance>) -> Result<Vec<Message>> {
+    unimplemented!("ActionTrait not implemented for DockerCompose::UpOptions")
+  }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StopOptions {
+  /// The services to stop along with everything depending on them. If empty, all services are stopped
+  services: Vec<String>,
+  /// Services grouped by the order they need to be stopped in, filled in by for_schema
+  #[serde(default)]
+  tiers: Vec<Vec<String>>,
+  /// All the compose files to pass with "-f". If empty, the config_file of the instance is used
+  #[serde(default)]
+  config_files: Vec<String>,
+}
+
+impl StopOptions {
+  pub fn new(services: Vec<String>) -> StopOptions {
+    StopOptions {
+      services,
+      ..Default::default()
+    }
+  }
+
+  /// Work out the tiers from the dependencies in the schema
+  pub fn for_schema(&self, conf: &Schema) -> Result<StopOptions> {
+    Ok(StopOptions {
+      tiers: DependencyGraph::new(conf)?.stop_tiers(&self.services)?,
+      config_files: conf.get_files(),
+      ..self.clone()
+    })
+  }
+}
+
+impl ActionTrait for StopOptions {
+  type RESPONSE = ActionResult;
+
+  fn run(&self, compose: AppInstance) -> Result<Self::RESPONSE> {
+    let mut stopped = vec![];
+    for tier in &self.tiers {
+      let mut cmd = compose_command(&compose, &self.config_files)?;
+      cmd.arg("stop").args(tier);
+
+      log::debug!("Docker compose is stopping a tier:\n{}", command_str(&cmd)?);
+      let result = launch(&compose, &cmd)?.output()?;
+      if !result.status.success() {
+        Err(FoundryError::RemoteError).context(format!(
+          "Docker compose failed to stop {:?}:\n{}",
+          tier,
+          String::from_utf8(result.stderr)?.trim_end()
+        ))?;
+      }
+      stopped.extend(tier.clone());
+    }
+    Ok(ActionResult::Stop(stopped))
+  }
+
+  fn to_message(&self, _target: Option<AppInstance>) -> Result<Vec<Message>> {
+    unimplemented!("ActionTrait not implemented for DockerCompose::StopOptions")
   }
 }
 
@@ -405,3 +1457,435 @@ pub enum CliActions {
   /// Show the Docker-Compose version information
   Version,
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn service(yaml: &str) -> Service {
+    serde_yaml::from_str(yaml).unwrap()
+  }
+
+  /// Compose run straight from the given executable, with a single config file
+  fn compose(path: &str) -> AppInstance {
+    AppInstance {
+      config_file: Some("/srv/app/docker-compose.yml".to_string()),
+      ..AppInstance::new("docker-compose".to_string())
+    }
+    .set_command_path(None, path.to_string())
+    .unwrap()
+  }
+
+  fn args(cmd: &std::process::Command) -> Vec<String> {
+    cmd.get_args().map(|x| x.to_string_lossy().to_string()).collect()
+  }
+
+  /// A directory of fake executables, which is all a Bin container can find
+  #[derive(Debug)]
+  struct Bin {
+    dir: std::path::PathBuf,
+  }
+
+  impl Bin {
+    fn new() -> Bin {
+      let dir = std::env::temp_dir().join(format!("foundry-{}", uuid::Uuid::new_v4()));
+      std::fs::create_dir_all(&dir).unwrap();
+      Bin { dir }
+    }
+
+    /// Add an executable running the script. It is written by a child process, so no other test's fork can
+    /// inherit an open handle to it and make exec fail with "Text file busy"
+    fn add(&self, name: &str, script: &str) -> String {
+      let path = self.dir.join(name).to_string_lossy().to_string();
+      let status = std::process::Command::new("sh")
+        .args(["-c", "printf '#!/bin/sh\\n%s\\n' \"$2\" > \"$1\" && chmod 755 \"$1\""])
+        .args(["sh", &path, script])
+        .status()
+        .unwrap();
+      assert!(status.success());
+      path
+    }
+  }
+
+  impl Drop for Bin {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.dir);
+    }
+  }
+
+  impl ContainerTrait for Bin {
+    fn find(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
+      let path = self.dir.join(&query.name);
+      match path.is_file() {
+        true => {
+          let path = path.to_string_lossy().to_string();
+          Ok(vec![AppInstance::new(query.name).set_command_path(None, path)?])
+        }
+        false => Ok(vec![]),
+      }
+    }
+
+    fn forward(&self, _to: AppInstance, _message: Message) -> Result<String> {
+      Err(FoundryError::NotImplemented).context("Nothing is sent to the test bin")
+    }
+
+    fn cached_apps(&self) -> Result<Vec<AppInstance>> {
+      Ok(vec![])
+    }
+
+    fn get_name(&self) -> String {
+      "Test Bin".to_string()
+    }
+  }
+
+  fn find_cli(bin: Bin, style: Option<CliStyle>) -> Result<AppInstance> {
+    DockerCompose::find_cli(Arc::new(bin), style)
+  }
+
+  #[test]
+  fn running_images_are_only_compared_when_configured() {
+    let details = json!({"Config": {"Image": "docker.io/library/myapp_web:latest", "Env": []}});
+
+    let built = running_service(&service("build: ."), &details).unwrap();
+    assert_eq!(built.get_image(), None);
+    let conf = Schema {
+      services: vec![("web".to_string(), service("build: ."))].into_iter().collect(),
+      ..Default::default()
+    };
+    let running = Schema {
+      services: vec![("web".to_string(), built)].into_iter().collect(),
+      ..Default::default()
+    };
+    assert_eq!(diff::diff(&running, &conf), vec![]);
+
+    let pulled = running_service(&service("image: postgres:13"), &details).unwrap();
+    assert_eq!(pulled.get_image(), Some("myapp_web:latest".to_string()));
+  }
+
+  #[test]
+  fn images_match_with_or_without_the_default_registry() {
+    for (configured, running) in [
+      ("postgres:13", "docker.io/library/postgres:13"),
+      ("docker.io/library/postgres:13", "docker.io/library/postgres:13"),
+      ("docker.io/library/postgres:13", "postgres:13"),
+      ("bitnami/redis:7", "docker.io/bitnami/redis:7"),
+      ("quay.io/minio/minio", "quay.io/minio/minio"),
+    ] {
+      let details = json!({"Config": {"Image": running}});
+      let conf = service(&format!("image: {}", configured));
+      let found = running_service(&conf, &details).unwrap();
+      assert_eq!(found.get_image(), Some(configured.to_string()), "{} running {}", configured, running);
+    }
+
+    let details = json!({"Config": {"Image": "docker.io/library/postgres:14"}});
+    let found = running_service(&service("image: docker.io/library/postgres:13"), &details).unwrap();
+    assert_eq!(found.get_image(), Some("postgres:14".to_string()));
+  }
+
+  #[test]
+  fn podman_compose_noise_is_not_a_container_id() {
+    let ps = "podman ps -a --filter label=io.podman.compose.project=myapp -q\n\
+      ['podman', '--version', '']\n\
+      using podman version: 4.3.1\n\
+      \n\
+      \x20 3f2a6b1c9d8e7f6a5b4c3d2e1f0a9b8c7d6e5f4a3b2c1d0e9f8a7b6c5d4e3f2a  \n\
+      8c1d2e3f4a5b\n\
+      exit code: 0\n";
+    assert_eq!(
+      first_container_id(ps).unwrap(),
+      Some("3f2a6b1c9d8e7f6a5b4c3d2e1f0a9b8c7d6e5f4a3b2c1d0e9f8a7b6c5d4e3f2a".to_string())
+    );
+    assert_eq!(first_container_id("8c1d2e3f4a5b\n").unwrap(), Some("8c1d2e3f4a5b".to_string()));
+    assert_eq!(first_container_id("using podman version: 4.3.1\nexit code: 0\n").unwrap(), None);
+    assert_eq!(first_container_id("").unwrap(), None);
+  }
+
+  #[test]
+  fn podman_inspect_matches_the_config() {
+    // Trimmed from podman 4.2 inspect of a podman-compose service
+    let details = json!({
+      "Config": {
+        "Image": "docker.io/library/postgres:13",
+        "Env": ["PATH=/usr/local/bin:/usr/bin", "POSTGRES_DB=app", "PGDATA=/var/lib/postgresql/data"],
+        "Labels": {
+          "io.podman.compose.project": "myapp",
+          "io.podman.compose.version": "1.0.3",
+          "com.docker.compose.service": "db"
+        }
+      },
+      "HostConfig": {"PortBindings": {"5432/tcp": [{"HostIp": "", "HostPort": "15432"}]}},
+      "Mounts": [
+        {"Type": "volume", "Name": "myapp_pgdata", "Destination": "/var/lib/postgresql/data", "RW": true},
+        {"Type": "bind", "Source": "/srv/app/init", "Destination": "/docker-entrypoint-initdb.d", "RW": false}
+      ],
+      "State": {"Status": "running", "Healthcheck": {"Status": "healthy"}}
+    });
+    let conf = service(
+      "image: postgres:13\n\
+       environment:\n  POSTGRES_DB: app\n\
+       ports:\n  - \"15432:5432\"\n\
+       volumes:\n  - pgdata:/var/lib/postgresql/data\n  - /srv/app/init:/docker-entrypoint-initdb.d:ro\n",
+    );
+
+    let running = running_service(&conf, &details).unwrap();
+    assert_eq!(running.get_image(), Some("postgres:13".to_string()));
+    // The project prefix is stripped from the volume name, and the image's own env is left out
+    let schema = |service: Service| Schema {
+      services: vec![("db".to_string(), service)].into_iter().collect(),
+      ..Default::default()
+    };
+    assert_eq!(diff::diff(&schema(running), &schema(conf)), vec![]);
+
+    // Podman before 4.3 reports the healthcheck under Healthcheck, newer ones and docker under Health
+    assert!(is_ready("db", &details["State"]).unwrap());
+    let starting = json!({"Status": "running", "Healthcheck": {"Status": "starting"}});
+    assert!(!is_ready("db", &starting).unwrap());
+    let unhealthy = json!({"Status": "running", "Healthcheck": {"Status": "unhealthy"}});
+    assert!(is_ready("db", &unhealthy).is_err());
+  }
+
+  #[test]
+  fn containers_are_only_named_when_the_config_names_them() {
+    let dir = std::env::temp_dir().join(format!("foundry-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("docker-compose.yml");
+    std::fs::write(
+      &file,
+      "version: \"3.8\"\nservices:\n  db:\n    image: postgres:13\n  web:\n    image: nginx\n    container_name: frontend\n",
+    )
+    .unwrap();
+
+    let compose = DockerCompose::build(AppInstance::new("docker-compose".to_string()), None)
+      .unwrap()
+      .load(file.to_string_lossy().to_string());
+    std::fs::remove_dir_all(&dir).unwrap();
+    let compose = compose.unwrap();
+
+    let db = compose.get_container("db".to_string()).unwrap();
+    assert_eq!(db.instance.instance_id, None);
+    assert_eq!(db.instance.name, "db");
+    let web = compose.get_container("web".to_string()).unwrap();
+    assert_eq!(web.instance.instance_id, Some("frontend".to_string()));
+  }
+
+  #[test]
+  fn services_are_ready_when_healthy_running_or_done() {
+    let ready = |state: serde_json::Value| is_ready("db", &state);
+    assert!(ready(json!({"Status": "running", "Health": {"Status": "healthy"}})).unwrap());
+    assert!(ready(json!({"Status": "running", "Healthcheck": {"Status": "healthy"}})).unwrap());
+    assert!(ready(json!({"Status": "running"})).unwrap());
+    assert!(ready(json!({"Status": "exited", "ExitCode": 0})).unwrap());
+
+    assert!(!ready(json!({"Status": "running", "Health": {"Status": "starting"}})).unwrap());
+    assert!(!ready(json!({"Status": "created"})).unwrap());
+    assert!(!ready(serde_json::Value::Null).unwrap());
+
+    assert!(ready(json!({"Status": "exited", "ExitCode": 1})).is_err());
+    assert!(ready(json!({"Status": "exited", "ExitCode": 0, "Health": {"Status": "starting"}})).is_err());
+    assert!(ready(json!({"Status": "running", "Health": {"Status": "unhealthy"}})).is_err());
+  }
+
+  #[test]
+  fn build_args_are_passed_in_order() {
+    let options = BuildOptions {
+      no_cache: true,
+      pull: true,
+      build_args: Some(
+        vec![("VERSION", "13"), ("BASE", "debian"), ("MIRROR", "")]
+          .into_iter()
+          .map(|(key, value)| (key.to_string(), value.to_string()))
+          .collect(),
+      ),
+      ..BuildOptions::new(vec!["db".to_string(), "web".to_string()])
+    };
+    let cmd = options.to_command(&compose("/usr/bin/docker-compose")).unwrap();
+    assert_eq!(cmd.get_program(), "/usr/bin/docker-compose");
+    assert_eq!(
+      args(&cmd),
+      vec![
+        "-f",
+        "/srv/app/docker-compose.yml",
+        "build",
+        "--no-cache",
+        "--pull",
+        "--build-arg",
+        "BASE=debian",
+        "--build-arg",
+        "MIRROR=",
+        "--build-arg",
+        "VERSION=13",
+        "db",
+        "web",
+      ]
+    );
+
+    let plugin = BuildOptions::new(vec![]).to_command(&compose("/usr/bin/docker")).unwrap();
+    assert_eq!(args(&plugin), vec!["compose", "-f", "/srv/app/docker-compose.yml", "build"]);
+  }
+
+  #[test]
+  fn build_options_only_take_buildable_services() {
+    let conf = Schema {
+      services: vec![
+        ("db".to_string(), service("image: postgres:13")),
+        ("web".to_string(), service("build:\n  context: https://github.com/example/web.git\n")),
+      ]
+      .into_iter()
+      .collect(),
+      ..Default::default()
+    };
+    let options = BuildOptions::default().for_schema(&conf).unwrap();
+    assert_eq!(options.services, vec!["web".to_string()]);
+
+    let not_built = BuildOptions::new(vec!["db".to_string()]).for_schema(&conf).unwrap_err();
+    assert!(matches!(not_built.downcast_ref::<FoundryError>(), Some(FoundryError::NotConfigured)));
+    let missing = BuildOptions::new(vec!["cache".to_string()]).for_schema(&conf).unwrap_err();
+    assert!(matches!(missing.downcast_ref::<FoundryError>(), Some(FoundryError::NotFound)));
+  }
+
+  #[test]
+  fn cli_styles_come_from_the_name_or_the_executable() {
+    let named = |name: &str| CliStyle::from_name(name);
+    assert_eq!(named("docker-compose"), Some(CliStyle::Standalone));
+    assert_eq!(named("Docker Compose"), Some(CliStyle::Plugin));
+    assert_eq!(named("docker"), Some(CliStyle::Plugin));
+    assert_eq!(named("podman-compose"), Some(CliStyle::PodmanCompose));
+    assert_eq!(named("podman compose"), Some(CliStyle::PodmanPlugin));
+    assert_eq!(named("compose"), None);
+
+    let style = |path: &str| CliStyle::for_instance(&compose(path));
+    assert_eq!(style("/usr/bin/docker"), CliStyle::Plugin);
+    assert_eq!(style("/usr/local/bin/docker-compose"), CliStyle::Standalone);
+    assert_eq!(style("/usr/bin/podman"), CliStyle::PodmanPlugin);
+    assert_eq!(style("podman-compose"), CliStyle::PodmanCompose);
+
+    for style in CliStyle::SEARCH_ORDER {
+      assert_eq!(CliStyle::from_name(style.get_executable()), Some(style));
+      assert_eq!(style.is_plugin(), matches!(style.get_executable(), "docker" | "podman"));
+    }
+    assert_eq!(CliStyle::PodmanCompose.get_engine(), "podman");
+    assert_eq!(CliStyle::Standalone.get_engine(), "docker");
+  }
+
+  #[test]
+  fn the_first_working_style_is_used() {
+    // docker is installed without the compose plugin, so the search falls through to docker-compose
+    let bin = Bin::new();
+    bin.add("docker", "echo \"docker: 'compose' is not a docker command.\" >&2; exit 1");
+    let standalone = bin.add("docker-compose", "echo 1.29.2");
+    bin.add("podman-compose", "echo 1.0.6");
+    let found = find_cli(bin, None).unwrap();
+    assert_eq!(found.get_command_path().unwrap(), standalone);
+    assert_eq!(found.version, Some(semver::Version::new(1, 29, 2)));
+
+    let bin = Bin::new();
+    let plugin = bin.add("podman", "[ \"$1 $2 $3\" = \"compose version --short\" ] && echo 4.3.1");
+    let found = find_cli(bin, None).unwrap();
+    assert_eq!(found.get_command_path().unwrap(), plugin);
+    assert_eq!(CliStyle::for_instance(&found), CliStyle::PodmanPlugin);
+  }
+
+  #[test]
+  fn missing_compose_lists_every_style_tried() {
+    let bin = Bin::new();
+    bin.add("docker", "exit 1");
+    let err = find_cli(bin, None).unwrap_err();
+    assert!(matches!(err.downcast_ref::<FoundryError>(), Some(FoundryError::NotFound)));
+    let message = format!("{:#}", err);
+    for style in CliStyle::SEARCH_ORDER {
+      assert!(message.contains(&format!("{:?}: ", style)), "{}", message);
+    }
+    assert!(message.contains("the compose plugin does not seem to be installed"), "{}", message);
+
+    // A style that was asked for isn't swapped for another
+    let bin = Bin::new();
+    bin.add("docker-compose", "echo 1.29.2");
+    assert!(find_cli(bin, Some(CliStyle::Plugin)).is_err());
+  }
+
+  #[test]
+  fn versions_are_found_in_the_output() {
+    let version = |output: &str| {
+      let bin = Bin::new();
+      bin.add("podman-compose", &format!("printf '%s\\n' '{}'", output));
+      find_cli(bin, Some(CliStyle::PodmanCompose)).unwrap().version
+    };
+    assert_eq!(version("2.20.0"), Some(semver::Version::new(2, 20, 0)));
+    assert_eq!(version("v2.20.3-desktop.1"), Some(semver::Version::new(2, 20, 3)));
+    assert_eq!(
+      version("podman-compose version: 1.0.6 using podman version: 4.3.1"),
+      Some(semver::Version::new(1, 0, 6))
+    );
+    // Still usable, just without a version
+    assert_eq!(version("dev"), None);
+  }
+
+  #[test]
+  fn exec_options_become_compose_arguments() {
+    let exec = ExecOptions::new("db".to_string(), "psql".to_string())
+      .args(vec!["-c".to_string(), "select 1".to_string()])
+      .user("postgres".to_string())
+      .env("PGUSER".to_string(), "admin".to_string())
+      .env("PGDATABASE".to_string(), "app".to_string())
+      .workdir("/var/lib/postgresql".to_string())
+      .index(2)
+      .privileged();
+    let cmd = exec.to_command(&compose("/usr/bin/docker-compose")).unwrap();
+    assert_eq!(
+      args(&cmd),
+      vec![
+        "-f",
+        "/srv/app/docker-compose.yml",
+        "exec",
+        "-T",
+        "--privileged",
+        "--index",
+        "2",
+        "--user",
+        "postgres",
+        "--env",
+        "PGDATABASE=app",
+        "--env",
+        "PGUSER=admin",
+        "--workdir",
+        "/var/lib/postgresql",
+        "db",
+        "psql",
+        "-c",
+        "select 1",
+      ]
+    );
+
+    // There is never a tty to allocate, even for the simplest command
+    let bare = ExecOptions::new("db".to_string(), "true".to_string())
+      .to_command(&compose("/usr/bin/docker"))
+      .unwrap();
+    assert_eq!(args(&bare), vec!["compose", "-f", "/srv/app/docker-compose.yml", "exec", "-T", "db", "true"]);
+  }
+
+  #[test]
+  fn input_is_streamed_to_the_command() {
+    // The fake compose ignores its arguments and echoes its stdin, so a large input only makes it back if it
+    // is written while the output is being read
+    let bin = Bin::new();
+    let path = bin.add("docker-compose", "exec cat");
+    let input = "insert into t values (1);\n".repeat(100_000);
+
+    let exec = ExecOptions::new("db".to_string(), "psql".to_string());
+    let result = exec
+      .run_with_input(compose(&path), std::io::Cursor::new(input.clone().into_bytes()))
+      .unwrap();
+    match result {
+      ActionResult::Exec(output) => assert_eq!(output, input.trim_end()),
+      x => panic!("Expected the exec output, got {:?}", x),
+    }
+
+    let failing = bin.add("failing-compose", "cat > /dev/null; echo 'no such service' >&2; exit 3");
+    let err = exec
+      .run_with_input(compose(&failing), std::io::Cursor::new(input.into_bytes()))
+      .unwrap_err();
+    let message = format!("{:#}", err);
+    assert!(message.contains("exit code 3") && message.contains("no such service"), "{}", message);
+  }
+}
@@ -0,0 +1,244 @@
+//! The order services need to be started and stopped in, based on depends_on
+//!
+//! Services are grouped into tiers, where everything in a tier only depends on services in earlier tiers. A
+//! whole tier can be started at once, and we wait for it to be healthy before moving on to the next one.
+
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::schema::Schema;
+use super::FoundryError;
+
+#[derive(Debug, Clone, Default)]
+pub struct DependencyGraph {
+  /// Each service and the services it depends on directly
+  depends_on: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl DependencyGraph {
+  /// Build the graph, making sure every dependency exists and there are no cycles
+  pub fn new(schema: &Schema) -> Result<DependencyGraph> {
+    let mut depends_on = BTreeMap::new();
+    for (name, service) in &schema.services {
+      let deps: BTreeSet<String> = service.get_depends_on().into_iter().collect();
+      for dep in &deps {
+        if !schema.services.contains_key(dep) {
+          Err(FoundryError::NotFound).context(format!(
+            "Service '{}' depends on '{}', which is not defined in '{}'",
+            name,
+            dep,
+            schema.get_source()
+          ))?;
+        }
+      }
+      depends_on.insert(name.clone(), deps);
+    }
+
+    let graph = DependencyGraph { depends_on };
+    graph.check_cycles()?;
+    Ok(graph)
+  }
+
+  fn check_cycles(&self) -> Result<()> {
+    // Depth first search, keeping the current path so we can show the user the loop
+    fn visit(
+      graph: &DependencyGraph,
+      name: &str,
+      path: &mut Vec<String>,
+      done: &mut BTreeSet<String>,
+    ) -> Result<()> {
+      if done.contains(name) {
+        return Ok(());
+      }
+      if let Some(idx) = path.iter().position(|x| x == name) {
+        let mut cycle = path[idx..].to_vec();
+        cycle.push(name.to_string());
+        Err(FoundryError::ConfigurationError).context(format!(
+          "The services have a circular dependency: {}",
+          cycle.join(" -> ")
+        ))?;
+      }
+      path.push(name.to_string());
+      for dep in graph.get_dependencies(name) {
+        visit(graph, &dep, path, done)?;
+      }
+      path.pop();
+      done.insert(name.to_string());
+      Ok(())
+    }
+
+    let mut done = BTreeSet::new();
+    for name in self.depends_on.keys() {
+      visit(self, name, &mut vec![], &mut done)?;
+    }
+    Ok(())
+  }
+
+  /// The services the given one depends on directly
+  pub fn get_dependencies(&self, service: &str) -> BTreeSet<String> {
+    self.depends_on.get(service).cloned().unwrap_or_default()
+  }
+
+  /// Everything the service needs to be running first, no matter how indirectly
+  pub fn requires(&self, service: &str) -> Result<BTreeSet<String>> {
+    if !self.depends_on.contains_key(service) {
+      Err(FoundryError::NotFound).context(format!(
+        "Cannot look up the dependencies of '{}': it is not a service",
+        service
+      ))?;
+    }
+
+    let mut found = BTreeSet::new();
+    let mut todo: Vec<String> = self.get_dependencies(service).into_iter().collect();
+    while let Some(name) = todo.pop() {
+      if found.insert(name.clone()) {
+        todo.extend(self.get_dependencies(&name));
+      }
+    }
+    Ok(found)
+  }
+
+  /// The services that need to be started to run the targets, including the targets themselves.
+  /// No targets means all of them.
+  pub fn closure(&self, targets: &[String]) -> Result<BTreeSet<String>> {
+    match targets.is_empty() {
+      true => Ok(self.depends_on.keys().cloned().collect()),
+      false => {
+        let mut all = BTreeSet::new();
+        for target in targets {
+          all.extend(self.requires(target)?);
+          all.insert(target.clone());
+        }
+        Ok(all)
+      }
+    }
+  }
+
+  /// Group the services needed by the targets into tiers that can each be started together, in order
+  pub fn start_tiers(&self, targets: &[String]) -> Result<Vec<Vec<String>>> {
+    let mut remaining = self.closure(targets)?;
+    let mut started = BTreeSet::new();
+    let mut tiers = vec![];
+
+    while !remaining.is_empty() {
+      let tier: Vec<String> = remaining
+        .iter()
+        .filter(|name| self.get_dependencies(name).is_subset(&started))
+        .cloned()
+        .collect();
+      if tier.is_empty() {
+        // check_cycles should make this impossible
+        Err(FoundryError::Unreachable).context(format!(
+          "Could not order the services {:?}, there seems to be a cycle",
+          remaining
+        ))?;
+      }
+      for name in &tier {
+        remaining.remove(name);
+        started.insert(name.clone());
+      }
+      tiers.push(tier);
+    }
+    Ok(tiers)
+  }
+
+  /// The order to start the services needed by the targets
+  pub fn start_order(&self, targets: &[String]) -> Result<Vec<String>> {
+    Ok(self.start_tiers(targets)?.into_iter().flatten().collect())
+  }
+
+  /// Tiers to stop the targets in, so nothing is stopped while a service depending on it is still running.
+  /// Unlike starting, this includes the services that depend on the targets rather than what they need.
+  pub fn stop_tiers(&self, targets: &[String]) -> Result<Vec<Vec<String>>> {
+    if let Some(target) = targets.iter().find(|x| !self.depends_on.contains_key(*x)) {
+      Err(FoundryError::NotFound).context(format!("Cannot stop '{}': it is not a service", target))?;
+    }
+    let all = self.start_tiers(&[])?;
+    let stopping: BTreeSet<String> = match targets.is_empty() {
+      true => self.depends_on.keys().cloned().collect(),
+      false => self
+        .depends_on
+        .keys()
+        .filter(|name| {
+          targets.contains(name)
+            || self
+              .requires(name)
+              .is_ok_and(|deps| targets.iter().any(|target| deps.contains(target)))
+        })
+        .cloned()
+        .collect(),
+    };
+
+    Ok(
+      all
+        .into_iter()
+        .rev()
+        .map(|tier| {
+          tier
+            .into_iter()
+            .filter(|name| stopping.contains(name))
+            .collect::<Vec<String>>()
+        })
+        .filter(|tier| !tier.is_empty())
+        .collect(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn graph() -> DependencyGraph {
+    let schema: Schema = serde_yaml::from_str(
+      "version: \"3.8\"
+services:
+  db:
+    image: postgres
+  cache:
+    image: redis
+  api:
+    image: api
+    depends_on: [db, cache]
+  web:
+    image: web
+    depends_on: [api]
+",
+    )
+    .unwrap();
+    DependencyGraph::new(&schema).unwrap()
+  }
+
+  fn names(tiers: &[&[&str]]) -> Vec<Vec<String>> {
+    tiers
+      .iter()
+      .map(|tier| tier.iter().map(|x| x.to_string()).collect())
+      .collect()
+  }
+
+  #[test]
+  fn tiers_follow_depends_on() {
+    let graph = graph();
+    assert_eq!(
+      graph.start_tiers(&["api".to_string()]).unwrap(),
+      names(&[&["cache", "db"], &["api"]])
+    );
+    assert_eq!(
+      graph.stop_tiers(&["db".to_string()]).unwrap(),
+      names(&[&["web"], &["api"], &["db"]])
+    );
+  }
+
+  #[test]
+  fn unknown_services_are_not_found() {
+    let graph = graph();
+    for result in [
+      graph.start_tiers(&["nope".to_string()]),
+      graph.stop_tiers(&["db".to_string(), "nope".to_string()]),
+    ] {
+      let err = result.unwrap_err();
+      assert!(matches!(err.downcast_ref::<FoundryError>(), Some(FoundryError::NotFound)));
+      assert!(format!("{:#}", err).contains("'nope'"), "{:#}", err);
+    }
+  }
+}
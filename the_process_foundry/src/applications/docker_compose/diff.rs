@@ -0,0 +1,232 @@
+//! Compare two docker-compose configurations
+//!
+//! This is a semantic diff, so reordering services or switching a port between the short and long syntax
+//! isn't reported. Only the things that change what gets deployed are listed.
+
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+use super::schema::{Schema, Service};
+
+/// A single difference between two schemas
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Change {
+  ServiceAdded(String),
+  ServiceRemoved(String),
+  ImageChanged {
+    service: String,
+    from: Option<String>,
+    to: Option<String>,
+  },
+  PortAdded {
+    service: String,
+    port: String,
+  },
+  PortRemoved {
+    service: String,
+    port: String,
+  },
+  VolumeAdded {
+    service: String,
+    volume: String,
+  },
+  VolumeRemoved {
+    service: String,
+    volume: String,
+  },
+  EnvAdded {
+    service: String,
+    key: String,
+    value: Option<String>,
+  },
+  EnvRemoved {
+    service: String,
+    key: String,
+  },
+  EnvChanged {
+    service: String,
+    key: String,
+    from: Option<String>,
+    to: Option<String>,
+  },
+}
+
+fn or_unset(value: &Option<String>) -> String {
+  value.clone().unwrap_or_else(|| "(unset)".to_string())
+}
+
+impl std::fmt::Display for Change {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Change::ServiceAdded(name) => write!(f, "+ service {}", name),
+      Change::ServiceRemoved(name) => write!(f, "- service {}", name),
+      Change::ImageChanged { service, from, to } => write!(
+        f,
+        "~ {}: image {} -> {}",
+        service,
+        or_unset(from),
+        or_unset(to)
+      ),
+      Change::PortAdded { service, port } => write!(f, "+ {}: port {}", service, port),
+      Change::PortRemoved { service, port } => write!(f, "- {}: port {}", service, port),
+      Change::VolumeAdded { service, volume } => write!(f, "+ {}: volume {}", service, volume),
+      Change::VolumeRemoved { service, volume } => write!(f, "- {}: volume {}", service, volume),
+      Change::EnvAdded {
+        service,
+        key,
+        value,
+      } => write!(f, "+ {}: env {}={}", service, key, or_unset(value)),
+      Change::EnvRemoved { service, key } => write!(f, "- {}: env {}", service, key),
+      Change::EnvChanged {
+        service,
+        key,
+        from,
+        to,
+      } => write!(
+        f,
+        "~ {}: env {} {} -> {}",
+        service,
+        key,
+        or_unset(from),
+        or_unset(to)
+      ),
+    }
+  }
+}
+
+/// Find everything that changes going from the old schema to the new one
+pub fn diff(old: &Schema, new: &Schema) -> Vec<Change> {
+  let mut changes = vec![];
+  let names: BTreeSet<&String> = old.services.keys().chain(new.services.keys()).collect();
+
+  for name in names {
+    match (old.services.get(name), new.services.get(name)) {
+      (Some(_), None) => changes.push(Change::ServiceRemoved(name.clone())),
+      (None, Some(_)) => changes.push(Change::ServiceAdded(name.clone())),
+      (Some(old_service), Some(new_service)) => {
+        changes.extend(diff_service(name, old_service, new_service))
+      }
+      (None, None) => (),
+    }
+  }
+  changes
+}
+
+/// Find the changes to a single service
+pub fn diff_service(name: &str, old: &Service, new: &Service) -> Vec<Change> {
+  let mut changes = vec![];
+  let service = name.to_string();
+
+  if old.get_image() != new.get_image() {
+    changes.push(Change::ImageChanged {
+      service: service.clone(),
+      from: old.get_image(),
+      to: new.get_image(),
+    });
+  }
+
+  let old_ports: BTreeSet<String> = old.get_ports().iter().map(|x| x.to_canonical()).collect();
+  let new_ports: BTreeSet<String> = new.get_ports().iter().map(|x| x.to_canonical()).collect();
+  for port in old_ports.difference(&new_ports) {
+    changes.push(Change::PortRemoved {
+      service: service.clone(),
+      port: port.clone(),
+    });
+  }
+  for port in new_ports.difference(&old_ports) {
+    changes.push(Change::PortAdded {
+      service: service.clone(),
+      port: port.clone(),
+    });
+  }
+
+  let old_volumes: BTreeSet<String> = old.get_volumes().iter().map(|x| x.to_canonical()).collect();
+  let new_volumes: BTreeSet<String> = new.get_volumes().iter().map(|x| x.to_canonical()).collect();
+  for volume in old_volumes.difference(&new_volumes) {
+    changes.push(Change::VolumeRemoved {
+      service: service.clone(),
+      volume: volume.clone(),
+    });
+  }
+  for volume in new_volumes.difference(&old_volumes) {
+    changes.push(Change::VolumeAdded {
+      service: service.clone(),
+      volume: volume.clone(),
+    });
+  }
+
+  let old_env = old.get_environment();
+  let new_env = new.get_environment();
+  for (key, value) in &old_env {
+    match new_env.get(key) {
+      None => changes.push(Change::EnvRemoved {
+        service: service.clone(),
+        key: key.clone(),
+      }),
+      Some(new_value) if new_value != value => changes.push(Change::EnvChanged {
+        service: service.clone(),
+        key: key.clone(),
+        from: value.clone(),
+        to: new_value.clone(),
+      }),
+      _ => (),
+    }
+  }
+  for (key, value) in &new_env {
+    if !old_env.contains_key(key) {
+      changes.push(Change::EnvAdded {
+        service: service.clone(),
+        key: key.clone(),
+        value: value.clone(),
+      });
+    }
+  }
+  changes
+}
+
+/// Write the changes out one per line, the way a person would review them
+pub fn render(changes: &[Change]) -> String {
+  match changes.is_empty() {
+    true => "No changes".to_string(),
+    false => changes
+      .iter()
+      .map(|change| change.to_string())
+      .collect::<Vec<String>>()
+      .join("\n"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn schema(services: &str) -> Schema {
+    serde_yaml::from_str(&format!("version: \"3.8\"\nservices:\n{}", services)).unwrap()
+  }
+
+  #[test]
+  fn read_write_is_the_default_mode() {
+    let unset = schema("  db:\n    image: postgres\n    volumes:\n      - data:/var/lib/postgresql/data\n");
+    let rw = schema("  db:\n    image: postgres\n    volumes:\n      - data:/var/lib/postgresql/data:rw\n");
+    let long = schema(
+      "  db:\n    image: postgres\n    volumes:\n      - type: volume\n        source: data\n        target: /var/lib/postgresql/data\n        read_only: false\n",
+    );
+    assert_eq!(diff(&unset, &rw), vec![]);
+    assert_eq!(diff(&rw, &long), vec![]);
+
+    let ro = schema("  db:\n    image: postgres\n    volumes:\n      - data:/var/lib/postgresql/data:ro\n");
+    assert_eq!(
+      diff(&unset, &ro),
+      vec![
+        Change::VolumeRemoved {
+          service: "db".to_string(),
+          volume: "data:/var/lib/postgresql/data".to_string()
+        },
+        Change::VolumeAdded {
+          service: "db".to_string(),
+          volume: "data:/var/lib/postgresql/data:ro".to_string()
+        },
+      ]
+    );
+  }
+}
@@ -0,0 +1,251 @@
+//! Environment variable substitution for docker-compose files
+//!
+//! Compose replaces $VAR and ${VAR} in every string value using the shell environment, falling back to the
+//! .env file in the project directory. This follows the rules at
+//! https://docs.docker.com/compose/compose-file/#variable-substitution
+//!
+//! - ${VAR:-default} uses default if VAR is unset or empty, ${VAR-default} only if it is unset
+//! - ${VAR:?err} fails if VAR is unset or empty, ${VAR?err} only if it is unset
+//! - $$ is a literal $
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::FoundryError;
+
+/// The variables available for substitution
+#[derive(Debug, Clone, Default)]
+pub struct Environment {
+  vars: HashMap<String, String>,
+}
+
+impl Environment {
+  /// Combine the .env file in the project directory with the current environment, which takes precedence
+  pub fn load(project_dir: &Path) -> Result<Environment> {
+    let mut vars = HashMap::new();
+    let env_file = project_dir.join(".env");
+    if env_file.is_file() {
+      log::debug!("Reading environment variables from {}", env_file.display());
+      let contents = std::fs::read_to_string(&env_file).context(format!(
+        "Failed to read the environment file at {}",
+        env_file.display()
+      ))?;
+      vars.extend(Environment::parse_env_file(&contents));
+    }
+    vars.extend(std::env::vars());
+    Ok(Environment { vars })
+  }
+
+  pub fn from_map(vars: HashMap<String, String>) -> Environment {
+    Environment { vars }
+  }
+
+  pub fn get(&self, name: &str) -> Option<String> {
+    self.vars.get(name).cloned()
+  }
+
+  /// Read "KEY=VALUE" lines, skipping blanks and comments and removing any quotes around the value
+  pub fn parse_env_file(contents: &str) -> HashMap<String, String> {
+    contents
+      .lines()
+      .map(|line| line.trim())
+      .filter(|line| !line.is_empty() && !line.starts_with('#'))
+      .filter_map(|line| line.split_once('='))
+      .map(|(key, value)| {
+        let value = value.trim();
+        let unquoted = match (value.chars().next(), value.chars().last()) {
+          (Some('"'), Some('"')) | (Some('\''), Some('\'')) if value.len() > 1 => {
+            &value[1..value.len() - 1]
+          }
+          _ => value,
+        };
+        (key.trim().to_string(), unquoted.to_string())
+      })
+      .collect()
+  }
+
+  /// Replace all the variables in a single string
+  pub fn interpolate_str(&self, value: &str) -> Result<String> {
+    let mut result = String::new();
+    let mut chars = value.chars().peekable();
+
+    while let Some(c) = chars.next() {
+      if c != '$' {
+        result.push(c);
+        continue;
+      }
+      match chars.peek() {
+        Some('$') => {
+          chars.next();
+          result.push('$');
+        }
+        Some('{') => {
+          chars.next();
+          // Defaults can have their own ${...} in them, so track the depth to find the closing brace
+          let mut expr = String::new();
+          let mut depth = 0;
+          loop {
+            match chars.next() {
+              Some('}') if depth == 0 => break,
+              Some('}') => {
+                depth -= 1;
+                expr.push('}');
+              }
+              Some('$') if chars.peek() == Some(&'{') => {
+                depth += 1;
+                expr.push('$');
+                expr.push(chars.next().unwrap());
+              }
+              Some(x) => expr.push(x),
+              None => Err(FoundryError::YamlError).context(format!(
+                "Missing closing brace for '${{{}' in '{}'",
+                expr, value
+              ))?,
+            }
+          }
+          result.push_str(&self.substitute(&expr, value)?);
+        }
+        Some(x) if x.is_ascii_alphabetic() || *x == '_' => {
+          let mut name = String::new();
+          while let Some(x) = chars.peek() {
+            match x.is_ascii_alphanumeric() || *x == '_' {
+              true => name.push(chars.next().unwrap()),
+              false => break,
+            }
+          }
+          result.push_str(&self.substitute(&name, value)?);
+        }
+        // A lone $ is left as is
+        _ => result.push('$'),
+      }
+    }
+    Ok(result)
+  }
+
+  /// Evaluate the contents of a single ${...}
+  fn substitute(&self, expr: &str, original: &str) -> Result<String> {
+    let split = expr.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'));
+    let (name, modifier) = match split {
+      None => (expr, ""),
+      Some(idx) => expr.split_at(idx),
+    };
+    if name.is_empty() {
+      Err(FoundryError::YamlError).context(format!(
+        "Invalid variable substitution '${{{}}}' in '{}'",
+        expr, original
+      ))?;
+    }
+
+    let value = self.get(name);
+    let is_empty = value.as_ref().is_none_or(|x| x.is_empty());
+    let interpolated = match modifier {
+      "" => match value {
+        Some(x) => x,
+        None => {
+          log::warn!("The {} variable is not set. Defaulting to a blank string.", name);
+          String::new()
+        }
+      },
+      x if x.starts_with(":-") => match is_empty {
+        true => self.interpolate_str(&x[2..])?,
+        false => value.unwrap_or_default(),
+      },
+      x if x.starts_with('-') => match value {
+        None => self.interpolate_str(&x[1..])?,
+        Some(val) => val,
+      },
+      x if x.starts_with(":?") => match is_empty {
+        true => Err(FoundryError::ConfigurationError).context(format!(
+          "Required variable {} is missing or empty: {}",
+          name,
+          self.interpolate_str(&x[2..])?
+        ))?,
+        false => value.unwrap_or_default(),
+      },
+      x if x.starts_with('?') => match value {
+        None => Err(FoundryError::ConfigurationError).context(format!(
+          "Required variable {} is missing: {}",
+          name,
+          self.interpolate_str(&x[1..])?
+        ))?,
+        Some(val) => val,
+      },
+      _ => Err(FoundryError::YamlError).context(format!(
+        "Invalid variable substitution '${{{}}}' in '{}'",
+        expr, original
+      ))?,
+    };
+    Ok(interpolated)
+  }
+
+  /// Replace the variables in every string value of a parsed compose file. Keys are left alone.
+  pub fn interpolate(&self, value: &Value) -> Result<Value> {
+    Ok(match value {
+      Value::String(x) => Value::String(self.interpolate_str(x)?),
+      Value::Array(items) => Value::Array(
+        items
+          .iter()
+          .map(|item| self.interpolate(item))
+          .collect::<Result<Vec<Value>>>()?,
+      ),
+      Value::Object(map) => {
+        let mut result = serde_json::Map::new();
+        for (key, item) in map {
+          result.insert(
+            key.clone(),
+            self
+              .interpolate(item)
+              .context(format!("Could not substitute variables in '{}'", key))?,
+          );
+        }
+        Value::Object(result)
+      }
+      x => x.clone(),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn env() -> Environment {
+    Environment::from_map(
+      [("A", "a"), ("B", "b"), ("EMPTY", "")]
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect(),
+    )
+  }
+
+  #[test]
+  fn substitutes_plain_and_braced_variables() {
+    assert_eq!(env().interpolate_str("$A/${B}-$$A-$").unwrap(), "a/b-$A-$");
+    assert_eq!(env().interpolate_str("${MISSING}x").unwrap(), "x");
+  }
+
+  #[test]
+  fn defaults_can_use_other_variables() {
+    assert_eq!(env().interpolate_str("${MISSING:-${B}}").unwrap(), "b");
+    assert_eq!(env().interpolate_str("${EMPTY:-${MISSING:-${A}}/x}!").unwrap(), "a/x!");
+    assert_eq!(env().interpolate_str("${EMPTY-${B}}").unwrap(), "");
+    assert_eq!(env().interpolate_str("${A:-${B}}").unwrap(), "a");
+  }
+
+  #[test]
+  fn required_messages_use_variables_too() {
+    let err = env().interpolate_str("${MISSING:?set it like ${A}}").unwrap_err();
+    assert!(format!("{:#}", err).contains("set it like a"), "{:#}", err);
+    assert!(env().interpolate_str("${EMPTY?${A}}").is_ok());
+  }
+
+  #[test]
+  fn unclosed_braces_are_errors() {
+    for value in ["${A", "${A:-${B}", "${A:-${B"] {
+      let err = env().interpolate_str(value).unwrap_err();
+      assert!(matches!(err.downcast_ref::<FoundryError>(), Some(FoundryError::YamlError)), "{}", value);
+    }
+  }
+}
@@ -0,0 +1,600 @@
+//! Combine several docker-compose files into the configuration docker-compose actually runs
+//!
+//! Each file has its variables substituted, its `extends` resolved and is validated on its own. Override files
+//! can leave out the version, in which case they are validated as the version of the files before them. Then
+//! they are merged in order following https://docs.docker.com/compose/extends/#adding-and-overriding-configuration:
+//!
+//! - Single values (image, command, healthcheck...) are replaced by the later file
+//! - Maps (environment, labels, build args...) are merged with the later file winning
+//! - Lists (ports, expose, dns, depends_on...) are combined
+//! - Volumes and devices are merged by the path inside the container
+//!
+//! This all happens on the raw yaml so keys the Schema doesn't model yet still merge correctly.
+
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use super::interpolation::Environment;
+use super::schema::{is_remote_context, resolve_host_path, Schema, ServiceVolume};
+use super::validation;
+use super::FoundryError;
+
+/// The files docker-compose looks for in the project directory when none are given
+pub const DEFAULT_FILES: [&str; 2] = ["docker-compose.yml", "docker-compose.yaml"];
+
+/// Automatically applied on top of the default file
+pub const OVERRIDE_FILES: [&str; 2] = [
+  "docker-compose.override.yml",
+  "docker-compose.override.yaml",
+];
+
+/// Keys that are never copied from a service being extended
+const NOT_EXTENDED: [&str; 3] = ["depends_on", "links", "volumes_from"];
+
+/// Find the default compose file and its override in a directory like docker-compose does without "-f"
+pub fn find_project_files(project_dir: &Path) -> Result<Vec<String>> {
+  let find = |names: &[&str]| {
+    names
+      .iter()
+      .map(|name| project_dir.join(name))
+      .find(|path| path.is_file())
+      .map(|path| path.to_string_lossy().to_string())
+  };
+
+  let base = match find(&DEFAULT_FILES) {
+    Some(x) => x,
+    None => Err(FoundryError::NotFound).context(format!(
+      "Could not find any of {:?} in {}",
+      DEFAULT_FILES,
+      project_dir.display()
+    ))?,
+  };
+  Ok(vec![base].into_iter().chain(find(&OVERRIDE_FILES)).collect())
+}
+
+/// Read all the files in order and merge them into a single schema
+///
+/// The first file sets the project directory, which is where .env is read from and what relative paths in
+/// every file are resolved against.
+pub fn load_files(files: &[String]) -> Result<Schema> {
+  let first = match files.first() {
+    Some(x) => x,
+    None => Err(FoundryError::ConfigurationError).context("No docker compose files were given to load")?,
+  };
+  let project_dir = Path::new(first)
+    .parent()
+    .map_or(PathBuf::from("."), |dir| dir.to_path_buf());
+
+  let mut loader = Loader {
+    env: Environment::load(&project_dir)?,
+    cache: HashMap::new(),
+  };
+
+  let mut merged: Option<Value> = None;
+  for file in files {
+    log::info!("Parsing the docker compose file at {}", file);
+    let resolved = loader.load_resolved(file)?;
+    match (merged.as_ref().and_then(get_version), get_version(&resolved)) {
+      (Some(version), None) => {
+        let mut versioned = resolved.clone();
+        versioned["version"] = Value::String(version);
+        validation::validate_value(&versioned, file)?;
+      }
+      _ => validation::validate_value(&resolved, file)?,
+    }
+    merged = Some(match merged {
+      None => resolved,
+      Some(base) => merge_files(&base, &resolved)
+        .context(format!("Could not merge {} into the previous files", file))?,
+    });
+  }
+
+  let schema: Schema = serde_json::from_value(merged.unwrap_or_default())
+    .map_err(|err| anyhow::Error::new(FoundryError::YamlError).context(err.to_string()))
+    .context(format!("Failed to parse the docker compose files {:?}", files))?;
+
+  Ok(Schema {
+    source: Some(first.clone()),
+    overrides: files[1..].to_vec(),
+    ..schema
+  })
+}
+
+/// Reads files with the variables substituted and resolves the extends between them
+struct Loader {
+  env: Environment,
+  /// Interpolated files, so extending several services from the same file only reads it once
+  cache: HashMap<String, Value>,
+}
+
+impl Loader {
+  fn read(&mut self, file: &str) -> Result<Value> {
+    if let Some(value) = self.cache.get(file) {
+      return Ok(value.clone());
+    }
+
+    let contents = std::fs::read_to_string(file)
+      .context(format!("Failed to open docker-compose file at {}", file))?;
+    let raw: Value = serde_yaml::from_str(&contents)
+      .map_err(|err| anyhow::Error::new(FoundryError::YamlError).context(err.to_string()))
+      .context(format!("'{}' is not a valid yaml file", file))?;
+    let value = self
+      .env
+      .interpolate(&raw)
+      .context(format!("Failed to substitute the variables in {}", file))?;
+
+    self.cache.insert(file.to_string(), value.clone());
+    Ok(value)
+  }
+
+  /// Read a file and replace every service that uses extends with the combined service
+  fn load_resolved(&mut self, file: &str) -> Result<Value> {
+    let mut value = self.read(file)?;
+    let names: Vec<String> = value
+      .get("services")
+      .and_then(|x| x.as_object())
+      .map_or(vec![], |services| services.keys().cloned().collect());
+
+    for name in names {
+      let service = self.resolve_service(file, &name, &[])?;
+      value["services"][&name] = service;
+    }
+    Ok(value)
+  }
+
+  fn resolve_service(&mut self, file: &str, name: &str, chain: &[(String, String)]) -> Result<Value> {
+    let key = (file.to_string(), name.to_string());
+    if chain.contains(&key) {
+      Err(FoundryError::ConfigurationError).context(format!(
+        "Circular extends: {} -> {}:{}",
+        chain
+          .iter()
+          .map(|(file, name)| format!("{}:{}", file, name))
+          .collect::<Vec<String>>()
+          .join(" -> "),
+        file,
+        name
+      ))?;
+    }
+
+    let raw = self.read(file)?;
+    let service = match raw.get("services").and_then(|x| x.get(name)) {
+      Some(x) => x.clone(),
+      None => Err(FoundryError::NotFound).context(format!(
+        "Cannot extend service '{}': it is not in {}",
+        name, file
+      ))?,
+    };
+
+    let (base_file, base_name) = match service.get("extends") {
+      None => return Ok(service),
+      Some(Value::String(base_name)) => (file.to_string(), base_name.clone()),
+      Some(Value::Object(ext)) => {
+        let base_name = match ext.get("service").and_then(|x| x.as_str()) {
+          Some(x) => x.to_string(),
+          None => Err(FoundryError::YamlError).context(format!(
+            "services.{}.extends: service is required in {}",
+            name, file
+          ))?,
+        };
+        let base_file = match ext.get("file").and_then(|x| x.as_str()) {
+          None => file.to_string(),
+          Some(x) => resolve_host_path(x, &dir_of(file))?,
+        };
+        (base_file, base_name)
+      }
+      Some(x) => Err(FoundryError::YamlError).context(format!(
+        "services.{}.extends: '{}' should be a service name or a map in {}",
+        name, x, file
+      ))?,
+    };
+
+    let mut chain = chain.to_vec();
+    chain.push(key);
+    let mut base = self
+      .resolve_service(&base_file, &base_name, &chain)
+      .context(format!("Could not extend service '{}' in {}", name, file))?;
+
+    // Paths in the other file are relative to that file rather than our project
+    if base_file != file {
+      base = resolve_service_paths(&base, &dir_of(&base_file))?;
+    }
+    if let Some(map) = base.as_object_mut() {
+      for key in NOT_EXTENDED.iter() {
+        map.remove(*key);
+      }
+    }
+
+    let mut child = service;
+    if let Some(map) = child.as_object_mut() {
+      map.remove("extends");
+    }
+    Ok(merge_service(&base, &child))
+  }
+}
+
+fn dir_of(file: &str) -> PathBuf {
+  Path::new(file)
+    .parent()
+    .map_or(PathBuf::from("."), |dir| dir.to_path_buf())
+}
+
+/// Make the volume sources and build context of a raw service absolute
+fn resolve_service_paths(service: &Value, base_dir: &Path) -> Result<Value> {
+  let mut service = service.clone();
+
+  if let Some(Value::Array(volumes)) = service.get_mut("volumes") {
+    for volume in volumes.iter_mut() {
+      match volume {
+        Value::String(short) => {
+          let resolved = ServiceVolume::parse_short(short)?.resolve_source(base_dir)?;
+          *volume = Value::String(resolved.to_short().unwrap_or_else(|| short.clone()));
+        }
+        Value::Object(long) => {
+          let is_bind = long.get("type").and_then(|x| x.as_str()) == Some("bind");
+          if let (true, Some(Value::String(src))) = (is_bind, long.get_mut("source")) {
+            *src = resolve_host_path(src, base_dir)?;
+          }
+        }
+        _ => (),
+      }
+    }
+  }
+
+  match service.get_mut("build") {
+    Some(Value::String(ctx)) if !is_remote_context(ctx) => *ctx = resolve_host_path(ctx, base_dir)?,
+    Some(Value::Object(build)) => {
+      if let Some(Value::String(ctx)) = build.get_mut("context") {
+        if !is_remote_context(ctx) {
+          *ctx = resolve_host_path(ctx, base_dir)?;
+        }
+      }
+    }
+    _ => (),
+  }
+  Ok(service)
+}
+
+/// The version a file declares. Yaml reads an unquoted "version: 3.8" as a number.
+fn get_version(file: &Value) -> Option<String> {
+  match file.get("version") {
+    Some(Value::String(ver)) => Some(ver.clone()),
+    Some(Value::Number(ver)) => Some(ver.to_string()),
+    _ => None,
+  }
+}
+
+/// Merge a whole file on top of the previous ones
+fn merge_files(base: &Value, over: &Value) -> Result<Value> {
+  let base_version = get_version(base).unwrap_or_default();
+  let over_version = get_version(over).unwrap_or_else(|| base_version.clone());
+  if Schema::parse_version(&base_version)?.major != Schema::parse_version(&over_version)?.major {
+    Err(FoundryError::ConfigurationError).context(format!(
+      "Version mismatch: cannot merge a version {} file into version {}",
+      over_version, base_version
+    ))?;
+  }
+
+  let mut merged = base.as_object().cloned().unwrap_or_default();
+  for (key, value) in over.as_object().cloned().unwrap_or_default() {
+    let combined = match (key.as_str(), merged.get(&key)) {
+      ("services", Some(Value::Object(services))) => {
+        let mut services = services.clone();
+        for (name, service) in value.as_object().cloned().unwrap_or_default() {
+          let combined = match services.get(&name) {
+            Some(existing) => merge_service(existing, &service),
+            None => service,
+          };
+          services.insert(name, combined);
+        }
+        Value::Object(services)
+      }
+      ("volumes", Some(existing))
+      | ("networks", Some(existing))
+      | ("secrets", Some(existing))
+      | ("configs", Some(existing)) => deep_merge(existing, &value),
+      _ => value,
+    };
+    merged.insert(key, combined);
+  }
+  Ok(Value::Object(merged))
+}
+
+/// Put the values of one service definition on top of another
+pub fn merge_service(base: &Value, over: &Value) -> Value {
+  let mut merged = base.as_object().cloned().unwrap_or_default();
+  for (key, value) in over.as_object().cloned().unwrap_or_default() {
+    let combined = match merged.get(&key) {
+      None => value,
+      Some(existing) => match key.as_str() {
+        "environment" | "labels" | "sysctls" | "extra_hosts" => {
+          Value::Object(merge_maps(&to_dict(existing), &to_dict(&value)))
+        }
+        "ports" | "expose" | "external_links" | "dns" | "dns_search" | "cap_add" | "cap_drop"
+        | "env_file" | "tmpfs" | "security_opt" | "depends_on" | "links" | "secrets" | "configs" => {
+          union(existing, &value)
+        }
+        "volumes" | "devices" => merge_by_target(existing, &value),
+        "networks" => match (existing, &value) {
+          (Value::Array(_), Value::Array(_)) => union(existing, &value),
+          _ => deep_merge(&to_dict(existing).into(), &to_dict(&value).into()),
+        },
+        "deploy" | "logging" | "ulimits" => deep_merge(existing, &value),
+        "build" => merge_build(existing, &value),
+        _ => value,
+      },
+    };
+    merged.insert(key, combined);
+  }
+  Value::Object(merged)
+}
+
+/// Convert a list of "KEY=VALUE" strings into a map, leaving maps as they are
+fn to_dict(value: &Value) -> Map<String, Value> {
+  match value {
+    Value::Object(map) => map.clone(),
+    Value::Array(items) => items
+      .iter()
+      .filter_map(|item| item.as_str())
+      .map(|item| match item.split_once('=') {
+        Some((key, val)) => (key.to_string(), Value::String(val.to_string())),
+        None => (item.to_string(), Value::Null),
+      })
+      .collect(),
+    _ => Map::new(),
+  }
+}
+
+/// Allow a single string where a list is expected
+fn to_list(value: &Value) -> Vec<Value> {
+  match value {
+    Value::Array(items) => items.clone(),
+    Value::Null => vec![],
+    x => vec![x.clone()],
+  }
+}
+
+fn merge_maps(base: &Map<String, Value>, over: &Map<String, Value>) -> Map<String, Value> {
+  let mut merged = base.clone();
+  for (key, value) in over {
+    merged.insert(key.clone(), value.clone());
+  }
+  merged
+}
+
+fn union(base: &Value, over: &Value) -> Value {
+  let mut merged = to_list(base);
+  for item in to_list(over) {
+    if !merged.contains(&item) {
+      merged.push(item);
+    }
+  }
+  Value::Array(merged)
+}
+
+fn deep_merge(base: &Value, over: &Value) -> Value {
+  match (base, over) {
+    (Value::Object(base_map), Value::Object(over_map)) => {
+      let mut merged = base_map.clone();
+      for (key, value) in over_map {
+        let combined = match merged.get(key) {
+          Some(existing) => deep_merge(existing, value),
+          None => value.clone(),
+        };
+        merged.insert(key.clone(), combined);
+      }
+      Value::Object(merged)
+    }
+    _ => over.clone(),
+  }
+}
+
+/// The path inside the container for a volume or device, in either the short or long syntax
+fn mount_target(value: &Value) -> Option<String> {
+  match value {
+    Value::String(short) => Some(match short.split_once(':') {
+      None => short.clone(),
+      Some((_, rest)) => rest.split(':').next().unwrap_or(rest).to_string(),
+    }),
+    Value::Object(long) => long.get("target").and_then(|x| x.as_str()).map(|x| x.to_string()),
+    _ => None,
+  }
+}
+
+/// Volumes mounted to the same place in the container are replaced instead of being added
+fn merge_by_target(base: &Value, over: &Value) -> Value {
+  let over = to_list(over);
+  let replaced: Vec<Option<String>> = over.iter().map(mount_target).collect();
+  let mut merged: Vec<Value> = to_list(base)
+    .into_iter()
+    .filter(|item| !replaced.contains(&mount_target(item)))
+    .collect();
+  merged.extend(over);
+  Value::Array(merged)
+}
+
+fn merge_build(base: &Value, over: &Value) -> Value {
+  let as_map = |value: &Value| match value {
+    Value::String(ctx) => {
+      let mut map = Map::new();
+      map.insert("context".to_string(), Value::String(ctx.clone()));
+      map
+    }
+    x => x.as_object().cloned().unwrap_or_default(),
+  };
+
+  let mut merged = as_map(base);
+  for (key, value) in as_map(over) {
+    let combined = match (key.as_str(), merged.get(&key)) {
+      ("args", Some(existing)) | ("labels", Some(existing)) => {
+        Value::Object(merge_maps(&to_dict(existing), &to_dict(&value)))
+      }
+      _ => value,
+    };
+    merged.insert(key, combined);
+  }
+  Value::Object(merged)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  /// Write the files into a new directory, returning it and the paths in the order given
+  fn project(files: &[(&str, &str)]) -> (PathBuf, Vec<String>) {
+    let dir = std::env::temp_dir().join(format!("foundry-{}", uuid::Uuid::new_v4()));
+    let paths = files
+      .iter()
+      .map(|(name, contents)| {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().to_string()
+      })
+      .collect();
+    (dir, paths)
+  }
+
+  fn load(files: &[(&str, &str)]) -> Result<Schema> {
+    let (dir, paths) = project(files);
+    let schema = load_files(&paths);
+    std::fs::remove_dir_all(&dir).unwrap();
+    schema
+  }
+
+  #[test]
+  fn overrides_without_a_version_are_merged() {
+    let schema = load(&[
+      (
+        "docker-compose.yml",
+        "version: \"3.8\"\nservices:\n  db:\n    image: postgres:12\n    ports:\n      - 5432\n    environment:\n      POSTGRES_DB: app\n      POSTGRES_USER: app\n",
+      ),
+      (
+        "docker-compose.override.yml",
+        "services:\n  db:\n    image: postgres:13\n    ports:\n      - \"15432:5432\"\n    environment:\n      - POSTGRES_USER=admin\n  cache:\n    image: redis\n",
+      ),
+    ]);
+    let schema = schema.unwrap();
+    assert_eq!(schema.version, "3.8");
+    assert_eq!(schema.overrides.len(), 1);
+
+    let db = &schema.services["db"];
+    assert_eq!(db.get_image(), Some("postgres:13".to_string()));
+    assert_eq!(db.get_ports().len(), 2);
+    let env = db.get_environment();
+    assert_eq!(env["POSTGRES_DB"], Some("app".to_string()));
+    assert_eq!(env["POSTGRES_USER"], Some("admin".to_string()));
+    assert!(schema.services.contains_key("cache"));
+  }
+
+  #[test]
+  fn overrides_are_still_validated() {
+    let schema = load(&[
+      ("docker-compose.yml", "version: \"3.8\"\nservices:\n  db:\n    image: postgres\n"),
+      ("docker-compose.override.yml", "services:\n  db:\n    image: 13\n"),
+    ]);
+    let err = schema.unwrap_err();
+    assert!(matches!(err.downcast_ref::<FoundryError>(), Some(FoundryError::YamlError)));
+    assert!(format!("{:#}", err).contains("services.db.image"), "{:#}", err);
+
+    // The first file still needs a version, since there is nothing before it to take one from
+    let schema = load(&[("docker-compose.yml", "services:\n  db:\n    image: postgres\n")]);
+    assert!(schema.is_err());
+  }
+
+  #[test]
+  fn volumes_are_merged_by_target() {
+    let base = json!({
+      "volumes": ["data:/var/lib/data", "./conf:/etc/conf:ro", {"type": "tmpfs", "target": "/tmp"}]
+    });
+    let over = json!({
+      "volumes": ["./dev-conf:/etc/conf", {"type": "bind", "source": "./tmp", "target": "/tmp"}]
+    });
+    let merged = merge_service(&base, &over);
+    assert_eq!(
+      merged["volumes"],
+      json!([
+        "data:/var/lib/data",
+        "./dev-conf:/etc/conf",
+        {"type": "bind", "source": "./tmp", "target": "/tmp"}
+      ])
+    );
+  }
+
+  #[test]
+  fn services_are_merged_by_kind_of_key() {
+    let base = json!({
+      "image": "postgres",
+      "command": ["postgres", "-c", "fsync=off"],
+      "labels": ["tier=db", "team=data"],
+      "ports": ["5432"],
+      "build": "./db",
+    });
+    let over = json!({
+      "command": "postgres",
+      "labels": {"tier": "storage"},
+      "ports": ["5432", "15432:5432"],
+      "build": {"args": {"VERSION": "13"}},
+    });
+    let merged = merge_service(&base, &over);
+    assert_eq!(merged["image"], "postgres");
+    assert_eq!(merged["command"], "postgres");
+    assert_eq!(merged["labels"], json!({"tier": "storage", "team": "data"}));
+    assert_eq!(merged["ports"], json!(["5432", "15432:5432"]));
+    assert_eq!(merged["build"], json!({"context": "./db", "args": {"VERSION": "13"}}));
+  }
+
+  #[test]
+  fn services_extend_others_in_the_same_and_other_files() {
+    let (dir, paths) = project(&[
+      (
+        "docker-compose.yml",
+        "version: \"3.8\"\nservices:\n  base:\n    image: postgres\n    environment:\n      A: \"1\"\n  db:\n    extends: base\n    environment:\n      B: \"2\"\n  web:\n    extends:\n      file: common/web.yml\n      service: web\n    depends_on:\n      - db\n",
+      ),
+      (
+        "common/web.yml",
+        "version: \"3.8\"\nservices:\n  web:\n    image: nginx\n    volumes:\n      - ./html:/usr/share/nginx/html\n    depends_on:\n      - cache\n",
+      ),
+    ]);
+    // Only the first file is loaded, the other is just extended
+    let schema = load_files(&paths[..1]);
+    std::fs::remove_dir_all(&dir).unwrap();
+    let schema = schema.unwrap();
+
+    let env = schema.services["db"].get_environment();
+    assert_eq!(env["A"], Some("1".to_string()));
+    assert_eq!(env["B"], Some("2".to_string()));
+
+    // Paths from the other file are relative to it, and depends_on is never inherited
+    let web = &schema.services["web"];
+    assert_eq!(web.get_image(), Some("nginx".to_string()));
+    let html = dir.join("common").join("html").to_string_lossy().to_string();
+    assert_eq!(web.get_volumes()[0].get_source(), Some(html));
+    assert_eq!(web.get_depends_on(), vec!["db".to_string()]);
+  }
+
+  #[test]
+  fn circular_extends_are_an_error() {
+    let schema = load(&[(
+      "docker-compose.yml",
+      "version: \"3.8\"\nservices:\n  a:\n    image: x\n    extends: b\n  b:\n    image: x\n    extends: a\n",
+    )]);
+    let err = schema.unwrap_err();
+    assert!(format!("{:#}", err).contains("Circular extends"), "{:#}", err);
+  }
+
+  #[test]
+  fn numeric_versions_are_compared() {
+    let base = json!({"version": 3.8, "services": {"db": {"image": "postgres"}}});
+    let merged = merge_files(&base, &json!({"version": "3.7", "services": {"db": {"image": "postgres:13"}}})).unwrap();
+    assert_eq!(merged["services"]["db"]["image"], "postgres:13");
+    assert!(merge_files(&base, &json!({"services": {}})).is_ok());
+
+    let err = merge_files(&base, &json!({"version": 2.4})).unwrap_err();
+    assert!(matches!(err.downcast_ref::<FoundryError>(), Some(FoundryError::ConfigurationError)));
+    assert!(format!("{:#}", err).contains("version 2.4 file into version 3.8"), "{:#}", err);
+  }
+}
@@ -9,7 +9,12 @@
 //! TODO: Figure out how to scan to guess the process being run.
 
 pub mod application;
+pub mod dependencies;
+pub mod diff;
+pub mod interpolation;
+pub mod merge;
 pub mod schema;
+pub mod validation;
 
 use super::*;
-pub use application::{Action, ActionResult, DockerCompose, Event};
+pub use application::{Action, ActionResult, CliStyle, DockerCompose, Event};
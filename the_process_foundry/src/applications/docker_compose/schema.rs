@@ -7,11 +7,13 @@
 //!
 //! TODO: Write stand alone CLI tool to convert JSON to structs "schema.rs" or some such.
 //! TODO: Write CLI tool to diff schema versions
-//! TOOD: Figure out versioning (#[serde_semver(x)], where x is semver::VersionReq.
-//!       https://docs.rs/semver/0.10.0/semver/struct.VersionReq.html)
 
+use anyhow::{Context, Result};
 use serde_derive::{Deserialize, Serialize};
 use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+use super::FoundryError;
 
 /// A Schema structure to contain all of the possible values that can be contained in a docker-compose.yml
 /// This is going to be incomplete, only adding things as I implement functions. See
@@ -22,6 +24,9 @@ pub struct Schema {
   /// The location of the serialized copy of the schema
   #[serde(skip)]
   pub source: Option<String>,
+  /// Files merged on top of the source, in order (eg: docker-compose.override.yml)
+  #[serde(skip)]
+  pub overrides: Vec<String>,
   pub version: String,
   pub services: BTreeMap<String, Service>,
   // networks: Vec<Network>,
@@ -33,6 +38,7 @@ impl Default for Schema {
   fn default() -> Self {
     Self {
       source: None,
+      overrides: vec![],
       version: "3.8".to_string(),
       services: Default::default(),
     }
@@ -47,42 +53,888 @@ impl Schema {
     }
   }
 
+  /// All the files that make up this schema, in the order docker-compose needs to be given them
+  pub fn get_files(&self) -> Vec<String> {
+    self.source.iter().chain(self.overrides.iter()).cloned().collect()
+  }
+
+  /// Compose versions are usually just "major.minor", so pad them out to a full semantic version
+  pub fn parse_version(version: &str) -> Result<semver::Version> {
+    let padded = match version.matches('.').count() {
+      0 => format!("{}.0.0", version),
+      1 => format!("{}.0", version),
+      _ => version.to_string(),
+    };
+    semver::Version::parse(&padded).map_or(
+      Err(FoundryError::UnexpectedValue).context(format!(
+        "'{}' is not a valid docker compose version",
+        version
+      )),
+      Ok,
+    )
+  }
+
+  pub fn get_version(&self) -> Result<semver::Version> {
+    Schema::parse_version(&self.version)
+  }
+
   pub fn list_service_names(&self) -> Vec<String> {
-    self.services.keys().map(|key| key.clone()).collect()
+    self.services.keys().cloned().collect()
+  }
+
+  /// The directory relative paths in the schema are resolved against. This is always absolute, since a
+  /// bare file name (eg: "docker-compose.yml") has an empty parent.
+  pub fn get_base_dir(&self) -> Result<PathBuf> {
+    let current_dir =
+      std::env::current_dir().context("Could not get the current directory for the schema")?;
+    match &self.source {
+      Some(src) => Ok(
+        current_dir
+          .join(src)
+          .parent()
+          .map_or(current_dir.clone(), |dir| dir.to_path_buf()),
+      ),
+      None => Ok(current_dir),
+    }
+  }
+
+  /// Find the host port that reaches a service's container port (eg: where can I connect to postgres)
+  pub fn get_host_port(
+    &self,
+    service_name: String,
+    container_port: u16,
+    protocol: PortProtocol,
+  ) -> Result<HostPort> {
+    let service = match self.services.get(&service_name) {
+      Some(x) => x,
+      None => Err(FoundryError::NotFound).context(format!(
+        "There is no service named '{}' in the schema at '{}'",
+        service_name,
+        self.get_source()
+      ))?,
+    };
+    match service.get_host_port(container_port, protocol.clone()) {
+      Some(host_port) => Ok(host_port),
+      None => Err(FoundryError::NotFound).context(format!(
+        "Port {}/{:?} on service '{}' is not published to a fixed host port",
+        container_port, protocol, service_name
+      )),
+    }
+  }
+
+  /// Make all the host paths in the services absolute
+  pub fn resolve_paths(&self) -> Result<Schema> {
+    let base_dir = self.get_base_dir()?;
+    let mut services = BTreeMap::new();
+    for (name, service) in &self.services {
+      services.insert(
+        name.clone(),
+        service
+          .resolve_paths(&base_dir)
+          .context(format!("Could not resolve the paths for service '{}'", name))?,
+      );
+    }
+    Ok(Schema {
+      services,
+      ..self.clone()
+    })
   }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Service {
+  #[serde(skip_serializing_if = "Option::is_none")]
   build: Option<Build>,
+  #[serde(skip_serializing_if = "Option::is_none")]
   image: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
   command: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  container_name: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
   depends_on: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  environment: Option<ListOrDict>,
+  #[serde(skip_serializing_if = "Option::is_none")]
   ports: Option<Vec<Port>>, //Unique
+  #[serde(skip_serializing_if = "Option::is_none")]
   restart: Option<Restart>,
+  #[serde(skip_serializing_if = "Option::is_none")]
   volumes: Option<Vec<ServiceVolume>>,
   // deploy: Option<Deployment>,
   // cap_add: Vec<String>, // Unique
   // cap_drop: Vec<String>, //Unique
   // cgroup_parent: Option<String>,
   // configs: Vec<Config>,
-  // container_name: Option<String>,
   // credential_spec: Option<CredentialSpec>,
   // devices: Vec<String>, //Unique
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct Build {}
+impl Service {
+  /// Describe a service from what is actually running in a container (eg: from docker inspect)
+  pub fn from_running(
+    image: Option<String>,
+    environment: BTreeMap<String, Option<String>>,
+    ports: Vec<Port>,
+    volumes: Vec<ServiceVolume>,
+  ) -> Service {
+    Service {
+      image,
+      environment: Some(ListOrDict {
+        values: environment,
+        is_list: false,
+      }),
+      ports: Some(ports),
+      volumes: Some(volumes),
+      ..Default::default()
+    }
+  }
+
+  pub fn get_image(&self) -> Option<String> {
+    self.image.clone()
+  }
+
+  /// The repository part of the image without the registry or tag (eg: "postgres" for "library/postgres:12")
+  pub fn get_image_name(&self) -> Option<String> {
+    self.image.as_ref().map(|image| split_image(image).0)
+  }
+
+  pub fn get_image_tag(&self) -> Option<String> {
+    self.image.as_ref().and_then(|image| split_image(image).1)
+  }
 
+  /// The version of the app in the image, if the tag looks like one (eg: "12.3-alpine")
+  pub fn get_image_version(&self) -> Option<semver::Version> {
+    self.get_image_tag().and_then(|tag| version_from_tag(&tag))
+  }
+
+  pub fn get_container_name(&self) -> Option<String> {
+    self.container_name.clone()
+  }
+
+  pub fn get_command(&self) -> Option<Vec<String>> {
+    self.command.clone()
+  }
+
+  pub fn get_depends_on(&self) -> Vec<String> {
+    self.depends_on.clone().unwrap_or_default()
+  }
+
+  pub fn get_volumes(&self) -> Vec<ServiceVolume> {
+    self.volumes.clone().unwrap_or_default()
+  }
+
+  pub fn get_ports(&self) -> Vec<Port> {
+    self.ports.clone().unwrap_or_default()
+  }
+
+  pub fn get_build(&self) -> Option<Build> {
+    self.build.clone()
+  }
+
+  /// The environment variables set in the configuration. None means it is taken from where compose is run
+  pub fn get_environment(&self) -> BTreeMap<String, Option<String>> {
+    self.environment.clone().map_or(BTreeMap::new(), |x| x.values)
+  }
+
+  /// Find where the given container port is published on the host
+  pub fn get_host_port(&self, container_port: u16, protocol: PortProtocol) -> Option<HostPort> {
+    self
+      .get_ports()
+      .iter()
+      .find_map(|port| port.host_port_for(container_port, protocol.clone()))
+  }
+
+  fn resolve_paths(&self, base_dir: &Path) -> Result<Service> {
+    let volumes = match &self.volumes {
+      None => None,
+      Some(vols) => Some(
+        vols
+          .iter()
+          .map(|vol| vol.resolve_source(base_dir))
+          .collect::<Result<Vec<ServiceVolume>>>()?,
+      ),
+    };
+    let build = match &self.build {
+      None => None,
+      Some(build) => Some(build.resolve_context(base_dir)?),
+    };
+    Ok(Service {
+      build,
+      volumes,
+      ..self.clone()
+    })
+  }
+}
+
+/// Which of the build syntaxes was used, so we can write it back out the same way
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum BuildSyntax {
+  /// Just the path to the context
+  Short,
+  #[default]
+  Long,
+}
+
+/// How to build the image for a service
+/// https://docs.docker.com/compose/compose-file/#build
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Build {
+  /// A path to a directory or a url to a git repository
+  context: Option<String>,
+  /// Relative to the context
+  dockerfile: Option<String>,
+  args: Option<ListOrDict>,
+  target: Option<String>,
+  cache_from: Option<Vec<String>>,
+  labels: Option<ListOrDict>,
+  network: Option<String>,
+  shm_size: Option<ShmSize>,
+  syntax: BuildSyntax,
+}
+
+/// The size of /dev/shm, either in bytes or as a string with a unit (eg: "2gb")
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ShmSize {
+  Bytes(u64),
+  Str(String),
+}
+
+/// The long form of the build, used to let serde do the map parsing
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct LongBuild {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  context: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  dockerfile: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  args: Option<ListOrDict>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  target: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  cache_from: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  labels: Option<ListOrDict>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  network: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  shm_size: Option<ShmSize>,
+}
+
+impl Build {
+  pub fn get_context(&self) -> Option<String> {
+    self.context.clone()
+  }
+
+  pub fn get_dockerfile(&self) -> Option<String> {
+    self.dockerfile.clone()
+  }
+
+  pub fn get_args(&self) -> BTreeMap<String, Option<String>> {
+    self.args.clone().map_or(BTreeMap::new(), |x| x.values)
+  }
+
+  pub fn get_target(&self) -> Option<String> {
+    self.target.clone()
+  }
+
+  pub fn get_labels(&self) -> BTreeMap<String, Option<String>> {
+    self.labels.clone().map_or(BTreeMap::new(), |x| x.values)
+  }
+
+  /// Remote contexts are passed straight through to docker, so we shouldn't treat them as a path
+  pub fn is_remote_context(&self) -> bool {
+    self.context.as_ref().is_some_and(|ctx| is_remote_context(ctx))
+  }
+
+  /// Make the context absolute using the compose file's directory
+  pub fn resolve_context(&self, base_dir: &Path) -> Result<Build> {
+    match (&self.context, self.is_remote_context()) {
+      (Some(ctx), false) => Ok(Build {
+        context: Some(resolve_host_path(ctx, base_dir)?),
+        ..self.clone()
+      }),
+      _ => Ok(self.clone()),
+    }
+  }
+}
+
+impl serde::Serialize for Build {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    let context_only = Build {
+      context: self.context.clone(),
+      syntax: self.syntax.clone(),
+      ..Default::default()
+    };
+    match (&self.syntax, &self.context) {
+      (BuildSyntax::Short, Some(ctx)) if *self == context_only => serializer.serialize_str(ctx),
+      _ => LongBuild {
+        context: self.context.clone(),
+        dockerfile: self.dockerfile.clone(),
+        args: self.args.clone(),
+        target: self.target.clone(),
+        cache_from: self.cache_from.clone(),
+        labels: self.labels.clone(),
+        network: self.network.clone(),
+        shm_size: self.shm_size.clone(),
+      }
+      .serialize(serializer),
+    }
+  }
+}
+
+impl<'de> serde::Deserialize<'de> for Build {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    struct BuildVisitor;
+    impl<'de> serde::de::Visitor<'de> for BuildVisitor {
+      type Value = Build;
+
+      fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("Build: https://docs.docker.com/compose/compose-file/#build")
+      }
+
+      fn visit_str<E>(self, value: &str) -> Result<Build, E>
+      where
+        E: serde::de::Error,
+      {
+        Ok(Build {
+          context: Some(value.to_string()),
+          syntax: BuildSyntax::Short,
+          ..Default::default()
+        })
+      }
+
+      fn visit_map<M>(self, map: M) -> Result<Build, M::Error>
+      where
+        M: serde::de::MapAccess<'de>,
+      {
+        let long: LongBuild =
+          serde::Deserialize::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+        Ok(Build {
+          context: long.context,
+          dockerfile: long.dockerfile,
+          args: long.args,
+          target: long.target,
+          cache_from: long.cache_from,
+          labels: long.labels,
+          network: long.network,
+          shm_size: long.shm_size,
+          syntax: BuildSyntax::Long,
+        })
+      }
+    }
+    deserializer.deserialize_any(BuildVisitor)
+  }
+}
+
+/// Several compose keys (environment, labels, build args) can be either a list of "KEY=VALUE" or a map.
+/// A missing value means it is taken from the environment docker-compose is run in.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ListOrDict {
+  pub values: BTreeMap<String, Option<String>>,
+  /// Remember the list form so we write it back out the same way
+  is_list: bool,
+}
+
+/// Map values in a ListOrDict can be any yaml scalar, but docker treats them all as strings
+#[derive(Clone, Debug, Deserialize)]
 #[serde(untagged)]
-pub enum Port {
+enum DictValue {
   Str(String),
-  Obj(PortObj),
+  Int(i64),
+  Float(f64),
+  Bool(bool),
+}
+
+impl std::fmt::Display for DictValue {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      DictValue::Str(x) => write!(f, "{}", x),
+      DictValue::Int(x) => write!(f, "{}", x),
+      DictValue::Float(x) => write!(f, "{}", x),
+      DictValue::Bool(x) => write!(f, "{}", x),
+    }
+  }
+}
+
+impl serde::Serialize for ListOrDict {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    match self.is_list {
+      true => self
+        .values
+        .iter()
+        .map(|(key, value)| match value {
+          Some(val) => format!("{}={}", key, val),
+          None => key.clone(),
+        })
+        .collect::<Vec<String>>()
+        .serialize(serializer),
+      false => self.values.serialize(serializer),
+    }
+  }
+}
+
+impl<'de> serde::Deserialize<'de> for ListOrDict {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    struct ListOrDictVisitor;
+    impl<'de> serde::de::Visitor<'de> for ListOrDictVisitor {
+      type Value = ListOrDict;
+
+      fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a list of \"KEY=VALUE\" strings or a map of KEY: VALUE")
+      }
+
+      fn visit_seq<A>(self, mut seq: A) -> Result<ListOrDict, A::Error>
+      where
+        A: serde::de::SeqAccess<'de>,
+      {
+        let mut values = BTreeMap::new();
+        while let Some(item) = seq.next_element::<String>()? {
+          match item.split_once('=') {
+            Some((key, value)) => values.insert(key.to_string(), Some(value.to_string())),
+            None => values.insert(item, None),
+          };
+        }
+        Ok(ListOrDict {
+          values,
+          is_list: true,
+        })
+      }
+
+      fn visit_map<M>(self, mut map: M) -> Result<ListOrDict, M::Error>
+      where
+        M: serde::de::MapAccess<'de>,
+      {
+        let mut values = BTreeMap::new();
+        while let Some((key, value)) = map.next_entry::<String, Option<DictValue>>()? {
+          values.insert(key, value.map(|x| x.to_string()));
+        }
+        Ok(ListOrDict {
+          values,
+          is_list: false,
+        })
+      }
+    }
+    deserializer.deserialize_any(ListOrDictVisitor)
+  }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortProtocol {
+  #[default]
+  Tcp,
+  Udp,
+  Sctp,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortMode {
+  Host,
+  Ingress,
+}
+
+/// An inclusive range of port numbers. A single port is a range with the same start and end.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortRange {
+  pub start: u16,
+  pub end: u16,
+}
+
+impl PortRange {
+  pub fn new(port: u16) -> PortRange {
+    PortRange {
+      start: port,
+      end: port,
+    }
+  }
+
+  /// The number of ports in the range. This is a u32 since "0-65535" holds one more port than fits in a u16
+  pub fn size(&self) -> u32 {
+    u32::from(self.end) - u32::from(self.start) + 1
+  }
+
+  pub fn is_single(&self) -> bool {
+    self.start == self.end
+  }
+
+  pub fn contains(&self, port: u16) -> bool {
+    self.start <= port && port <= self.end
+  }
+
+  /// Parse "5432" or "9000-9005"
+  pub fn parse(value: &str) -> Result<PortRange> {
+    let parse_port = |port: &str| {
+      port.trim().parse::<u16>().context(format!(
+        "'{}' is not a valid port number in '{}'",
+        port, value
+      ))
+    };
+    let range = match value.split_once('-') {
+      None => PortRange::new(parse_port(value)?),
+      Some((start, end)) => PortRange {
+        start: parse_port(start)?,
+        end: parse_port(end)?,
+      },
+    };
+    match range.start <= range.end {
+      true => Ok(range),
+      false => Err(FoundryError::UnexpectedValue).context(format!(
+        "The port range '{}' ends before it starts",
+        value
+      )),
+    }
+  }
 }
 
+impl std::fmt::Display for PortRange {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.is_single() {
+      true => write!(f, "{}", self.start),
+      false => write!(f, "{}-{}", self.start, self.end),
+    }
+  }
+}
+
+/// Single ports are written as a number and ranges as a string, which is what compose accepts
+impl serde::Serialize for PortRange {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    match self.is_single() {
+      true => serializer.serialize_u16(self.start),
+      false => serializer.serialize_str(&self.to_string()),
+    }
+  }
+}
+
+impl<'de> serde::Deserialize<'de> for PortRange {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    struct PortRangeVisitor;
+    impl<'de> serde::de::Visitor<'de> for PortRangeVisitor {
+      type Value = PortRange;
+
+      fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a port number or a range like \"9000-9005\"")
+      }
+
+      fn visit_u64<E>(self, value: u64) -> Result<PortRange, E>
+      where
+        E: serde::de::Error,
+      {
+        match value <= u16::MAX as u64 {
+          true => Ok(PortRange::new(value as u16)),
+          false => Err(E::custom(format!("{} is not a valid port number", value))),
+        }
+      }
+
+      fn visit_i64<E>(self, value: i64) -> Result<PortRange, E>
+      where
+        E: serde::de::Error,
+      {
+        match value < 0 {
+          true => Err(E::custom(format!("{} is not a valid port number", value))),
+          false => self.visit_u64(value as u64),
+        }
+      }
+
+      fn visit_str<E>(self, value: &str) -> Result<PortRange, E>
+      where
+        E: serde::de::Error,
+      {
+        PortRange::parse(value).map_err(|err| E::custom(format!("{:#}", err)))
+      }
+    }
+    deserializer.deserialize_any(PortRangeVisitor)
+  }
+}
+
+/// Which of the port syntaxes was used, so we can write it back out the same way
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum PortSyntax {
+  /// A bare container port number
+  Number,
+  /// "[[HOST_IP:]PUBLISHED:]TARGET[/PROTOCOL]"
+  Short,
+  /// The map with target/published/protocol/mode
+  #[default]
+  Long,
+}
+
+/// A port exposed by a service, stored structured regardless of how it was written
+/// https://docs.docker.com/compose/compose-file/#ports
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Port {
+  host_ip: Option<String>,
+  target: Option<PortRange>,
+  /// If this is empty, docker picks a random host port
+  published: Option<PortRange>,
+  protocol: Option<PortProtocol>,
+  mode: Option<PortMode>,
+  syntax: PortSyntax,
+}
+
+/// The long form of the port, used to let serde do the map parsing
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct PortObj {}
+#[serde(deny_unknown_fields)]
+struct LongPort {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  host_ip: Option<String>,
+  target: u16,
+  /// Compose takes a number or a string here, and the string can be a range (eg: "8080-8081")
+  #[serde(skip_serializing_if = "Option::is_none")]
+  published: Option<PortRange>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  protocol: Option<PortProtocol>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  mode: Option<PortMode>,
+}
+
+/// Where to reach a container's port from the host
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostPort {
+  /// None means the port is bound on all interfaces
+  pub host_ip: Option<String>,
+  pub port: u16,
+  pub protocol: PortProtocol,
+}
+
+impl Port {
+  /// A string that is the same for equivalent ports no matter how they were written, for comparing them
+  pub fn to_canonical(&self) -> String {
+    Port {
+      protocol: Some(self.get_protocol()),
+      ..self.clone()
+    }
+    .to_short()
+    .unwrap_or_default()
+  }
+
+  pub fn get_target(&self) -> Option<PortRange> {
+    self.target.clone()
+  }
+
+  pub fn get_published(&self) -> Option<PortRange> {
+    self.published.clone()
+  }
+
+  pub fn get_protocol(&self) -> PortProtocol {
+    self.protocol.clone().unwrap_or_default()
+  }
+
+  pub fn get_syntax(&self) -> PortSyntax {
+    self.syntax.clone()
+  }
+
+  /// Parse the short syntax "[[HOST_IP:]PUBLISHED:]TARGET[/PROTOCOL]"
+  pub fn parse_short(value: &str) -> Result<Port> {
+    let (mapping, protocol) = match value.rsplit_once('/') {
+      None => (value, None),
+      Some((mapping, proto)) => (
+        mapping,
+        Some(match &proto.to_lowercase()[..] {
+          "tcp" => PortProtocol::Tcp,
+          "udp" => PortProtocol::Udp,
+          "sctp" => PortProtocol::Sctp,
+          x => Err(FoundryError::UnexpectedValue).context(format!(
+            "Unknown protocol '{}' in port '{}'",
+            x, value
+          ))?,
+        }),
+      ),
+    };
+
+    // The host ip can be IPv6, so we split from the right
+    let mut parts = mapping.rsplitn(3, ':');
+    let target = PortRange::parse(parts.next().unwrap_or_default())?;
+    let published = match parts.next() {
+      None | Some("") => None,
+      Some(x) => Some(PortRange::parse(x)?),
+    };
+    let host_ip = parts
+      .next()
+      .map(|ip| ip.trim_start_matches('[').trim_end_matches(']').to_string());
+
+    if let Some(publ) = &published {
+      if !target.is_single() && publ.size() != target.size() {
+        Err(FoundryError::UnexpectedValue).context(format!(
+          "The published and target port ranges in '{}' are different sizes",
+          value
+        ))?;
+      }
+    }
+
+    Ok(Port {
+      host_ip,
+      target: Some(target),
+      published,
+      protocol,
+      mode: None,
+      syntax: PortSyntax::Short,
+    })
+  }
+
+  /// Write the port back out as "[[HOST_IP:]PUBLISHED:]TARGET[/PROTOCOL]"
+  pub fn to_short(&self) -> Option<String> {
+    let target = self.target.clone()?;
+    let mut short = String::new();
+    if let Some(ip) = &self.host_ip {
+      match ip.contains(':') {
+        true => short.push_str(&format!("[{}]:", ip)),
+        false => short.push_str(&format!("{}:", ip)),
+      }
+      if self.published.is_none() {
+        short.push(':');
+      }
+    }
+    if let Some(publ) = &self.published {
+      short.push_str(&format!("{}:", publ));
+    }
+    short.push_str(&target.to_string());
+    match &self.protocol {
+      None => (),
+      Some(PortProtocol::Tcp) => short.push_str("/tcp"),
+      Some(PortProtocol::Udp) => short.push_str("/udp"),
+      Some(PortProtocol::Sctp) => short.push_str("/sctp"),
+    }
+    Some(short)
+  }
+
+  /// The host port that reaches the given container port, if it is published by this mapping
+  ///
+  /// A target range mapped to a single published port, or a port with no published value, means docker
+  /// picks the host port at runtime so we can't know it from the configuration.
+  pub fn host_port_for(&self, container_port: u16, protocol: PortProtocol) -> Option<HostPort> {
+    let target = self.target.clone()?;
+    let published = self.published.clone()?;
+    if !target.contains(container_port) || self.get_protocol() != protocol {
+      return None;
+    }
+
+    let port = match (target.is_single(), published.is_single()) {
+      (true, true) => published.start,
+      (false, false) => published.start + (container_port - target.start),
+      // A single target with a published range gets a random port from that range
+      _ => return None,
+    };
+    Some(HostPort {
+      host_ip: self.host_ip.clone(),
+      port,
+      protocol,
+    })
+  }
+}
+
+impl serde::Serialize for Port {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    let target = self.target.clone().unwrap_or_else(|| PortRange::new(0));
+    let is_simple = self.host_ip.is_none()
+      && self.published.is_none()
+      && self.protocol.is_none()
+      && self.mode.is_none()
+      && target.is_single();
+    let is_long = target.is_single();
+
+    match &self.syntax {
+      PortSyntax::Number if is_simple => serializer.serialize_u16(target.start),
+      PortSyntax::Long if is_long => LongPort {
+        host_ip: self.host_ip.clone(),
+        target: target.start,
+        published: self.published.clone(),
+        protocol: self.protocol.clone(),
+        mode: self.mode.clone(),
+      }
+      .serialize(serializer),
+      _ => serializer.serialize_str(&self.to_short().unwrap_or_default()),
+    }
+  }
+}
+
+impl<'de> serde::Deserialize<'de> for Port {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    struct PortVisitor;
+    impl<'de> serde::de::Visitor<'de> for PortVisitor {
+      type Value = Port;
+
+      fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("Port: https://docs.docker.com/compose/compose-file/#ports")
+      }
+
+      fn visit_u64<E>(self, value: u64) -> Result<Port, E>
+      where
+        E: serde::de::Error,
+      {
+        match value <= u16::MAX as u64 {
+          true => Ok(Port {
+            target: Some(PortRange::new(value as u16)),
+            syntax: PortSyntax::Number,
+            ..Default::default()
+          }),
+          false => Err(E::custom(format!("{} is not a valid port number", value))),
+        }
+      }
+
+      fn visit_i64<E>(self, value: i64) -> Result<Port, E>
+      where
+        E: serde::de::Error,
+      {
+        match value < 0 {
+          true => Err(E::custom(format!("{} is not a valid port number", value))),
+          false => self.visit_u64(value as u64),
+        }
+      }
+
+      fn visit_str<E>(self, value: &str) -> Result<Port, E>
+      where
+        E: serde::de::Error,
+      {
+        Port::parse_short(value).map_err(|err| E::custom(format!("{:#}", err)))
+      }
+
+      fn visit_map<M>(self, map: M) -> Result<Port, M::Error>
+      where
+        M: serde::de::MapAccess<'de>,
+      {
+        let long: LongPort =
+          serde::Deserialize::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+        Ok(Port {
+          host_ip: long.host_ip,
+          target: Some(PortRange::new(long.target)),
+          published: long.published,
+          protocol: long.protocol,
+          mode: long.mode,
+          syntax: PortSyntax::Long,
+        })
+      }
+    }
+    deserializer.deserialize_any(PortVisitor)
+  }
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Restart {
@@ -96,21 +948,17 @@ pub enum Restart {
   UnlessStopped,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ServiceVolumeType {
   Volume,
+  #[default]
   Bind,
   Tmpfs,
   Npipe,
 }
-impl Default for ServiceVolumeType {
-  fn default() -> Self {
-    ServiceVolumeType::Bind
-  }
-}
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ServiceVolumeConsistency {
   Consistent,
@@ -118,22 +966,357 @@ pub enum ServiceVolumeConsistency {
   Delegated,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct ServiceVolumeTmpfs {
-  size: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  size: Option<i64>,
+}
+
+/// Options only used when the volume is a bind mount
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceVolumeBind {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  propagation: Option<String>,
+  /// The "z" and "Z" flags from the short syntax
+  #[serde(skip_serializing_if = "Option::is_none")]
+  selinux: Option<String>,
+}
+
+/// Options only used when the volume is a named volume
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceVolumeOptions {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  nocopy: Option<bool>,
+}
+
+/// Which of the two syntaxes the volume was written in, so we can write it back out the same way
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum VolumeSyntax {
+  /// "[SOURCE:]TARGET[:MODE]"
+  Short,
+  /// The map with an explicit type
+  #[default]
+  Long,
 }
 
-#[derive(Clone, Debug, Default, Serialize)]
+/// A volume mounted into a service, stored in the long form regardless of how it was written
+/// https://docs.docker.com/compose/compose-file/#volumes
+#[derive(Clone, Debug, Default)]
 pub struct ServiceVolume {
+  volume_type: ServiceVolumeType,
+  source: Option<String>,
+  target: Option<String>,
+  read_only: Option<bool>,
+  bind: Option<ServiceVolumeBind>,
+  volume: Option<ServiceVolumeOptions>,
+  tmpfs: Option<ServiceVolumeTmpfs>,
+  consistency: Option<ServiceVolumeConsistency>,
+  syntax: VolumeSyntax,
+}
+
+/// The long form of the volume, used to let serde do the map parsing
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct LongServiceVolume {
   #[serde(rename = "type")]
   volume_type: ServiceVolumeType,
+  #[serde(skip_serializing_if = "Option::is_none")]
   source: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
   target: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
   read_only: Option<bool>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  bind: Option<ServiceVolumeBind>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  volume: Option<ServiceVolumeOptions>,
+  #[serde(skip_serializing_if = "Option::is_none")]
   tmpfs: Option<ServiceVolumeTmpfs>,
+  #[serde(skip_serializing_if = "Option::is_none")]
   consistency: Option<ServiceVolumeConsistency>,
 }
 
+impl From<LongServiceVolume> for ServiceVolume {
+  fn from(long: LongServiceVolume) -> ServiceVolume {
+    ServiceVolume {
+      volume_type: long.volume_type,
+      source: long.source,
+      target: long.target,
+      read_only: long.read_only,
+      bind: long.bind,
+      volume: long.volume,
+      tmpfs: long.tmpfs,
+      consistency: long.consistency,
+      syntax: VolumeSyntax::Long,
+    }
+  }
+}
+
+impl From<ServiceVolume> for LongServiceVolume {
+  fn from(vol: ServiceVolume) -> LongServiceVolume {
+    LongServiceVolume {
+      volume_type: vol.volume_type,
+      source: vol.source,
+      target: vol.target,
+      read_only: vol.read_only,
+      bind: vol.bind,
+      volume: vol.volume,
+      tmpfs: vol.tmpfs,
+      consistency: vol.consistency,
+    }
+  }
+}
+
+impl ServiceVolume {
+  /// A string that is the same for equivalent volumes no matter how they were written, for comparing them
+  pub fn to_canonical(&self) -> String {
+    // Volumes are read/write unless they say otherwise, so "rw" is the same as no mode at all
+    let normalized = ServiceVolume {
+      read_only: Some(true).filter(|_| self.is_read_only()),
+      ..self.clone()
+    };
+    match normalized.to_short() {
+      Some(short) => short,
+      None => format!(
+        "{:?}:{}",
+        self.volume_type,
+        self.target.clone().unwrap_or_default()
+      )
+      .to_lowercase(),
+    }
+  }
+
+  pub fn get_type(&self) -> ServiceVolumeType {
+    self.volume_type.clone()
+  }
+
+  /// The host path for a bind mount or the name of a named volume
+  pub fn get_source(&self) -> Option<String> {
+    self.source.clone()
+  }
+
+  /// The path inside the container
+  pub fn get_target(&self) -> Option<String> {
+    self.target.clone()
+  }
+
+  pub fn is_read_only(&self) -> bool {
+    self.read_only.unwrap_or(false)
+  }
+
+  pub fn get_syntax(&self) -> VolumeSyntax {
+    self.syntax.clone()
+  }
+
+  /// Parse the short syntax "[SOURCE:]TARGET[:MODE]" into the long form
+  ///
+  /// This follows docker-compose's splitting rules: everything before the first colon is the source and
+  /// anything after the last colon of the remainder is the mode.
+  pub fn parse_short(value: &str) -> Result<ServiceVolume> {
+    let (source, target, mode) = match value.split_once(':') {
+      None => (None, value, None),
+      Some((source, rest)) => match rest.rsplit_once(':') {
+        None => (Some(source), rest, None),
+        Some((target, mode)) => (Some(source), target, Some(mode)),
+      },
+    };
+
+    if target.is_empty() || source == Some("") {
+      Err(FoundryError::UnexpectedValue).context(format!(
+        "'{}' is not a valid service volume. Expected [SOURCE:]TARGET[:MODE]",
+        value
+      ))?;
+    }
+    if !target.starts_with('/') {
+      Err(FoundryError::UnexpectedValue).context(format!(
+        "The container path '{}' in service volume '{}' must be absolute",
+        target, value
+      ))?;
+    }
+
+    let volume_type = match source {
+      // Paths are bind mounts, anything else is the name of a volume (or anonymous if there is no source)
+      Some(src) if src.starts_with('.') || src.starts_with('/') || src.starts_with('~') => {
+        ServiceVolumeType::Bind
+      }
+      _ => ServiceVolumeType::Volume,
+    };
+
+    let mut vol = ServiceVolume {
+      volume_type,
+      source: source.map(|x| x.to_string()),
+      target: Some(target.to_string()),
+      syntax: VolumeSyntax::Short,
+      ..Default::default()
+    };
+
+    for flag in mode.map_or(vec![], |x| x.split(',').collect()) {
+      match flag {
+        "ro" => vol.read_only = Some(true),
+        "rw" => vol.read_only = Some(false),
+        "consistent" => vol.consistency = Some(ServiceVolumeConsistency::Consistent),
+        "cached" => vol.consistency = Some(ServiceVolumeConsistency::Cached),
+        "delegated" => vol.consistency = Some(ServiceVolumeConsistency::Delegated),
+        "nocopy" => vol.volume = Some(ServiceVolumeOptions { nocopy: Some(true) }),
+        "z" | "Z" => {
+          vol.bind = Some(ServiceVolumeBind {
+            selinux: Some(flag.to_string()),
+            ..vol.bind.unwrap_or_default()
+          })
+        }
+        "shared" | "rshared" | "slave" | "rslave" | "private" | "rprivate" => {
+          vol.bind = Some(ServiceVolumeBind {
+            propagation: Some(flag.to_string()),
+            ..vol.bind.unwrap_or_default()
+          })
+        }
+        x => Err(FoundryError::UnexpectedValue).context(format!(
+          "Unknown mode '{}' in service volume '{}'",
+          x, value
+        ))?,
+      }
+    }
+    Ok(vol)
+  }
+
+  /// Write the volume back out as "[SOURCE:]TARGET[:MODE]"
+  ///
+  /// Returns None if the volume has options that can only be expressed in the long form (eg tmpfs)
+  pub fn to_short(&self) -> Option<String> {
+    match (&self.volume_type, &self.target) {
+      (ServiceVolumeType::Bind, Some(_)) | (ServiceVolumeType::Volume, Some(_)) => (),
+      _ => return None,
+    }
+
+    let mut mode = vec![];
+    match self.read_only {
+      Some(true) => mode.push("ro".to_string()),
+      Some(false) => mode.push("rw".to_string()),
+      None => (),
+    }
+    if let Some(consistency) = &self.consistency {
+      mode.push(
+        match consistency {
+          ServiceVolumeConsistency::Consistent => "consistent",
+          ServiceVolumeConsistency::Cached => "cached",
+          ServiceVolumeConsistency::Delegated => "delegated",
+        }
+        .to_string(),
+      );
+    }
+    if let Some(ServiceVolumeOptions { nocopy: Some(true) }) = &self.volume {
+      mode.push("nocopy".to_string());
+    }
+    if let Some(bind) = &self.bind {
+      mode.extend(bind.propagation.clone());
+      mode.extend(bind.selinux.clone());
+    }
+
+    let mut short = vec![];
+    short.extend(self.source.clone());
+    short.extend(self.target.clone());
+    if !mode.is_empty() {
+      short.push(mode.join(","));
+    }
+    Some(short.join(":"))
+  }
+
+  /// Expand "~" and make relative bind mount sources absolute using the compose file's directory
+  pub fn resolve_source(&self, base_dir: &Path) -> Result<ServiceVolume> {
+    let source = match (&self.volume_type, &self.source) {
+      (ServiceVolumeType::Bind, Some(src)) => src,
+      _ => return Ok(self.clone()),
+    };
+
+    Ok(ServiceVolume {
+      source: Some(resolve_host_path(source, base_dir)?),
+      ..self.clone()
+    })
+  }
+}
+
+/// Split an image reference into the repository name and the tag, dropping the registry, path and digest
+pub fn split_image(image: &str) -> (String, Option<String>) {
+  let without_digest = image.split('@').next().unwrap_or(image);
+  let last = without_digest.rsplit('/').next().unwrap_or(without_digest);
+  match last.split_once(':') {
+    Some((name, tag)) => (name.to_string(), Some(tag.to_string())),
+    None => (last.to_string(), None),
+  }
+}
+
+/// Docker tags are free form, so take the leading "major[.minor[.patch]]" and ignore any suffix like
+/// "-alpine". Tags like "latest" don't have a version.
+pub fn version_from_tag(tag: &str) -> Option<semver::Version> {
+  let regex = regex::Regex::new(r"^v?(\d+)(?:\.(\d+))?(?:\.(\d+))?").ok()?;
+  let cap = regex.captures(tag)?;
+  let part = |idx: usize| cap.get(idx).map_or(Some(0), |x| x.as_str().parse::<u64>().ok());
+  Some(semver::Version::new(part(1)?, part(2)?, part(3)?))
+}
+
+/// Remote build contexts are passed straight through to docker, so we shouldn't treat them as a path
+pub fn is_remote_context(context: &str) -> bool {
+  context.contains("://") || context.starts_with("git@") || context.starts_with("github.com/")
+}
+
+/// Expand "~" to the home directory and make relative paths absolute from the base directory
+pub fn resolve_host_path(path: &str, base_dir: &Path) -> Result<String> {
+  let full = match path.strip_prefix('~') {
+    Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+      let home = dirs::home_dir().map_or(
+        Err(FoundryError::ConfigurationError).context(format!(
+          "Could not find the home directory to expand '{}'",
+          path
+        )),
+        Ok,
+      )?;
+      home.join(rest.trim_start_matches('/'))
+    }
+    // A relative result would be read back as the name of a volume, so the base has to be absolute too
+    _ if base_dir.is_relative() => std::env::current_dir()
+      .context(format!("Could not get the current directory to resolve '{}'", path))?
+      .join(base_dir)
+      .join(path),
+    _ => base_dir.join(path),
+  };
+  Ok(normalize_path(&full).to_string_lossy().to_string())
+}
+
+/// Lexically remove "." and ".." from a path, since the directory may not exist on this machine. A ".." that
+/// would go above the start of a relative path is kept, and one above the root is dropped like the kernel does.
+fn normalize_path(path: &Path) -> PathBuf {
+  let mut result = PathBuf::new();
+  for component in path.components() {
+    match component {
+      Component::CurDir => (),
+      Component::ParentDir => match result.components().next_back() {
+        Some(Component::Normal(_)) => {
+          result.pop();
+        }
+        Some(Component::RootDir) | Some(Component::Prefix(_)) => (),
+        _ => result.push(".."),
+      },
+      x => result.push(x.as_os_str()),
+    }
+  }
+  result
+}
+
+impl serde::Serialize for ServiceVolume {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    match (&self.syntax, self.to_short()) {
+      (VolumeSyntax::Short, Some(short)) => serializer.serialize_str(&short),
+      _ => LongServiceVolume::from(self.clone()).serialize(serializer),
+    }
+  }
+}
+
 impl<'de> serde::Deserialize<'de> for ServiceVolume {
   fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
   where
@@ -151,11 +1334,16 @@ impl<'de> serde::Deserialize<'de> for ServiceVolume {
       where
         E: serde::de::Error,
       {
-        // TODO: Convert short to long form
-        Ok(ServiceVolume {
-          source: Some(value.to_string()),
-          ..Default::default()
-        })
+        ServiceVolume::parse_short(value).map_err(|err| E::custom(format!("{:#}", err)))
+      }
+
+      fn visit_map<M>(self, map: M) -> Result<ServiceVolume, M::Error>
+      where
+        M: serde::de::MapAccess<'de>,
+      {
+        let long: LongServiceVolume =
+          serde::Deserialize::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+        Ok(long.into())
       }
     }
     // Instantiate our Visitor and ask the Deserializer to drive
@@ -164,36 +1352,6 @@ impl<'de> serde::Deserialize<'de> for ServiceVolume {
   }
 }
 
-// Port
-// "items": {
-//   "oneOf": [
-//     {
-//       "type": "number",
-//       "format": "ports"
-//     },
-//     {
-//       "type": "string",
-//       "format": "ports"
-//     },
-//     {
-//       "type": "object",
-//       "properties": {
-//         "mode": {
-//           "type": "string"
-//         },
-//         "target": {
-//           "type": "integer"
-//         },
-//         "published": {
-//           "type": "integer"
-//         },
-//         "protocol": {
-//           "type": "string"
-//         }
-//       },
-//   ]
-// },
-
 /*
 Sample
 version: "3.6.0"
@@ -219,3 +1377,309 @@ services:
       - ~/Foundry/Panama/installers/taiga/build/src/taiga-back:/src
 
 */
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn volume(yaml: &str) -> ServiceVolume {
+    serde_yaml::from_str(yaml).unwrap()
+  }
+
+  fn is_bind(vol: &ServiceVolume) -> bool {
+    matches!(vol.get_type(), ServiceVolumeType::Bind)
+  }
+
+  #[test]
+  fn short_volumes_pick_their_type_from_the_source() {
+    let anonymous = ServiceVolume::parse_short("/var/lib/data").unwrap();
+    assert!(matches!(anonymous.get_type(), ServiceVolumeType::Volume));
+    assert_eq!(anonymous.get_source(), None);
+
+    let named = ServiceVolume::parse_short("data:/var/lib/data").unwrap();
+    assert!(matches!(named.get_type(), ServiceVolumeType::Volume));
+    assert_eq!(named.get_source(), Some("data".to_string()));
+
+    for source in &["./data", "../data", "/srv/data", "~/data"] {
+      let vol = ServiceVolume::parse_short(&format!("{}:/data:ro", source)).unwrap();
+      assert!(is_bind(&vol), "{}", source);
+      assert!(vol.is_read_only());
+      assert_eq!(vol.get_target(), Some("/data".to_string()));
+    }
+  }
+
+  #[test]
+  fn short_modes_become_long_options() {
+    let vol = ServiceVolume::parse_short("./src:/src:rw,cached,z,rshared").unwrap();
+    let long = serde_yaml::to_value(LongServiceVolume::from(vol)).unwrap();
+    assert_eq!(long["read_only"], serde_yaml::Value::Bool(false));
+    assert_eq!(long["consistency"], serde_yaml::Value::String("cached".to_string()));
+    assert_eq!(long["bind"]["selinux"], serde_yaml::Value::String("z".to_string()));
+    assert_eq!(long["bind"]["propagation"], serde_yaml::Value::String("rshared".to_string()));
+
+    let nocopy = ServiceVolume::parse_short("data:/data:nocopy").unwrap();
+    assert_eq!(nocopy.to_short(), Some("data:/data:nocopy".to_string()));
+  }
+
+  #[test]
+  fn bad_short_volumes_are_rejected() {
+    for value in &["", ":/data", "data:relative", "data:/data:bogus"] {
+      let err = ServiceVolume::parse_short(value).unwrap_err();
+      assert!(
+        matches!(err.downcast_ref::<FoundryError>(), Some(FoundryError::UnexpectedValue)),
+        "{}",
+        value
+      );
+    }
+  }
+
+  #[test]
+  fn volumes_are_written_back_in_their_own_syntax() {
+    let short = volume("\"data:/var/lib/data:ro\"");
+    assert_eq!(
+      serde_yaml::to_value(&short).unwrap(),
+      serde_yaml::Value::String("data:/var/lib/data:ro".to_string())
+    );
+
+    let long = volume("type: bind\nsource: ./src\ntarget: /src\n");
+    assert_eq!(long.get_syntax(), VolumeSyntax::Long);
+    let written = serde_yaml::to_value(&long).unwrap();
+    assert_eq!(written["type"], serde_yaml::Value::String("bind".to_string()));
+    assert_eq!(written["source"], serde_yaml::Value::String("./src".to_string()));
+
+    // tmpfs only has a long form, so it can't come back short
+    let tmpfs = volume("type: tmpfs\ntarget: /tmp\ntmpfs:\n  size: 1000\n");
+    assert_eq!(tmpfs.to_short(), None);
+  }
+
+  #[test]
+  fn a_bare_file_name_resolves_from_the_current_dir() {
+    let current_dir = std::env::current_dir().unwrap();
+    let schema = Schema {
+      source: Some("docker-compose.yml".to_string()),
+      ..Default::default()
+    };
+    assert_eq!(schema.get_base_dir().unwrap(), current_dir);
+
+    let resolved = ServiceVolume::parse_short("./data:/x")
+      .unwrap()
+      .resolve_source(&schema.get_base_dir().unwrap())
+      .unwrap();
+    let source = resolved.get_source().unwrap();
+    assert_eq!(source, current_dir.join("data").to_string_lossy());
+
+    // Written back out and read again, it has to still be a bind mount rather than a volume named "data"
+    let reread = ServiceVolume::parse_short(&resolved.to_short().unwrap()).unwrap();
+    assert!(is_bind(&reread));
+    assert_eq!(reread.get_source(), Some(source));
+  }
+
+  #[test]
+  fn relative_base_dirs_are_made_absolute() {
+    let current_dir = std::env::current_dir().unwrap();
+    let resolved = resolve_host_path("./data", Path::new("")).unwrap();
+    assert_eq!(resolved, current_dir.join("data").to_string_lossy());
+    assert!(Path::new(&resolve_host_path("data", Path::new("compose")).unwrap()).is_absolute());
+  }
+
+  #[test]
+  fn paths_are_resolved_against_the_base_dir() {
+    let base = Path::new("/srv/app");
+    assert_eq!(resolve_host_path("./data", base).unwrap(), "/srv/app/data");
+    assert_eq!(resolve_host_path("../data", base).unwrap(), "/srv/data");
+    assert_eq!(resolve_host_path("../../../data", base).unwrap(), "/data");
+    assert_eq!(resolve_host_path("/abs/./x/../y", base).unwrap(), "/abs/y");
+
+    let home = dirs::home_dir().unwrap();
+    assert_eq!(resolve_host_path("~/data", base).unwrap(), home.join("data").to_string_lossy());
+    // Only a leading "~/" is the home directory
+    assert_eq!(resolve_host_path("~user", base).unwrap(), "/srv/app/~user");
+  }
+
+  #[test]
+  fn parent_dirs_are_kept_above_a_relative_start() {
+    assert_eq!(normalize_path(Path::new("../data")), PathBuf::from("../data"));
+    assert_eq!(normalize_path(Path::new("a/../../b")), PathBuf::from("../b"));
+    assert_eq!(normalize_path(Path::new("a/./b/..")), PathBuf::from("a"));
+    assert_eq!(normalize_path(Path::new("/..")), PathBuf::from("/"));
+  }
+
+  #[test]
+  fn resolving_a_schema_only_touches_bind_mounts() {
+    let schema: Schema = serde_yaml::from_str(
+      "version: \"3.8\"\nservices:\n  db:\n    image: postgres\n    volumes:\n      - data:/var/lib/data\n      - ./conf:/etc/conf:ro\n",
+    )
+    .unwrap();
+    let schema = Schema {
+      source: Some("/srv/app/docker-compose.yml".to_string()),
+      ..schema
+    };
+    let volumes = schema.resolve_paths().unwrap().services["db"].get_volumes();
+    assert_eq!(volumes[0].get_source(), Some("data".to_string()));
+    assert_eq!(volumes[1].get_source(), Some("/srv/app/conf".to_string()));
+    assert_eq!(volumes[1].to_short(), Some("/srv/app/conf:/etc/conf:ro".to_string()));
+  }
+
+  fn port(yaml: &str) -> Port {
+    serde_yaml::from_str(yaml).unwrap()
+  }
+
+  #[test]
+  fn short_ports_parse_every_part() {
+    let simple = Port::parse_short("5432").unwrap();
+    assert_eq!(simple.get_target(), Some(PortRange::new(5432)));
+    assert_eq!(simple.get_published(), None);
+
+    let full = Port::parse_short("127.0.0.1:8000-8001:80-81/udp").unwrap();
+    assert_eq!(full.host_ip, Some("127.0.0.1".to_string()));
+    assert_eq!(full.get_published(), Some(PortRange { start: 8000, end: 8001 }));
+    assert_eq!(full.get_target(), Some(PortRange { start: 80, end: 81 }));
+    assert_eq!(full.get_protocol(), PortProtocol::Udp);
+    assert_eq!(full.to_short(), Some("127.0.0.1:8000-8001:80-81/udp".to_string()));
+
+    let ipv6 = Port::parse_short("[::1]::80").unwrap();
+    assert_eq!(ipv6.host_ip, Some("::1".to_string()));
+    assert_eq!(ipv6.get_published(), None);
+    assert_eq!(ipv6.to_short(), Some("[::1]::80".to_string()));
+  }
+
+  #[test]
+  fn bad_short_ports_are_rejected() {
+    for value in &["", "http", "80/icmp", "90-80", "70000", "8000-8002:80-81"] {
+      assert!(Port::parse_short(value).is_err(), "{}", value);
+    }
+  }
+
+  #[test]
+  fn the_whole_port_range_has_a_size() {
+    let all = PortRange::parse("0-65535").unwrap();
+    assert_eq!(all.size(), 65536);
+    assert_eq!(PortRange::new(80).size(), 1);
+    assert!(Port::parse_short("0-65535:0-65535").is_ok());
+  }
+
+  #[test]
+  fn long_ports_take_published_as_a_number_or_a_string() {
+    assert_eq!(port("target: 80\npublished: 8080\n").get_published(), Some(PortRange::new(8080)));
+    assert_eq!(port("target: 80\npublished: \"8080\"\n").get_published(), Some(PortRange::new(8080)));
+
+    let range = port("target: 80\npublished: \"8080-8081\"\n");
+    assert_eq!(range.get_published(), Some(PortRange { start: 8080, end: 8081 }));
+    let written = serde_yaml::to_value(&range).unwrap();
+    assert_eq!(written["published"], serde_yaml::Value::String("8080-8081".to_string()));
+    assert_eq!(port(&serde_yaml::to_string(&range).unwrap()), range);
+
+    assert!(serde_yaml::from_str::<Port>("target: 80\npublished: \"http\"\n").is_err());
+    assert!(serde_yaml::from_str::<Port>("target: 80\npublished: 70000\n").is_err());
+  }
+
+  #[test]
+  fn host_ports_follow_the_mapping() {
+    let single = Port::parse_short("127.0.0.1:15432:5432").unwrap();
+    assert_eq!(
+      single.host_port_for(5432, PortProtocol::Tcp),
+      Some(HostPort {
+        host_ip: Some("127.0.0.1".to_string()),
+        port: 15432,
+        protocol: PortProtocol::Tcp,
+      })
+    );
+    assert_eq!(single.host_port_for(5432, PortProtocol::Udp), None);
+    assert_eq!(single.host_port_for(5433, PortProtocol::Tcp), None);
+
+    let range = Port::parse_short("9000-9005:8000-8005").unwrap();
+    assert_eq!(range.host_port_for(8003, PortProtocol::Tcp).map(|x| x.port), Some(9003));
+
+    // Docker picks these ports when the container starts
+    for value in &["5432", ":5432", "9000-9005:5432"] {
+      let port = Port::parse_short(value).unwrap();
+      assert_eq!(port.host_port_for(5432, PortProtocol::Tcp), None, "{}", value);
+    }
+  }
+
+  #[test]
+  fn schemas_find_the_host_port_of_a_service() {
+    let schema: Schema = serde_yaml::from_str(
+      "version: \"3.8\"\nservices:\n  db:\n    image: postgres\n    ports:\n      - 5432\n      - target: 5432\n        published: \"15432\"\n",
+    )
+    .unwrap();
+    let host_port = schema.get_host_port("db".to_string(), 5432, PortProtocol::Tcp).unwrap();
+    assert_eq!(host_port.port, 15432);
+    assert_eq!(host_port.host_ip, None);
+
+    for (service, port) in &[("db", 5433), ("web", 5432)] {
+      let err = schema.get_host_port(service.to_string(), *port, PortProtocol::Tcp).unwrap_err();
+      assert!(matches!(err.downcast_ref::<FoundryError>(), Some(FoundryError::NotFound)));
+    }
+  }
+
+  fn build(yaml: &str) -> Build {
+    serde_yaml::from_str(yaml).unwrap()
+  }
+
+  #[test]
+  fn builds_keep_their_syntax() {
+    let short = build("./web");
+    assert_eq!(short.get_context(), Some("./web".to_string()));
+    assert_eq!(short.syntax, BuildSyntax::Short);
+    assert_eq!(serde_yaml::to_value(&short).unwrap(), serde_yaml::Value::String("./web".to_string()));
+
+    let long = build("context: ./web\ndockerfile: Dockerfile.dev\ntarget: dev\nshm_size: 2gb\n");
+    assert_eq!(long.get_dockerfile(), Some("Dockerfile.dev".to_string()));
+    assert_eq!(long.get_target(), Some("dev".to_string()));
+    assert_eq!(long.shm_size, Some(ShmSize::Str("2gb".to_string())));
+    assert_eq!(build(&serde_yaml::to_string(&long).unwrap()), long);
+
+    // A short build that gained more settings has to be written in the long form
+    let grown = Build {
+      dockerfile: Some("Dockerfile.dev".to_string()),
+      ..short
+    };
+    let written = serde_yaml::to_value(&grown).unwrap();
+    assert_eq!(written["dockerfile"], serde_yaml::Value::String("Dockerfile.dev".to_string()));
+
+    assert!(serde_yaml::from_str::<Build>("context: .\nbogus: true\n").is_err());
+  }
+
+  #[test]
+  fn build_contexts_resolve_unless_remote() {
+    let base = Path::new("/srv/app");
+    let local = build("../web").resolve_context(base).unwrap();
+    assert_eq!(local.get_context(), Some("/srv/web".to_string()));
+
+    let remote = build("https://github.com/example/web.git#main");
+    assert!(remote.is_remote_context());
+    assert_eq!(remote.resolve_context(base).unwrap(), remote);
+  }
+
+  #[test]
+  fn lists_and_dicts_hold_the_same_values() {
+    let list = build("context: .\nargs:\n  - VERSION=13\n  - EMPTY=\n  - FROM_ENV\n");
+    let dict = build("context: .\nargs:\n  VERSION: 13\n  EMPTY: \"\"\n  FROM_ENV:\n");
+    assert_eq!(list.get_args(), dict.get_args());
+
+    let args = list.get_args();
+    assert_eq!(args["VERSION"], Some("13".to_string()));
+    assert_eq!(args["EMPTY"], Some("".to_string()));
+    assert_eq!(args["FROM_ENV"], None);
+
+    // Non-string scalars in a dict are passed to docker as strings
+    let labels = build("context: .\nlabels:\n  enabled: true\n  ratio: 0.5\n").get_labels();
+    assert_eq!(labels["enabled"], Some("true".to_string()));
+    assert_eq!(labels["ratio"], Some("0.5".to_string()));
+  }
+
+  #[test]
+  fn lists_and_dicts_are_written_back_the_same_way() {
+    let list: ListOrDict = serde_yaml::from_str("- B=2\n- A\n").unwrap();
+    let sorted: serde_yaml::Value = serde_yaml::from_str("- A\n- B=2\n").unwrap();
+    assert_eq!(serde_yaml::to_value(&list).unwrap(), sorted);
+
+    let dict: ListOrDict = serde_yaml::from_str("B: 2\nA:\n").unwrap();
+    let written = serde_yaml::to_value(&dict).unwrap();
+    assert_eq!(written["B"], serde_yaml::Value::String("2".to_string()));
+    assert_eq!(written["A"], serde_yaml::Value::Null);
+
+    assert!(serde_yaml::from_str::<ListOrDict>("\"A=1\"").is_err());
+  }
+}
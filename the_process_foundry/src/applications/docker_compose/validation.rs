@@ -0,0 +1,235 @@
+//! Validate docker-compose files against the official JSON Schemas
+//!
+//! Serde quietly ignores keys that aren't in the structs, so a typo like "volume:" just disappears and
+//! mistakes in the values give errors without a location. Checking the raw yaml against the published schema
+//! first catches both and tells the user exactly where the problem is.
+//!
+//! Only the 3.8 schema is bundled. Every 3.x release only added to the format, so the schemas for the earlier
+//! versions are made by removing what was added after them, following
+//! https://docs.docker.com/compose/compose-file/compose-versioning/
+
+use anyhow::{Context, Result};
+use jsonschema::paths::{Location, LocationSegment};
+use serde_json::Value;
+
+use super::schema::Schema;
+use super::FoundryError;
+
+const CONFIG_SCHEMA_V3_8: &str = include_str!("schemas/config_schema_v3.8.json");
+
+/// What each 3.x release added to the format, as the minor version and where it is in the 3.8 schema
+const ADDED_IN: &[(u64, &str)] = &[
+  (1, "/properties/secrets"),
+  (1, "/definitions/service/properties/secrets"),
+  (2, "/definitions/service/properties/build/oneOf/1/properties/cache_from"),
+  // The long syntax for ports and volumes
+  (2, "/definitions/service/properties/ports/items/oneOf/2"),
+  (2, "/definitions/service/properties/volumes/items/oneOf/1"),
+  (2, "/definitions/network/properties/attachable"),
+  (2, "/definitions/deployment/properties/endpoint_mode"),
+  (2, "/definitions/deployment/properties/placement/properties/preferences"),
+  (3, "/properties/configs"),
+  (3, "/definitions/service/properties/configs"),
+  (3, "/definitions/service/properties/credential_spec"),
+  (3, "/definitions/service/properties/build/oneOf/1/properties/labels"),
+  // Extension fields (x-*) at the top of the file
+  (4, "/patternProperties"),
+  (4, "/definitions/service/properties/build/oneOf/1/properties/network"),
+  (4, "/definitions/service/properties/build/oneOf/1/properties/target"),
+  (4, "/definitions/healthcheck/properties/start_period"),
+  (4, "/definitions/deployment/properties/update_config/properties/order"),
+  (4, "/definitions/volume/properties/name"),
+  (5, "/definitions/service/properties/build/oneOf/1/properties/shm_size"),
+  (5, "/definitions/service/properties/isolation"),
+  (5, "/definitions/network/properties/name"),
+  (5, "/definitions/secret/properties/name"),
+  (5, "/definitions/config/properties/name"),
+  (6, "/definitions/service/properties/volumes/items/oneOf/1/properties/tmpfs"),
+  (7, "/definitions/service/properties/init"),
+  (7, "/definitions/deployment/properties/rollback_config"),
+  // Extension fields inside the definitions
+  (7, "/definitions/service/patternProperties"),
+  (7, "/definitions/network/patternProperties"),
+  (7, "/definitions/volume/patternProperties"),
+  (7, "/definitions/secret/patternProperties"),
+  (7, "/definitions/config/patternProperties"),
+  (8, "/definitions/deployment/properties/placement/properties/max_replicas_per_node"),
+  (8, "/definitions/secret/properties/template_driver"),
+  (8, "/definitions/config/properties/template_driver"),
+];
+
+/// Find the JSON Schema used to validate the given version of a compose file
+pub fn get_json_schema(version: &semver::Version) -> Result<Value> {
+  if version.major != 3 || version.minor > 8 {
+    Err(FoundryError::YamlError).context(format!(
+      "Docker compose file version {} is not supported. Only versions 3.0 through 3.8 can be loaded",
+      version
+    ))?;
+  }
+  let mut schema: Value = serde_json::from_str(CONFIG_SCHEMA_V3_8)
+    .context("The bundled JSON Schema for docker compose 3.8 is not valid json")?;
+
+  // Newest first, since later additions can be inside earlier ones (eg: tmpfs in the long volume syntax)
+  for (_, pointer) in ADDED_IN.iter().rev().filter(|(minor, _)| *minor > version.minor) {
+    remove_pointer(&mut schema, pointer)
+      .context(format!("Could not make the JSON Schema for docker compose {}", version))?;
+  }
+  Ok(schema)
+}
+
+/// Remove the object key or array item the json pointer refers to
+fn remove_pointer(value: &mut Value, pointer: &str) -> Result<()> {
+  let (parent, last) = pointer.rsplit_once('/').unwrap_or(("", pointer));
+  let removed = match value.pointer_mut(parent) {
+    Some(Value::Object(map)) => map.remove(last).is_some(),
+    Some(Value::Array(items)) => match last.parse::<usize>() {
+      Ok(idx) if idx < items.len() => {
+        items.remove(idx);
+        true
+      }
+      _ => false,
+    },
+    _ => false,
+  };
+  match removed {
+    true => Ok(()),
+    false => Err(FoundryError::Unreachable).context(format!("The 3.8 schema has nothing at '{}'", pointer)),
+  }
+}
+
+/// Check the contents of a compose file against the JSON Schema for the version it declares
+pub fn validate(contents: &str, source: &str) -> Result<()> {
+  let raw: Value = serde_yaml::from_str(contents)
+    .map_err(|err| anyhow::Error::new(FoundryError::YamlError).context(err.to_string()))
+    .context(format!("'{}' is not a valid yaml file", source))?;
+  validate_value(&raw, source)
+}
+
+/// Check an already parsed compose file against the JSON Schema for the version it declares
+pub fn validate_value(raw: &Value, source: &str) -> Result<()> {
+  let version = match raw.get("version") {
+    Some(Value::String(ver)) => Schema::parse_version(ver),
+    Some(Value::Number(ver)) => Schema::parse_version(&ver.to_string()),
+    Some(x) => Err(FoundryError::YamlError).context(format!("version: '{}' is not a string", x)),
+    None => Err(FoundryError::YamlError)
+      .context("version: is missing. Files without a version (compose-spec) are not supported yet"),
+  }
+  .context(format!("Could not get the docker compose version for '{}'", source))?;
+
+  let schema = get_json_schema(&version)?;
+  let validator = jsonschema::draft4::new(&schema)
+    .map_err(|err| anyhow::Error::new(FoundryError::YamlError).context(err.to_string()))
+    .context(format!(
+      "Could not build a validator for docker compose version {}",
+      version
+    ))?;
+
+  let errors: Vec<String> = validator
+    .iter_errors(raw)
+    .map(|err| format!("{}: {}", to_yaml_path(err.instance_path()), err))
+    .collect();
+  match errors.is_empty() {
+    true => Ok(()),
+    false => Err(FoundryError::YamlError).context(format!(
+      "'{}' is not a valid docker compose {} file:\n\t{}",
+      source,
+      version,
+      errors.join("\n\t")
+    )),
+  }
+}
+
+/// Convert a json pointer (/services/postgres/ports/0) into the way people read yaml (services.postgres.ports[0])
+fn to_yaml_path(location: &Location) -> String {
+  let mut path = String::new();
+  for segment in location {
+    match segment {
+      LocationSegment::Index(idx) => path.push_str(&format!("[{}]", idx)),
+      LocationSegment::Property(prop) => {
+        if !path.is_empty() {
+          path.push('.');
+        }
+        path.push_str(&prop);
+      }
+    }
+  }
+  match path.is_empty() {
+    true => "(root)".to_string(),
+    false => path,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn check(version: &str, service: &str) -> Result<()> {
+    validate(
+      &format!("version: \"{}\"\nservices:\n  postgres:\n    image: postgres\n{}", version, service),
+      "docker-compose.yml",
+    )
+  }
+
+  fn error_text(result: Result<()>) -> String {
+    match result {
+      Ok(_) => panic!("The file should not have been valid"),
+      Err(err) => {
+        assert!(matches!(err.downcast_ref::<FoundryError>(), Some(FoundryError::YamlError)));
+        format!("{:#}", err)
+      }
+    }
+  }
+
+  #[test]
+  fn every_supported_version_has_a_schema() {
+    for minor in 0..=8 {
+      let schema = get_json_schema(&semver::Version::new(3, minor, 0)).unwrap();
+      assert!(jsonschema::draft4::new(&schema).is_ok(), "3.{} did not build", minor);
+    }
+  }
+
+  #[test]
+  fn unsupported_versions_are_rejected() {
+    for version in ["2.4", "3.9", "4"] {
+      let text = error_text(check(version, ""));
+      assert!(text.contains("is not supported"), "{}", text);
+    }
+  }
+
+  #[test]
+  fn bad_ports_are_reported_with_their_path() {
+    let text = error_text(check("3.8", "    ports:\n      - target: http\n"));
+    assert!(text.contains("services.postgres.ports[0]: "), "{}", text);
+  }
+
+  #[test]
+  fn bad_volumes_are_reported_with_their_path() {
+    let text = error_text(check("3.8", "    volumes:\n      - ./data:/data\n      - source: ./backup\n"));
+    assert!(text.contains("services.postgres.volumes[1]: "), "{}", text);
+    assert!(!text.contains("volumes[0]"), "{}", text);
+  }
+
+  #[test]
+  fn keys_are_only_allowed_from_the_version_that_added_them() {
+    let long_port = "    ports:\n      - target: 5432\n        published: 5432\n";
+    assert!(check("3.2", long_port).is_ok());
+    assert!(error_text(check("3.1", long_port)).contains("services.postgres.ports[0]: "));
+
+    assert!(check("3.7", "    init: true\n").is_ok());
+    assert!(error_text(check("3.0", "    init: true\n")).contains("services.postgres: "));
+
+    let start_period = "    healthcheck:\n      test: pg_isready\n      start_period: 10s\n";
+    assert!(check("3.4", start_period).is_ok());
+    assert!(error_text(check("3.3", start_period)).contains("services.postgres.healthcheck: "));
+  }
+
+  #[test]
+  fn extension_fields_depend_on_the_version() {
+    let root = |version: &str| validate(&format!("version: \"{}\"\nx-common: {{}}\nservices: {{}}\n", version), "x.yml");
+    assert!(root("3.4").is_ok());
+    assert!(error_text(root("3.3")).contains("(root): "));
+
+    assert!(check("3.7", "    x-note: hi\n").is_ok());
+    assert!(check("3.6", "    x-note: hi\n").is_err());
+  }
+}
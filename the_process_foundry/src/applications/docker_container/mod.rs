@@ -6,10 +6,12 @@
 //! THINK: Should this assume it is clean (freshly spun up) or can it be dirty?
 
 const APP_NAME: &str = "Docker Container";
-const MODULE_VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const MODULE_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 use anyhow::{Context, Result};
+use async_trait::async_trait;
 use serde_derive::{Deserialize, Serialize};
+use std::sync::OnceLock;
 // use schemars::JsonSchema;
 // use shiplift::Docker;
 
@@ -26,6 +28,18 @@ pub enum Status {
   Exited,
 }
 
+impl Status {
+  /// Convert the state docker reports (eg: "running", "exited")
+  pub fn from_state(state: &str) -> Status {
+    match state {
+      "running" | "restarting" | "paused" => Status::Up,
+      "exited" | "dead" => Status::Exited,
+      "created" | "removing" => Status::Down,
+      _ => Status::Unknown,
+    }
+  }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DockerContainer {
   pub status: Status,
@@ -33,62 +47,125 @@ pub struct DockerContainer {
 
   /// The container who owns this instance, and how we send manipulation commands (eg Docker, DockerCompose)
   #[serde(skip)]
-  pub parent: Option<Rc<dyn ContainerTrait>>,
+  pub parent: Option<Arc<dyn ContainerTrait>>,
 
   /// The shell to use inside this container when running additional executables
+  pub shell: Option<ShellType>,
+
+  /// The shell found the first time one was needed, if it wasn't set. Clones share it, so it is only probed
+  /// for once.
   #[serde(skip)]
-  pub shell: Option<Rc<dyn ContainerTrait>>,
+  probed_shell: Arc<OnceLock<ShellType>>,
 }
 
 impl DockerContainer {
-  /// If we don't have a parent, set it to a local instance of docker
-  /// TODO: Actually make this happen. Currently already using Docker-Compose
-  pub fn set_parent(&self, parent: Rc<dyn ContainerTrait>) -> Result<DockerContainer> {
-    match &self.parent {
-      Some(x) => log::info!(
+  pub fn status(&self, status: Status) -> DockerContainer {
+    DockerContainer {
+      status,
+      ..self.clone()
+    }
+  }
+
+  /// Replace the container used to manage this one (eg: Docker, DockerCompose)
+  pub fn set_parent(&self, parent: Arc<dyn ContainerTrait>) -> Result<DockerContainer> {
+    if let Some(x) = &self.parent {
+      log::info!(
         "Replacing parent {} on container {} with {}",
         x.get_name(),
         self.get_name(),
         parent.get_name()
-      ),
-      None => (),
+      )
     }
     Ok(DockerContainer {
       parent: Some(parent),
+      // The new parent may reach the container differently, so look again
+      probed_shell: Default::default(),
       ..self.clone()
     })
   }
 
   /// Find and verify the shell on the container
   pub fn set_shell(&self, preferred: Option<AppQuery>) -> Result<DockerContainer> {
-    let query = preferred.unwrap_or(AppQuery::new("bash".to_string()));
+    let shell = self.probe_shell(preferred)?;
 
-    match &self.shell {
-      Some(x) => log::info!(
-        "Replacing shell {} on container {} with {}",
-        x.get_name(),
+    if let Some(x) = &self.shell {
+      log::info!(
+        "Replacing shell {:?} on container {} with {:?}",
+        x,
         self.get_name(),
-        query.name
-      ),
-      None => (),
+        shell
+      )
     }
 
-    // HACK: Still trying to get my head around this special case
-    let shell = Rc::new(Bash::build(AppInstance::new("bash".to_string()), None)?);
-
     Ok(DockerContainer {
       shell: Some(shell),
       ..self.clone()
     })
   }
 
+  /// Try running each shell in the container through the parent, starting with the preferred ones
+  pub fn probe_shell(&self, preferred: Option<AppQuery>) -> Result<ShellType> {
+    block_on(self.probe_shell_async(preferred))
+  }
+
+  /// The shell set with set_shell, otherwise the one probed for the first time it was needed
+  pub async fn get_shell_async(&self) -> Result<ShellType> {
+    if let Some(shell) = self.shell.or_else(|| self.probed_shell.get().copied()) {
+      return Ok(shell);
+    }
+    let shell = self.probe_shell_async(None).await?;
+    // If another call probed at the same time, it found the same shell
+    let _ = self.probed_shell.set(shell);
+    Ok(shell)
+  }
+
+  pub async fn probe_shell_async(&self, preferred: Option<AppQuery>) -> Result<ShellType> {
+    let parent = match &self.parent {
+      None => Err(FoundryError::NotConfigured).context(format!(
+        "Cannot look for a shell: No parent set in '{}'",
+        self.get_name()
+      ))?,
+      Some(x) => x,
+    };
+
+    let mut candidates = vec![];
+    if let Some(query) = &preferred {
+      for name in std::iter::once(&query.name).chain(query.aliases.iter().flatten()) {
+        match ShellType::from_name(name) {
+          Some(shell) => candidates.push(shell),
+          None => log::warn!("'{}' is not a shell we know how to use, ignoring it", name),
+        }
+      }
+    }
+    for shell in ShellType::PROBE_ORDER.iter() {
+      if !candidates.contains(shell) {
+        candidates.push(*shell);
+      }
+    }
+
+    for shell in candidates {
+      match parent
+        .forward_async(self.instance.clone(), Message::Command(shell.probe()))
+        .await
+      {
+        Ok(_) => {
+          log::debug!("Using {:?} as the shell for {}", shell, self.instance.name);
+          return Ok(shell);
+        }
+        Err(err) => log::debug!("{:?} is not available in {}: {:#}", shell, self.instance.name, err),
+      }
+    }
+    Err(FoundryError::NotFound).context(format!(
+      "Could not find any usable shell in container '{}'",
+      self.instance.name
+    ))
+  }
+
   fn get_module_version() -> Result<semver::Version> {
-    Ok({
-      semver::Version::parse(MODULE_VERSION).context(format!(
-        "{} has an invalid version number '{}' Cargo.toml",
-        APP_NAME, MODULE_VERSION
-      ))
-    }?)
+    semver::Version::parse(MODULE_VERSION).context(format!(
+      "{} has an invalid version number '{}' Cargo.toml",
+      APP_NAME, MODULE_VERSION
+    ))
   }
 
   fn get_name(&self) -> String {
@@ -104,10 +181,15 @@ impl AppTrait for DockerContainer {
     self.get_name()
   }
 
+  /// If we don't have a parent, it is managed through the local docker daemon
   fn build(
     instance: AppInstance,
-    parent: Option<Rc<dyn ContainerTrait>>,
+    parent: Option<Arc<dyn ContainerTrait>>,
   ) -> Result<DockerContainer> {
+    let parent = match parent {
+      Some(x) => Some(x),
+      None => Some(Arc::new(Docker::build(Docker::get_local()?, None)?) as Arc<dyn ContainerTrait>),
+    };
     let base = DockerContainer {
       status: Status::Down,
       instance: AppInstance {
@@ -115,9 +197,11 @@ impl AppTrait for DockerContainer {
         ..instance.clone()
       },
       parent,
+      // The container may not be running yet, so the shell is probed for when it is first needed
       shell: None,
+      probed_shell: Default::default(),
     };
-    base.set_shell(None)
+    Ok(base)
   }
 
   /// Knows how to get the version number of the installed app (not the module version)
@@ -128,24 +212,20 @@ impl AppTrait for DockerContainer {
   fn set_cli(
     &self,
     _instance: AppInstance,
-    _container: Rc<dyn ContainerTrait>,
+    _container: Arc<dyn ContainerTrait>,
   ) -> Result<AppInstance> {
     unimplemented!()
   }
 }
 
+#[async_trait]
 impl ContainerTrait for DockerContainer {
-  /// This will find a list of apps with configurations that the container knows about
   fn find(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
-    // Is there a shell
-    let shell = match &self.shell {
-      None => Err(FoundryError::NotConfigured).context(format!(
-        "Cannot run find: No shell set in '{}'",
-        self.get_name()
-      ))?,
-      Some(x) => x,
-    };
+    block_on(self.find_async(query))
+  }
 
+  /// This will find a list of apps with configurations that the container knows about
+  async fn find_async(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
     // Is there a parent container
     let parent = match &self.parent {
       None => Err(FoundryError::NotConfigured).context(format!(
@@ -155,44 +235,17 @@ impl ContainerTrait for DockerContainer {
       Some(x) => x,
     };
 
-    // HACK: This should be generic container work, but not quite sure how to make it so
-
-    let regex = regex::Regex::new(r"^(\w+) \(([\w\.\s]+)\)$")?;
-    let lc = shell.get_name().to_lowercase();
-    let name = regex.captures(&lc).map_or(
-      Err(FoundryError::UnexpectedValue).context(format!(
-        "'{}' does not appear to be a valid shell name",
-        shell.get_name()
-      )),
-      |cap| {
-        cap.get(1).map_or(
-          Err(FoundryError::UnexpectedValue).context(format!(
-            "'{}' does not appear to be a valid shell name",
-            shell.get_name()
-          )),
-          |val| Ok(val.as_str()),
-        )
-      },
-    )?;
-
-    let cmd = match name {
-      "bash" => Ok(Message::Command(Cmd {
-        run_as: None,
-        command: "bash".to_string(),
-        args: ["-c", &format!("command -v {}", query.name)]
-          .iter()
-          .map(|x| x.to_string())
-          .collect(),
-      })),
-      _ => Err(FoundryError::UnexpectedValue).context(format!(
-        "Docker containers are not currently set to use '{}' shells",
-        name
-      )),
-    }?;
+    let cmd = Message::Command(self.get_shell_async().await?.find_app(&query.name));
 
-    let location = parent.forward(self.instance.clone(), cmd)?;
+    let location = parent
+      .forward_async(self.instance.clone(), cmd)
+      .await
+      .context(format!(
+        "Could not find '{}' in container '{}'",
+        query.name, self.instance.name
+      ))?;
     Ok(vec![AppInstance::new(query.name.clone())
-      .set_command_path(Some(Rc::new(self.clone())), location)?])
+      .set_command_path(Some(Arc::new(self.clone())), location)?])
   }
 
   /// List the known items in the app cache
@@ -200,14 +253,18 @@ impl ContainerTrait for DockerContainer {
     unimplemented!("No App Cache for Bash Yet")
   }
 
-  fn forward(&self, _to: AppInstance, message: Message) -> Result<String> {
+  fn forward(&self, to: AppInstance, message: Message) -> Result<String> {
+    block_on(self.forward_async(to, message))
+  }
+
+  async fn forward_async(&self, _to: AppInstance, message: Message) -> Result<String> {
     // Just send it along to
     match &self.parent {
       None => Err(FoundryError::NotConfigured).context(format!(
         "Parent isn't set up for forwarding on container {}",
         self.instance.name
       )),
-      Some(x) => x.forward(self.instance.clone(), message),
+      Some(x) => x.forward_async(self.instance.clone(), message).await,
     }
   }
 
@@ -233,7 +290,7 @@ pub enum ActionResult {
 }
 
 impl Action {
-  fn _run(&self, container: DockerContainer) -> Result<ActionResult> {
+  fn _run(&self, _container: DockerContainer) -> Result<ActionResult> {
     // We shouldn't be able to run anything without a valid configuration
     // let conf = match &compose.config {
     //   Some(conf) => conf,
@@ -263,7 +320,7 @@ impl ActionTrait for FindApp {
     unimplemented!()
   }
 
-  fn to_message(&self, target: Option<AppInstance>) -> Result<Vec<Message>> {
+  fn to_message(&self, _target: Option<AppInstance>) -> Result<Vec<Message>> {
     unimplemented!("ActionTrait not implemented for shell")
   }
 }
@@ -278,7 +335,64 @@ impl ActionTrait for InspectOptions {
     unimplemented!()
   }
 
-  fn to_message(&self, target: Option<AppInstance>) -> Result<Vec<Message>> {
+  fn to_message(&self, _target: Option<AppInstance>) -> Result<Vec<Message>> {
     unimplemented!("ActionTrait not implemented for shell")
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Mutex;
+
+  /// A parent that only has sh, recording every command sent through it
+  #[derive(Debug, Default)]
+  struct Parent {
+    sent: Mutex<Vec<String>>,
+  }
+
+  impl ContainerTrait for Parent {
+    fn find(&self, _query: AppQuery) -> Result<Vec<AppInstance>> {
+      Ok(vec![])
+    }
+
+    fn forward(&self, _to: AppInstance, message: Message) -> Result<String> {
+      let cmd = match message {
+        Message::Command(cmd) => cmd,
+        _ => Err(FoundryError::UnexpectedValue).context("Only commands are sent")?,
+      };
+      self.sent.lock().unwrap().push(cmd.command.clone());
+      match (cmd.command.as_str(), cmd.args.last().map(|x| x.as_str())) {
+        ("sh", Some("exit 0")) => Ok(String::new()),
+        ("sh", Some(_)) => Ok("/usr/bin/psql".to_string()),
+        _ => Err(FoundryError::NotFound).context(format!("{} is not installed", cmd.command)),
+      }
+    }
+
+    fn cached_apps(&self) -> Result<Vec<AppInstance>> {
+      Ok(vec![])
+    }
+
+    fn get_name(&self) -> String {
+      "Test Parent".to_string()
+    }
+  }
+
+  #[test]
+  fn the_shell_is_probed_once() {
+    let parent = Arc::new(Parent::default());
+    let container = DockerContainer::build(AppInstance::new("db".to_string()), Some(parent.clone())).unwrap();
+
+    let psql = container.find(AppQuery::new("psql".to_string())).unwrap();
+    assert_eq!(psql[0].get_command_path().unwrap(), "/usr/bin/psql");
+    // bash is tried first, then sh works, then the lookup itself
+    assert_eq!(*parent.sent.lock().unwrap(), vec!["bash", "sh", "sh"]);
+
+    container.clone().find(AppQuery::new("pg_dump".to_string())).unwrap();
+    assert_eq!(parent.sent.lock().unwrap().len(), 4);
+
+    let fresh = container.set_parent(parent.clone()).unwrap();
+    fresh.find(AppQuery::new("psql".to_string())).unwrap();
+    assert_eq!(parent.sent.lock().unwrap().len(), 7);
+  }
+}
@@ -0,0 +1,428 @@
+//! A container that sends REST requests to the apps it is asked to forward to
+
+const APP_NAME: &str = "Http";
+const MODULE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// How long to wait for a server before giving up, unless the container is told otherwise
+const DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::client::{self, Response, Url};
+use super::FoundryError;
+use super::{ApiAccess, ApiAuth, AppInstance, AppQuery, AppTrait, ContainerTrait, Message, RestRequest};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Http {
+  /// The api here is the default endpoint, for apps that don't have their own
+  instance: AppInstance,
+
+  /// Headers sent with every request, which the request's own headers override
+  headers: BTreeMap<String, String>,
+
+  timeout: Duration,
+}
+
+impl Http {
+  fn get_module_version() -> Result<semver::Version> {
+    semver::Version::parse(MODULE_VERSION).context(format!(
+      "{} has an invalid version number '{}' Cargo.toml",
+      APP_NAME, MODULE_VERSION
+    ))
+  }
+
+  fn get_name(&self) -> String {
+    match &self.instance.api {
+      Some(api) => format!("{} ({})", APP_NAME, api.uri),
+      None => APP_NAME.to_string(),
+    }
+  }
+
+  pub fn header(&self, key: String, value: String) -> Http {
+    let mut headers = self.headers.clone();
+    headers.insert(key, value);
+    Http {
+      headers,
+      ..self.clone()
+    }
+  }
+
+  pub fn timeout(&self, timeout: Duration) -> Http {
+    Http {
+      timeout,
+      ..self.clone()
+    }
+  }
+
+  /// The endpoint for the app, falling back to our own
+  fn get_api(&self, to: &AppInstance) -> Result<ApiAccess> {
+    match to.api.clone().or_else(|| self.instance.api.clone()) {
+      Some(api) => Ok(api),
+      None => Err(FoundryError::NotConfigured).context(format!(
+        "{} does not have an API uri to send requests to",
+        to.full_name()
+      )),
+    }
+  }
+
+  /// Send the request to the app and return the response, whatever its status
+  pub fn send(&self, to: &AppInstance, request: &RestRequest) -> Result<Response> {
+    let api = self.get_api(to)?;
+    let url = Url::parse(&api.uri)?;
+
+    let mut headers: BTreeMap<String, String> = BTreeMap::new();
+    headers.insert("Accept".to_string(), "application/json".to_string());
+    headers.insert("User-Agent".to_string(), format!("the_process_foundry/{}", MODULE_VERSION));
+    headers.extend(self.headers.clone());
+    match &api.auth {
+      Some(ApiAuth::Basic { username, password }) => {
+        let credentials = client::base64(format!("{}:{}", username, password).as_bytes());
+        headers.insert("Authorization".to_string(), format!("Basic {}", credentials));
+      }
+      Some(ApiAuth::Bearer(token)) => {
+        headers.insert("Authorization".to_string(), format!("Bearer {}", token));
+      }
+      Some(ApiAuth::Header { name, value }) => {
+        headers.insert(name.clone(), value.clone());
+      }
+      None => (),
+    }
+    headers.extend(request.headers.clone());
+
+    client::send(
+      &url,
+      request.method.as_str(),
+      &request.path,
+      &headers.into_iter().collect::<Vec<_>>(),
+      request.body.clone().unwrap_or_default().as_bytes(),
+      self.timeout,
+    )
+    .context(format!("{} {} on {} failed", request.method.as_str(), request.path, api.uri))
+  }
+
+  /// Send the request, making sure it succeeded
+  pub fn check(&self, to: &AppInstance, request: &RestRequest) -> Result<Response> {
+    let response = self.send(to, request)?;
+    if response.is_success() {
+      return Ok(response);
+    }
+
+    let error = match response.status {
+      401 | 403 => FoundryError::NotConfigured,
+      404 => FoundryError::NotFound,
+      _ => FoundryError::RemoteError,
+    };
+    Err(error).context(format!(
+      "{} {} on {} returned status {}: {}",
+      request.method.as_str(),
+      request.path,
+      self.get_api(to)?.uri,
+      response.status,
+      response.get_message()
+    ))
+  }
+
+  /// Send the request and parse the json response into the type the caller expects
+  pub fn call<T: DeserializeOwned>(&self, to: &AppInstance, request: &RestRequest) -> Result<T> {
+    self.check(to, request)?.parse()
+  }
+}
+
+impl AppTrait for Http {
+  fn get_name(&self) -> String {
+    self.get_name()
+  }
+
+  /// The instance's api, if set, is used for apps that don't have one of their own
+  fn build(instance: AppInstance, _parent: Option<Arc<dyn ContainerTrait>>) -> Result<Http> {
+    if let Some(api) = &instance.api {
+      // Catch bad uris now instead of on the first request
+      Url::parse(&api.uri)?;
+    }
+    Ok(Http {
+      instance: AppInstance {
+        module_version: Some(Http::get_module_version()?),
+        ..instance
+      },
+      headers: BTreeMap::new(),
+      timeout: DEFAULT_TIMEOUT,
+    })
+  }
+
+  /// Knows how to get the version number of the installed app (not the module version)
+  fn set_version(&self, _instance: AppInstance) -> Result<AppInstance> {
+    Err(FoundryError::NotImplemented).context(format!("{} can't look up its version yet", APP_NAME))
+  }
+
+  /// Figures out how to call the cli using the given container
+  fn set_cli(
+    &self,
+    _instance: AppInstance,
+    _container: Arc<dyn ContainerTrait>,
+  ) -> Result<AppInstance> {
+    Err(FoundryError::NotImplemented).context(format!("{} can't set its cli yet", APP_NAME))
+  }
+}
+
+#[async_trait]
+impl ContainerTrait for Http {
+  /// There is no general way to list what is behind a uri, so apps are given their ApiAccess directly
+  fn find(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
+    Err(FoundryError::NotFound).context(format!(
+      "{} cannot look up {}. Set the api on its AppInstance instead",
+      self.get_name(),
+      query.name
+    ))
+  }
+
+  /// List the known items in the app cache
+  fn cached_apps(&self) -> Result<Vec<AppInstance>> {
+    Err(FoundryError::NotImplemented).context("No App Cache for Http Yet")
+  }
+
+  /// Send the request to the app's API and return the body of the response
+  fn forward(&self, to: AppInstance, message: Message) -> Result<String> {
+    match message {
+      Message::Rest(request) => Ok(self.check(&to, &request)?.text()),
+      _ => Err(FoundryError::UnexpectedValue)
+        .context(format!("{} can only forward REST requests", self.get_name())),
+    }
+  }
+
+  /// The request blocks, so it is sent from its own thread
+  async fn forward_async(&self, to: AppInstance, message: Message) -> Result<String> {
+    let http = self.clone();
+    tokio::task::spawn_blocking(move || http.forward(to, message))
+      .await
+      .context("The thread sending the request panicked")?
+  }
+
+  /// Get the name/version of the container, usually for use in logging/errors.
+  fn get_name(&self) -> String {
+    self.get_name()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{BufRead, BufReader, Read, Write};
+  use std::net::TcpListener;
+  use std::sync::Mutex;
+
+  /// What the stub saw of each request
+  #[derive(Debug, Clone)]
+  struct Seen {
+    request_line: String,
+    headers: Vec<(String, String)>,
+    body: String,
+  }
+
+  impl Seen {
+    fn get_all(&self, name: &str) -> Vec<String> {
+      self
+        .headers
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.clone())
+        .collect()
+    }
+  }
+
+  /// A local http server that answers every request with the same raw response
+  struct Server {
+    address: String,
+    requests: Arc<Mutex<Vec<Seen>>>,
+  }
+
+  impl Server {
+    fn start(response: &[u8]) -> Server {
+      let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+      let address = listener.local_addr().unwrap().to_string();
+      let response = response.to_vec();
+      let requests = Arc::new(Mutex::new(vec![]));
+      let seen = requests.clone();
+      std::thread::spawn(move || {
+        for stream in listener.incoming() {
+          let mut stream = stream.unwrap();
+          let request = Server::read_request(&mut stream);
+          seen.lock().unwrap().push(request);
+          stream.write_all(&response).unwrap();
+        }
+      });
+      Server { address, requests }
+    }
+
+    fn read_request(stream: &mut std::net::TcpStream) -> Seen {
+      let mut reader = BufReader::new(stream);
+      let mut request_line = String::new();
+      reader.read_line(&mut request_line).unwrap();
+      let mut headers = vec![];
+      loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).unwrap();
+        match header.trim_end().split_once(':') {
+          Some((key, value)) => headers.push((key.to_string(), value.trim().to_string())),
+          None => break,
+        }
+      }
+      let seen = Seen {
+        request_line: request_line.trim_end().to_string(),
+        headers,
+        body: String::new(),
+      };
+      let length: usize = seen
+        .get_all("Content-Length")
+        .first()
+        .map_or(0, |x| x.parse().unwrap());
+      let mut body = vec![0; length];
+      reader.read_exact(&mut body).unwrap();
+      Seen {
+        body: String::from_utf8(body).unwrap(),
+        ..seen
+      }
+    }
+
+    fn app(&self, auth: Option<ApiAuth>) -> AppInstance {
+      let api = ApiAccess::new(format!("http://{}/api", self.address));
+      AppInstance {
+        api: Some(match auth {
+          Some(auth) => api.auth(auth),
+          None => api,
+        }),
+        ..AppInstance::new("rest app".to_string())
+      }
+    }
+
+    fn requests(&self) -> Vec<Seen> {
+      self.requests.lock().unwrap().clone()
+    }
+  }
+
+  fn http() -> Http {
+    Http::build(AppInstance::new("http".to_string()), None)
+      .unwrap()
+      .timeout(Duration::from_secs(10))
+  }
+
+  fn kind(err: &anyhow::Error) -> Option<FoundryError> {
+    err.downcast_ref::<FoundryError>().cloned()
+  }
+
+  #[test]
+  fn chunked_json_is_decoded() {
+    let server = Server::start(
+      b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n9\r\n{\"items\":\r\n6\r\n [1,2]\r\n1\r\n}\r\n0\r\n\r\n",
+    );
+    let value: serde_json::Value = http()
+      .call(&server.app(None), &RestRequest::get("/items".to_string()))
+      .unwrap();
+    assert_eq!(value["items"], serde_json::json!([1, 2]));
+    assert_eq!(server.requests()[0].request_line, "GET /api/items HTTP/1.1");
+  }
+
+  #[test]
+  fn bodies_are_sent_with_their_length() {
+    let server = Server::start(b"HTTP/1.1 201 Created\r\nContent-Length: 4\r\n\r\ndoneIGNORED");
+    let request = RestRequest::post("items".to_string()).body("{\"name\": \"caf\u{e9}\"}".to_string());
+    let body = http().forward(server.app(None), Message::Rest(request)).unwrap();
+    assert_eq!(body, "done");
+
+    let seen = &server.requests()[0];
+    assert_eq!(seen.request_line, "POST /api/items HTTP/1.1");
+    assert_eq!(seen.get_all("Content-Length"), vec!["17".to_string()]);
+    assert_eq!(seen.body, "{\"name\": \"caf\u{e9}\"}");
+  }
+
+  #[test]
+  fn each_kind_of_auth_is_sent() {
+    let server = Server::start(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n");
+    let auths = vec![
+      ApiAuth::Basic {
+        username: "Aladdin".to_string(),
+        password: "open sesame".to_string(),
+      },
+      ApiAuth::Bearer("token123".to_string()),
+      ApiAuth::Header {
+        name: "X-API-Key".to_string(),
+        value: "key456".to_string(),
+      },
+    ];
+    for auth in auths {
+      http()
+        .check(&server.app(Some(auth)), &RestRequest::get("/".to_string()))
+        .unwrap();
+    }
+
+    let seen = server.requests();
+    assert_eq!(
+      seen[0].get_all("Authorization"),
+      vec!["Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==".to_string()]
+    );
+    assert_eq!(seen[1].get_all("Authorization"), vec!["Bearer token123".to_string()]);
+    assert_eq!(seen[2].get_all("X-API-Key"), vec!["key456".to_string()]);
+    assert!(seen[2].get_all("Authorization").is_empty());
+  }
+
+  #[test]
+  fn statuses_map_to_error_kinds() {
+    let cases = vec![
+      (&b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n"[..], FoundryError::NotConfigured),
+      (b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n", FoundryError::NotConfigured),
+      (
+        b"HTTP/1.1 404 Not Found\r\nContent-Length: 25\r\n\r\n{\"message\": \"no such id\"}",
+        FoundryError::NotFound,
+      ),
+      (b"HTTP/1.1 500 Oops\r\nContent-Length: 0\r\n\r\n", FoundryError::RemoteError),
+    ];
+    for (response, expected) in cases {
+      let server = Server::start(response);
+      let err = http()
+        .check(&server.app(None), &RestRequest::get("/items/7".to_string()))
+        .unwrap_err();
+      assert_eq!(
+        std::mem::discriminant(&kind(&err).unwrap()),
+        std::mem::discriminant(&expected),
+        "{:#}",
+        err
+      );
+      if let FoundryError::NotFound = expected {
+        assert!(format!("{:#}", err).contains("no such id"), "{:#}", err);
+      }
+    }
+  }
+
+  #[test]
+  fn paths_and_reserved_headers_cant_smuggle_requests() {
+    let server = Server::start(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    let request = RestRequest::get("/x HTTP/1.1\r\nX-Injected: 1".to_string())
+      .header("Host".to_string(), "evil.example.com".to_string())
+      .header("content-length".to_string(), "999".to_string())
+      .header("X-Kept".to_string(), "yes".to_string());
+    http().check(&server.app(None), &request).unwrap();
+
+    let seen = &server.requests()[0];
+    assert_eq!(seen.request_line, "GET /api/x%20HTTP/1.1%0D%0AX-Injected:%201 HTTP/1.1");
+    assert!(seen.get_all("X-Injected").is_empty());
+    assert_eq!(seen.get_all("Host"), vec![server.address.clone()]);
+    assert_eq!(seen.get_all("Content-Length"), vec!["0".to_string()]);
+    assert_eq!(seen.get_all("X-Kept"), vec!["yes".to_string()]);
+  }
+
+  #[test]
+  fn unreachable_servers_are_errors() {
+    // Bind then drop to get a port nothing is listening on
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let app = AppInstance {
+      api: Some(ApiAccess::new(format!("http://127.0.0.1:{}", port))),
+      ..AppInstance::new("gone".to_string())
+    };
+    assert!(http().check(&app, &RestRequest::get("/".to_string())).is_err());
+  }
+}
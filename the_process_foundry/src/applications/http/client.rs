@@ -0,0 +1,360 @@
+//! A minimal HTTP/1.1 client
+//!
+//! Like the docker client, this sends one request per connection with "Connection: close" and reads until the
+//! server hangs up, which is all we need for simple REST calls. Only plain http is supported for now.
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use super::FoundryError;
+
+/// Headers we set ourselves from the url and body. Sending a second copy would let the request be read
+/// differently by each server along the way, so the caller's are dropped.
+const RESERVED_HEADERS: &[&str] = &["Host", "Content-Length", "Connection", "Transfer-Encoding"];
+
+/// A parsed response from the server
+#[derive(Debug, Clone)]
+pub struct Response {
+  pub status: u16,
+  pub headers: Vec<(String, String)>,
+  pub body: Vec<u8>,
+}
+
+impl Response {
+  pub fn is_success(&self) -> bool {
+    (200..300).contains(&self.status)
+  }
+
+  pub fn get_header(&self, name: &str) -> Option<String> {
+    self
+      .headers
+      .iter()
+      .find(|(key, _)| key.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.clone())
+  }
+
+  pub fn text(&self) -> String {
+    String::from_utf8_lossy(&self.body).to_string()
+  }
+
+  pub fn json(&self) -> Result<Value> {
+    self.parse()
+  }
+
+  /// Deserialize the json body into the type the caller expects
+  pub fn parse<T: DeserializeOwned>(&self) -> Result<T> {
+    serde_json::from_slice(&self.body).context(format!(
+      "The server returned json that doesn't match what was expected:\n{}",
+      self.text()
+    ))
+  }
+
+  /// The error message servers usually send back as {"message": "..."}, or the raw body if it isn't json
+  pub fn get_message(&self) -> String {
+    match self.json() {
+      Ok(json) => json["message"]
+        .as_str()
+        .map_or(json.to_string(), |x| x.to_string()),
+      Err(_) => self.text().trim_end().to_string(),
+    }
+  }
+}
+
+/// Where an http server is, split out of a uri like "http://localhost:8080/api/v1"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+  pub host: String,
+  pub port: u16,
+  /// Prefixed to the path of every request
+  pub base_path: String,
+}
+
+impl Url {
+  pub fn parse(uri: &str) -> Result<Url> {
+    let rest = match uri.split_once("://") {
+      Some(("http", rest)) => rest,
+      Some(("https", _)) => Err(FoundryError::ConfigurationError)
+        .context(format!("'{}' uses https, which isn't supported yet", uri))?,
+      Some((scheme, _)) => Err(FoundryError::ConfigurationError)
+        .context(format!("'{}' has the scheme '{}' instead of http", uri, scheme))?,
+      None => uri,
+    };
+    let (authority, base_path) = match rest.find('/') {
+      Some(idx) => (&rest[..idx], rest[idx..].trim_end_matches('/')),
+      None => (rest, ""),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+      // An IPv6 address without a port still has colons, but ends with "]"
+      Some((host, port)) if !port.ends_with(']') => (
+        host,
+        port
+          .parse::<u16>()
+          .context(format!("'{}' does not have a valid port number", uri))?,
+      ),
+      _ => (authority, 80),
+    };
+    if host.is_empty() {
+      Err(FoundryError::ConfigurationError).context(format!("'{}' does not have a host", uri))?;
+    }
+
+    Ok(Url {
+      host: host.to_string(),
+      port,
+      base_path: base_path.to_string(),
+    })
+  }
+}
+
+/// Send the request to the server and wait for the whole response
+pub fn send(
+  url: &Url,
+  method: &str,
+  path: &str,
+  headers: &[(String, String)],
+  body: &[u8],
+  timeout: Duration,
+) -> Result<Response> {
+  let full_path = encode_path(&format!("{}/{}", url.base_path, path.trim_start_matches('/')));
+  log::debug!("HTTP request: {} {}:{}{}", method, url.host, url.port, full_path);
+
+  let mut stream = TcpStream::connect((url.host.trim_start_matches('[').trim_end_matches(']'), url.port))
+    .context(format!("Could not connect to {}:{}", url.host, url.port))?;
+  stream.set_read_timeout(Some(timeout))?;
+  stream.set_write_timeout(Some(timeout))?;
+
+  let mut request = format!(
+    "{} {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\nContent-Length: {}\r\n",
+    method,
+    full_path,
+    url.host,
+    url.port,
+    body.len()
+  );
+  for (key, value) in headers {
+    if key.contains(['\r', '\n', ':']) || value.contains(['\r', '\n']) {
+      Err(FoundryError::ConfigurationError)
+        .context(format!("The header '{}' has characters that can't be sent", key))?;
+    }
+    if RESERVED_HEADERS.iter().any(|x| x.eq_ignore_ascii_case(key.trim())) {
+      log::warn!("Ignoring the '{}' header, since it is set from the request itself", key);
+      continue;
+    }
+    request.push_str(&format!("{}: {}\r\n", key, value));
+  }
+  request.push_str("\r\n");
+
+  stream.write_all(request.as_bytes())?;
+  stream.write_all(body)?;
+
+  let mut raw = vec![];
+  stream.read_to_end(&mut raw).context(format!(
+    "Failed reading the response to {} {} from {}:{}",
+    method, full_path, url.host, url.port
+  ))?;
+  parse_response(&raw).context(format!("Bad response to {} {}", method, full_path))
+}
+
+/// Percent-encode whatever would break the request line: whitespace, control characters and non-ASCII.
+/// Everything else is left as is, so existing escapes and the query string still work.
+pub fn encode_path(path: &str) -> String {
+  let mut encoded = String::with_capacity(path.len());
+  for byte in path.bytes() {
+    match byte {
+      0x21..=0x7e => encoded.push(byte as char),
+      _ => encoded.push_str(&format!("%{:02X}", byte)),
+    }
+  }
+  encoded
+}
+
+/// Percent-encode everything but the unreserved characters, for putting a value (eg: a name) into one segment
+/// of a path. Unlike encode_path, "/", "?", "#" and "%" are escaped too.
+pub fn encode_segment(segment: &str) -> String {
+  let mut encoded = String::with_capacity(segment.len());
+  for byte in segment.bytes() {
+    match byte {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+      _ => encoded.push_str(&format!("%{:02X}", byte)),
+    }
+  }
+  encoded
+}
+
+/// Split the raw bytes into the status, headers and (decoded) body
+pub fn parse_response(raw: &[u8]) -> Result<Response> {
+  let split = match raw.windows(4).position(|x| x == b"\r\n\r\n") {
+    Some(idx) => idx,
+    None => Err(FoundryError::UnexpectedValue)
+      .context("The server closed the connection before sending the headers")?,
+  };
+  let head = String::from_utf8_lossy(&raw[..split]).to_string();
+  let rest = &raw[split + 4..];
+
+  let mut lines = head.lines();
+  let status_line = lines.next().unwrap_or_default();
+  let status = match status_line.split_whitespace().nth(1).map(|x| x.parse::<u16>()) {
+    Some(Ok(x)) => x,
+    _ => Err(FoundryError::UnexpectedValue)
+      .context(format!("'{}' is not a valid http status line", status_line))?,
+  };
+  let headers: Vec<(String, String)> = lines
+    .filter_map(|line| line.split_once(':'))
+    .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+    .collect();
+
+  let mut response = Response {
+    status,
+    headers,
+    body: vec![],
+  };
+  response.body = match (
+    response.get_header("Transfer-Encoding"),
+    response.get_header("Content-Length"),
+  ) {
+    (Some(encoding), _) if encoding.eq_ignore_ascii_case("chunked") => dechunk(rest)?,
+    (_, Some(length)) => {
+      let length: usize = length.parse()?;
+      rest[..length.min(rest.len())].to_vec()
+    }
+    _ => rest.to_vec(),
+  };
+  Ok(response)
+}
+
+/// Undo chunked transfer encoding: "<hex size>\r\n<data>\r\n" repeated until a zero sized chunk
+fn dechunk(mut raw: &[u8]) -> Result<Vec<u8>> {
+  let mut body = vec![];
+  loop {
+    let line_end = match raw.windows(2).position(|x| x == b"\r\n") {
+      Some(idx) => idx,
+      None => Err(FoundryError::UnexpectedValue).context("Truncated chunk in the http response")?,
+    };
+    let size_str = String::from_utf8_lossy(&raw[..line_end]).to_string();
+    // Chunk extensions after a ";" are allowed but we don't use them
+    let size_hex = size_str.split(';').next().unwrap_or_default().trim();
+    let size = usize::from_str_radix(size_hex, 16).context(format!(
+      "'{}' is not a valid chunk size in the http response",
+      size_str
+    ))?;
+    if size == 0 {
+      return Ok(body);
+    }
+    let start = line_end + 2;
+    if raw.len() < start + size {
+      Err(FoundryError::UnexpectedValue).context("Truncated chunk in the http response")?;
+    }
+    body.extend_from_slice(&raw[start..start + size]);
+    raw = raw.get(start + size + 2..).unwrap_or_default();
+  }
+}
+
+/// Standard base64 with padding, for basic auth
+pub fn base64(input: &[u8]) -> String {
+  const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+  let mut output = String::new();
+  for chunk in input.chunks(3) {
+    let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+    let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+    for idx in 0..4 {
+      match idx <= chunk.len() {
+        true => output.push(ALPHABET[(n >> (18 - 6 * idx) & 0x3f) as usize] as char),
+        false => output.push('='),
+      }
+    }
+  }
+  output
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn paths_cant_break_the_request_line() {
+    assert_eq!(encode_path("/api/v1/items?name=a%20b"), "/api/v1/items?name=a%20b");
+    assert_eq!(encode_path("/a b"), "/a%20b");
+    assert_eq!(
+      encode_path("/x HTTP/1.1\r\nHost: evil\r\n\r\n"),
+      "/x%20HTTP/1.1%0D%0AHost:%20evil%0D%0A%0D%0A"
+    );
+    assert_eq!(encode_path("/tab\there\u{7f}"), "/tab%09here%7F");
+    assert_eq!(encode_path("/caf\u{e9}"), "/caf%C3%A9");
+  }
+
+  #[test]
+  fn segments_escape_path_separators() {
+    assert_eq!(encode_segment("db_1.web-2~x"), "db_1.web-2~x");
+    assert_eq!(encode_segment("../images/x?all=1#y"), "..%2Fimages%2Fx%3Fall%3D1%23y");
+    assert_eq!(encode_segment("a%20b c"), "a%2520b%20c");
+    // Already escaped segments pass through encode_path untouched
+    let path = format!("/containers/{}/json", encode_segment("a/b c"));
+    assert_eq!(encode_path(&path), "/containers/a%2Fb%20c/json");
+  }
+
+  #[test]
+  fn chunked_bodies_are_joined() {
+    let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4;ext=1\r\nWiki\r\n6\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\n\r\n";
+    let response = parse_response(raw).unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "Wikipedia in \r\n\r\nchunks.");
+  }
+
+  #[test]
+  fn truncated_chunks_are_an_error() {
+    let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n10\r\nshort";
+    let err = parse_response(raw).unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<FoundryError>(),
+      Some(FoundryError::UnexpectedValue)
+    ));
+  }
+
+  #[test]
+  fn content_length_limits_the_body() {
+    let raw = b"HTTP/1.1 404 Not Found\r\ncontent-length: 2\r\n\r\n{}trailing";
+    let response = parse_response(raw).unwrap();
+    assert_eq!(response.status, 404);
+    assert_eq!(response.body, b"{}");
+  }
+
+  #[test]
+  fn missing_headers_are_an_error() {
+    assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+    assert!(parse_response(b"garbage\r\n\r\n").is_err());
+  }
+
+  #[test]
+  fn base64_matches_the_rfc_vectors() {
+    let vectors = [
+      ("", ""),
+      ("f", "Zg=="),
+      ("fo", "Zm8="),
+      ("foo", "Zm9v"),
+      ("foob", "Zm9vYg=="),
+      ("fooba", "Zm9vYmE="),
+      ("foobar", "Zm9vYmFy"),
+    ];
+    for (input, output) in vectors {
+      assert_eq!(base64(input.as_bytes()), output);
+    }
+  }
+
+  #[test]
+  fn urls_are_split() {
+    assert_eq!(
+      Url::parse("http://localhost:8080/api/v1/").unwrap(),
+      Url {
+        host: "localhost".to_string(),
+        port: 8080,
+        base_path: "/api/v1".to_string(),
+      }
+    );
+    assert_eq!(Url::parse("[::1]").unwrap().port, 80);
+    assert!(Url::parse("https://example.com").is_err());
+    assert!(Url::parse("http://:80").is_err());
+  }
+}
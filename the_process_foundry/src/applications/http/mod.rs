@@ -0,0 +1,10 @@
+//! Call REST APIs over http
+//!
+//! Many of the services we wire together only have a REST API, so this delivers Message::Rest to whatever
+//! ApiAccess an app instance has, adding its credentials.
+
+pub mod application;
+pub mod client;
+
+use super::*;
+pub use application::Http;
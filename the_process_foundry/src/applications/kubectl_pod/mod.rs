@@ -0,0 +1,609 @@
+//! A Kubernetes pod, reached with kubectl exec
+//!
+//! The pod is either named directly or picked with a label selector, in which case the first running pod that
+//! matches is used. Pods get replaced, so the selector is looked up again for every command unless the pod is
+//! pinned with select_pod. kubectl itself runs in the parent container (the local machine if there isn't one)
+//! and uses its normal kubeconfig unless a context is given.
+//!
+//! kubectl exec runs the program directly, so a shell is only used for the things it can't do itself, such as
+//! changing directory. The pod needs sh for that and for finding apps.
+
+const APP_NAME: &str = "Kubectl Pod";
+const MODULE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde_derive::{Deserialize, Serialize};
+
+use super::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KubectlPod {
+  /// The instance_id is the pod name, if it is known, and the cli is the kubectl client
+  instance: AppInstance,
+
+  /// The namespace the pod is in, otherwise the one set in the kubeconfig context
+  namespace: Option<String>,
+
+  /// The kubeconfig context (cluster and user) to use instead of the current one
+  context: Option<String>,
+
+  /// A label selector to find the pod with when it isn't named (eg: "app=postgres,tier=db")
+  selector: Option<String>,
+
+  /// The container in the pod to run commands in, otherwise the pod's default container
+  container: Option<String>,
+
+  /// How to run commands as another user in the pod. Defaults to su, since images rarely include sudo.
+  escalation: Option<Escalation>,
+
+  /// Where kubectl is run. If this is empty, it is run on the local machine.
+  #[serde(skip)]
+  parent: Option<Arc<dyn ContainerTrait>>,
+}
+
+impl KubectlPod {
+  fn get_module_version() -> Result<semver::Version> {
+    semver::Version::parse(MODULE_VERSION).context(format!(
+      "{} has an invalid version number '{}' Cargo.toml",
+      APP_NAME, MODULE_VERSION
+    ))
+  }
+
+  fn get_name(&self) -> String {
+    let pod = match (&self.instance.instance_id, &self.selector) {
+      (Some(pod), _) => pod.clone(),
+      (None, Some(selector)) => format!("-l {}", selector),
+      (None, None) => "no pod set".to_string(),
+    };
+    match &self.namespace {
+      Some(namespace) => format!("{} ({}/{})", APP_NAME, namespace, pod),
+      None => format!("{} ({})", APP_NAME, pod),
+    }
+  }
+
+  pub fn namespace(&self, namespace: String) -> KubectlPod {
+    KubectlPod {
+      namespace: Some(namespace),
+      ..self.clone()
+    }
+  }
+
+  pub fn context(&self, context: String) -> KubectlPod {
+    KubectlPod {
+      context: Some(context),
+      ..self.clone()
+    }
+  }
+
+  pub fn selector(&self, selector: String) -> KubectlPod {
+    KubectlPod {
+      selector: Some(selector),
+      ..self.clone()
+    }
+  }
+
+  pub fn container(&self, container: String) -> KubectlPod {
+    KubectlPod {
+      container: Some(container),
+      ..self.clone()
+    }
+  }
+
+  /// Choose how commands with a run_as are run as the other user in the pod
+  pub fn set_escalation(&self, escalation: Escalation) -> KubectlPod {
+    KubectlPod {
+      escalation: Some(escalation),
+      ..self.clone()
+    }
+  }
+
+  /// Look up the pod with the selector now and keep using it, rather than looking it up for every command
+  pub fn select_pod(&self) -> Result<KubectlPod> {
+    block_on(self.select_pod_async())
+  }
+
+  pub async fn select_pod_async(&self) -> Result<KubectlPod> {
+    let pod = self.get_pod_async().await?;
+    Ok(KubectlPod {
+      instance: AppInstance {
+        instance_id: Some(pod),
+        ..self.instance.clone()
+      },
+      ..self.clone()
+    })
+  }
+
+  /// The pod to run commands in, finding the first running one that matches the selector if it isn't named
+  pub fn get_pod(&self) -> Result<String> {
+    block_on(self.get_pod_async())
+  }
+
+  pub async fn get_pod_async(&self) -> Result<String> {
+    if let Some(pod) = &self.instance.instance_id {
+      return Ok(pod.clone());
+    }
+    let selector = match &self.selector {
+      Some(x) => x.clone(),
+      None => Err(FoundryError::NotConfigured).context(
+        "KubectlPod needs either a pod name as the instance_id or a selector to find one with",
+      )?,
+    };
+
+    let mut args = self.global_args();
+    args.extend([
+      "get".to_string(),
+      "pods".to_string(),
+      "--selector".to_string(),
+      selector.clone(),
+      "--field-selector".to_string(),
+      "status.phase=Running".to_string(),
+      "--output".to_string(),
+      "jsonpath={.items[*].metadata.name}".to_string(),
+    ]);
+    let pods = self.execute(Cmd::argv(self.get_executable(), args)).await?;
+    let mut names = pods.split_whitespace();
+    match names.next() {
+      Some(pod) => {
+        if names.next().is_some() {
+          log::debug!("Several pods match '{}', using {}", selector, pod);
+        }
+        Ok(pod.to_string())
+      }
+      None => Err(FoundryError::NotFound).context(format!(
+        "No running pods match the selector '{}'{}",
+        selector,
+        self
+          .namespace
+          .as_ref()
+          .map_or(String::new(), |namespace| format!(" in namespace {}", namespace))
+      )),
+    }
+  }
+
+  /// The kubectl executable, using the path we found if we have one
+  fn get_executable(&self) -> String {
+    self
+      .instance
+      .get_command_path()
+      .unwrap_or_else(|_| "kubectl".to_string())
+  }
+
+  /// Options that go before the kubectl subcommand
+  fn global_args(&self) -> Vec<String> {
+    let mut args = vec![];
+    if let Some(context) = &self.context {
+      args.extend(["--context".to_string(), context.clone()]);
+    }
+    if let Some(namespace) = &self.namespace {
+      args.extend(["--namespace".to_string(), namespace.clone()]);
+    }
+    args
+  }
+
+  /// The local kubectl command that runs the command in the pod
+  pub fn to_cmd(&self, cmd: &Cmd) -> Result<Cmd> {
+    Ok(self.exec_cmd(self.get_pod()?, cmd))
+  }
+
+  /// The kubectl exec for the command once we know the pod
+  fn exec_cmd(&self, pod: String, cmd: &Cmd) -> Cmd {
+    let words = match &cmd.run_as {
+      Some(user) => self
+        .escalation
+        .unwrap_or(Escalation::Su)
+        .switch_user(user, cmd)
+        .words(),
+      None => cmd.words(),
+    };
+    // exec has no option for the working directory, so that needs a shell
+    let words = match &cmd.workdir {
+      Some(workdir) => vec![
+        "sh".to_string(),
+        "-c".to_string(),
+        format!("cd {} && {}", ShellType::Sh.quote(workdir), ShellType::Sh.join(&words)),
+      ],
+      None => words,
+    };
+
+    let mut args = self.global_args();
+    args.extend(["exec".to_string(), "-i".to_string(), pod]);
+    if let Some(container) = &self.container {
+      args.extend(["-c".to_string(), container.clone()]);
+    }
+    args.push("--".to_string());
+    args.extend(words);
+
+    Cmd::argv(self.get_executable(), args).inherit_limits(cmd)
+  }
+
+  /// Run the command in the pod and return stdout
+  pub fn run(&self, cmd: &Cmd) -> Result<String> {
+    block_on(self.run_async(cmd))
+  }
+
+  /// Stopping kubectl doesn't stop the command in the pod, so one that hits its limits is killed there too
+  pub async fn run_async(&self, cmd: &Cmd) -> Result<String> {
+    let pod = self.get_pod_async().await?;
+    cmd
+      .run_tagged(|cmd| self.execute(self.exec_cmd(pod.clone(), &cmd)))
+      .await
+  }
+
+  /// Run a kubectl command in the parent, or locally if there isn't one
+  async fn execute(&self, kubectl: Cmd) -> Result<String> {
+    if let Some(parent) = &self.parent {
+      return parent
+        .forward_async(self.instance.clone(), Message::Command(kubectl))
+        .await;
+    }
+
+    let output = kubectl.output().await?;
+    match output.status.success() {
+      true => Ok(String::from_utf8(output.stdout)?.trim_end().to_string()),
+      false => Err(FoundryError::RemoteError).context(format!(
+        "'{}' failed with exit code {}:\n{}",
+        kubectl.to_script(ShellType::Sh),
+        output
+          .status
+          .code()
+          .map_or("unknown".to_string(), |x| x.to_string()),
+        String::from_utf8_lossy(&output.stderr).trim_end()
+      )),
+    }
+  }
+}
+
+impl AppTrait for KubectlPod {
+  fn get_name(&self) -> String {
+    self.get_name()
+  }
+
+  /// The instance_id is the pod name, which can be left empty to use a selector instead. If the instance
+  /// doesn't say where kubectl is, it is looked up in the parent.
+  fn build(instance: AppInstance, parent: Option<Arc<dyn ContainerTrait>>) -> Result<KubectlPod> {
+    let instance = match (&instance.cli, &parent) {
+      (None, Some(container)) => AppInstance {
+        instance_id: instance.instance_id.clone(),
+        ..container
+          .find_one(AppQuery::new("kubectl".to_string()))
+          .context(format!("Could not find kubectl in {}", container.get_name()))?
+      },
+      _ => instance,
+    };
+
+    Ok(KubectlPod {
+      instance: AppInstance {
+        module_version: Some(KubectlPod::get_module_version()?),
+        ..instance
+      },
+      namespace: None,
+      context: None,
+      selector: None,
+      container: None,
+      escalation: None,
+      parent,
+    })
+  }
+
+  /// Knows how to get the version number of the installed app (not the module version)
+  fn set_version(&self, _instance: AppInstance) -> Result<AppInstance> {
+    Err(FoundryError::NotImplemented).context(format!("{} can't look up its version yet", APP_NAME))
+  }
+
+  /// Figures out how to call the cli using the given container
+  fn set_cli(
+    &self,
+    _instance: AppInstance,
+    _container: Arc<dyn ContainerTrait>,
+  ) -> Result<AppInstance> {
+    Err(FoundryError::NotImplemented).context(format!("{} can't set its cli yet", APP_NAME))
+  }
+}
+
+#[async_trait]
+impl ContainerTrait for KubectlPod {
+  fn find(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
+    block_on(self.find_async(query))
+  }
+
+  /// Look up the executable in the pod by name, then each of the aliases, returning the first one found
+  /// TODO: Check works_with once apps know how to report their own version
+  async fn find_async(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
+    // Look the pod up once, rather than for every name
+    let pod = self.select_pod_async().await?;
+    let names = std::iter::once(query.name.clone()).chain(query.aliases.clone().unwrap_or_default());
+
+    let mut errors = vec![];
+    for name in names {
+      match pod.run_async(&ShellType::Sh.find_app(&name)).await {
+        Ok(path) if !path.is_empty() => {
+          return Ok(vec![AppInstance::new(query.name.clone())
+            .set_command_path(Some(Arc::new(self.clone())), path)?])
+        }
+        Ok(_) => errors.push(format!("{}: not found", name)),
+        Err(err) => errors.push(format!("{}: {:#}", name, err)),
+      }
+    }
+    Err(FoundryError::NotFound).context(format!(
+      "{} could not find {}:\n{}",
+      pod.get_name(),
+      query.name,
+      errors.join("\n")
+    ))
+  }
+
+  /// List the known items in the app cache
+  fn cached_apps(&self) -> Result<Vec<AppInstance>> {
+    Err(FoundryError::NotImplemented).context("No App Cache for KubectlPod Yet")
+  }
+
+  fn forward(&self, to: AppInstance, message: Message) -> Result<String> {
+    block_on(self.forward_async(to, message))
+  }
+
+  /// Run the command in the pod
+  async fn forward_async(&self, _to: AppInstance, message: Message) -> Result<String> {
+    match message {
+      Message::Command(cmd) => self.run_async(&cmd).await,
+      _ => Err(FoundryError::UnexpectedValue)
+        .context(format!("{} can only forward commands", self.get_name())),
+    }
+  }
+
+  /// The kubectl command, started however the parent starts things
+  fn local_command(&self, cmd: Cmd) -> Result<Cmd> {
+    let kubectl = self.to_cmd(&cmd)?;
+    match &self.parent {
+      Some(parent) => parent.local_command(kubectl),
+      None => Ok(kubectl),
+    }
+  }
+
+  /// Get the name/version of the container, usually for use in logging/errors.
+  fn get_name(&self) -> String {
+    self.get_name()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::BTreeMap;
+
+  /// Stands in for kubectl: it appends its arguments to "calls" next to itself, lists two pods for
+  /// "get pods", and prints a path for anything run with exec.
+  const FAKE_KUBECTL: &str = r#"#!/bin/sh
+dir=$(dirname "$0")
+printf '%s\0' "$@" >> "$dir/calls"
+printf '\036' >> "$dir/calls"
+for arg in "$@"; do
+  case "$arg" in
+    get) echo "web-1 web-2"; exit 0 ;;
+    exec) echo /usr/bin/found; exit 0 ;;
+  esac
+done
+exit 1
+"#;
+
+  struct Fake {
+    dir: std::path::PathBuf,
+  }
+
+  impl Fake {
+    fn new(name: &str) -> Fake {
+      let dir = std::env::temp_dir().join(format!("foundry-kubectl-{}-{}", std::process::id(), name));
+      std::fs::create_dir_all(&dir).unwrap();
+      // Written by a child process, so no other test's fork can inherit an open handle to it and make
+      // exec fail with "Text file busy"
+      let status = std::process::Command::new("sh")
+        .args(["-c", "printf '%s' \"$2\" > \"$1\" && chmod 755 \"$1\""])
+        .args(["sh", dir.join("kubectl").to_str().unwrap(), FAKE_KUBECTL])
+        .status()
+        .unwrap();
+      assert!(status.success());
+      Fake { dir }
+    }
+
+    fn pod(&self, name: Option<&str>) -> KubectlPod {
+      let instance = AppInstance::new("kubectl".to_string())
+        .set_command_path(None, self.dir.join("kubectl").to_str().unwrap().to_string())
+        .unwrap();
+      KubectlPod::build(
+        AppInstance {
+          instance_id: name.map(|x| x.to_string()),
+          ..instance
+        },
+        None,
+      )
+      .unwrap()
+    }
+
+    /// The argv of each time kubectl was run
+    fn calls(&self) -> Vec<Vec<String>> {
+      let calls = std::fs::read_to_string(self.dir.join("calls")).unwrap_or_default();
+      calls
+        .split('\u{1e}')
+        .filter(|call| !call.is_empty())
+        .map(|call| {
+          let mut args: Vec<String> = call.split('\0').map(|x| x.to_string()).collect();
+          args.pop();
+          args
+        })
+        .collect()
+    }
+  }
+
+  impl Drop for Fake {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.dir);
+    }
+  }
+
+  fn strings(words: &[&str]) -> Vec<String> {
+    words.iter().map(|x| x.to_string()).collect()
+  }
+
+  #[test]
+  fn exec_args_follow_the_global_options() {
+    let fake = Fake::new("exec");
+    let pod = fake
+      .pod(Some("db-0"))
+      .context("staging".to_string())
+      .namespace("data".to_string())
+      .container("postgres".to_string());
+
+    let output = pod
+      .run(&Cmd::argv("psql".to_string(), strings(&["-c", "select 'a b'"])))
+      .unwrap();
+    assert_eq!(output, "/usr/bin/found");
+    assert_eq!(
+      fake.calls(),
+      vec![strings(&[
+        "--context",
+        "staging",
+        "--namespace",
+        "data",
+        "exec",
+        "-i",
+        "db-0",
+        "-c",
+        "postgres",
+        "--",
+        "psql",
+        "-c",
+        "select 'a b'",
+      ])]
+    );
+  }
+
+  #[test]
+  fn the_selector_only_looks_at_running_pods() {
+    let fake = Fake::new("selector");
+    let pod = fake
+      .pod(None)
+      .namespace("web".to_string())
+      .selector("app=web,tier=front".to_string());
+
+    assert_eq!(pod.get_pod().unwrap(), "web-1");
+    pod.run(&Cmd::argv("true".to_string(), vec![])).unwrap();
+
+    let calls = fake.calls();
+    let lookup = strings(&[
+      "--namespace",
+      "web",
+      "get",
+      "pods",
+      "--selector",
+      "app=web,tier=front",
+      "--field-selector",
+      "status.phase=Running",
+      "--output",
+      "jsonpath={.items[*].metadata.name}",
+    ]);
+    assert_eq!(calls[0], lookup);
+    // The selector is looked up again for the command, since pods come and go
+    assert_eq!(calls[1], lookup);
+    assert_eq!(calls[2], strings(&["--namespace", "web", "exec", "-i", "web-1", "--", "true"]));
+  }
+
+  #[test]
+  fn a_pod_or_selector_is_required() {
+    let fake = Fake::new("unset");
+    let err = fake.pod(None).get_pod().unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<FoundryError>(),
+      Some(FoundryError::NotConfigured)
+    ));
+    assert!(fake.calls().is_empty());
+  }
+
+  #[test]
+  fn workdir_is_changed_in_a_shell() {
+    let fake = Fake::new("workdir");
+    let cmd = Cmd {
+      workdir: Some("/srv/my app".to_string()),
+      ..Cmd::argv("ls".to_string(), strings(&["-la", "it's"]))
+    };
+    fake.pod(Some("web-1")).run(&cmd).unwrap();
+
+    assert_eq!(
+      fake.calls(),
+      vec![strings(&[
+        "exec",
+        "-i",
+        "web-1",
+        "--",
+        "sh",
+        "-c",
+        "cd '/srv/my app' && ls -la 'it'\\''s'",
+      ])]
+    );
+  }
+
+  #[test]
+  fn run_as_uses_su_by_default() {
+    let fake = Fake::new("su");
+    let mut env = BTreeMap::new();
+    env.insert("PGPASSWORD".to_string(), "$ecret".to_string());
+    let cmd = Cmd {
+      run_as: Some("postgres".to_string()),
+      env,
+      ..Cmd::argv("psql".to_string(), strings(&["-l"]))
+    };
+    fake.pod(Some("db-0")).run(&cmd).unwrap();
+    fake
+      .pod(Some("db-0"))
+      .set_escalation(Escalation::Sudo)
+      .run(&cmd)
+      .unwrap();
+
+    let calls = fake.calls();
+    assert_eq!(
+      calls[0],
+      strings(&[
+        "exec",
+        "-i",
+        "db-0",
+        "--",
+        "su",
+        "postgres",
+        "-s",
+        "/bin/sh",
+        "-c",
+        "env 'PGPASSWORD=$ecret' psql -l",
+      ])
+    );
+    assert_eq!(
+      calls[1],
+      strings(&[
+        "exec",
+        "-i",
+        "db-0",
+        "--",
+        "sudo",
+        "-n",
+        "-u",
+        "postgres",
+        "--",
+        "env",
+        "PGPASSWORD=$ecret",
+        "psql",
+        "-l",
+      ])
+    );
+  }
+
+  #[test]
+  fn find_looks_the_pod_up_once() {
+    let fake = Fake::new("find");
+    let pod = fake.pod(None).selector("app=db".to_string());
+    let found = pod.find(AppQuery::new("psql".to_string())).unwrap();
+    assert_eq!(found[0].get_command_path().unwrap(), "/usr/bin/found");
+
+    let calls = fake.calls();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[0][..2], strings(&["get", "pods"])[..]);
+    assert_eq!(calls[1][..5], strings(&["exec", "-i", "web-1", "--", "sh"])[..]);
+  }
+}
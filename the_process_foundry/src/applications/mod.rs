@@ -2,20 +2,32 @@
 
 use super::FoundryError;
 use super::{ActionTrait, AppTrait, ContainerTrait, LocalTrait};
-use super::{AppInstance, AppQuery, Cmd, Message, Shell};
+use super::{get_current_uid, ApiAccess, ApiAuth, AppInstance, AppQuery, Cmd, Escalation, Message, RestRequest};
+use super::{block_on, CancellationToken, RpcRequest};
+use super::{Shell, ShellType};
 
 pub mod bash;
-// pub mod docker;
+pub mod docker;
 pub mod docker_compose;
 pub mod docker_container;
+pub mod http;
+pub mod kubectl_pod;
 pub mod pg_basebackup;
 pub mod postgres;
+pub mod rpc;
+pub mod shell;
+pub mod ssh;
 
-pub use std::rc::Rc;
+pub use std::sync::Arc;
 
 pub use bash::Bash;
-// pub use docker::Docker;
+pub use docker::Docker;
 pub use docker_compose::DockerCompose;
 pub use docker_container::DockerContainer;
+pub use http::Http;
+pub use kubectl_pod::KubectlPod;
 pub use pg_basebackup::{Options, PgBaseBackup};
 pub use postgres::Postgres;
+pub use rpc::{RemoteFoundry, RpcServer};
+pub use shell::PosixShell;
+pub use ssh::Ssh;
@@ -4,29 +4,34 @@
 //! https://www.postgresql.org/docs/12/app-pgbasebackup.html
 
 const APP_NAME: &str = "pg_basebackup";
-const MODULE_VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
 use super::*;
 use anyhow::{Context, Result};
 use serde_derive::{Deserialize, Serialize};
-use std::rc::Rc;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct PgBaseBackup {
   pub instance: AppInstance,
-  pub parent: Rc<dyn ContainerTrait>,
+  pub parent: Arc<dyn ContainerTrait>,
 }
 
 impl PgBaseBackup {
-  // TODO: Convert this to async. Spawn the run function off so it can throw events.
   pub fn run(&self, opts: Options) -> Result<String> {
+    block_on(self.run_async(opts))
+  }
+
+  /// Take the backup without blocking, so it can run alongside other work. Dropping the future stops it.
+  // TODO: Spawn the run function off so it can throw events.
+  pub async fn run_async(&self, opts: Options) -> Result<String> {
     log::debug!("Running PgBaseBackup - saving to {:#?}", opts.pgdata);
 
     let msg = opts.to_message(Some(self.instance.clone()))?;
     log::debug!("msg:\n{:#?}", msg);
     self
       .parent
-      .forward(self.instance.clone(), msg.get(0).unwrap().clone())
+      .forward_async(self.instance.clone(), msg[0].clone())
+      .await
   }
 }
 
@@ -38,8 +43,8 @@ impl AppTrait for PgBaseBackup {
     }
   }
 
-  fn build(instance: AppInstance, parent: Option<Rc<dyn ContainerTrait>>) -> Result<PgBaseBackup> {
-    let container: Rc<dyn ContainerTrait> = match parent {
+  fn build(instance: AppInstance, parent: Option<Arc<dyn ContainerTrait>>) -> Result<PgBaseBackup> {
+    let container: Arc<dyn ContainerTrait> = match parent {
       Some(x) => x,
       None => {
         let shell = Shell::get_local_shell()?;
@@ -60,7 +65,7 @@ impl AppTrait for PgBaseBackup {
   fn set_cli(
     &self,
     _instance: AppInstance,
-    _container: Rc<dyn ContainerTrait>,
+    _container: Arc<dyn ContainerTrait>,
   ) -> Result<AppInstance> {
     unimplemented!()
   }
@@ -172,31 +177,61 @@ pub struct Options {
 
   ///  -W, --password         force password prompt (should happen automatically)
   password: bool,
+
+  /// The OS user to run pg_basebackup as. Peer authentication usually means this has to be the database
+  /// superuser, which is "postgres" on most installs.
+  run_as: Option<String>,
+
+  /// Kill the backup if it takes longer than this, so a hung one can't hold everything else up
+  #[serde(default)]
+  timeout: Option<std::time::Duration>,
+
+  /// Kill the backup when this is cancelled
+  #[serde(skip)]
+  cancel: Option<CancellationToken>,
 }
 
 impl Options {
   pub fn new(path: String) -> Options {
     Options {
       pgdata: Some(path),
+      run_as: Some("postgres".to_string()),
       ..Default::default()
     }
   }
+
+  /// Change the user to run as, or None to run as whoever the container runs commands as
+  pub fn run_as(&self, user: Option<String>) -> Options {
+    Options {
+      run_as: user,
+      ..self.clone()
+    }
+  }
+
+  pub fn timeout(&self, timeout: std::time::Duration) -> Options {
+    Options {
+      timeout: Some(timeout),
+      ..self.clone()
+    }
+  }
+
+  pub fn cancel_on(&self, token: CancellationToken) -> Options {
+    Options {
+      cancel: Some(token),
+      ..self.clone()
+    }
+  }
 }
 
 /// The encoding of the output file
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub enum Compression {
+  #[default]
   None,
   Tar,
   Gzip(u8),
 }
 
-impl Default for Compression {
-  fn default() -> Self {
-    Compression::None
-  }
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Rate {
   /// Kilobytes per second
@@ -238,9 +273,12 @@ impl ActionTrait for Options {
     };
 
     let cmd = Message::Command(Cmd {
-      run_as: Some("postgres".to_string()),
+      run_as: self.run_as.clone(),
       command: target.unwrap().get_command_path()?.clone(),
       args,
+      timeout: self.timeout,
+      cancel: self.cancel.clone(),
+      ..Default::default()
     });
 
     Ok(vec![cmd])
@@ -3,7 +3,7 @@
 //! THINK: What is the scope of this module. Does it include managing the internal data?
 
 const APP_NAME: &str = "Postgres";
-const MODULE_VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const MODULE_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 use anyhow::{Context, Result};
 use serde_derive::{Deserialize, Serialize};
@@ -19,12 +19,10 @@ pub struct Postgres {
 
 impl Postgres {
   fn get_module_version() -> Result<semver::Version> {
-    Ok({
-      semver::Version::parse(MODULE_VERSION).context(format!(
-        "{} has an invalid version number '{}' Cargo.toml",
-        APP_NAME, MODULE_VERSION
-      ))
-    }?)
+    semver::Version::parse(MODULE_VERSION).context(format!(
+      "{} has an invalid version number '{}' Cargo.toml",
+      APP_NAME, MODULE_VERSION
+    ))
   }
 
   fn get_name(&self) -> String {
@@ -40,7 +38,7 @@ impl AppTrait for Postgres {
     self.get_name()
   }
 
-  fn build(instance: AppInstance, _parent: Option<Rc<dyn ContainerTrait>>) -> Result<Postgres> {
+  fn build(instance: AppInstance, _parent: Option<Arc<dyn ContainerTrait>>) -> Result<Postgres> {
     Ok(Postgres {
       instance: AppInstance {
         module_version: Some(Postgres::get_module_version()?),
@@ -57,7 +55,7 @@ impl AppTrait for Postgres {
   fn set_cli(
     &self,
     _instance: AppInstance,
-    _container: Rc<dyn ContainerTrait>,
+    _container: Arc<dyn ContainerTrait>,
   ) -> Result<AppInstance> {
     unimplemented!()
   }
@@ -0,0 +1,265 @@
+//! Another foundry node, used as a container
+
+const APP_NAME: &str = "Remote Foundry";
+
+/// How long to wait for the other node to accept the connection
+const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde_derive::{Deserialize, Serialize};
+use std::io::BufReader;
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{block_on, get_module_version, is_compatible, read_message, write_message, PROTOCOL_VERSION};
+use super::{AppInstance, AppQuery, AppTrait, Cmd, ContainerTrait, FoundryError, Message};
+use super::{RpcRequest, RpcResponse};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteFoundry {
+  /// The api uri is where the node listens (eg: "tcp://db-host:7070"), and its auth is the node's shared
+  /// secret. The version is the node's foundry version.
+  instance: AppInstance,
+
+  /// The name of the container the node is serving, from the handshake
+  remote_name: String,
+
+  /// How long to wait for a reply. Forwarded commands can run for a long time (eg: backups), so there is no
+  /// limit unless one is set.
+  timeout: Option<Duration>,
+}
+
+impl RemoteFoundry {
+  fn get_name(&self) -> String {
+    format!("{} ({}: {})", APP_NAME, self.get_address(), self.remote_name)
+  }
+
+  fn get_address(&self) -> String {
+    self.instance.api.as_ref().map_or(String::new(), |api| {
+      api
+        .uri
+        .strip_prefix("tcp://")
+        .unwrap_or(&api.uri)
+        .trim_end_matches('/')
+        .to_string()
+    })
+  }
+
+  pub fn timeout(&self, timeout: Duration) -> RemoteFoundry {
+    RemoteFoundry {
+      timeout: Some(timeout),
+      ..self.clone()
+    }
+  }
+
+  /// Connect and shake hands, returning the connection and the node's reply
+  fn connect(instance: &AppInstance, timeout: Option<Duration>) -> Result<(TcpStream, RpcResponse)> {
+    let uri = match &instance.api {
+      Some(api) => api.uri.clone(),
+      None => Err(FoundryError::NotConfigured).context(format!(
+        "{} needs an api uri (eg: tcp://host:7070) to reach the other node",
+        instance.full_name()
+      ))?,
+    };
+    let address = uri.strip_prefix("tcp://").unwrap_or(&uri).trim_end_matches('/');
+    let socket = match address.to_socket_addrs()?.next() {
+      Some(x) => x,
+      None => Err(FoundryError::ConfigurationError).context(format!("'{}' did not resolve to an address", uri))?,
+    };
+
+    let mut stream = TcpStream::connect_timeout(&socket, CONNECT_TIMEOUT)
+      .context(format!("Could not connect to the foundry node at {}", address))?;
+    stream.set_read_timeout(timeout)?;
+
+    write_message(
+      &mut stream,
+      &RpcRequest::Hello {
+        protocol: PROTOCOL_VERSION,
+        module_version: get_module_version()?,
+        auth: instance.api.as_ref().and_then(|api| api.auth.clone()),
+      },
+    )?;
+    let welcome = read_reply(&mut BufReader::new(stream.try_clone()?), address)?;
+    match welcome {
+      RpcResponse::Welcome { .. } => Ok((stream, welcome)),
+      x => Err(FoundryError::UnexpectedValue).context(format!(
+        "The node at {} answered the handshake with:\n{:#?}",
+        address, x
+      )),
+    }
+  }
+
+  /// Send a request on a new connection and wait for the answer
+  pub fn call(&self, request: RpcRequest) -> Result<RpcResponse> {
+    let (stream, _) = RemoteFoundry::connect(&self.instance, self.timeout)?;
+    self.send(stream, &request)
+  }
+
+  fn send(&self, mut stream: TcpStream, request: &RpcRequest) -> Result<RpcResponse> {
+    write_message(&mut stream, request)?;
+    read_reply(&mut BufReader::new(stream), &self.get_address())
+  }
+
+  fn forwarded(&self, response: RpcResponse) -> Result<String> {
+    match response {
+      RpcResponse::Forwarded(output) => Ok(output),
+      x => Err(FoundryError::UnexpectedValue).context(format!(
+        "{} answered a Forward with:\n{:#?}",
+        self.get_name(),
+        x
+      )),
+    }
+  }
+}
+
+/// Closes the connection when dropped, even while another thread is still waiting on it. The other node takes
+/// that as a cancel.
+struct HangUp(TcpStream);
+
+impl Drop for HangUp {
+  fn drop(&mut self) {
+    let _ = self.0.shutdown(Shutdown::Both);
+  }
+}
+
+/// Read the node's answer, turning a remote error back into a local one
+fn read_reply(reader: &mut BufReader<TcpStream>, address: &str) -> Result<RpcResponse> {
+  match read_message::<RpcResponse>(reader)? {
+    Some(RpcResponse::Error { kind, message }) => {
+      Err(kind).context(format!("The foundry node at {} returned an error:\n{}", address, message))
+    }
+    Some(response) => Ok(response),
+    None => Err(FoundryError::RemoteError)
+      .context(format!("The foundry node at {} closed the connection without answering", address)),
+  }
+}
+
+impl AppTrait for RemoteFoundry {
+  fn get_name(&self) -> String {
+    self.get_name()
+  }
+
+  /// Connects once to make sure the node is there and speaks a compatible version
+  fn build(instance: AppInstance, _parent: Option<Arc<dyn ContainerTrait>>) -> Result<RemoteFoundry> {
+    let (_, welcome) = RemoteFoundry::connect(&instance, Some(CONNECT_TIMEOUT))?;
+    let (version, name) = match welcome {
+      RpcResponse::Welcome {
+        module_version, name, ..
+      } => (module_version, name),
+      _ => Err(FoundryError::Unreachable).context("connect only returns a Welcome")?,
+    };
+
+    // The node checks this too, but it is clearer to say it from here
+    let ours = get_module_version()?;
+    if !is_compatible(&ours, &version) {
+      Err(FoundryError::ConfigurationError).context(format!(
+        "The node runs foundry {}, which isn't compatible with {} here",
+        version, ours
+      ))?;
+    }
+
+    Ok(RemoteFoundry {
+      instance: AppInstance {
+        version: Some(version),
+        module_version: Some(ours),
+        ..instance
+      },
+      remote_name: name,
+      timeout: None,
+    })
+  }
+
+  /// The version comes from the handshake
+  fn set_version(&self, instance: AppInstance) -> Result<AppInstance> {
+    Ok(AppInstance {
+      version: self.instance.version.clone(),
+      ..instance
+    })
+  }
+
+  /// Figures out how to call the cli using the given container
+  fn set_cli(
+    &self,
+    _instance: AppInstance,
+    _container: Arc<dyn ContainerTrait>,
+  ) -> Result<AppInstance> {
+    Err(FoundryError::NotImplemented).context(format!("{} can't set its cli yet", APP_NAME))
+  }
+}
+
+#[async_trait]
+impl ContainerTrait for RemoteFoundry {
+  /// Run find on the other node. The apps found point back here, so forwarding to them goes to that node.
+  fn find(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
+    match self.call(RpcRequest::Find(query))? {
+      RpcResponse::Found(apps) => apps
+        .into_iter()
+        .map(|app| match app.get_command_path() {
+          Ok(path) => AppInstance { cli: None, ..app }.set_command_path(Some(Arc::new(self.clone())), path),
+          Err(_) => Ok(app),
+        })
+        .collect(),
+      x => Err(FoundryError::UnexpectedValue).context(format!(
+        "{} answered a Find with:\n{:#?}",
+        self.get_name(),
+        x
+      )),
+    }
+  }
+
+  /// The call blocks, so it is made from its own thread
+  async fn find_async(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
+    let node = self.clone();
+    tokio::task::spawn_blocking(move || node.find(query))
+      .await
+      .context("The thread calling the other node panicked")?
+  }
+
+  /// List the known items in the app cache
+  fn cached_apps(&self) -> Result<Vec<AppInstance>> {
+    Err(FoundryError::NotImplemented).context("No App Cache for RemoteFoundry Yet")
+  }
+
+  fn forward(&self, to: AppInstance, message: Message) -> Result<String> {
+    block_on(self.forward_async(to, message))
+  }
+
+  /// Have the other node forward the message. Rpc messages are sent as they are.
+  ///
+  /// The call blocks, so it is made from its own thread. The timeout is sent along, but the cancel token
+  /// can't be, so the connection is closed instead if the command is cancelled, times out, or the future is
+  /// dropped. The other node then kills whatever it started.
+  async fn forward_async(&self, to: AppInstance, message: Message) -> Result<String> {
+    let limits = match &message {
+      Message::Command(cmd) => cmd.clone(),
+      _ => Cmd::default(),
+    };
+    let request = match message {
+      Message::Rpc(request) => *request,
+      message => RpcRequest::Forward {
+        to: Box::new(to),
+        message,
+      },
+    };
+
+    let call = async {
+      let (instance, timeout) = (self.instance.clone(), self.timeout);
+      let (stream, _) = tokio::task::spawn_blocking(move || RemoteFoundry::connect(&instance, timeout))
+        .await
+        .context("The thread connecting to the other node panicked")??;
+      let _hang_up = HangUp(stream.try_clone()?);
+      let node = self.clone();
+      tokio::task::spawn_blocking(move || node.send(stream, &request))
+        .await
+        .context("The thread calling the other node panicked")?
+    };
+    self.forwarded(limits.limit(call).await?)
+  }
+
+  /// Get the name/version of the container, usually for use in logging/errors.
+  fn get_name(&self) -> String {
+    self.get_name()
+  }
+}
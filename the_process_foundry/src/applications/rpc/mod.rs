@@ -0,0 +1,503 @@
+//! Let foundry nodes forward messages to each other
+//!
+//! A remote node looks like any other container: find and forward are sent over the network and run against
+//! the container the other node is serving. Messages are newline delimited json over tcp, one connection per
+//! call, and each connection starts with a handshake so the nodes can agree on the protocol and check their
+//! module versions are compatible. Hanging up before the answer comes back cancels a forwarded command.
+//!
+//! Anyone who can connect can run commands as the node, so a node only listens beyond localhost when it has a
+//! shared secret (ApiAuth) that every Hello has to include.
+//!
+//! Nothing is encrypted: the secret, the commands and their output all cross the network in plain text. Only
+//! listen beyond localhost on a network you trust, or reach the node through an ssh tunnel or a VPN.
+//! THINK: Switch to tarpc (or similar) once the containers are async
+
+pub mod application;
+pub mod server;
+
+use anyhow::{Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::io::{BufRead, Read, Write};
+
+use super::*;
+pub use application::RemoteFoundry;
+pub use server::RpcServer;
+
+/// The newest version of the protocol this node speaks
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The oldest version we still accept
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+const MODULE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The largest message we will read, so a bad peer can't make us buffer forever. Forwarded command output is
+/// the only thing that gets big.
+pub const MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The largest Hello we will read. It comes before the secret is checked, so anyone can send one.
+pub const MAX_HELLO_SIZE: u64 = 4 * 1024;
+
+/// The answer to each RpcRequest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RpcResponse {
+  /// The reply to Hello with the protocol both sides will use
+  Welcome {
+    protocol: u32,
+    module_version: semver::Version,
+    /// The name of the container the node is serving, for logging/errors
+    name: String,
+  },
+  Found(Vec<AppInstance>),
+  Forwarded(String),
+  /// The request failed on the remote node. The kind is kept so callers can still match on it.
+  Error { kind: FoundryError, message: String },
+}
+
+impl RpcResponse {
+  /// Wrap an error so it can be sent back, keeping the FoundryError if there is one. Its description is left
+  /// out of the message, since it is added back when the error is rebuilt on the other side.
+  pub fn from_error(err: anyhow::Error) -> RpcResponse {
+    match err.downcast_ref::<FoundryError>() {
+      Some(kind) => RpcResponse::Error {
+        kind: kind.clone(),
+        message: err
+          .chain()
+          .filter(|cause| cause.downcast_ref::<FoundryError>().is_none())
+          .map(|cause| cause.to_string())
+          .collect::<Vec<String>>()
+          .join(": "),
+      },
+      None => RpcResponse::Error {
+        kind: FoundryError::UnhandledError,
+        message: format!("{:#}", err),
+      },
+    }
+  }
+}
+
+/// The version of the foundry running this node
+pub fn get_module_version() -> Result<semver::Version> {
+  semver::Version::parse(MODULE_VERSION).context(format!(
+    "The foundry has an invalid version number '{}' Cargo.toml",
+    MODULE_VERSION
+  ))
+}
+
+/// Whether code at the two versions can talk to each other. Like cargo, anything before 1.0 needs the same minor
+/// version, otherwise the major versions need to match.
+pub fn is_compatible(ours: &semver::Version, theirs: &semver::Version) -> bool {
+  match ours.major {
+    0 => theirs.major == 0 && ours.minor == theirs.minor,
+    major => theirs.major == major,
+  }
+}
+
+/// Whether the credentials sent in a Hello are the ones the node was started with. The secrets are compared
+/// in constant time, so they can't be guessed a byte at a time.
+pub fn is_authorized(expected: &Option<ApiAuth>, given: &Option<ApiAuth>) -> bool {
+  match (expected, given) {
+    (None, _) => true,
+    (Some(_), None) => false,
+    (Some(expected), Some(given)) => constant_time_eq(&auth_bytes(expected), &auth_bytes(given)),
+  }
+}
+
+/// Everything that has to match, with the kind of auth so different kinds never compare equal
+fn auth_bytes(auth: &ApiAuth) -> Vec<u8> {
+  match auth {
+    ApiAuth::Basic { username, password } => format!("basic\0{}\0{}", username, password),
+    ApiAuth::Bearer(token) => format!("bearer\0{}", token),
+    ApiAuth::Header { name, value } => format!("header\0{}\0{}", name.to_lowercase(), value),
+  }
+  .into_bytes()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Send one message as a line of json
+pub fn write_message<T: serde::Serialize>(stream: &mut impl Write, message: &T) -> Result<()> {
+  let mut line = serde_json::to_string(message)?;
+  line.push('\n');
+  stream.write_all(line.as_bytes())?;
+  stream.flush()?;
+  Ok(())
+}
+
+/// Read the next message, or None if the other side closed the connection
+pub fn read_message<T: serde::de::DeserializeOwned>(stream: &mut impl BufRead) -> Result<Option<T>> {
+  read_limited_message(stream, MAX_MESSAGE_SIZE)
+}
+
+fn read_limited_message<T: serde::de::DeserializeOwned>(
+  stream: &mut impl BufRead,
+  limit: u64,
+) -> Result<Option<T>> {
+  let mut line = String::new();
+  // One more than the limit, to tell a message of exactly the limit from one that is cut off
+  let read = stream.take(limit + 1).read_line(&mut line)?;
+  if read as u64 > limit {
+    Err(FoundryError::UnexpectedValue).context(format!("Received a message over the {} byte limit", limit))?;
+  }
+  match read {
+    0 => Ok(None),
+    _ => Ok(Some(serde_json::from_str(&line).context(format!(
+      "Received a message that isn't part of the foundry protocol:\n{}",
+      line.trim_end()
+    ))?)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{BufReader, Cursor};
+  use std::net::TcpStream;
+
+  /// The container the test node serves
+  #[derive(Debug)]
+  struct Root;
+
+  impl ContainerTrait for Root {
+    fn find(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
+      match query.name.as_str() {
+        "missing" => Err(FoundryError::NotFound).context("missing is not installed"),
+        name => Ok(vec![
+          AppInstance::new(name.to_string()).set_command_path(None, format!("/usr/bin/{}", name))?
+        ]),
+      }
+    }
+
+    fn forward(&self, to: AppInstance, message: Message) -> Result<String> {
+      match message {
+        Message::Command(cmd) if cmd.command == "sleep" => {
+          Err(FoundryError::Timeout).context("sleep ran out of time")
+        }
+        // Ignores the timeout it was sent
+        Message::Command(cmd) if cmd.command == "hang" => {
+          std::thread::sleep(std::time::Duration::from_secs(10));
+          Ok("hang finished".to_string())
+        }
+        Message::Command(cmd) => Ok(format!("{} ran {}", to.name, cmd.to_script(ShellType::Sh))),
+        _ => Err(FoundryError::UnexpectedValue).context("Only commands are sent"),
+      }
+    }
+
+    fn cached_apps(&self) -> Result<Vec<AppInstance>> {
+      Ok(vec![])
+    }
+
+    fn get_name(&self) -> String {
+      "Test Root".to_string()
+    }
+  }
+
+  /// Start a node on a free local port, returning its uri
+  fn serve(auth: Option<ApiAuth>) -> String {
+    start(RpcServer::bind("127.0.0.1:0", Arc::new(Root), auth).unwrap())
+  }
+
+  fn start(server: RpcServer) -> String {
+    let address = server.local_addr().unwrap();
+    std::thread::spawn(move || server.serve());
+    format!("tcp://{}", address)
+  }
+
+  /// Connect without the handshake, to act like a misbehaving node
+  fn raw_connect(uri: &str) -> TcpStream {
+    let stream = TcpStream::connect(uri.trim_start_matches("tcp://")).unwrap();
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(10))).unwrap();
+    stream
+  }
+
+  fn connect(uri: &str, auth: Option<ApiAuth>) -> Result<RemoteFoundry> {
+    let api = ApiAccess::new(uri.to_string());
+    let api = match auth {
+      Some(auth) => api.auth(auth),
+      None => api,
+    };
+    RemoteFoundry::build(
+      AppInstance {
+        api: Some(api),
+        ..AppInstance::new("node".to_string())
+      },
+      None,
+    )
+  }
+
+  fn kind(err: &anyhow::Error) -> Option<FoundryError> {
+    err.downcast_ref::<FoundryError>().cloned()
+  }
+
+  #[test]
+  fn found_apps_forward_through_the_node() {
+    let node = connect(&serve(None), None).unwrap();
+    let name = ContainerTrait::get_name(&node);
+    assert!(name.contains("Test Root"), "{}", name);
+
+    let psql = node.find_one(AppQuery::new("psql".to_string())).unwrap();
+    assert_eq!(psql.get_command_path().unwrap(), "/usr/bin/psql");
+
+    // The found app points back at the node, so forwarding to it goes over the wire
+    let container = psql.cli.clone().unwrap().container.unwrap();
+    let output = container
+      .forward(
+        psql.clone(),
+        Message::Command(Cmd::argv("psql".to_string(), vec!["-c".to_string(), "select 1".to_string()])),
+      )
+      .unwrap();
+    assert_eq!(output, "psql ran psql -c 'select 1'");
+  }
+
+  #[test]
+  fn error_kinds_survive_the_round_trip() {
+    let node = connect(&serve(None), None).unwrap();
+
+    let err = node.find(AppQuery::new("missing".to_string())).unwrap_err();
+    assert!(matches!(kind(&err), Some(FoundryError::NotFound)), "{:#}", err);
+    assert!(format!("{:#}", err).contains("missing is not installed"), "{:#}", err);
+
+    let err = node
+      .forward(
+        AppInstance::new("sleeper".to_string()),
+        Message::Command(Cmd::argv("sleep".to_string(), vec!["60".to_string()])),
+      )
+      .unwrap_err();
+    assert!(matches!(kind(&err), Some(FoundryError::Timeout)), "{:#}", err);
+    assert!(FoundryError::is_interrupted(&err));
+  }
+
+  #[test]
+  fn incompatible_module_versions_are_rejected() {
+    let uri = serve(None);
+    let future = semver::Version::new(99, 0, 0);
+
+    // An app set up by a newer foundry
+    let node = connect(&uri, None).unwrap();
+    let app = AppInstance {
+      module_version: Some(future.clone()),
+      ..AppInstance::new("psql".to_string())
+    };
+    let err = node
+      .forward(app, Message::Command(Cmd::argv("psql".to_string(), vec![])))
+      .unwrap_err();
+    assert!(matches!(kind(&err), Some(FoundryError::ConfigurationError)), "{:#}", err);
+
+    // A newer foundry saying hello
+    let mut stream = TcpStream::connect(uri.trim_start_matches("tcp://")).unwrap();
+    write_message(
+      &mut stream,
+      &RpcRequest::Hello {
+        protocol: PROTOCOL_VERSION,
+        module_version: future,
+        auth: None,
+      },
+    )
+    .unwrap();
+    let mut reader = BufReader::new(stream);
+    match read_message::<RpcResponse>(&mut reader).unwrap() {
+      Some(RpcResponse::Error {
+        kind: FoundryError::ConfigurationError,
+        ..
+      }) => (),
+      x => panic!("Expected a ConfigurationError, got {:?}", x),
+    }
+    // And the node hangs up after a failed handshake
+    assert!(read_message::<RpcResponse>(&mut reader).unwrap().is_none());
+  }
+
+  #[test]
+  fn nodes_with_auth_need_the_secret() {
+    let uri = serve(Some(ApiAuth::Bearer("s3cret".to_string())));
+
+    for auth in [
+      None,
+      Some(ApiAuth::Bearer("wrong".to_string())),
+      Some(ApiAuth::Header {
+        name: "Bearer".to_string(),
+        value: "s3cret".to_string(),
+      }),
+    ] {
+      let err = connect(&uri, auth).unwrap_err();
+      assert!(matches!(kind(&err), Some(FoundryError::NotConfigured)), "{:#}", err);
+    }
+
+    let node = connect(&uri, Some(ApiAuth::Bearer("s3cret".to_string()))).unwrap();
+    assert!(node.find(AppQuery::new("psql".to_string())).is_ok());
+  }
+
+  #[test]
+  fn listening_beyond_localhost_needs_auth() {
+    let err = RpcServer::bind("0.0.0.0:0", Arc::new(Root), None).unwrap_err();
+    assert!(matches!(kind(&err), Some(FoundryError::ConfigurationError)), "{:#}", err);
+
+    let auth = ApiAuth::Basic {
+      username: "node".to_string(),
+      password: "s3cret".to_string(),
+    };
+    assert!(RpcServer::bind("0.0.0.0:0", Arc::new(Root), Some(auth)).is_ok());
+  }
+
+  #[test]
+  fn big_hellos_are_dropped_before_the_secret_is_checked() {
+    let uri = serve(Some(ApiAuth::Bearer("s3cret".to_string())));
+    let mut stream = raw_connect(&uri);
+    let hello = RpcRequest::Hello {
+      protocol: PROTOCOL_VERSION,
+      module_version: get_module_version().unwrap(),
+      auth: Some(ApiAuth::Bearer("x".repeat(MAX_HELLO_SIZE as usize))),
+    };
+    write_message(&mut stream, &hello).unwrap();
+
+    // A wrong secret that fits gets an answer, this one just gets hung up on
+    let reply = read_message::<RpcResponse>(&mut BufReader::new(stream));
+    assert!(!matches!(reply, Ok(Some(_))), "{:?}", reply);
+  }
+
+  #[test]
+  fn idle_connections_are_closed() {
+    let server = RpcServer::bind("127.0.0.1:0", Arc::new(Root), None).unwrap();
+    let uri = start(server.idle_timeout(std::time::Duration::from_millis(100)));
+    let stream = raw_connect(&uri);
+
+    let start = std::time::Instant::now();
+    assert!(read_message::<RpcResponse>(&mut BufReader::new(stream)).unwrap().is_none());
+    assert!(start.elapsed() < std::time::Duration::from_secs(5), "{:?}", start.elapsed());
+
+    // Nodes that send their requests in time are still served
+    let node = connect(&uri, None).unwrap();
+    assert!(node.find(AppQuery::new("psql".to_string())).is_ok());
+  }
+
+  #[test]
+  fn busy_nodes_turn_connections_away() {
+    let server = RpcServer::bind("127.0.0.1:0", Arc::new(Root), None).unwrap();
+    let uri = start(server.max_connections(1));
+
+    // Finish the handshake, so the node has certainly counted the connection
+    let mut first = raw_connect(&uri);
+    let hello = RpcRequest::Hello {
+      protocol: PROTOCOL_VERSION,
+      module_version: get_module_version().unwrap(),
+      auth: None,
+    };
+    write_message(&mut first, &hello).unwrap();
+    let welcome = read_message::<RpcResponse>(&mut BufReader::new(first.try_clone().unwrap())).unwrap();
+    assert!(matches!(welcome, Some(RpcResponse::Welcome { .. })), "{:?}", welcome);
+
+    let err = connect(&uri, None).unwrap_err();
+    // The node may hang up before the error is read, which leaves only the io error
+    if let Some(kind) = kind(&err) {
+      assert!(matches!(kind, FoundryError::RemoteError), "{:#}", err);
+    }
+
+    // Once the first one hangs up, there is room again
+    drop(first);
+    let node = (0..100).find_map(|_| {
+      std::thread::sleep(std::time::Duration::from_millis(20));
+      connect(&uri, None).ok()
+    });
+    assert!(node.is_some());
+  }
+
+  /// Forward a script that starts a sleep through the node, and cancel it once the sleep is running. Returns
+  /// the pid of the sleep.
+  fn cancel_sleep(node: &RemoteFoundry) -> String {
+    let pidfile = std::env::temp_dir().join(format!("foundry-rpc-{}.pid", uuid::Uuid::new_v4()));
+    let pidfile = pidfile.to_string_lossy().to_string();
+    let script = format!("sleep 60 & echo $! > {}; wait", ShellType::Sh.quote(&pidfile));
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    let started = pidfile.clone();
+    std::thread::spawn(move || {
+      while !std::path::Path::new(&started).exists() {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+      }
+      cancel.cancel();
+    });
+
+    let start = std::time::Instant::now();
+    let cmd = ShellType::Sh.script(script).cancel_on(token);
+    let err = node.forward(AppInstance::new("sh".to_string()), Message::Command(cmd)).unwrap_err();
+    assert!(matches!(kind(&err), Some(FoundryError::Cancelled)), "{:#}", err);
+    assert!(start.elapsed() < std::time::Duration::from_secs(30), "{:?}", start.elapsed());
+
+    let pid = std::fs::read_to_string(&pidfile).unwrap().trim().to_string();
+    let _ = std::fs::remove_file(&pidfile);
+    pid
+  }
+
+  fn wait_until_gone(pid: &str) {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    // Zombies count as gone, since there may be no init to reap them
+    let is_running = || match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+      Ok(stat) => !stat.rsplit(')').next().unwrap_or_default().trim_start().starts_with('Z'),
+      Err(_) => false,
+    };
+    while is_running() {
+      assert!(std::time::Instant::now() < deadline, "{} is still running", pid);
+      std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+  }
+
+  #[test]
+  fn cancelled_forwards_are_killed_on_every_node() {
+    // The sleep runs on the last node, and the one in the middle only passes the command on
+    let shell = PosixShell::build(AppInstance::new("sh".to_string()), None).unwrap();
+    let last = start(RpcServer::bind("127.0.0.1:0", Arc::new(shell), None).unwrap());
+    let middle = connect(&last, None).unwrap();
+    let first = start(RpcServer::bind("127.0.0.1:0", Arc::new(middle), None).unwrap());
+
+    let pid = cancel_sleep(&connect(&first, None).unwrap());
+    wait_until_gone(&pid);
+
+    let pid = cancel_sleep(&connect(&last, None).unwrap());
+    wait_until_gone(&pid);
+  }
+
+  #[test]
+  fn forwards_time_out_on_the_caller_too() {
+    // Stands in for a node that is too busy to enforce the timeout itself
+    let uri = serve(None);
+    let node = connect(&uri, None).unwrap();
+    let start = std::time::Instant::now();
+    let cmd = Cmd::argv("hang".to_string(), vec![]).timeout(std::time::Duration::from_millis(200));
+    let err = node.forward(AppInstance::new("hang".to_string()), Message::Command(cmd)).unwrap_err();
+    assert!(matches!(kind(&err), Some(FoundryError::Timeout)), "{:#}", err);
+    assert!(start.elapsed() < std::time::Duration::from_secs(5), "{:?}", start.elapsed());
+  }
+
+  #[test]
+  fn messages_over_the_limit_are_refused() {
+    let message = "\"Forwarded\"\n";
+    let fits = read_limited_message::<String>(&mut Cursor::new(message), message.len() as u64).unwrap();
+    assert_eq!(fits, Some("Forwarded".to_string()));
+
+    let err =
+      read_limited_message::<String>(&mut Cursor::new(message), message.len() as u64 - 1).unwrap_err();
+    assert!(matches!(kind(&err), Some(FoundryError::UnexpectedValue)), "{:#}", err);
+
+    assert!(read_limited_message::<String>(&mut Cursor::new(""), 10).unwrap().is_none());
+  }
+
+  #[test]
+  fn secrets_are_compared_whole() {
+    let bearer = |x: &str| Some(ApiAuth::Bearer(x.to_string()));
+    assert!(is_authorized(&None, &None));
+    assert!(is_authorized(&None, &bearer("anything")));
+    assert!(is_authorized(&bearer("abc"), &bearer("abc")));
+    assert!(!is_authorized(&bearer("abc"), &bearer("abcd")));
+    assert!(!is_authorized(&bearer("abc"), &bearer("abd")));
+    assert!(!is_authorized(&bearer("abc"), &None));
+
+    let header = |name: &str| {
+      Some(ApiAuth::Header {
+        name: name.to_string(),
+        value: "key".to_string(),
+      })
+    };
+    assert!(is_authorized(&header("X-API-Key"), &header("x-api-key")));
+  }
+}
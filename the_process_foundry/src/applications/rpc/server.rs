@@ -0,0 +1,283 @@
+//! Serve a container to other foundry nodes
+
+use anyhow::{Context, Result};
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{block_on, get_module_version, is_authorized, is_compatible, read_limited_message, write_message};
+use super::{ApiAuth, AppInstance, CancellationToken, ContainerTrait, FoundryError, Message};
+use super::{RpcRequest, RpcResponse};
+use super::{MAX_HELLO_SIZE, MAX_MESSAGE_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+/// How many connections are handled at once before new ones are turned away
+const MAX_CONNECTIONS: usize = 64;
+
+/// How long a connection can go without sending a request before it is closed. A forward that is still
+/// running doesn't count, since nothing is read while waiting for it.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Answers requests from other nodes using the root container
+///
+/// Each connection is handled on its own thread, so a slow forward doesn't hold up the other nodes.
+#[derive(Debug)]
+pub struct RpcServer {
+  listener: TcpListener,
+  root: Arc<dyn ContainerTrait>,
+
+  /// What other nodes have to send in their Hello. Without it, only local connections are allowed.
+  auth: Option<ApiAuth>,
+
+  /// Connections past this are sent an error and closed, so they can't use up all the threads
+  max_connections: usize,
+
+  /// Connections that don't send anything for this long are closed
+  idle_timeout: Duration,
+}
+
+impl RpcServer {
+  /// Listen on the address (eg: "0.0.0.0:7070", or "127.0.0.1:0" for any free port). Other nodes can run
+  /// anything the root container can, so listening beyond localhost needs auth.
+  pub fn bind(address: &str, root: Arc<dyn ContainerTrait>, auth: Option<ApiAuth>) -> Result<RpcServer> {
+    let listener =
+      TcpListener::bind(address).context(format!("Could not listen for other nodes on {}", address))?;
+    if auth.is_none() && !listener.local_addr()?.ip().is_loopback() {
+      Err(FoundryError::ConfigurationError).context(format!(
+        "Refusing to serve {} on {} without auth. Set a shared secret or listen on localhost",
+        root.get_name(),
+        address
+      ))?;
+    }
+    Ok(RpcServer {
+      listener,
+      root,
+      auth,
+      max_connections: MAX_CONNECTIONS,
+      idle_timeout: IDLE_TIMEOUT,
+    })
+  }
+
+  pub fn max_connections(self, max_connections: usize) -> RpcServer {
+    RpcServer {
+      max_connections,
+      ..self
+    }
+  }
+
+  pub fn idle_timeout(self, idle_timeout: Duration) -> RpcServer {
+    RpcServer { idle_timeout, ..self }
+  }
+
+  pub fn local_addr(&self) -> Result<SocketAddr> {
+    Ok(self.listener.local_addr()?)
+  }
+
+  /// Handle connections until the listener fails. Problems with a single connection are only logged.
+  /// If the listener fails, this waits for the open connections to finish before returning the error.
+  pub fn serve(&self) -> Result<()> {
+    log::info!(
+      "Serving {} to other nodes on {}",
+      self.root.get_name(),
+      self.local_addr()?
+    );
+    // Only this thread adds to the count, so it can't go over between the check and the add
+    let open = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+      for stream in self.listener.incoming() {
+        let stream = stream.context("Failed to accept a connection from another node")?;
+        let peer = stream
+          .peer_addr()
+          .map_or("an unknown address".to_string(), |x| x.to_string());
+        if open.load(Ordering::SeqCst) >= self.max_connections {
+          log::warn!(
+            "Refused the connection from {}, since {} are already open",
+            peer,
+            self.max_connections
+          );
+          self.refuse(stream);
+          continue;
+        }
+
+        open.fetch_add(1, Ordering::SeqCst);
+        let open = &open;
+        scope.spawn(move || {
+          if let Err(err) = self.handle(stream) {
+            log::warn!("The connection from {} failed:\n{:#}", peer, err);
+          }
+          open.fetch_sub(1, Ordering::SeqCst);
+        });
+      }
+      Ok(())
+    })
+  }
+
+  /// Tell the other node we are too busy. This is on the accepting thread, so it can't wait on a slow peer.
+  fn refuse(&self, mut stream: TcpStream) {
+    let busy = anyhow::Error::new(FoundryError::RemoteError).context(format!(
+      "The node already has {} open connections, try again later",
+      self.max_connections
+    ));
+    // The error easily fits in an empty socket buffer, so the write only fails if the peer is already gone
+    let _ = stream.set_nonblocking(true);
+    let _ = write_message(&mut stream, &RpcResponse::from_error(busy));
+  }
+
+  /// Answer every request on the connection until the other node closes it
+  pub fn handle(&self, stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(self.idle_timeout))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut protocol = None;
+    loop {
+      // Anyone can send a Hello, so it gets a much smaller limit than the requests after it
+      let limit = match protocol {
+        None => MAX_HELLO_SIZE,
+        Some(_) => MAX_MESSAGE_SIZE,
+      };
+      let request = match read_limited_message::<RpcRequest>(&mut reader, limit)
+        .context(format!("Could not read a request, they have to come within {:?}", self.idle_timeout))?
+      {
+        Some(x) => x,
+        None => break,
+      };
+      log::debug!("Received a request from another node:\n{:#?}", request);
+      let response = match protocol {
+        None => self.hello(request),
+        Some(_) => self.respond(request, &writer),
+      };
+      if let RpcResponse::Welcome { protocol: agreed, .. } = &response {
+        protocol = Some(*agreed);
+      }
+      let is_error = matches!(response, RpcResponse::Error { .. });
+      write_message(&mut writer, &response)?;
+
+      // Nothing else will work after a failed handshake
+      if is_error && protocol.is_none() {
+        break;
+      }
+    }
+    Ok(())
+  }
+
+  /// Agree on the protocol and make sure the other node's modules are compatible with ours
+  fn hello(&self, request: RpcRequest) -> RpcResponse {
+    let (theirs, their_version, auth) = match request {
+      RpcRequest::Hello {
+        protocol,
+        module_version,
+        auth,
+      } => (protocol, module_version, auth),
+      _ => {
+        return RpcResponse::from_error(
+          anyhow::Error::new(FoundryError::NotConfigured)
+            .context("The connection has to start with a Hello"),
+        )
+      }
+    };
+
+    // Checked first, so nodes without the secret don't even learn our version
+    if !is_authorized(&self.auth, &auth) {
+      log::warn!("Refused a node that didn't send the right credentials");
+      return RpcResponse::from_error(
+        anyhow::Error::new(FoundryError::NotConfigured)
+          .context("This node needs credentials, and the ones sent don't match"),
+      );
+    }
+
+    let result = get_module_version().and_then(|ours| {
+      let protocol = theirs.min(PROTOCOL_VERSION);
+      if protocol < MIN_PROTOCOL_VERSION {
+        Err(FoundryError::ConfigurationError).context(format!(
+          "The other node speaks protocol {}, but this one needs at least {}",
+          theirs, MIN_PROTOCOL_VERSION
+        ))?;
+      }
+      if !is_compatible(&ours, &their_version) {
+        Err(FoundryError::ConfigurationError).context(format!(
+          "The other node runs foundry {}, which isn't compatible with {} on this one",
+          their_version, ours
+        ))?;
+      }
+      Ok(RpcResponse::Welcome {
+        protocol,
+        module_version: ours,
+        name: self.root.get_name(),
+      })
+    });
+    result.unwrap_or_else(RpcResponse::from_error)
+  }
+
+  fn respond(&self, request: RpcRequest, stream: &TcpStream) -> RpcResponse {
+    let result = match request {
+      RpcRequest::Hello { .. } => Err(FoundryError::UnexpectedValue).context("Already said hello"),
+      RpcRequest::Find(query) => self.root.find(query).map(RpcResponse::Found),
+      RpcRequest::Forward { to, message } => self
+        .check_module(&to)
+        .and_then(|_| self.forward(*to, message, stream))
+        .map(RpcResponse::Forwarded),
+    };
+    result.unwrap_or_else(RpcResponse::from_error)
+  }
+
+  /// Forward the message, cancelling it if the other node hangs up first. The caller's cancel token can't be
+  /// sent, so this is how a cancelled call keeps its command from running on here.
+  fn forward(&self, to: AppInstance, message: Message, stream: &TcpStream) -> Result<String> {
+    let (message, token) = match message {
+      Message::Command(cmd) => {
+        let token = CancellationToken::new();
+        (Message::Command(cmd.cancel_on(token.clone())), Some(token))
+      }
+      message => (message, None),
+    };
+    let peer = stream.try_clone()?;
+    peer.set_nonblocking(true)?;
+    let result = block_on(async {
+      let forward = self.root.forward_async(to, message);
+      tokio::pin!(forward);
+      tokio::select! {
+        result = &mut forward => result,
+        _ = hung_up(peer) => match token {
+          // Commands get to clean up after themselves, which can take another call to wherever they run
+          Some(token) => {
+            token.cancel();
+            forward.await
+          }
+          None => Err(FoundryError::Cancelled).context("The other node hung up before the answer was ready"),
+        },
+      }
+    });
+    // The clone shares the flag with the stream the answer is written to
+    stream.set_nonblocking(false)?;
+    result
+  }
+
+  /// An instance built by an incompatible module may not mean what we think it does
+  fn check_module(&self, to: &AppInstance) -> Result<()> {
+    let ours = get_module_version()?;
+    match &to.module_version {
+      Some(theirs) if !is_compatible(&ours, theirs) => Err(FoundryError::ConfigurationError).context(format!(
+        "{} was set up by module version {}, which isn't compatible with {} on this node",
+        to.full_name(),
+        theirs,
+        ours
+      )),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// Wait for the other node to close the connection. Nothing else is sent while it waits for an answer, so
+/// anything that does arrive is left for after it.
+async fn hung_up(stream: TcpStream) {
+  let stream = match tokio::net::TcpStream::from_std(stream) {
+    Ok(x) => x,
+    Err(_) => return std::future::pending().await,
+  };
+  let mut buf = [0; 1];
+  match stream.peek(&mut buf).await {
+    Ok(0) | Err(_) => (),
+    Ok(_) => std::future::pending().await,
+  }
+}
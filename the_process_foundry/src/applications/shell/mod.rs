@@ -0,0 +1,435 @@
+//! A generic POSIX shell (sh, dash, ash, bash, zsh)
+//!
+//! The shells mostly differ in their interactive features, so scripting through them is the same apart from
+//! a few flavor specific pieces (see ShellType). The shell can be local or inside another container, in
+//! which case the commands are forwarded to the parent.
+
+const APP_NAME: &str = "Shell";
+const MODULE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::process::Command;
+
+use super::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PosixShell {
+  instance: AppInstance,
+  flavor: ShellType,
+
+  /// Run as a login shell, so the profile files are read first (eg: to pick up PATH changes)
+  login: bool,
+
+  /// Variables exported before every command
+  env: BTreeMap<String, String>,
+
+  /// Where the shell is running. If this is empty, it is run on the local machine.
+  #[serde(skip)]
+  parent: Option<Arc<dyn ContainerTrait>>,
+}
+
+impl PosixShell {
+  fn get_module_version() -> Result<semver::Version> {
+    semver::Version::parse(MODULE_VERSION).context(format!(
+      "{} has an invalid version number '{}' Cargo.toml",
+      APP_NAME, MODULE_VERSION
+    ))
+  }
+
+  fn get_name(&self) -> String {
+    match &self.instance.version {
+      Some(ver) => format!("{} ({} {})", APP_NAME, self.flavor.get_executable(), ver),
+      None => format!("{} ({} Unknown Version)", APP_NAME, self.flavor.get_executable()),
+    }
+  }
+
+  /// Find where a local shell lives, and its version if it reports one (only zsh and bash do)
+  pub fn get_local_at(flavor: ShellType, path: &str) -> Result<AppInstance> {
+    let script = format!(
+      r#"command -v {}; printf '%s\n' "$ZSH_VERSION$BASH_VERSION""#,
+      flavor.quote(path)
+    );
+    let output = Command::new(path)
+      .args(["-c", &script])
+      .output()
+      .context(format!("Could not run the local shell at '{}'", path))?;
+    if !output.status.success() {
+      Err(FoundryError::RemoteError).context(format!(
+        "The shell at '{}' failed to report where it is:\n{}",
+        path,
+        String::from_utf8_lossy(&output.stderr).trim_end()
+      ))?;
+    }
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let mut lines = stdout.lines();
+    let location = lines.next().unwrap_or(path).to_string();
+    // zsh only gives "major.minor", so pad it out to a full semantic version
+    let version = lines
+      .next()
+      .and_then(|ver| ver.split(|c: char| !(c.is_ascii_digit() || c == '.')).next())
+      .and_then(|ver| {
+        let parts: Vec<&str> = ver.split('.').filter(|x| !x.is_empty()).collect();
+        match parts.len() {
+          0 => None,
+          x if x < 3 => semver::Version::parse(&format!("{}{}", parts.join("."), ".0".repeat(3 - x))).ok(),
+          _ => semver::Version::parse(&parts[..3].join(".")).ok(),
+        }
+      });
+
+    Ok(AppInstance {
+      version,
+      ..AppInstance::new(flavor.get_executable()).set_command_path(None, location)?
+    })
+  }
+
+  pub fn get_flavor(&self) -> ShellType {
+    self.flavor
+  }
+
+  /// Use a login shell, reading the profile before running anything
+  pub fn login(&self) -> PosixShell {
+    PosixShell {
+      login: true,
+      ..self.clone()
+    }
+  }
+
+  /// Add a variable to export before every command
+  pub fn env(&self, key: String, value: String) -> Result<PosixShell> {
+    // Check the name now rather than every time it is used
+    self.flavor.export(&key, &value)?;
+    let mut env = self.env.clone();
+    env.insert(key, value);
+    Ok(PosixShell {
+      env,
+      ..self.clone()
+    })
+  }
+
+  /// The executable for the shell, using the path we found if we have one
+  fn get_executable(&self) -> String {
+    self
+      .instance
+      .get_command_path()
+      .unwrap_or_else(|_| self.flavor.get_executable())
+  }
+
+  /// Wrap a script so it runs with our options and environment
+  pub fn to_cmd(&self, script: &str) -> Result<Cmd> {
+    let mut lines = vec![];
+    for (key, value) in &self.env {
+      lines.push(self.flavor.export(key, value)?);
+    }
+    lines.push(script.to_string());
+
+    let mut args = vec![];
+    if self.login {
+      args.push("-l".to_string());
+    }
+    args.push("-c".to_string());
+    args.push(lines.join("; "));
+
+    Ok(Cmd {
+      command: self.get_executable(),
+      args,
+      ..Default::default()
+    })
+  }
+
+  /// Run a script in the shell and return stdout
+  pub fn run(&self, script: &str) -> Result<String> {
+    block_on(self.run_async(script))
+  }
+
+  pub async fn run_async(&self, script: &str) -> Result<String> {
+    self.execute(self.to_cmd(script)?).await
+  }
+
+  /// Run a single command. If we don't need the shell for a login or environment, the arguments are passed
+  /// straight to the program, otherwise each word is quoted so the shell doesn't interpret them.
+  pub fn run_command(&self, command: &str, args: &[String]) -> Result<String> {
+    block_on(self.run_cmd(Cmd::argv(command.to_string(), args.to_vec())))
+  }
+
+  /// Same as run_command, also setting the command's own variables and working directory
+  async fn run_cmd(&self, cmd: Cmd) -> Result<String> {
+    self.execute(self.prepare(cmd)?).await
+  }
+
+  /// Put the command through the shell if it needs a login or our environment, otherwise leave it alone
+  fn prepare(&self, cmd: Cmd) -> Result<Cmd> {
+    if !self.login && self.env.is_empty() {
+      return Ok(cmd);
+    }
+
+    let mut lines = vec![];
+    for (key, value) in &cmd.env {
+      lines.push(self.flavor.export(key, value)?);
+    }
+    if let Some(workdir) = &cmd.workdir {
+      lines.push(format!("cd {}", self.flavor.quote(workdir)));
+    }
+    lines.push(cmd.to_script(self.flavor));
+    // && so the command isn't run in the wrong place if the cd fails
+    Ok(self.to_cmd(&lines.join(" && "))?.inherit_limits(&cmd))
+  }
+
+  /// Prepare a command for a parent that switches users itself (eg: compose exec --user), so the login and
+  /// environment still apply but the user is left for the parent
+  fn prepare_for_parent(&self, cmd: Cmd) -> Result<Cmd> {
+    let run_as = cmd.run_as.clone();
+    Ok(Cmd {
+      run_as,
+      ..self.prepare(cmd)?
+    })
+  }
+
+  /// Send the command to the parent, or run it locally if there isn't one
+  async fn execute(&self, cmd: Cmd) -> Result<String> {
+    if let Some(parent) = &self.parent {
+      return parent
+        .forward_async(self.instance.clone(), Message::Command(cmd))
+        .await;
+    }
+
+    let output = cmd.output().await?;
+    match output.status.success() {
+      true => Ok(String::from_utf8(output.stdout)?.trim_end().to_string()),
+      false => Err(FoundryError::RemoteError).context(format!(
+        "'{}' failed with exit code {}:\n{}",
+        cmd.to_script(self.flavor),
+        output
+          .status
+          .code()
+          .map_or("unknown".to_string(), |x| x.to_string()),
+        String::from_utf8_lossy(&output.stderr).trim_end()
+      )),
+    }
+  }
+}
+
+impl AppTrait for PosixShell {
+  fn get_name(&self) -> String {
+    self.get_name()
+  }
+
+  /// The flavor comes from the instance name or path (eg: "dash", "/bin/zsh"), defaulting to plain sh
+  fn build(instance: AppInstance, parent: Option<Arc<dyn ContainerTrait>>) -> Result<PosixShell> {
+    let flavor = instance
+      .get_command_path()
+      .ok()
+      .and_then(|path| ShellType::from_name(&path))
+      .or_else(|| ShellType::from_name(&instance.name))
+      .unwrap_or(ShellType::Sh);
+
+    Ok(PosixShell {
+      instance: AppInstance {
+        module_version: Some(PosixShell::get_module_version()?),
+        ..instance
+      },
+      flavor,
+      login: false,
+      env: BTreeMap::new(),
+      parent,
+    })
+  }
+
+  /// Knows how to get the version number of the installed app (not the module version)
+  fn set_version(&self, _instance: AppInstance) -> Result<AppInstance> {
+    Err(FoundryError::NotImplemented).context(format!("{} can't look up its version yet", APP_NAME))
+  }
+
+  /// Figures out how to call the cli using the given container
+  fn set_cli(
+    &self,
+    _instance: AppInstance,
+    _container: Arc<dyn ContainerTrait>,
+  ) -> Result<AppInstance> {
+    Err(FoundryError::NotImplemented).context(format!("{} can't set its cli yet", APP_NAME))
+  }
+}
+
+#[async_trait]
+impl ContainerTrait for PosixShell {
+  fn find(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
+    block_on(self.find_async(query))
+  }
+
+  /// Look up the executable by name, then each of the aliases, returning the first one found
+  /// TODO: Check works_with once apps know how to report their own version
+  async fn find_async(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
+    let names = std::iter::once(query.name.clone()).chain(query.aliases.clone().unwrap_or_default());
+
+    let mut errors = vec![];
+    for name in names {
+      match self.run_async(&self.flavor.lookup(&name)).await {
+        Ok(path) if !path.is_empty() => {
+          return Ok(vec![AppInstance::new(query.name.clone())
+            .set_command_path(Some(Arc::new(self.clone())), path)?])
+        }
+        Ok(_) => errors.push(format!("{}: not found", name)),
+        Err(err) => errors.push(format!("{}: {:#}", name, err)),
+      }
+    }
+    Err(FoundryError::NotFound).context(format!(
+      "{} could not find {}:\n{}",
+      self.get_name(),
+      query.name,
+      errors.join("\n")
+    ))
+  }
+
+  /// List the known items in the app cache
+  fn cached_apps(&self) -> Result<Vec<AppInstance>> {
+    Err(FoundryError::NotImplemented).context("No App Cache for Shell Yet")
+  }
+
+  fn forward(&self, to: AppInstance, message: Message) -> Result<String> {
+    block_on(self.forward_async(to, message))
+  }
+
+  /// Run the command through the shell
+  async fn forward_async(&self, _to: AppInstance, message: Message) -> Result<String> {
+    match message {
+      // The parent knows how to switch users in its own environment (eg: compose exec --user)
+      Message::Command(cmd) if cmd.run_as.is_some() && self.parent.is_some() => {
+        self.execute(self.prepare_for_parent(cmd)?).await
+      }
+      Message::Command(cmd) => {
+        let cmd = match &cmd.run_as {
+          Some(user) => Escalation::detect().wrap(user, &cmd),
+          None => cmd,
+        };
+        self.run_cmd(cmd).await
+      }
+      _ => Err(FoundryError::UnexpectedValue)
+        .context(format!("{} can only forward commands", self.get_name())),
+    }
+  }
+
+  /// The same command forward would run, left for the caller to start
+  fn local_command(&self, cmd: Cmd) -> Result<Cmd> {
+    match (&self.parent, &cmd.run_as) {
+      (Some(parent), Some(_)) => parent.local_command(self.prepare_for_parent(cmd)?),
+      (Some(parent), None) => parent.local_command(self.prepare(cmd)?),
+      (None, Some(user)) => self.prepare(Escalation::detect().wrap(user, &cmd)),
+      (None, None) => self.prepare(cmd),
+    }
+  }
+
+  /// Get the name/version of the container, usually for use in logging/errors.
+  fn get_name(&self) -> String {
+    self.get_name()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Mutex;
+
+  /// A parent that records the commands forwarded to it, and hands back local commands unchanged
+  #[derive(Debug, Default)]
+  struct Parent {
+    sent: Mutex<Vec<Cmd>>,
+  }
+
+  impl ContainerTrait for Parent {
+    fn find(&self, _query: AppQuery) -> Result<Vec<AppInstance>> {
+      Ok(vec![])
+    }
+
+    fn forward(&self, _to: AppInstance, message: Message) -> Result<String> {
+      match message {
+        Message::Command(cmd) => self.sent.lock().unwrap().push(cmd),
+        _ => Err(FoundryError::UnexpectedValue).context("Only commands are sent")?,
+      }
+      Ok(String::new())
+    }
+
+    fn local_command(&self, cmd: Cmd) -> Result<Cmd> {
+      Ok(cmd)
+    }
+
+    fn cached_apps(&self) -> Result<Vec<AppInstance>> {
+      Ok(vec![])
+    }
+
+    fn get_name(&self) -> String {
+      "Test Parent".to_string()
+    }
+  }
+
+  fn shell(parent: Option<Arc<dyn ContainerTrait>>) -> PosixShell {
+    PosixShell::build(AppInstance::new("bash".to_string()), parent)
+      .unwrap()
+      .login()
+      .env("PGDATA".to_string(), "/var/lib/data".to_string())
+      .unwrap()
+  }
+
+  fn as_postgres() -> Cmd {
+    Cmd {
+      run_as: Some("postgres".to_string()),
+      workdir: Some("/tmp".to_string()),
+      ..Cmd::argv("pg_ctl".to_string(), vec!["status".to_string()])
+    }
+  }
+
+  #[test]
+  fn parents_switching_users_still_get_the_login_and_env() {
+    let parent = Arc::new(Parent::default());
+    let bash = shell(Some(parent.clone()));
+
+    bash
+      .forward(AppInstance::new("bash".to_string()), Message::Command(as_postgres()))
+      .unwrap();
+    let local = bash.local_command(as_postgres()).unwrap();
+
+    for cmd in [parent.sent.lock().unwrap()[0].clone(), local] {
+      assert_eq!(cmd.run_as, Some("postgres".to_string()));
+      assert_eq!(cmd.command, "bash");
+      assert_eq!(cmd.args, vec!["-l", "-c", "export PGDATA=/var/lib/data; cd /tmp && pg_ctl status"]);
+    }
+  }
+
+  #[test]
+  fn plain_shells_pass_commands_through() {
+    let parent = Arc::new(Parent::default());
+    let sh = PosixShell::build(AppInstance::new("sh".to_string()), Some(parent.clone())).unwrap();
+    sh.forward(AppInstance::new("sh".to_string()), Message::Command(as_postgres())).unwrap();
+
+    let sent = parent.sent.lock().unwrap()[0].clone();
+    assert_eq!(sent.run_as, Some("postgres".to_string()));
+    assert_eq!(sent.words(), vec!["pg_ctl", "status"]);
+    assert_eq!(sent.workdir, Some("/tmp".to_string()));
+  }
+
+  #[test]
+  fn local_shells_run_the_script() {
+    let sh = PosixShell::build(AppInstance::new("sh".to_string()), None)
+      .unwrap()
+      .env("GREETING".to_string(), "hello there".to_string())
+      .unwrap();
+    assert_eq!(sh.run("echo \"$GREETING\"").unwrap(), "hello there");
+    let args = vec!["%s-%s".to_string(), "a b".to_string(), "c".to_string()];
+    assert_eq!(sh.run_command("printf", &args).unwrap(), "a b-c");
+
+    let err = sh.run("echo oops >&2; exit 4").unwrap_err();
+    assert!(matches!(err.downcast_ref::<FoundryError>(), Some(FoundryError::RemoteError)));
+    assert!(format!("{:#}", err).contains("exit code 4:\noops"), "{:#}", err);
+  }
+
+  #[test]
+  fn unsupported_features_are_errors() {
+    let sh = PosixShell::build(AppInstance::new("sh".to_string()), None).unwrap();
+    let err = sh.cached_apps().unwrap_err();
+    assert!(matches!(err.downcast_ref::<FoundryError>(), Some(FoundryError::NotImplemented)));
+    let err = sh.set_version(AppInstance::new("sh".to_string())).unwrap_err();
+    assert!(matches!(err.downcast_ref::<FoundryError>(), Some(FoundryError::NotImplemented)));
+  }
+}
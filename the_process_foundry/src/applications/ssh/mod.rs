@@ -0,0 +1,563 @@
+//! A remote host reached over ssh
+//!
+//! Commands are sent as a single script to the user's shell on the remote host, so each word is quoted the
+//! same way as for a local POSIX shell. The ssh client itself runs in the parent container (the local machine
+//! if there isn't one), and we never give it a terminal so anything wanting a password fails instead of
+//! hanging. Keys or an agent need to be set up ahead of time.
+//!
+//! Since it can start commands from the local machine, it can also be the parent of apps that manage their own
+//! processes, such as DockerCompose. Their files (eg: docker-compose.yml) need to be on the remote host at the
+//! same path.
+
+const APP_NAME: &str = "Ssh";
+const MODULE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::*;
+
+/// ssh exits with this when it couldn't connect, rather than the command failing
+const CONNECTION_ERROR: i32 = 255;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ssh {
+  /// The instance_id is the host to connect to and the cli is the local ssh client
+  instance: AppInstance,
+
+  port: Option<u16>,
+
+  /// The user to log in as, otherwise ssh picks (usually the local user or what ~/.ssh/config says)
+  user: Option<String>,
+
+  /// The private key to use instead of the default ones or the agent
+  identity_file: Option<String>,
+
+  /// Connect through this host first (ssh -J), as [user@]host[:port]
+  jump_host: Option<String>,
+
+  /// Extra ssh_config options (-o Key=Value), eg: StrictHostKeyChecking
+  options: BTreeMap<String, String>,
+
+  /// How to run commands as another user on the remote host. Defaults to sudo.
+  escalation: Option<Escalation>,
+
+  /// Where the ssh client is run. If this is empty, it is run on the local machine.
+  #[serde(skip)]
+  parent: Option<Arc<dyn ContainerTrait>>,
+}
+
+impl Ssh {
+  fn get_module_version() -> Result<semver::Version> {
+    semver::Version::parse(MODULE_VERSION).context(format!(
+      "{} has an invalid version number '{}' Cargo.toml",
+      APP_NAME, MODULE_VERSION
+    ))
+  }
+
+  fn get_name(&self) -> String {
+    format!("{} ({})", APP_NAME, self.get_destination())
+  }
+
+  /// The host as ssh would be told to connect to it, for logging/errors
+  fn get_destination(&self) -> String {
+    let host = self.get_host();
+    let host = match &self.user {
+      Some(user) => format!("{}@{}", user, host),
+      None => host,
+    };
+    match self.port {
+      Some(port) => format!("{}:{}", host, port),
+      None => host,
+    }
+  }
+
+  pub fn get_host(&self) -> String {
+    self.instance.instance_id.clone().unwrap_or_default()
+  }
+
+  pub fn port(&self, port: u16) -> Ssh {
+    Ssh {
+      port: Some(port),
+      ..self.clone()
+    }
+  }
+
+  pub fn user(&self, user: String) -> Ssh {
+    Ssh {
+      user: Some(user),
+      ..self.clone()
+    }
+  }
+
+  pub fn identity_file(&self, path: String) -> Ssh {
+    Ssh {
+      identity_file: Some(path),
+      ..self.clone()
+    }
+  }
+
+  pub fn jump_host(&self, host: String) -> Ssh {
+    Ssh {
+      jump_host: Some(host),
+      ..self.clone()
+    }
+  }
+
+  pub fn option(&self, key: String, value: String) -> Ssh {
+    let mut options = self.options.clone();
+    options.insert(key, value);
+    Ssh {
+      options,
+      ..self.clone()
+    }
+  }
+
+  /// Choose how commands with a run_as are run as the other user on the remote host
+  pub fn set_escalation(&self, escalation: Escalation) -> Ssh {
+    Ssh {
+      escalation: Some(escalation),
+      ..self.clone()
+    }
+  }
+
+  /// The script the remote shell runs for the command, including changing users and directory
+  pub fn remote_script(&self, cmd: &Cmd) -> String {
+    let words = match &cmd.run_as {
+      // We are already logged in as them
+      Some(user) if Some(user) == self.user.as_ref() => cmd.words(),
+      Some(user) => self
+        .escalation
+        .unwrap_or(Escalation::Sudo)
+        .switch_user(user, cmd)
+        .words(),
+      None => cmd.words(),
+    };
+    let script = ShellType::Sh.join(&words);
+    match &cmd.workdir {
+      // && so the command isn't run in the wrong place if the cd fails
+      Some(workdir) => format!("cd {} && {}", ShellType::Sh.quote(workdir), script),
+      None => script,
+    }
+  }
+
+  /// The local ssh command that runs the command on the remote host
+  pub fn to_cmd(&self, cmd: &Cmd) -> Result<Cmd> {
+    let host = self.get_host();
+    if host.is_empty() {
+      Err(FoundryError::NotConfigured)
+        .context("Ssh needs a host to connect to. Set it as the instance_id when building it")?;
+    }
+
+    // BatchMode makes ssh fail instead of prompting for passwords or unknown host keys
+    let mut args = vec!["-T".to_string(), "-o".to_string(), "BatchMode=yes".to_string()];
+    if let Some(port) = self.port {
+      args.extend(["-p".to_string(), port.to_string()]);
+    }
+    if let Some(user) = &self.user {
+      args.extend(["-l".to_string(), user.clone()]);
+    }
+    if let Some(path) = &self.identity_file {
+      args.extend(["-i".to_string(), path.clone()]);
+      // Otherwise the agent's keys are tried first, and too many failures gets us disconnected
+      args.extend(["-o".to_string(), "IdentitiesOnly=yes".to_string()]);
+    }
+    if let Some(jump) = &self.jump_host {
+      args.extend(["-J".to_string(), jump.clone()]);
+    }
+    for (key, value) in &self.options {
+      args.extend(["-o".to_string(), format!("{}={}", key, value)]);
+    }
+    args.extend(["--".to_string(), host, self.remote_script(cmd)]);
+
+    Ok(
+      Cmd::argv(
+        self
+          .instance
+          .get_command_path()
+          .unwrap_or_else(|_| "ssh".to_string()),
+        args,
+      )
+      .inherit_limits(cmd),
+    )
+  }
+
+  /// Run the command on the remote host and return stdout
+  pub fn run(&self, cmd: &Cmd) -> Result<String> {
+    block_on(self.run_async(cmd))
+  }
+
+  /// Stopping ssh doesn't stop the command on the host, so one that hits its limits is killed there too
+  pub async fn run_async(&self, cmd: &Cmd) -> Result<String> {
+    cmd.run_tagged(|cmd| self.execute(cmd)).await
+  }
+
+  /// Run the ssh client in the parent, or locally if there isn't one
+  async fn execute(&self, cmd: Cmd) -> Result<String> {
+    let ssh = self.to_cmd(&cmd)?;
+    if let Some(parent) = &self.parent {
+      return parent
+        .forward_async(self.instance.clone(), Message::Command(ssh))
+        .await;
+    }
+
+    let output = ssh.output().await?;
+    let stderr = String::from_utf8_lossy(&output.stderr).trim_end().to_string();
+    match output.status.code() {
+      Some(0) => Ok(String::from_utf8(output.stdout)?.trim_end().to_string()),
+      Some(CONNECTION_ERROR) => Err(FoundryError::RemoteError).context(format!(
+        "Could not connect to {}:\n{}",
+        self.get_destination(),
+        stderr
+      )),
+      code => Err(FoundryError::RemoteError).context(format!(
+        "'{}' failed on {} with exit code {}:\n{}",
+        cmd.to_script(ShellType::Sh),
+        self.get_host(),
+        code.map_or("unknown".to_string(), |x| x.to_string()),
+        stderr
+      )),
+    }
+  }
+}
+
+impl AppTrait for Ssh {
+  fn get_name(&self) -> String {
+    self.get_name()
+  }
+
+  /// The instance_id is the host to connect to. If the instance doesn't say where the ssh client is, it is
+  /// looked up in the parent.
+  fn build(instance: AppInstance, parent: Option<Arc<dyn ContainerTrait>>) -> Result<Ssh> {
+    let instance = match (&instance.cli, &parent) {
+      (None, Some(container)) => AppInstance {
+        instance_id: instance.instance_id.clone(),
+        ..container
+          .find_one(AppQuery::new("ssh".to_string()))
+          .context(format!("Could not find the ssh client in {}", container.get_name()))?
+      },
+      _ => instance,
+    };
+
+    Ok(Ssh {
+      instance: AppInstance {
+        module_version: Some(Ssh::get_module_version()?),
+        ..instance
+      },
+      port: None,
+      user: None,
+      identity_file: None,
+      jump_host: None,
+      options: BTreeMap::new(),
+      escalation: None,
+      parent,
+    })
+  }
+
+  /// Knows how to get the version number of the installed app (not the module version)
+  fn set_version(&self, _instance: AppInstance) -> Result<AppInstance> {
+    Err(FoundryError::NotImplemented).context(format!("{} can't look up its version yet", APP_NAME))
+  }
+
+  /// Figures out how to call the cli using the given container
+  fn set_cli(
+    &self,
+    _instance: AppInstance,
+    _container: Arc<dyn ContainerTrait>,
+  ) -> Result<AppInstance> {
+    Err(FoundryError::NotImplemented).context(format!("{} can't set its cli yet", APP_NAME))
+  }
+}
+
+#[async_trait]
+impl ContainerTrait for Ssh {
+  fn find(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
+    block_on(self.find_async(query))
+  }
+
+  /// Look up the executable on the remote host by name, then each of the aliases, returning the first found
+  /// TODO: Check works_with once apps know how to report their own version
+  async fn find_async(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
+    let names = std::iter::once(query.name.clone()).chain(query.aliases.clone().unwrap_or_default());
+
+    let mut errors = vec![];
+    for name in names {
+      match self.run_async(&ShellType::Sh.find_app(&name)).await {
+        Ok(path) if !path.is_empty() => {
+          return Ok(vec![AppInstance::new(query.name.clone())
+            .set_command_path(Some(Arc::new(self.clone())), path)?])
+        }
+        Ok(_) => errors.push(format!("{}: not found", name)),
+        Err(err) => errors.push(format!("{}: {:#}", name, err)),
+      }
+    }
+    Err(FoundryError::NotFound).context(format!(
+      "{} could not find {}:\n{}",
+      self.get_name(),
+      query.name,
+      errors.join("\n")
+    ))
+  }
+
+  /// List the known items in the app cache
+  fn cached_apps(&self) -> Result<Vec<AppInstance>> {
+    Err(FoundryError::NotImplemented).context("No App Cache for Ssh Yet")
+  }
+
+  fn forward(&self, to: AppInstance, message: Message) -> Result<String> {
+    block_on(self.forward_async(to, message))
+  }
+
+  /// Run the command on the remote host
+  async fn forward_async(&self, _to: AppInstance, message: Message) -> Result<String> {
+    match message {
+      Message::Command(cmd) => self.run_async(&cmd).await,
+      _ => Err(FoundryError::UnexpectedValue)
+        .context(format!("{} can only forward commands", self.get_name())),
+    }
+  }
+
+  /// The ssh command, started however the parent starts things
+  fn local_command(&self, cmd: Cmd) -> Result<Cmd> {
+    let ssh = self.to_cmd(&cmd)?;
+    match &self.parent {
+      Some(parent) => parent.local_command(ssh),
+      None => Ok(ssh),
+    }
+  }
+
+  /// Get the name/version of the container, usually for use in logging/errors.
+  fn get_name(&self) -> String {
+    self.get_name()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const AWKWARD: &[&str] = &[
+    "",
+    "two words",
+    "it's",
+    "\"double\"",
+    "$HOME",
+    "${HOME}",
+    "$(id)",
+    "`id`",
+    "; echo injected",
+    "*",
+    "~",
+    "new\nline",
+  ];
+
+  fn ssh() -> Ssh {
+    Ssh::build(
+      AppInstance {
+        instance_id: Some("db.example.com".to_string()),
+        ..AppInstance::new("Ssh".to_string())
+      },
+      None,
+    )
+    .unwrap()
+  }
+
+  fn strings(words: &[&str]) -> Vec<String> {
+    words.iter().map(|x| x.to_string()).collect()
+  }
+
+  /// Run a script with the local sh as the remote user's shell would, returning the NUL separated output
+  fn run_sh(script: &str) -> Vec<String> {
+    let output = std::process::Command::new("sh")
+      .args(["-c", script])
+      .env("HOME", "/expanded/home")
+      .output()
+      .unwrap();
+    assert!(output.status.success(), "{}: {}", script, String::from_utf8_lossy(&output.stderr));
+    let mut printed: Vec<String> = String::from_utf8(output.stdout)
+      .unwrap()
+      .split('\0')
+      .map(|x| x.to_string())
+      .collect();
+    printed.pop();
+    printed
+  }
+
+  /// The words the shell splits a simple command into
+  fn words_of(script: &str) -> Vec<String> {
+    run_sh(&format!("printf '%s\\0' {}", script))
+  }
+
+  #[test]
+  fn ssh_options_come_before_the_host() {
+    let cmd = ssh()
+      .port(2222)
+      .user("deploy".to_string())
+      .identity_file("/keys/id ed25519".to_string())
+      .jump_host("bastion.example.com:22".to_string())
+      .option("StrictHostKeyChecking".to_string(), "accept-new".to_string())
+      .to_cmd(&Cmd::argv("uptime".to_string(), vec![]))
+      .unwrap();
+
+    assert_eq!(cmd.command, "ssh");
+    assert_eq!(
+      cmd.args,
+      strings(&[
+        "-T",
+        "-o",
+        "BatchMode=yes",
+        "-p",
+        "2222",
+        "-l",
+        "deploy",
+        "-i",
+        "/keys/id ed25519",
+        "-o",
+        "IdentitiesOnly=yes",
+        "-J",
+        "bastion.example.com:22",
+        "-o",
+        "StrictHostKeyChecking=accept-new",
+        "--",
+        "db.example.com",
+        "uptime",
+      ])
+    );
+  }
+
+  #[test]
+  fn the_defaults_only_add_batch_mode() {
+    let cmd = ssh()
+      .to_cmd(&Cmd::argv("ls".to_string(), strings(&["-la", "my dir"])))
+      .unwrap();
+    assert_eq!(
+      cmd.args,
+      strings(&["-T", "-o", "BatchMode=yes", "--", "db.example.com", "ls -la 'my dir'"])
+    );
+  }
+
+  #[test]
+  fn a_host_is_required() {
+    let err = Ssh::build(AppInstance::new("Ssh".to_string()), None)
+      .unwrap()
+      .to_cmd(&Cmd::argv("ls".to_string(), vec![]))
+      .unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<FoundryError>(),
+      Some(FoundryError::NotConfigured)
+    ));
+  }
+
+  #[test]
+  fn the_remote_shell_gets_back_each_word() {
+    let args: Vec<String> = std::iter::once("%s\\0".to_string()).chain(strings(AWKWARD)).collect();
+    let script = ssh().remote_script(&Cmd::argv("printf".to_string(), args));
+    assert_eq!(run_sh(&script), AWKWARD);
+  }
+
+  #[test]
+  fn env_and_workdir_survive_the_remote_shell() {
+    let workdir = std::env::temp_dir().join(format!("foundry ssh 'test' $x {}", std::process::id()));
+    std::fs::create_dir_all(&workdir).unwrap();
+    let workdir = workdir.to_str().unwrap().to_string();
+
+    let mut env = BTreeMap::new();
+    env.insert("ODD".to_string(), "it's $HOME `id`".to_string());
+    env.insert("PLAIN".to_string(), "1".to_string());
+    let cmd = Cmd {
+      env,
+      workdir: Some(workdir.clone()),
+      ..Cmd::argv(
+        "sh".to_string(),
+        strings(&["-c", "printf '%s\\0' \"$PWD\" \"$ODD\" \"$PLAIN\" \"$@\"", "sh", "$(id)", "a b"]),
+      )
+    };
+    let script = ssh().remote_script(&cmd);
+    assert!(script.starts_with("cd '"), "{}", script);
+
+    let printed = run_sh(&script);
+    std::fs::remove_dir(&workdir).unwrap();
+    assert_eq!(printed, strings(&[&workdir, "it's $HOME `id`", "1", "$(id)", "a b"]));
+  }
+
+  #[test]
+  fn a_missing_workdir_stops_the_command() {
+    let cmd = Cmd {
+      workdir: Some("/no/such/dir".to_string()),
+      ..Cmd::argv("echo".to_string(), strings(&["ran"]))
+    };
+    let output = std::process::Command::new("sh")
+      .args(["-c", &ssh().remote_script(&cmd)])
+      .output()
+      .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+  }
+
+  #[test]
+  fn run_as_the_login_user_skips_escalation() {
+    let cmd = Cmd {
+      run_as: Some("deploy".to_string()),
+      ..Cmd::argv("whoami".to_string(), vec![])
+    };
+    assert_eq!(ssh().user("deploy".to_string()).remote_script(&cmd), "whoami");
+  }
+
+  #[test]
+  fn run_as_another_user_goes_through_sudo() {
+    let mut env = BTreeMap::new();
+    env.insert("PGPASSWORD".to_string(), "p@ss word's".to_string());
+    let cmd = Cmd {
+      run_as: Some("postgres".to_string()),
+      env,
+      workdir: Some("/var/lib/postgresql".to_string()),
+      ..Cmd::argv("psql".to_string(), strings(&["-c", "select '$1'"]))
+    };
+    let script = ssh().user("deploy".to_string()).remote_script(&cmd);
+
+    let (cd, rest) = script.split_once(" && ").unwrap();
+    assert_eq!(words_of(cd), strings(&["cd", "/var/lib/postgresql"]));
+    // The variables are set after sudo, since it resets the environment
+    assert_eq!(
+      words_of(rest),
+      strings(&[
+        "sudo",
+        "-n",
+        "-u",
+        "postgres",
+        "--",
+        "env",
+        "PGPASSWORD=p@ss word's",
+        "psql",
+        "-c",
+        "select '$1'",
+      ])
+    );
+  }
+
+  #[test]
+  fn su_gets_the_command_as_one_script() {
+    let mut env = BTreeMap::new();
+    env.insert("A".to_string(), "$(id)".to_string());
+    let args: Vec<String> = std::iter::once("%s\\0".to_string()).chain(strings(AWKWARD)).collect();
+    let cmd = Cmd {
+      run_as: Some("app".to_string()),
+      env,
+      ..Cmd::argv("printf".to_string(), args)
+    };
+    let script = ssh().set_escalation(Escalation::Su).remote_script(&cmd);
+
+    let words = words_of(&script);
+    assert_eq!(words[..5], strings(&["su", "app", "-s", "/bin/sh", "-c"])[..]);
+    assert_eq!(words.len(), 6);
+
+    // The script su hands to /bin/sh still has every word intact
+    let expected: Vec<String> = strings(&["env", "A=$(id)", "printf", "%s\\0"])
+      .into_iter()
+      .chain(strings(AWKWARD))
+      .collect();
+    assert_eq!(words_of(&words[5]), expected);
+  }
+}
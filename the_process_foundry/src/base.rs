@@ -7,12 +7,19 @@
 //!   - Actionable: Possibly part of app trait, since all should be able to utilize and emit Actions/Events
 //!   - ActionResult: To abstract the result so we can pass it to something that has the code to actually use
 //!   - Routable (possibly part of action)
-//!   - consider https://abronan.com/rust-trait-objects-box-and-rc/ - Arc<Mutex<impl trait>>
 
 use anyhow::{Context, Result};
+use async_trait::async_trait;
 use serde_derive::{Deserialize, Serialize};
-use std::rc::Rc;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
+pub use tokio_util::sync::CancellationToken;
+
+use super::applications::shell::PosixShell;
+use super::config::FoundryConfig;
 use super::Bash;
 use super::FoundryError;
 
@@ -20,7 +27,7 @@ use super::FoundryError;
 pub trait AppTrait {
   /// Construct the metadata for the module controlling the app instance
   /// This is important to make sure the module aligns with the actual installed instance
-  fn build(instance: AppInstance, parent: Option<Rc<dyn ContainerTrait>>) -> Result<Self>
+  fn build(instance: AppInstance, parent: Option<Arc<dyn ContainerTrait>>) -> Result<Self>
   where
     Self: Sized;
 
@@ -35,15 +42,29 @@ pub trait AppTrait {
   fn set_cli(
     &self,
     instance: AppInstance,
-    container: Rc<dyn ContainerTrait>,
+    container: Arc<dyn ContainerTrait>,
   ) -> Result<AppInstance>;
 }
 
 /// Ways to manage applications (eg Docker, Bash) contained within itself
-pub trait ContainerTrait: std::fmt::Debug {
+///
+/// The async versions of find and forward call the sync ones unless the container has something better to do,
+/// like running a local process without blocking. Containers that do should make the sync version a block_on
+/// of the async one. The sync ones are run with block_in_place, so they only hold up other tasks on a single
+/// threaded runtime; containers that can be cloned can use spawn_blocking instead to avoid even that.
+///
+/// Containers are shared between threads, so they can't change once built. Setters return an updated copy
+/// instead, and anything that has to be shared and changed later needs its own lock.
+#[async_trait]
+pub trait ContainerTrait: std::fmt::Debug + Send + Sync {
   /// This will find a list of apps with configurations that the container knows about
   fn find(&self, query: AppQuery) -> Result<Vec<AppInstance>>;
 
+  /// Find without blocking
+  async fn find_async(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
+    block_in_place(|| self.find(query))
+  }
+
   /// Find a unique app that matches the query
   fn find_one(&self, query: AppQuery) -> Result<AppInstance> {
     let all = self.find(query.clone())?;
@@ -52,7 +73,7 @@ pub trait ContainerTrait: std::fmt::Debug {
           "No instances matching your query of {} have been registered",
           query.name
       )),
-      1 => Ok(all.get(0).unwrap().clone()),
+      1 => Ok(all[0].clone()),
       x => Err(FoundryError::MultipleMatches).context(format!(
           "{} instances matching your defition for {} have been registered. Please narrow your search criteria",
           x, query.name
@@ -63,6 +84,20 @@ pub trait ContainerTrait: std::fmt::Debug {
   /// Send a stringified action to the AppInstance
   fn forward(&self, to: AppInstance, message: Message) -> Result<String>;
 
+  /// Forward without blocking. Dropping the future cancels the message, killing any local process it started.
+  async fn forward_async(&self, to: AppInstance, message: Message) -> Result<String> {
+    block_in_place(|| self.forward(to, message))
+  }
+
+  /// The command to start on the local machine so the given one runs inside this container (eg: wrapped in
+  /// ssh). This is for apps that need to manage the process themselves rather than just get its output.
+  fn local_command(&self, _cmd: Cmd) -> Result<Cmd> {
+    Err(FoundryError::NotConfigured).context(format!(
+      "{} cannot start commands from the local machine",
+      self.get_name()
+    ))
+  }
+
   /// List the known items in the app cache
   fn cached_apps(&self) -> Result<Vec<AppInstance>>;
 
@@ -167,7 +202,7 @@ impl AppInstance {
 
   pub fn set_command_path(
     &self,
-    container: Option<Rc<dyn ContainerTrait>>,
+    container: Option<Arc<dyn ContainerTrait>>,
     path: String,
   ) -> Result<AppInstance> {
     let cli = self.cli.clone().map_or(
@@ -213,9 +248,7 @@ impl std::fmt::Debug for AppInstance {
         .cli
         .clone()
         .map_or("None".to_string(), |cli| cli.path.clone()),
-      self.api.clone().map_or("None", |_net| {
-        "Networking not implemented yet for AppInstance"
-      }),
+      self.api.clone().map_or("None".to_string(), |api| format!("{:?}", api)),
     )
   }
 }
@@ -230,7 +263,7 @@ impl std::fmt::Display for AppInstance {
 pub struct CliAccess {
   /// The container where this App is found
   #[serde(skip)]
-  pub container: Option<Rc<dyn ContainerTrait>>,
+  pub container: Option<Arc<dyn ContainerTrait>>,
 
   /// The location of the executable
   pub path: String,
@@ -239,49 +272,208 @@ pub struct CliAccess {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApiAccess {
   pub uri: String,
+
+  /// How to log in to the API, if it needs it
+  #[serde(default)]
+  pub auth: Option<ApiAuth>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl ApiAccess {
+  pub fn new(uri: String) -> ApiAccess {
+    ApiAccess { uri, auth: None }
+  }
+
+  pub fn auth(&self, auth: ApiAuth) -> ApiAccess {
+    ApiAccess {
+      auth: Some(auth),
+      ..self.clone()
+    }
+  }
+}
+
+/// Credentials sent with every request to an API
+#[derive(Clone, Serialize, Deserialize)]
+pub enum ApiAuth {
+  /// HTTP basic auth
+  Basic { username: String, password: String },
+  /// An "Authorization: Bearer <token>" header
+  Bearer(String),
+  /// A key sent in a header of its own (eg: X-API-Key)
+  Header { name: String, value: String },
+}
+
+/// Keep the secrets out of the logs
+impl std::fmt::Debug for ApiAuth {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ApiAuth::Basic { username, .. } => write!(f, "Basic({}, ********)", username),
+      ApiAuth::Bearer(_) => write!(f, "Bearer(********)"),
+      ApiAuth::Header { name, .. } => write!(f, "Header({}: ********)", name),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ShellType {
-  Dash,
+  Ash,
   Bash,
+  Dash,
+  Sh,
   Zsh,
 }
 
+impl ShellType {
+  /// The order to look for shells in when there isn't a preference. Alpine/busybox images only have ash/sh.
+  pub const PROBE_ORDER: [ShellType; 4] = [ShellType::Bash, ShellType::Sh, ShellType::Ash, ShellType::Dash];
+
+  /// Match the name or path of a shell executable (eg: "/bin/bash")
+  pub fn from_name(name: &str) -> Option<ShellType> {
+    let executable = name.rsplit('/').next().unwrap_or(name).to_lowercase();
+    match &executable[..] {
+      "ash" => Some(ShellType::Ash),
+      "bash" => Some(ShellType::Bash),
+      "dash" => Some(ShellType::Dash),
+      "sh" => Some(ShellType::Sh),
+      "zsh" => Some(ShellType::Zsh),
+      _ => None,
+    }
+  }
+
+  /// The executable to run, which is expected to be on the path
+  pub fn get_executable(&self) -> String {
+    match self {
+      ShellType::Ash => "ash",
+      ShellType::Bash => "bash",
+      ShellType::Dash => "dash",
+      ShellType::Sh => "sh",
+      ShellType::Zsh => "zsh",
+    }
+    .to_string()
+  }
+
+  /// Run a script with this shell. Any values put into the script need to go through quote first.
+  pub fn script(&self, script: String) -> Cmd {
+    Cmd {
+      command: self.get_executable(),
+      args: vec!["-c".to_string(), script],
+      ..Default::default()
+    }
+  }
+
+  /// Quote a value so the shell treats it as a single literal word
+  ///
+  /// Everything inside single quotes is literal in all the POSIX shells, so the only special case is a single
+  /// quote itself, which has to be closed, escaped and reopened: 'it'\''s'. Words are left bare when they
+  /// can't be expanded, which depends on the shell: zsh replaces a word starting with "=" with the path of
+  /// the command (=ls is /bin/ls). "~" is always quoted.
+  ///
+  /// Fish isn't one of the shells, since backslashes are escapes inside its single quotes.
+  pub fn quote(&self, value: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    let is_expanded = match self {
+      ShellType::Zsh => value.starts_with('='),
+      ShellType::Ash | ShellType::Bash | ShellType::Dash | ShellType::Sh => false,
+    };
+    match !value.is_empty() && !is_expanded && value.chars().all(is_safe) {
+      true => value.to_string(),
+      false => format!("'{}'", value.replace('\'', r"'\''")),
+    }
+  }
+
+  /// Quote each word and join them into a single command line
+  pub fn join(&self, words: &[String]) -> String {
+    words
+      .iter()
+      .map(|word| self.quote(word))
+      .collect::<Vec<String>>()
+      .join(" ")
+  }
+
+  /// The script to look up the path of an executable, ignoring aliases and functions where the shell lets
+  /// us. "command -v" is the POSIX fallback, but it returns just the name for builtins.
+  pub fn lookup(&self, name: &str) -> String {
+    let name = self.quote(name);
+    match self {
+      ShellType::Bash => format!("type -P {}", name),
+      ShellType::Zsh => format!("whence -p {}", name),
+      _ => format!("command -v {}", name),
+    }
+  }
+
+  /// Look up the path of an executable
+  pub fn find_app(&self, name: &str) -> Cmd {
+    self.script(self.lookup(name))
+  }
+
+  /// Set a variable for the rest of the script
+  pub fn export(&self, key: &str, value: &str) -> Result<String> {
+    let is_valid = !key.is_empty()
+      && !key.starts_with(|c: char| c.is_ascii_digit())
+      && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match is_valid {
+      true => Ok(format!("export {}={}", key, self.quote(value))),
+      false => Err(FoundryError::ConfigurationError)
+        .context(format!("'{}' is not a valid environment variable name", key)),
+    }
+  }
+
+  /// A command that does nothing, used to check the shell exists
+  pub fn probe(&self) -> Cmd {
+    self.script("exit 0".to_string())
+  }
+
+  /// Kill every process with RUN_TAG set to the tag in its environment. Children inherit the environment, so
+  /// this gets everything the tagged command started too. It reads /proc, so only works on Linux.
+  pub fn kill_tagged(&self, tag: &str) -> Cmd {
+    self.script(format!(
+      r#"for proc in /proc/[0-9]*; do if tr '\0' '\n' 2>/dev/null < "$proc/environ" | grep -qx {}; then kill -KILL "${{proc#/proc/}}" 2>/dev/null; fi; done; exit 0"#,
+      self.quote(&format!("{}={}", RUN_TAG, tag))
+    ))
+  }
+}
+
 /// A special case for bootstrapping. I'm trying to find the enumerations that actually deserve to be
 /// traits themselves
 #[derive(Debug, Clone)]
 pub struct Shell {
   pub shell_type: ShellType,
   pub instance: AppInstance,
-  pub running: Rc<dyn ContainerTrait>,
+  pub running: Arc<dyn ContainerTrait>,
 }
 
+/// Set this to the name or path of a shell to use it instead of the user's login shell. The shell in the
+/// foundry config takes precedence over this.
+pub const SHELL_OVERRIDE_ENV: &str = "FOUNDRY_SHELL";
+
 impl Shell {
+  /// The shell the user running the foundry would get
   pub fn get_local_shell() -> Result<Shell> {
-    // Bash seems to be on most systems, so we'll prefer that
-    let default = "bash";
-    let shell_name = option_env!("SHELL").map_or(default, |cmd| {
-      let regex = regex::Regex::new(r"/([\w-]+)$").unwrap();
-      regex.captures(&cmd).map_or(default, |cap| {
-        cap.get(1).map_or(default, |val| val.as_str())
-      })
-    });
+    Shell::get_shell(None)
+  }
 
-    let shell_type = match &shell_name.to_lowercase()[..] {
-      "bash" => ShellType::Bash,
-      x => {
-        log::warn!("Found preferred shell is '{}', but using bash anyways", x);
-        ShellType::Bash
-      }
-    };
+  /// Use the preferred shell if given. Otherwise the first usable one from the foundry config,
+  /// FOUNDRY_SHELL, SHELL, or the user's passwd entry, falling back to bash.
+  pub fn get_shell(preferred: Option<String>) -> Result<Shell> {
+    let config = FoundryConfig::load().context("Could not read the foundry config to choose a shell")?;
+    let candidates = vec![
+      ("the preference", preferred),
+      ("the foundry config", config.shell),
+      (SHELL_OVERRIDE_ENV, std::env::var(SHELL_OVERRIDE_ENV).ok()),
+      ("SHELL", std::env::var("SHELL").ok()),
+      ("/etc/passwd", Shell::get_passwd_shell()),
+    ];
+    let (shell_type, path) = Shell::choose(candidates);
 
-    let (instance, running) = match shell_type {
+    let (instance, running): (AppInstance, Arc<dyn ContainerTrait>) = match shell_type {
       ShellType::Bash => {
-        let instance = Bash::get_local().context("Could not get a local Bash shell")?;
-        (instance.clone(), Rc::new(Bash::build(instance, None)?))
+        let instance = Bash::get_local_at(&path).context("Could not get a local Bash shell")?;
+        (instance.clone(), Arc::new(Bash::build(instance, None)?))
+      }
+      x => {
+        let instance = PosixShell::get_local_at(x, &path)
+          .context(format!("Could not get a local {:?} shell", x))?;
+        (instance.clone(), Arc::new(PosixShell::build(instance, None)?))
       }
-      _ => unreachable!("Should currently not be able to use any local shell other than bash"),
     };
 
     Ok(Shell {
@@ -290,15 +482,488 @@ impl Shell {
       running,
     })
   }
+
+  /// The first (source, name or path) candidate that is a POSIX shell we know how to drive
+  fn choose(candidates: Vec<(&str, Option<String>)>) -> (ShellType, String) {
+    for (source, value) in candidates {
+      let path = match value {
+        Some(x) if !x.trim().is_empty() => x.trim().to_string(),
+        _ => continue,
+      };
+      match ShellType::from_name(&path) {
+        Some(shell_type) => {
+          log::debug!("Using the shell '{}' from {}", path, source);
+          return (shell_type, path);
+        }
+        None => log::warn!(
+          "The shell from {} is '{}', which isn't a POSIX shell. Skipping it",
+          source,
+          path
+        ),
+      }
+    }
+
+    // Bash seems to be on most systems, so we'll prefer that
+    (ShellType::Bash, "bash".to_string())
+  }
+
+  /// The login shell of the current user, matching on the uid since USER can be stale (eg: with sudo)
+  fn get_passwd_shell() -> Option<String> {
+    get_passwd_entry().map(|fields| fields[6].clone())
+  }
+}
+
+/// The real uid of the foundry process. Linux only, but so is /etc/passwd being the whole story.
+pub fn get_current_uid() -> Option<String> {
+  std::fs::read_to_string("/proc/self/status")
+    .ok()
+    .and_then(|status| {
+      status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))
+        .and_then(|ids| ids.split_whitespace().next().map(|x| x.to_string()))
+    })
+}
+
+/// The fields of the /etc/passwd line for the current user
+pub fn get_passwd_entry() -> Option<Vec<String>> {
+  let passwd = std::fs::read_to_string("/etc/passwd").ok()?;
+  let uid = get_current_uid();
+  let user = std::env::var("USER").or_else(|_| std::env::var("LOGNAME")).ok();
+
+  let entries: Vec<Vec<String>> = passwd
+    .lines()
+    .map(|line| line.split(':').map(|x| x.to_string()).collect::<Vec<String>>())
+    .filter(|fields| fields.len() == 7)
+    .collect();
+  entries
+    .iter()
+    .find(|fields| uid.as_deref() == Some(&fields[2][..]))
+    .or_else(|| entries.iter().find(|fields| user.as_deref() == Some(&fields[0][..])))
+    .cloned()
+}
+
+/// How to run a local command as a different user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Escalation {
+  /// sudo -n -u <user> -- <cmd>: Needs a sudoers entry that doesn't ask for a password
+  Sudo,
+  /// runuser -u <user> -- <cmd>: Never prompts, but only works when we are already root
+  Runuser,
+  /// su <user> -s /bin/sh -c '<cmd>': Asks for the user's password unless we are root
+  Su,
+}
+
+impl Escalation {
+  /// Root can switch users without a password using runuser (or su on systems without it). Everyone else
+  /// has to go through sudo.
+  pub fn detect() -> Escalation {
+    match get_current_uid().as_deref() {
+      Some("0") => match std::path::Path::new("/sbin/runuser").exists()
+        || std::path::Path::new("/usr/sbin/runuser").exists()
+      {
+        true => Escalation::Runuser,
+        false => Escalation::Su,
+      },
+      _ => Escalation::Sudo,
+    }
+  }
+
+  /// Wrap the command so it runs as the user. If we already are that user, it is left alone.
+  pub fn wrap(&self, user: &str, cmd: &Cmd) -> Cmd {
+    if get_passwd_entry().is_some_and(|fields| fields[0] == user) {
+      return Cmd {
+        run_as: None,
+        ..cmd.clone()
+      };
+    }
+
+    self.switch_user(user, cmd)
+  }
+
+  /// Wrap the command so it runs as the user, without checking who we are. This is for commands run
+  /// somewhere else (eg: over ssh), where the local user doesn't matter.
+  pub fn switch_user(&self, user: &str, cmd: &Cmd) -> Cmd {
+    // sudo and friends reset the environment, so the variables are set by env after switching users
+    let mut words = cmd.words();
+    let (command, prefix) = match self {
+      // -n makes sudo fail instead of asking for a password
+      Escalation::Sudo => ("sudo", vec!["-n", "-u", user, "--"]),
+      Escalation::Runuser => ("runuser", vec!["-u", user, "--"]),
+      // su only takes a script, and the user's login shell may not be POSIX (or may be nologin)
+      Escalation::Su => {
+        words = vec![ShellType::Sh.join(&words)];
+        ("su", vec![user, "-s", "/bin/sh", "-c"])
+      }
+    };
+    Cmd {
+      workdir: cmd.workdir.clone(),
+      escalated: Some((*self, user.to_string())),
+      ..Cmd::argv(
+        command.to_string(),
+        prefix.into_iter().map(|x| x.to_string()).chain(words).collect(),
+      )
+    }
+    .inherit_limits(cmd)
+  }
+
+  /// Check the error output for signs it wanted to ask for a password. We never give it a terminal, so it
+  /// fails instead of hanging, but the messages differ for each tool.
+  pub fn needs_password(&self, stderr: &str) -> bool {
+    let lower = stderr.to_lowercase();
+    match self {
+      Escalation::Sudo => lower.contains("a password is required") || lower.contains("a terminal is required"),
+      Escalation::Runuser => lower.contains("may not be used by non-root users"),
+      Escalation::Su => {
+        lower.contains("authentication failure")
+          || lower.contains("must be run from a terminal")
+          || lower.contains("password:")
+      }
+    }
+  }
 }
 
 // THINK: This is very specific to forwarding to shell and is more like a script. Does this belong with
 //        the future workflows?
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Cmd {
   pub run_as: Option<String>,
   pub command: String,
   pub args: Vec<String>,
+  /// Extra variables to set for the command, on top of the environment it is run in
+  #[serde(default)]
+  pub env: BTreeMap<String, String>,
+  /// The directory to run the command in, otherwise wherever the container starts it
+  #[serde(default)]
+  pub workdir: Option<String>,
+  /// Give up on the command if it runs for longer than this, killing whatever it started
+  #[serde(default)]
+  pub timeout: Option<Duration>,
+  /// Lets the caller stop the command early. Tokens can't be sent to another node, only the timeout is.
+  #[serde(skip)]
+  pub cancel: Option<CancellationToken>,
+  /// How the command was wrapped to run as another user, so what it leaves behind can be killed as them
+  #[serde(skip)]
+  pub escalated: Option<(Escalation, String)>,
+}
+
+/// The variable set on commands we may need to kill later, so they can be found in a container
+pub const RUN_TAG: &str = "FOUNDRY_RUN_ID";
+
+/// How long cleaning up after an interrupted command can take
+pub const KILL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long an interrupted command gets to exit after SIGTERM before it is killed outright
+pub const TERM_GRACE: Duration = Duration::from_secs(2);
+
+impl Cmd {
+  /// Run the program directly with these arguments. Nothing goes through a shell, so the arguments don't
+  /// need quoting and can't be used to inject commands. Prefer this unless shell features are needed.
+  pub fn argv(command: String, args: Vec<String>) -> Cmd {
+    Cmd {
+      command,
+      args,
+      ..Default::default()
+    }
+  }
+
+  /// The command and its arguments, run through env if it has variables to set (eg: env A=1 echo hi)
+  pub fn words(&self) -> Vec<String> {
+    let mut words = vec![];
+    if !self.env.is_empty() {
+      words.push("env".to_string());
+      words.extend(self.env.iter().map(|(key, value)| format!("{}={}", key, value)));
+    }
+    words.push(self.command.clone());
+    words.extend(self.args.clone());
+    words
+  }
+
+  /// The command as a single line for the shell, with every word quoted
+  pub fn to_script(&self, shell: ShellType) -> String {
+    let words: Vec<String> = std::iter::once(self.command.clone())
+      .chain(self.args.clone())
+      .collect();
+    shell.join(&words)
+  }
+
+  /// Run the same command through "<shell> -c", for when it needs things only the shell can do
+  pub fn in_shell(&self, shell: ShellType) -> Cmd {
+    Cmd {
+      run_as: self.run_as.clone(),
+      env: self.env.clone(),
+      workdir: self.workdir.clone(),
+      ..shell.script(self.to_script(shell))
+    }
+    .inherit_limits(self)
+  }
+
+  pub fn timeout(&self, timeout: Duration) -> Cmd {
+    Cmd {
+      timeout: Some(timeout),
+      ..self.clone()
+    }
+  }
+
+  /// Stop the command when the token is cancelled
+  pub fn cancel_on(&self, token: CancellationToken) -> Cmd {
+    Cmd {
+      cancel: Some(token),
+      ..self.clone()
+    }
+  }
+
+  /// Give a command that wraps another (eg: in sudo or ssh) the same timeout and cancellation, and the same
+  /// way of killing it if it is still escalated
+  pub fn inherit_limits(&self, cmd: &Cmd) -> Cmd {
+    Cmd {
+      timeout: cmd.timeout,
+      cancel: cmd.cancel.clone(),
+      escalated: self.escalated.clone().or_else(|| cmd.escalated.clone()),
+      ..self.clone()
+    }
+  }
+
+  pub fn is_limited(&self) -> bool {
+    self.timeout.is_some() || self.cancel.is_some()
+  }
+
+  /// Wait for the future until the command's timeout runs out or it is cancelled, whichever comes first. The
+  /// future is dropped if we give up on it, so whatever it started needs to clean up after itself.
+  pub async fn limit<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+    let timeout = async {
+      match self.timeout {
+        Some(x) => tokio::time::sleep(x).await,
+        None => std::future::pending().await,
+      }
+    };
+    let cancelled = async {
+      match &self.cancel {
+        Some(token) => token.cancelled().await,
+        None => std::future::pending().await,
+      }
+    };
+    tokio::select! {
+      result = future => result,
+      _ = timeout => Err(FoundryError::Timeout).context(format!(
+        "'{}' was stopped after running for {:?}",
+        self.to_script(ShellType::Sh),
+        self.timeout.unwrap_or_default()
+      )),
+      _ = cancelled => Err(FoundryError::Cancelled)
+        .context(format!("'{}' was cancelled", self.to_script(ShellType::Sh))),
+    }
+  }
+
+  /// Run a command that is going somewhere we can't kill it directly, like inside a container. If it is limited,
+  /// it gets RUN_TAG set so that if it times out or is cancelled, a kill_tagged can be run the same way (and as
+  /// the same user) to clean up everything it started. Stopping the client (eg: docker compose exec) on its own
+  /// would leave it running there.
+  pub async fn run_tagged<T, F, Fut>(&self, run: F) -> Result<T>
+  where
+    F: Fn(Cmd) -> Fut,
+    Fut: Future<Output = Result<T>>,
+  {
+    if !self.is_limited() {
+      return run(self.clone()).await;
+    }
+
+    let tag = uuid::Uuid::new_v4().to_string();
+    let mut tagged = self.clone();
+    tagged.env.insert(RUN_TAG.to_string(), tag.clone());
+    let result = run(tagged).await;
+    if let Err(err) = &result {
+      if FoundryError::is_interrupted(err) {
+        let kill = Cmd {
+          run_as: self.run_as.clone(),
+          ..ShellType::Sh.kill_tagged(&tag)
+        }
+        .timeout(KILL_TIMEOUT);
+        if let Err(kill_err) = run(kill).await {
+          log::warn!(
+            "Could not kill what was left of '{}':\n{:#}",
+            self.to_script(ShellType::Sh),
+            kill_err
+          );
+        }
+      }
+    }
+    result
+  }
+
+  /// Run the program on the local machine and wait for it to finish. run_as is left to the caller, since only
+  /// it knows how to switch users. Nothing can answer a prompt, so stdin is closed to make them fail rather than
+  /// hang, and the process is killed if the future is dropped before it finishes.
+  ///
+  /// A limited command gets its own process group, so anything it started is stopped along with it when it
+  /// times out or is cancelled: SIGTERM, then SIGKILL after TERM_GRACE. One wrapped by an Escalation is
+  /// killed through that escalation, since we can't signal another user's processes ourselves.
+  pub async fn output(&self) -> Result<std::process::Output> {
+    let mut command = tokio::process::Command::new(&self.command);
+    command
+      .args(&self.args)
+      .envs(&self.env)
+      .stdin(std::process::Stdio::null())
+      // spawn inherits these unless told otherwise, unlike Command::output
+      .stdout(std::process::Stdio::piped())
+      .stderr(std::process::Stdio::piped())
+      // Limited commands are stopped as a group by the GroupKill instead, since a SIGKILL here would only kill
+      // sudo, leaving the command it started running
+      .kill_on_drop(!self.is_limited());
+    if let Some(workdir) = &self.workdir {
+      command.current_dir(workdir);
+    }
+    if self.is_limited() {
+      command.process_group(0);
+    }
+    let child = command
+      .spawn()
+      .context(format!("Could not start '{}'", self.command))?;
+
+    let mut guard = GroupKill {
+      group: child.id().filter(|_| self.is_limited()),
+      escalated: self.escalated.clone(),
+    };
+    let result = self
+      .limit(async { Ok(child.wait_with_output().await?) })
+      .await;
+    match &result {
+      Err(err) if FoundryError::is_interrupted(err) => (),
+      // It finished on its own, so whatever it left running is meant to be
+      _ => guard.group = None,
+    }
+    result
+  }
+}
+
+/// Stops the process group of a command that was given up on, including when the future running it is dropped
+struct GroupKill {
+  /// Nothing is killed if this is empty
+  group: Option<u32>,
+  escalated: Option<(Escalation, String)>,
+}
+
+impl Drop for GroupKill {
+  /// This can wait for the grace period, so it is done on a thread of its own
+  fn drop(&mut self) {
+    if let Some(group) = self.group {
+      let escalated = self.escalated.take();
+      std::thread::spawn(move || kill_group(group, escalated));
+    }
+  }
+}
+
+/// Kill everything left in the process group. SIGTERM goes first, since sudo passes it on to the command, then
+/// SIGKILL for anything still there after the grace period. We can't signal processes running as another user,
+/// so for an escalated command that SIGKILL is sent through the same escalation.
+fn kill_group(group: u32, escalated: Option<(Escalation, String)>) {
+  let group = group as libc::pid_t;
+  if !signal_group(group, libc::SIGTERM) {
+    return;
+  }
+  let deadline = std::time::Instant::now() + TERM_GRACE;
+  while std::time::Instant::now() < deadline {
+    std::thread::sleep(Duration::from_millis(50));
+    if !signal_group(group, 0) {
+      return;
+    }
+  }
+
+  let (escalation, user) = match escalated {
+    Some(x) => x,
+    None => {
+      signal_group(group, libc::SIGKILL);
+      return;
+    }
+  };
+  let kill = escalation.switch_user(
+    &user,
+    &Cmd::argv(
+      "kill".to_string(),
+      vec!["-KILL".to_string(), "--".to_string(), format!("-{}", group)],
+    ),
+  );
+  match std::process::Command::new(&kill.command)
+    .args(&kill.args)
+    .stdin(std::process::Stdio::null())
+    .output()
+  {
+    Ok(output) if output.status.success() => (),
+    // Everything exited between the last check and now
+    Ok(output) if String::from_utf8_lossy(&output.stderr).contains("No such process") => (),
+    Ok(output) => log::warn!(
+      "Could not kill process group {} as {}:\n{}",
+      group,
+      user,
+      String::from_utf8_lossy(&output.stderr).trim_end()
+    ),
+    Err(err) => log::warn!("Could not run '{}' to kill process group {}: {}", kill.command, group, err),
+  }
+}
+
+/// Send the signal to the process group, returning whether anything is left in it. Signal 0 only checks.
+fn signal_group(group: libc::pid_t, signal: libc::c_int) -> bool {
+  // SAFETY: killpg only reads its arguments
+  if unsafe { libc::killpg(group, signal) } == 0 {
+    return true;
+  }
+  let err = std::io::Error::last_os_error();
+  match err.raw_os_error() {
+    Some(libc::ESRCH) => false,
+    // Someone we can't signal is still there, such as sudo's command running as another user
+    Some(libc::EPERM) => {
+      log::debug!("Not allowed to signal everything in process group {}: {}", group, err);
+      true
+    }
+    _ => {
+      log::warn!("Could not signal process group {}: {}", group, err);
+      false
+    }
+  }
+}
+
+/// Shared by every block_on outside of async code, so each call doesn't start its own threads
+static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+
+/// Wait for an async function from sync code. This is what the sync versions of the async functions use.
+///
+/// Sync code that is itself called from async code (eg: a container that only has a sync forward) works on a
+/// multi-threaded runtime, but blocks one of its threads while it waits. It can't work on a single threaded
+/// one, so that is an error rather than the deadlock it would cause.
+pub fn block_on<T>(future: impl Future<Output = Result<T>>) -> Result<T> {
+  if let Ok(handle) = tokio::runtime::Handle::try_current() {
+    return match handle.runtime_flavor() {
+      tokio::runtime::RuntimeFlavor::CurrentThread => Err(FoundryError::NotConfigured).context(
+        "A sync function was called from async code on a single threaded runtime. Use the async version or \
+         a multi-threaded runtime",
+      ),
+      _ => tokio::task::block_in_place(|| handle.block_on(future)),
+    };
+  }
+
+  let runtime = match RUNTIME.get() {
+    Some(x) => x,
+    None => {
+      let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_name("foundry")
+        .build()
+        .context("Could not start the async runtime")?;
+      // If another thread got there first, theirs is used and this one is dropped
+      let _ = RUNTIME.set(runtime);
+      RUNTIME.get().unwrap()
+    }
+  };
+  runtime.block_on(future)
+}
+
+/// Run sync code that blocks from async code. A multi-threaded runtime moves its other tasks off this thread
+/// while it waits, but a single threaded one has nowhere to move them, so they wait too.
+pub fn block_in_place<T>(f: impl FnOnce() -> T) -> T {
+  match tokio::runtime::Handle::try_current().map(|handle| handle.runtime_flavor()) {
+    Ok(tokio::runtime::RuntimeFlavor::MultiThread) => tokio::task::block_in_place(f),
+    _ => f(),
+  }
 }
 
 ///  A generic message designed to be sent to a container
@@ -307,22 +972,124 @@ pub enum Message {
   /// For use in a remote shell, like one contained within a docker container
   Command(Cmd),
 
-  /// Build an Rpc call
-  Rpc,
+  /// A request for another foundry node, sent as is rather than wrapped in a Forward
+  Rpc(Box<RpcRequest>),
 
   /// Call a Restful API
-  Rest,
+  Rest(RestRequest),
+}
+
+/// What one foundry node can ask another to do. Every connection starts with a Hello, so the nodes can agree on
+/// a protocol and check their modules will understand each other.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RpcRequest {
+  Hello {
+    /// The newest protocol the caller speaks
+    protocol: u32,
+    /// The version of the caller's foundry
+    module_version: semver::Version,
+    /// The shared secret the remote node was started with, if it needs one
+    #[serde(default)]
+    auth: Option<ApiAuth>,
+  },
+  /// Run find on the remote node's container
+  Find(AppQuery),
+  /// Send the message to an app the remote node found
+  Forward { to: Box<AppInstance>, message: Message },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HttpMethod {
+  Get,
+  Post,
+  Put,
+  Patch,
+  Delete,
+  Head,
+}
+
+impl HttpMethod {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      HttpMethod::Get => "GET",
+      HttpMethod::Post => "POST",
+      HttpMethod::Put => "PUT",
+      HttpMethod::Patch => "PATCH",
+      HttpMethod::Delete => "DELETE",
+      HttpMethod::Head => "HEAD",
+    }
+  }
+}
+
+/// A call to a REST API. The path is relative to the uri in the app's ApiAccess.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestRequest {
+  pub method: HttpMethod,
+  pub path: String,
+  #[serde(default)]
+  pub headers: BTreeMap<String, String>,
+  pub body: Option<String>,
+}
+
+impl RestRequest {
+  pub fn new(method: HttpMethod, path: String) -> RestRequest {
+    RestRequest {
+      method,
+      path,
+      headers: BTreeMap::new(),
+      body: None,
+    }
+  }
+
+  pub fn get(path: String) -> RestRequest {
+    RestRequest::new(HttpMethod::Get, path)
+  }
+
+  pub fn post(path: String) -> RestRequest {
+    RestRequest::new(HttpMethod::Post, path)
+  }
+
+  pub fn header(&self, key: String, value: String) -> RestRequest {
+    let mut headers = self.headers.clone();
+    headers.insert(key, value);
+    RestRequest {
+      headers,
+      ..self.clone()
+    }
+  }
+
+  pub fn body(&self, body: String) -> RestRequest {
+    RestRequest {
+      body: Some(body),
+      ..self.clone()
+    }
+  }
+
+  /// Send the value as a json body
+  pub fn json<T: serde::Serialize>(&self, value: &T) -> Result<RestRequest> {
+    Ok(
+      self
+        .header("Content-Type".to_string(), "application/json".to_string())
+        .body(serde_json::to_string(value)?),
+    )
+  }
 }
 
 /// Handlers for serialized action requests
 ///
 /// THINK: Should there be a send/receive?
+#[async_trait]
 pub trait ActionTrait {
   type RESPONSE;
 
   // Have the application directly run the function run the command and return the result
   fn run(&self, target: AppInstance) -> Result<Self::RESPONSE>;
 
+  // The same as run, without blocking. Actions that start processes override this and make run a block_on.
+  async fn run_async(&self, target: AppInstance) -> Result<Self::RESPONSE> {
+    self.run(target)
+  }
+
   // Convert this action into a std::process::Command style vector to be run in a place where the
   // foundry cannot directly access (like inside docker container)
   // THINK: Should run just naturally use this when the target is remote?
@@ -331,3 +1098,460 @@ pub trait ActionTrait {
   // This is a long term goal, be able to generate stand-alone scripts based on the container actions
   // fn to_file(&self, application: Box<dyn AppTrait>) -> Result<String, Error>;
 }
+
+#[cfg(test)]
+mod tests {
+  use super::super::applications::{Http, KubectlPod, Ssh};
+  use super::*;
+
+  const HOSTILE: &[&str] = &[
+    "",
+    "plain",
+    "two words",
+    "it's",
+    "'",
+    "''\\''",
+    "\"double\"",
+    "back\\slash\\",
+    "$HOME",
+    "${HOME}",
+    "$(id)",
+    "`id`",
+    "; rm -rf /tmp/x",
+    "a && b || c | d &",
+    "*",
+    "?.txt",
+    "[abc]",
+    "{a,b}",
+    "~",
+    "~root/x",
+    "a=~/x",
+    "=ls",
+    "-n",
+    "!!",
+    "%1",
+    "#comment",
+    "tab\there",
+    "new\nline",
+    "trailing\n",
+    "ünïcödé",
+    "<in >out 2>&1",
+  ];
+
+  fn installed(shell: ShellType) -> bool {
+    std::process::Command::new(shell.get_executable())
+      .args(["-c", "exit 0"])
+      .output()
+      .is_ok_and(|x| x.status.success())
+  }
+
+  /// Have the shell print each word on its own, NUL separated, to check it got back exactly what we quoted
+  fn round_trip(shell: ShellType, words: &[&str]) -> Vec<String> {
+    let words: Vec<String> = words.iter().map(|x| x.to_string()).collect();
+    let output = std::process::Command::new(shell.get_executable())
+      .args(["-c", &format!("printf '%s\\0' {}", shell.join(&words))])
+      .env("HOME", "/expanded/home")
+      .output()
+      .unwrap();
+    assert!(output.status.success(), "{:?}: {}", shell, String::from_utf8_lossy(&output.stderr));
+    let mut printed: Vec<String> = String::from_utf8(output.stdout)
+      .unwrap()
+      .split('\0')
+      .map(|x| x.to_string())
+      .collect();
+    printed.pop();
+    printed
+  }
+
+  /// Whether the process is still alive. Zombies count as gone, since there may be no init to reap them.
+  fn is_running(pid: &str) -> bool {
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+      Ok(stat) => !stat.rsplit(')').next().unwrap_or_default().trim_start().starts_with('Z'),
+      Err(_) => false,
+    }
+  }
+
+  /// Run a script that starts a background sleep and waits, returning the pid of that sleep once it is
+  /// supposed to have been killed
+  fn interrupt(name: &str, setup: &str, limit: impl Fn(Cmd) -> Cmd) -> String {
+    let pidfile = std::env::temp_dir().join(format!("foundry-{}-{}.pid", name, std::process::id()));
+    let _ = std::fs::remove_file(&pidfile);
+    let script = format!(
+      "{} sleep 60 & echo $! > {}; wait",
+      setup,
+      ShellType::Sh.quote(pidfile.to_str().unwrap())
+    );
+    let err = block_on(limit(ShellType::Sh.script(script)).output()).unwrap_err();
+    assert!(FoundryError::is_interrupted(&err), "{:#}", err);
+
+    let pid = std::fs::read_to_string(&pidfile).unwrap().trim().to_string();
+    let _ = std::fs::remove_file(&pidfile);
+    pid
+  }
+
+  fn wait_until_gone(pid: &str) {
+    let deadline = std::time::Instant::now() + TERM_GRACE + Duration::from_secs(5);
+    while is_running(pid) {
+      assert!(std::time::Instant::now() < deadline, "{} is still running", pid);
+      std::thread::sleep(Duration::from_millis(50));
+    }
+  }
+
+  #[test]
+  fn timed_out_commands_leave_no_children() {
+    let pid = interrupt("timeout", "", |cmd| cmd.timeout(Duration::from_millis(300)));
+    wait_until_gone(&pid);
+  }
+
+  #[test]
+  fn cancelled_commands_that_ignore_term_are_killed() {
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    std::thread::spawn(move || {
+      std::thread::sleep(Duration::from_millis(300));
+      cancel.cancel();
+    });
+    // Ignoring SIGTERM carries over to the sleep, so only the SIGKILL after the grace period stops it
+    let pid = interrupt("cancel", "trap '' TERM;", |cmd| cmd.cancel_on(token.clone()));
+    wait_until_gone(&pid);
+  }
+
+  #[test]
+  fn escalated_commands_are_killed_as_their_user() {
+    // Only root can switch users without a password
+    if get_current_uid().as_deref() != Some("0") || Escalation::detect() != Escalation::Runuser {
+      return;
+    }
+    let pid = interrupt("escalated", "trap '' TERM;", |cmd| {
+      Escalation::Runuser
+        .wrap("nobody", &cmd)
+        .timeout(Duration::from_millis(300))
+    });
+    wait_until_gone(&pid);
+  }
+
+  #[test]
+  fn output_is_captured_with_or_without_limits() {
+    let cmd = ShellType::Sh.script("echo out; echo err >&2".to_string());
+    for cmd in [cmd.clone(), cmd.timeout(Duration::from_secs(10))] {
+      let output = block_on(cmd.output()).unwrap();
+      assert_eq!(output.stdout, b"out\n");
+      assert_eq!(output.stderr, b"err\n");
+    }
+  }
+
+  #[test]
+  fn the_config_shell_wins_over_the_env_fallback() {
+    let chosen = Shell::choose(vec![
+      ("the preference", None),
+      ("the foundry config", Some("/bin/dash".to_string())),
+      (SHELL_OVERRIDE_ENV, Some("/bin/bash".to_string())),
+    ]);
+    assert_eq!(chosen, (ShellType::Dash, "/bin/dash".to_string()));
+
+    // Unusable and blank entries fall through to the next source
+    let chosen = Shell::choose(vec![
+      ("the foundry config", Some("/usr/bin/fish".to_string())),
+      (SHELL_OVERRIDE_ENV, Some("  ".to_string())),
+      ("SHELL", Some("/bin/sh".to_string())),
+    ]);
+    assert_eq!(chosen, (ShellType::Sh, "/bin/sh".to_string()));
+
+    assert_eq!(Shell::choose(vec![]), (ShellType::Bash, "bash".to_string()));
+  }
+
+  #[test]
+  fn quoted_words_come_back_unchanged() {
+    let mut tested = vec![];
+    for shell in [ShellType::Sh, ShellType::Bash, ShellType::Dash, ShellType::Ash, ShellType::Zsh] {
+      if installed(shell) {
+        assert_eq!(round_trip(shell, HOSTILE), HOSTILE, "{:?}", shell);
+        tested.push(shell);
+      }
+    }
+    assert!(!tested.is_empty(), "No shells to test with");
+  }
+
+  #[test]
+  fn only_words_that_cant_expand_are_left_bare() {
+    for shell in [ShellType::Sh, ShellType::Bash, ShellType::Dash, ShellType::Ash, ShellType::Zsh] {
+      assert_eq!(shell.quote("/usr/bin/pg_dump"), "/usr/bin/pg_dump");
+      assert_eq!(shell.quote("key=value"), "key=value");
+      assert_eq!(shell.quote(""), "''");
+      assert_eq!(shell.quote("it's"), "'it'\\''s'");
+      assert_eq!(shell.quote("~"), "'~'");
+      assert_eq!(shell.quote("$(id)"), "'$(id)'");
+    }
+    for shell in [ShellType::Sh, ShellType::Bash, ShellType::Dash, ShellType::Ash] {
+      assert_eq!(shell.quote("=ls"), "=ls");
+    }
+    assert_eq!(ShellType::Zsh.quote("=ls"), "'=ls'");
+    assert_eq!(ShellType::Zsh.quote("a=ls"), "a=ls");
+  }
+
+  #[test]
+  fn fish_is_not_treated_as_posix() {
+    assert_eq!(ShellType::from_name("/usr/bin/fish"), None);
+    assert_eq!(ShellType::from_name("/bin/zsh"), Some(ShellType::Zsh));
+  }
+
+  fn stdout(cmd: Cmd) -> Vec<String> {
+    let output = block_on(cmd.output()).unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let mut printed: Vec<String> = String::from_utf8(output.stdout)
+      .unwrap()
+      .split('\0')
+      .map(|x| x.to_string())
+      .collect();
+    printed.pop();
+    printed
+  }
+
+  #[test]
+  fn plain_commands_never_see_a_shell() {
+    let args = std::iter::once("%s\\0").chain(HOSTILE.iter().copied()).map(|x| x.to_string());
+    let cmd = Cmd::argv("printf".to_string(), args.collect());
+    assert_eq!(stdout(cmd.clone()), HOSTILE);
+
+    // The same words survive being put through a shell script
+    let scripted = cmd.in_shell(ShellType::Sh);
+    assert_eq!(scripted.command, "sh");
+    assert_eq!(scripted.args[0], "-c");
+    assert_eq!(stdout(scripted), HOSTILE);
+  }
+
+  #[test]
+  fn shell_commands_keep_their_settings() {
+    let cmd = Cmd {
+      run_as: Some("postgres".to_string()),
+      workdir: Some("/tmp".to_string()),
+      ..Cmd::argv("pwd".to_string(), vec![])
+    }
+    .timeout(Duration::from_secs(5));
+    let scripted = cmd.in_shell(ShellType::Bash);
+    assert_eq!(scripted.args, vec!["-c", "pwd"]);
+    assert_eq!(scripted.run_as, Some("postgres".to_string()));
+    assert_eq!(scripted.workdir, Some("/tmp".to_string()));
+    assert_eq!(scripted.timeout, Some(Duration::from_secs(5)));
+  }
+
+  #[test]
+  fn lookups_and_exports_are_quoted() {
+    assert_eq!(ShellType::Bash.lookup("pg dump"), "type -P 'pg dump'");
+    assert_eq!(ShellType::Zsh.lookup("psql"), "whence -p psql");
+    assert_eq!(ShellType::Dash.lookup("$(id)"), "command -v '$(id)'");
+
+    let export = ShellType::Sh.export("PGDATA", "/var/lib/my data").unwrap();
+    assert_eq!(export, "export PGDATA='/var/lib/my data'");
+    for key in ["", "1ABC", "A-B", "A;rm -rf /", "A B"] {
+      let err = ShellType::Sh.export(key, "x").unwrap_err();
+      assert!(matches!(err.downcast_ref::<FoundryError>(), Some(FoundryError::ConfigurationError)), "{}", key);
+    }
+  }
+
+  #[test]
+  fn escalation_switches_users_before_setting_the_env() {
+    let cmd = Cmd {
+      env: vec![("PGDATA".to_string(), "/var/lib/my data".to_string())].into_iter().collect(),
+      workdir: Some("/tmp".to_string()),
+      ..Cmd::argv("pg_ctl".to_string(), vec!["status".to_string()])
+    }
+    .timeout(Duration::from_secs(5));
+
+    let sudo = Escalation::Sudo.switch_user("postgres", &cmd);
+    assert_eq!(sudo.command, "sudo");
+    assert_eq!(
+      sudo.args,
+      vec!["-n", "-u", "postgres", "--", "env", "PGDATA=/var/lib/my data", "pg_ctl", "status"]
+    );
+    assert_eq!(sudo.workdir, Some("/tmp".to_string()));
+    assert_eq!(sudo.timeout, Some(Duration::from_secs(5)));
+    assert_eq!(sudo.escalated, Some((Escalation::Sudo, "postgres".to_string())));
+    assert!(sudo.env.is_empty() && sudo.run_as.is_none());
+
+    let runuser = Escalation::Runuser.switch_user("postgres", &cmd);
+    assert_eq!(runuser.command, "runuser");
+    assert_eq!(runuser.args[..3], ["-u", "postgres", "--"]);
+
+    // su takes a single script, which has to give back the same words
+    let words = HOSTILE.iter().map(|x| x.to_string()).collect();
+    let su = Escalation::Su.switch_user("postgres", &Cmd::argv("printf".to_string(), words));
+    assert_eq!(su.command, "su");
+    assert_eq!(su.args[..4], ["postgres", "-s", "/bin/sh", "-c"]);
+    let script = format!("set -- {}; shift; printf '%s\\0' \"$@\"", su.args[4]);
+    assert_eq!(stdout(ShellType::Sh.script(script)), HOSTILE);
+  }
+
+  #[test]
+  fn wrapping_for_the_current_user_changes_nothing() {
+    let me = match get_passwd_entry() {
+      Some(fields) => fields[0].clone(),
+      None => return,
+    };
+    let cmd = Cmd {
+      run_as: Some(me.clone()),
+      ..Cmd::argv("id".to_string(), vec![])
+    };
+    let wrapped = Escalation::Sudo.wrap(&me, &cmd);
+    assert_eq!(wrapped.command, "id");
+    assert_eq!(wrapped.run_as, None);
+    assert_eq!(wrapped.escalated, None);
+  }
+
+  #[test]
+  fn root_runs_commands_as_other_users() {
+    if get_current_uid().as_deref() != Some("0") || Escalation::detect() != Escalation::Runuser {
+      return;
+    }
+    let cmd = Escalation::detect().wrap("nobody", &Cmd::argv("id".to_string(), vec!["-un".to_string()]));
+    let output = block_on(cmd.output()).unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "nobody");
+  }
+
+  #[test]
+  fn password_prompts_are_recognized() {
+    assert!(Escalation::Sudo.needs_password("sudo: a password is required\n"));
+    assert!(Escalation::Sudo.needs_password("sudo: a terminal is required to read the password"));
+    assert!(Escalation::Runuser.needs_password("runuser: may not be used by non-root users"));
+    assert!(Escalation::Su.needs_password("Password: su: Authentication failure"));
+    assert!(Escalation::Su.needs_password("su: must be run from a terminal"));
+
+    assert!(!Escalation::Sudo.needs_password("sudo: unknown user postgres"));
+    assert!(!Escalation::Su.needs_password("pg_ctl: no server running"));
+  }
+
+  /// Only has the sync versions, so it gets the default async ones
+  #[derive(Debug)]
+  struct SyncOnly;
+
+  impl ContainerTrait for SyncOnly {
+    fn find(&self, query: AppQuery) -> Result<Vec<AppInstance>> {
+      Ok(vec![AppInstance::new(query.name)])
+    }
+
+    fn forward(&self, to: AppInstance, _message: Message) -> Result<String> {
+      std::thread::sleep(Duration::from_millis(300));
+      Ok(to.name)
+    }
+
+    fn cached_apps(&self) -> Result<Vec<AppInstance>> {
+      Ok(vec![])
+    }
+
+    fn get_name(&self) -> String {
+      "Sync Only".to_string()
+    }
+  }
+
+  fn echo(word: &str) -> Message {
+    Message::Command(Cmd::argv("echo".to_string(), vec![word.to_string()]))
+  }
+
+  #[tokio::test]
+  async fn sync_code_on_a_single_threaded_runtime() {
+    let err = block_on(async { Ok(()) }).unwrap_err();
+    assert!(matches!(err.downcast_ref::<FoundryError>(), Some(FoundryError::NotConfigured)), "{:#}", err);
+    let sh = PosixShell::build(AppInstance::new("sh".to_string()), None).unwrap();
+    assert!(sh.forward(AppInstance::new("sh".to_string()), echo("hello")).is_err());
+
+    // The default async versions still work, they just hold up the runtime while they run
+    let container: Arc<dyn ContainerTrait> = Arc::new(SyncOnly);
+    let found = container.find_async(AppQuery::new("psql".to_string())).await.unwrap();
+    assert_eq!(found[0].name, "psql");
+    let output = container.forward_async(AppInstance::new("db".to_string()), echo("hello")).await;
+    assert_eq!(output.unwrap(), "db");
+  }
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+  async fn sync_code_on_a_multi_threaded_runtime() {
+    let sh = PosixShell::build(AppInstance::new("sh".to_string()), None).unwrap();
+    let output = sh.forward(AppInstance::new("sh".to_string()), echo("hello")).unwrap();
+    assert_eq!(output.trim_end(), "hello");
+
+    // The only worker is given up while the sync forward blocks, so the other task still gets to run
+    let container: Arc<dyn ContainerTrait> = Arc::new(SyncOnly);
+    let blocking = tokio::spawn(async move {
+      container
+        .forward_async(AppInstance::new("db".to_string()), echo("hello"))
+        .await
+    });
+    let start = std::time::Instant::now();
+    let other = tokio::spawn(async move {
+      tokio::time::sleep(Duration::from_millis(50)).await;
+      start.elapsed()
+    });
+    assert_eq!(blocking.await.unwrap().unwrap(), "db");
+    let other = other.await.unwrap();
+    assert!(other < Duration::from_millis(250), "The other task waited {:?}", other);
+  }
+
+  fn shared_shell() -> Arc<dyn ContainerTrait> {
+    Arc::new(PosixShell::build(AppInstance::new("sh".to_string()), None).unwrap())
+  }
+
+  #[test]
+  fn containers_can_be_shared_between_threads() {
+    let sh = shared_shell();
+    let threads: Vec<_> = (0..8)
+      .map(|idx| {
+        let sh = sh.clone();
+        std::thread::spawn(move || sh.forward(AppInstance::new("sh".to_string()), echo(&idx.to_string())))
+      })
+      .collect();
+    for (idx, thread) in threads.into_iter().enumerate() {
+      assert_eq!(thread.join().unwrap().unwrap().trim_end(), idx.to_string());
+    }
+  }
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+  async fn containers_can_be_shared_between_tasks() {
+    let sh = shared_shell();
+    let tasks: Vec<_> = (0..8)
+      .map(|idx| {
+        let sh = sh.clone();
+        tokio::spawn(async move {
+          sh.forward_async(AppInstance::new("sh".to_string()), echo(&idx.to_string()))
+            .await
+        })
+      })
+      .collect();
+    for (idx, task) in tasks.into_iter().enumerate() {
+      assert_eq!(task.await.unwrap().unwrap().trim_end(), idx.to_string());
+    }
+  }
+
+  /// Call the parts of an app that aren't written yet
+  fn unfinished<T: AppTrait + ContainerTrait + 'static>(app: T) -> Vec<Result<()>> {
+    let app = Arc::new(app);
+    vec![
+      app.cached_apps().map(|_| ()),
+      app.set_cli(AppInstance::new("cli".to_string()), app.clone()).map(|_| ()),
+    ]
+  }
+
+  #[test]
+  fn unfinished_features_are_errors_not_panics() {
+    let remote = AppInstance {
+      instance_id: Some("db.example.com".to_string()),
+      ..AppInstance::new("remote".to_string())
+    };
+    let apps = vec![
+      ("Ssh", unfinished(Ssh::build(remote.clone(), None).unwrap())),
+      ("KubectlPod", unfinished(KubectlPod::build(remote.clone(), None).unwrap())),
+      ("Http", unfinished(Http::build(remote, None).unwrap())),
+    ];
+
+    for (name, results) in apps {
+      for (idx, result) in results.into_iter().enumerate() {
+        let err = result.err().unwrap_or_else(|| panic!("{} #{} should not work yet", name, idx));
+        assert!(
+          matches!(err.downcast_ref::<FoundryError>(), Some(FoundryError::NotImplemented)),
+          "{} #{}: {:#}",
+          name,
+          idx,
+          err
+        );
+      }
+    }
+  }
+}
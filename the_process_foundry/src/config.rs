@@ -0,0 +1,94 @@
+//! Settings for the foundry itself
+//!
+//! These live in `<config dir>/the_process_foundry/config.yml` (eg: ~/.config on Linux), or wherever
+//! FOUNDRY_CONFIG points. Every setting is optional, so a missing file is the same as an empty one.
+
+use anyhow::{Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use super::FoundryError;
+
+/// Set this to the path of a config file to use it instead of the default location
+pub const CONFIG_PATH_ENV: &str = "FOUNDRY_CONFIG";
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FoundryConfig {
+  /// The name or path of the shell to run local commands with, instead of the user's login shell
+  pub shell: Option<String>,
+}
+
+impl FoundryConfig {
+  /// Read the config from its usual location
+  pub fn load() -> Result<FoundryConfig> {
+    match FoundryConfig::get_path() {
+      Some(path) => FoundryConfig::load_from(&path),
+      None => Ok(FoundryConfig::default()),
+    }
+  }
+
+  /// Read the config at the given path, using the defaults if there is no file there
+  pub fn load_from(path: &PathBuf) -> Result<FoundryConfig> {
+    if !path.exists() {
+      log::debug!("No foundry config at {}. Using the defaults", path.display());
+      return Ok(FoundryConfig::default());
+    }
+
+    let contents = std::fs::read_to_string(path)
+      .context(format!("Failed to open the foundry config at {}", path.display()))?;
+    FoundryConfig::parse(&contents, &path.display().to_string())
+  }
+
+  /// Parse the contents of a config file. An empty file is valid and uses all the defaults
+  pub fn parse(contents: &str, source: &str) -> Result<FoundryConfig> {
+    // serde_yaml can't parse a document that is only comments
+    let is_empty = contents
+      .lines()
+      .all(|line| line.trim().is_empty() || line.trim_start().starts_with('#'));
+    if is_empty {
+      return Ok(FoundryConfig::default());
+    }
+
+    serde_yaml::from_str(contents)
+      .map_err(|err| anyhow::Error::new(FoundryError::ConfigurationError).context(err.to_string()))
+      .context(format!("'{}' is not a valid foundry config", source))
+  }
+
+  /// FOUNDRY_CONFIG if it is set, otherwise the default location in the user's config dir
+  pub fn get_path() -> Option<PathBuf> {
+    match std::env::var(CONFIG_PATH_ENV) {
+      Ok(path) if !path.trim().is_empty() => Some(PathBuf::from(path.trim())),
+      _ => dirs::config_dir().map(|dir| dir.join("the_process_foundry").join("config.yml")),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_the_shell() {
+    let config = FoundryConfig::parse("shell: /bin/dash\n", "test").unwrap();
+    assert_eq!(config.shell, Some("/bin/dash".to_string()));
+  }
+
+  #[test]
+  fn empty_and_missing_files_use_the_defaults() {
+    assert_eq!(FoundryConfig::parse("", "test").unwrap(), FoundryConfig::default());
+    assert_eq!(FoundryConfig::parse("# nothing yet\n", "test").unwrap(), FoundryConfig::default());
+
+    let missing = std::env::temp_dir().join(format!("foundry-missing-{}.yml", std::process::id()));
+    assert_eq!(FoundryConfig::load_from(&missing).unwrap(), FoundryConfig::default());
+  }
+
+  #[test]
+  fn unknown_settings_are_a_configuration_error() {
+    let err = FoundryConfig::parse("sehll: /bin/dash\n", "test").unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<FoundryError>(),
+      Some(FoundryError::ConfigurationError)
+    ));
+  }
+}
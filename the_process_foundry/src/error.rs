@@ -7,6 +7,9 @@ use thiserror::Error;
 
 #[derive(Debug, Clone, Error, Deserialize, Serialize)]
 pub enum FoundryError {
+  #[error("The command was cancelled before it finished")]
+  Cancelled,
+
   #[error("There was an error attempting to convert from one type to another")]
   ConversionError,
 
@@ -28,9 +31,15 @@ pub enum FoundryError {
   #[error("The item you were looking for was not found")]
   NotFound,
 
+  #[error("The module doesn't support this yet")]
+  NotImplemented,
+
   #[error("The command sent to the container caused an error")]
   RemoteError,
 
+  #[error("The command did not finish before its deadline")]
+  Timeout,
+
   #[error("The value received doesn't appear to match the expected format")]
   UnexpectedValue,
 
@@ -44,6 +53,16 @@ pub enum FoundryError {
   YamlError,
 }
 
+impl FoundryError {
+  /// Whether we gave up on the command (Timeout or Cancelled), rather than it failing on its own
+  pub fn is_interrupted(err: &anyhow::Error) -> bool {
+    matches!(
+      err.downcast_ref::<FoundryError>(),
+      Some(FoundryError::Timeout | FoundryError::Cancelled)
+    )
+  }
+}
+
 impl From<std::num::ParseIntError> for FoundryError {
   fn from(err: std::num::ParseIntError) -> FoundryError {
     log::warn!("Received Parse Int Error:\n{:#?}", err);
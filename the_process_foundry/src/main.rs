@@ -16,16 +16,17 @@ use error::FoundryError;
 
 pub mod applications;
 pub mod base;
+pub mod config;
 pub mod error;
 // pub mod helpers;
 // pub mod registry;
 
-pub use std::rc::Rc;
+pub use std::sync::Arc;
 
 use applications::{Bash, DockerCompose, PgBaseBackup};
 use base::*;
 
-pub fn find(container: Rc<dyn ContainerTrait>, app_name: String) -> Result<AppInstance> {
+pub fn find(container: Arc<dyn ContainerTrait>, app_name: String) -> Result<AppInstance> {
     let query = AppQuery::new(app_name.clone());
     let instance = container
         .find_one(query)
@@ -35,15 +36,32 @@ pub fn find(container: Rc<dyn ContainerTrait>, app_name: String) -> Result<AppIn
 
 #[derive(Debug, Clone, Default)]
 pub struct VolumeQuery {
+    service: String,
+    /// The name of a named volume or the host path of a bind mount
     name: Option<String>,
+    /// Where the volume is mounted inside the container
     location: Option<String>,
 }
 
 pub fn get_or_create_volume(
-    _compose: Rc<DockerCompose>,
-    _query: VolumeQuery,
+    compose: Arc<DockerCompose>,
+    query: VolumeQuery,
 ) -> Result<Vec<applications::docker_compose::schema::ServiceVolume>> {
-    unimplemented!()
+    let found: Vec<_> = compose
+        .get_volumes(query.service.clone())?
+        .into_iter()
+        .filter(|vol| query.name.is_none() || vol.get_source() == query.name)
+        .filter(|vol| query.location.is_none() || vol.get_target() == query.location)
+        .collect();
+
+    match found.len() {
+        // TODO: Add the volume to the compose config and write it back out
+        0 => Err(FoundryError::NotFound).context(format!(
+            "No volume matching {:#?} was found and creating them isn't implemented yet",
+            query
+        )),
+        _ => Ok(found),
+    }
 }
 
 pub fn bootstrap() -> Result<()> {
@@ -51,15 +69,15 @@ pub fn bootstrap() -> Result<()> {
     let shell = base::Shell::get_local_shell()
         .context("Oh noes, my bootstrap failed to get a local shell")?;
 
-    let bash = Rc::new(
+    let bash = Arc::new(
         Bash::build(shell.instance.clone(), None)
             .context("Building bash from the local shell didn't work")?,
     );
 
     // Find Docker Compose using local bash and load the test compose file
-    let dc = Rc::new(
+    let dc = Arc::new(
         DockerCompose::build(
-            find(bash.clone(), "docker-compose".to_string())?,
+            DockerCompose::find_cli(bash.clone(), None)?,
             Some(bash.clone())
         )?
         .load("/home/dfogelson/Foundry/TheProcessFoundry/the_process_foundry/tests/data/postgres.docker-compose.yml".to_string())?);
@@ -68,7 +86,7 @@ pub fn bootstrap() -> Result<()> {
 
     // Find postgres container
     let pg_service = find(dc.clone(), "postgres".to_string())?;
-    let pg_container = Rc::new(dc.get_container(pg_service.name)?);
+    let pg_container = Arc::new(dc.get_container(pg_service.name)?);
 
     // TODO: Is the container running? Start if not
 
@@ -84,13 +102,56 @@ pub fn bootstrap() -> Result<()> {
     Ok(())
 }
 
+/// Compare two compose files, or a compose file against what is currently running
+///
+/// Usage:
+///   the_process_foundry diff <old.yml> <new.yml>
+///   the_process_foundry diff --running <docker-compose.yml>
+///
+/// Returns true if there are any differences
+pub fn diff_compose(args: &[String]) -> Result<bool> {
+    let usage = "Usage: the_process_foundry diff <old.yml> <new.yml> | diff --running <docker-compose.yml>";
+    let changes = match args {
+        [flag, file] if flag == "--running" => {
+            let shell = base::Shell::get_local_shell()?;
+            let bash = Arc::new(Bash::build(shell.instance.clone(), None)?);
+            DockerCompose::build(DockerCompose::find_cli(bash.clone(), None)?, Some(bash))?
+            .load(file.clone())?
+            .diff_running()?
+        }
+        [old, new] => {
+            let compose =
+                DockerCompose::build(AppInstance::new("docker-compose".to_string()), None)?;
+            let old_conf = compose.load(old.clone())?.get_config()?;
+            let new_conf = compose.load(new.clone())?.get_config()?;
+            applications::docker_compose::diff::diff(&old_conf, &new_conf)
+        }
+        _ => Err(FoundryError::ConfigurationError).context(usage)?,
+    };
+
+    println!("{}", applications::docker_compose::diff::render(&changes));
+    Ok(!changes.is_empty())
+}
+
 fn main() {
     env_logger::init();
+
+    let args: Vec<String> = std::env::args().collect();
+    if let Some("diff") = args.get(1).map(|x| x.as_str()) {
+        match diff_compose(&args[2..]) {
+            Ok(false) => std::process::exit(0),
+            Ok(true) => std::process::exit(1),
+            Err(err) => {
+                log::error!("Failed to diff the compose files:\n{:#}", err);
+                std::process::exit(2)
+            }
+        }
+    }
+
     log::info!("Starting to run the Process foundry");
 
     // Convert "bootstrap" into workflow ()
     // Change "Bash" to "Shell" with a language type of Bash
-    // Make containers Async
     // Register each type of container/app factory?
     // Make instance registry for routing - Container should have "register_known_children" function
     // Make workflow for postgres backup